@@ -1,21 +1,14 @@
-use std::path::PathBuf;
-
-use criterion::{criterion_group, criterion_main, BatchSize::SmallInput, Criterion};
-use kvs::{BitcaskEngine, KvsEngine, Result};
-use rand::{seq::IteratorRandom, thread_rng};
+use criterion::{
+    criterion_group, criterion_main, BatchSize::SmallInput, BenchmarkId, Criterion, Throughput,
+};
+use kvs::{BitcaskEngine, BitcaskOptions, EntryFormat, KvsEngine};
+use rand::{seq::IteratorRandom, thread_rng, Rng};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
-use walkdir::WalkDir;
-
-fn get_dir_size(path: PathBuf) -> Result<u64> {
-    let entries = WalkDir::new(path).into_iter();
-    let len: walkdir::Result<u64> = entries
-        .map(|res| {
-            res.and_then(|entry| entry.metadata())
-                .map(|metadata| metadata.len())
-        })
-        .sum();
-    Ok(len.unwrap())
-}
 
 fn write_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("write");
@@ -35,8 +28,6 @@ fn write_benchmark(c: &mut Criterion) {
                         .set(format!("key{}", i), format!("value{}", i))
                         .expect("unable to write KvStore");
                 }
-                // let size = get_dir_size(path).unwrap();
-                // println!("dir size: {}", size);
             },
             SmallInput,
         )
@@ -130,5 +121,290 @@ fn read_benchmark(c: &mut Criterion) {
     group.finish()
 }
 
-criterion_group!(benches, write_benchmark, read_benchmark);
+fn concurrent_increment_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("increment");
+    let thread_count = 8;
+    let keys_per_thread = 1000;
+    group.bench_function("kvs_distinct_keys", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = BitcaskEngine::open(temp_dir.path()).expect("unable to init KvStore");
+                (store, temp_dir)
+            },
+            |(store, _temp_dir)| {
+                let handles: Vec<_> = (0..thread_count)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for i in 0..keys_per_thread {
+                                store
+                                    .increment(format!("key{}_{}", thread_id, i), 1)
+                                    .expect("unable to increment KvStore");
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            },
+            SmallInput,
+        )
+    });
+    group.finish()
+}
+
+/// Shape of the mixed benchmark, override with `KVS_BENCH_THREADS` and `KVS_BENCH_READ_PERCENT`
+#[derive(Clone, Copy)]
+struct MixedWorkload {
+    threads: usize,
+    read_percent: u32,
+    keys: usize,
+    ops_per_thread: usize,
+}
+
+impl MixedWorkload {
+    fn from_env() -> Self {
+        let var = |name: &str, default| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        MixedWorkload {
+            threads: var("KVS_BENCH_THREADS", 8),
+            read_percent: var("KVS_BENCH_READ_PERCENT", 90) as u32,
+            keys: 1000,
+            ops_per_thread: 1000,
+        }
+    }
+
+    fn ops(&self) -> u64 {
+        (self.threads * self.ops_per_thread) as u64
+    }
+
+    /// Run one round of the workload on all threads and return the latency of every operation
+    fn run<S, G, W>(&self, store: &S, get: G, set: W) -> Vec<Duration>
+    where
+        S: Clone + Send + 'static,
+        G: Fn(&S, String) + Copy + Send + 'static,
+        W: Fn(&S, String, String) + Copy + Send + 'static,
+    {
+        let workload = *self;
+        let handles: Vec<_> = (0..workload.threads)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let mut rng = thread_rng();
+                    let mut latencies = Vec::with_capacity(workload.ops_per_thread);
+                    for _ in 0..workload.ops_per_thread {
+                        let key = format!("key{}", rng.gen_range(0..workload.keys));
+                        let start = Instant::now();
+                        if rng.gen_range(0..100) < workload.read_percent {
+                            get(&store, key);
+                        } else {
+                            set(&store, key, format!("value{}", rng.gen::<u32>()));
+                        }
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    }
+}
+
+fn report_latencies(name: &str, mut latencies: Vec<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{}: p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+        name,
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1]
+    );
+}
+
+fn mixed_benchmark(c: &mut Criterion) {
+    let workload = MixedWorkload::from_env();
+    let mut group = c.benchmark_group("mixed");
+    group.throughput(Throughput::Elements(workload.ops()));
+    let parameter = format!("{}threads_{}read", workload.threads, workload.read_percent);
+
+    let mut latencies = Vec::new();
+    group.bench_function(BenchmarkId::new("kvs", &parameter), |b| {
+        b.iter_custom(|iters| {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = BitcaskEngine::open(temp_dir.path()).expect("unable to init KvStore");
+            for i in 0..workload.keys {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .expect("unable to write KvStore");
+            }
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                latencies.extend(workload.run(
+                    &store,
+                    |store: &BitcaskEngine, key| {
+                        store.get(key).expect("unable to read KvStore");
+                    },
+                    |store: &BitcaskEngine, key, value| {
+                        store.set(key, value).expect("unable to write KvStore");
+                    },
+                ));
+                elapsed += start.elapsed();
+            }
+            elapsed
+        })
+    });
+    report_latencies(&format!("mixed/kvs/{}", parameter), latencies);
+
+    let mut latencies = Vec::new();
+    group.bench_function(BenchmarkId::new("sled", &parameter), |b| {
+        b.iter_custom(|iters| {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = sled::open(temp_dir.path()).expect("unable to init SledKvsEngine");
+            for i in 0..workload.keys {
+                store
+                    .insert(format!("key{}", i), format!("value{}", i).as_bytes())
+                    .expect("unable to write SledKvsEngine");
+            }
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                latencies.extend(workload.run(
+                    &store,
+                    |store: &sled::Db, key| {
+                        store.get(key).expect("unable to read SledKvsEngine");
+                    },
+                    |store: &sled::Db, key, value| {
+                        store
+                            .insert(key, value.as_bytes())
+                            .expect("unable to write SledKvsEngine");
+                    },
+                ));
+                elapsed += start.elapsed();
+            }
+            elapsed
+        })
+    });
+    report_latencies(&format!("mixed/sled/{}", parameter), latencies);
+    group.finish()
+}
+
+fn bulk_load_benchmark(c: &mut Criterion) {
+    const KEYS: usize = 1_000_000;
+    let mut group = c.benchmark_group("load");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let entries = || (0..KEYS).map(|i| (format!("key{}", i), format!("value{}", i)));
+    group.bench_function("bulk_load", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = BitcaskEngine::open(temp_dir.path()).expect("unable to init KvStore");
+                (store, temp_dir)
+            },
+            |(store, _temp_dir)| {
+                store
+                    .bulk_load(entries())
+                    .expect("unable to bulk load KvStore");
+            },
+            SmallInput,
+        )
+    });
+    group.bench_function("set", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                let store = BitcaskEngine::open(temp_dir.path()).expect("unable to init KvStore");
+                (store, temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for (key, value) in entries() {
+                    store.set(key, value).expect("unable to write KvStore");
+                }
+            },
+            SmallInput,
+        )
+    });
+    group.finish()
+}
+
+// Total bytes of the `.log` files in `dir`
+fn log_files_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .map(|entry| entry.expect("unable to list the store directory").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).map_or(0, |metadata| metadata.len()))
+        .sum()
+}
+
+fn entry_format_benchmark(c: &mut Criterion) {
+    const KEYS: usize = 100_000;
+    let mut group = c.benchmark_group("entry_format");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for (name, entry_format) in [
+        ("fixed", EntryFormat::Fixed),
+        ("varint", EntryFormat::Varint),
+    ] {
+        let options = BitcaskOptions {
+            entry_format,
+            ..BitcaskOptions::default()
+        };
+        let open = || {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())
+                .expect("unable to init KvStore");
+            (store, temp_dir)
+        };
+        // small records, where the header is a large part of each entry
+        let write = |store: &BitcaskEngine| {
+            for i in 0..KEYS {
+                store
+                    .set(format!("k{}", i), format!("v{}", i))
+                    .expect("unable to write KvStore");
+            }
+        };
+        let (store, temp_dir) = open();
+        write(&store);
+        drop(store);
+        println!(
+            "entry_format/{}: {} bytes of log files for {} small records",
+            name,
+            log_files_size(temp_dir.path()),
+            KEYS
+        );
+        group.bench_function(name, |b| {
+            b.iter_batched(open, |(store, _temp_dir)| write(&store), SmallInput)
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(
+    benches,
+    write_benchmark,
+    read_benchmark,
+    concurrent_increment_benchmark,
+    mixed_benchmark,
+    bulk_load_benchmark,
+    entry_format_benchmark
+);
 criterion_main!(benches);
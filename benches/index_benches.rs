@@ -0,0 +1,88 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{BitcaskEngine, BitcaskOptions, IndexKind, KvsEngine};
+use rand::{thread_rng, Rng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+// Counts the bytes currently allocated, to tell how much memory the index takes
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const LOOKUPS: usize = 10000;
+
+// How many keys to load, `INDEX_KEYS` overrides the default of 5M
+fn key_count() -> usize {
+    env::var("INDEX_KEYS")
+        .ok()
+        .and_then(|keys| keys.parse().ok())
+        .unwrap_or(5_000_000)
+}
+
+fn index_benchmark(c: &mut Criterion) {
+    let keys = key_count();
+    let mut group = c.benchmark_group("index");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(LOOKUPS as u64));
+    for (name, index_kind) in [
+        ("dashmap", IndexKind::DashMap),
+        ("sharded_hashmap", IndexKind::ShardedHashMap),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            index_kind,
+            ..Default::default()
+        };
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)
+            .expect("unable to init KvStore");
+        store
+            .bulk_load((0..keys).map(|i| (format!("key{}", i), format!("value{}", i))))
+            .expect("unable to bulk load KvStore");
+        println!(
+            "index/{}: {} bytes for {} keys",
+            name,
+            ALLOCATED.load(Ordering::Relaxed) - before,
+            keys
+        );
+
+        let mut rng = thread_rng();
+        let lookups: Vec<String> = (0..LOOKUPS)
+            .map(|_| format!("key{}", rng.gen_range(0..keys)))
+            .collect();
+        group.bench_function(BenchmarkId::new("get", name), |b| {
+            b.iter(|| {
+                for key in &lookups {
+                    store.get(key.clone()).expect("unable to read KvStore");
+                }
+            })
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(benches, index_benchmark);
+criterion_main!(benches);
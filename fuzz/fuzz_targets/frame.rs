@@ -0,0 +1,18 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use kvs::Frame;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes read the way `Connection` reads them, only errors may come out
+fuzz_target!(|bytes: &[u8]| {
+    let mut buf = Cursor::new(bytes);
+    if Frame::check(&mut buf).is_ok() {
+        buf.set_position(0);
+        if let Ok(frame) = Frame::parse(&mut buf) {
+            let _ = frame.decompress();
+        }
+    }
+    let _ = Frame::parse(&mut Cursor::new(bytes));
+});
@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
 
 use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
 use kvs::Client;
 use log::info;
 use tokio::net::TcpStream;
@@ -23,6 +24,12 @@ enum Commands {
     Get { key: String },
     #[clap(arg_required_else_help = true, name = "rm")]
     Remove { key: String },
+    #[clap(name = "pause-compaction")]
+    PauseCompaction,
+    #[clap(name = "resume-compaction")]
+    ResumeCompaction,
+    #[clap(name = "keys")]
+    Keys,
 }
 
 #[tokio::main]
@@ -53,5 +60,28 @@ async fn main() {
                 println!("Remove key: {} success!", key);
             }
         }
+        Commands::PauseCompaction => match client.pause_compaction().await {
+            Ok(_) => println!("Pause compaction success!"),
+            Err(err) => eprintln!("Pause compaction error: {}", err),
+        },
+        Commands::ResumeCompaction => match client.resume_compaction().await {
+            Ok(_) => println!("Resume compaction success!"),
+            Err(err) => eprintln!("Resume compaction error: {}", err),
+        },
+        Commands::Keys => match client.keys().await {
+            Ok(keys) => {
+                let mut keys = Box::pin(keys);
+                while let Some(key) = keys.next().await {
+                    match key {
+                        Ok(key) => println!("{}", key),
+                        Err(err) => {
+                            eprintln!("List keys error: {}", err);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => eprintln!("List keys error: {}", err),
+        },
     }
 }
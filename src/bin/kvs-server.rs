@@ -1,14 +1,20 @@
 use clap::{Parser, ValueEnum};
-use kvs::{BitcaskEngine, Server};
+use kvs::{
+    AccessList, BitcaskEngine, BitcaskOptions, KvsEngine, Result, ServerBuilder, SledEngine,
+};
 use log::{error, info};
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process,
+    sync::Arc,
+};
 use tokio::net::TcpListener;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:13131";
 const DEFAULT_ENGIN: &str = "kvs";
 
-const DEFAULT_PATH: &str = "/Users/lcy/kvs";
-
 #[derive(Parser, Debug)]
 #[clap(
     name = "kvs-server",
@@ -21,6 +27,12 @@ struct Cli {
     address: SocketAddr,
     #[clap(long = "engine", name = "ENGINE", required = false, value_enum, default_value = DEFAULT_ENGIN, value_enum)]
     engin: Engine,
+    /// Serve only clients from this address, can be repeated
+    #[clap(long = "allow", name = "ALLOWED_IP", conflicts_with = "DENIED_IP")]
+    allow: Vec<IpAddr>,
+    /// Refuse clients from this address, can be repeated
+    #[clap(long = "deny", name = "DENIED_IP")]
+    deny: Vec<IpAddr>,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -48,12 +60,44 @@ async fn main() {
         || (bitcask_path.exists() && matches!(cli.engin, Engine::Sled))
     {
         error!("engine confilcts");
-        panic!();
+        process::exit(1);
     }
     let path = env::current_dir().unwrap().join(cli.engin.name());
-    let kv = BitcaskEngine::open(&path).unwrap();
+    match cli.engin {
+        Engine::Kvs => {
+            // a write is acknowledged only once it's handed to the file system,
+            // so it survives the server being killed
+            let options = BitcaskOptions {
+                write_flush_bytes: 0,
+                ..BitcaskOptions::default()
+            };
+            let kv = BitcaskEngine::open_with_options(&path, options);
+            serve(cli, opened(kv, &path)).await
+        }
+        Engine::Sled => serve(cli, opened(SledEngine::open(&path), &path)).await,
+    }
+}
+
+// The engine opened at `path`, or exit when it couldn't be
+fn opened<E: KvsEngine>(kv: Result<E>, path: &Path) -> E {
+    match kv {
+        Ok(kv) => kv,
+        Err(err) => {
+            error!("open {:?} failed: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+async fn serve(cli: Cli, kv: impl KvsEngine) {
     info!("kv open successfully!");
     let listener = TcpListener::bind(cli.address).await.unwrap();
     info!("starting server");
-    let _ = Server::start(listener, Arc::new(kv)).await.unwrap();
+    let mut builder = ServerBuilder::new();
+    if !cli.allow.is_empty() {
+        builder = builder.access_list(AccessList::Allow(cli.allow));
+    } else if !cli.deny.is_empty() {
+        builder = builder.access_list(AccessList::Deny(cli.deny));
+    }
+    let _ = builder.start(listener, Arc::new(kv)).await.unwrap();
 }
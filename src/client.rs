@@ -1,70 +1,495 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
 use log::info;
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{
+    connection::{Connection, SocketOptions, DEFAULT_MAX_FRAME_BYTES},
+    protocol::from_bitmap,
+    Frame, KeyEvent, KvStoreErr, Result, COMPRESSION_FEATURE,
+};
 
-use crate::{connection::Connection, Frame, KvStoreErr, Result};
+const DEFAULT_BUFFER_SIZE: usize = 4 * 1024;
 
 pub struct Client {
     conn: Connection,
+    request_timeout: Option<Duration>,
+    trace_requests: bool,
+    last_trace_id: Option<u64>,
 }
 
-impl Client {
-    pub fn new(socket: TcpStream) -> Self {
+/// Builder to tune a `Client` before connecting it
+///
+/// No timeout is set by default, so a stalled server stalls the client too.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    max_frame_bytes: usize,
+    buffer_size: usize,
+    compression: bool,
+    socket_options: SocketOptions,
+    trace_requests: bool,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder {
+            connect_timeout: None,
+            request_timeout: None,
+            read_timeout: None,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            compression: false,
+            socket_options: SocketOptions::default(),
+            trace_requests: false,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail `connect` with `KvStoreErr::Timeout` if it takes longer than this
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request with `KvStoreErr::Timeout` if the server doesn't respond within this,
+    /// the client should be dropped after that since a late response may still arrive
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request with `KvStoreErr::Timeout` if the server sends nothing for this long
+    /// while the client waits for the response, however long the request took so far
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request with `KvStoreErr::FrameTooLarge` if its response grows beyond this,
+    /// instead of buffering whatever a broken server sends
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    /// Initial size of the read and write buffers of the connection
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Offer the server to compress the frames both ways with zstd when connecting,
+    /// which pays off for large values on slow links
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Whether to set `TCP_NODELAY` on the socket, which it is by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Probe the connection after it's idle this long, to notice a dead server
+    pub fn keepalive(mut self, time: Duration) -> Self {
+        self.socket_options.keepalive = Some(time);
+        self
+    }
+
+    /// Send every request with a fresh trace id, which the client logs when sending it
+    /// and the server when handling it, to follow one request through both logs
+    pub fn trace_requests(mut self, enabled: bool) -> Self {
+        self.trace_requests = enabled;
+        self
+    }
+
+    pub async fn connect(self, addr: impl ToSocketAddrs) -> Result<Client> {
+        let socket = with_timeout(self.connect_timeout, async {
+            Ok(TcpStream::connect(addr).await?)
+        })
+        .await?;
+        self.socket_options.apply(&socket)?;
+        let compression = self.compression;
+        let mut client = self.build(socket);
+        if compression {
+            client.negotiate_compression().await?;
+        }
+        Ok(client)
+    }
+
+    /// Build a client on an already connected socket, which keeps its socket options,
+    /// call `Client::negotiate_compression` to compress frames on it
+    pub fn build(self, socket: TcpStream) -> Client {
+        let mut conn = Connection::with_capacity(socket, self.buffer_size);
+        conn.set_read_timeout(self.read_timeout);
+        conn.set_max_frame_bytes(self.max_frame_bytes);
         Client {
-            conn: Connection::new(socket),
+            conn,
+            request_timeout: self.request_timeout,
+            trace_requests: self.trace_requests,
+            last_trace_id: None,
         }
     }
 }
 
 impl Client {
+    pub fn new(socket: TcpStream) -> Self {
+        ClientBuilder::new().build(socket)
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// The socket connected to the server
+    pub fn socket(&self) -> &TcpStream {
+        self.conn.get_ref()
+    }
+
+    /// The trace id of the last request sent, `None` unless requests are traced
+    pub fn last_trace_id(&self) -> Option<u64> {
+        self.last_trace_id
+    }
+}
+
+impl Client {
+    /// Return `Ok(())` only if the server acknowledged the set with `Frame::Ok`
     pub async fn set(&mut self, key: String, value: String) -> Result<()> {
         let cmd = Frame::Set(key, value);
-        info!("client start to request to server with frame: {:?}", cmd);
-        self.conn.write_frame(cmd).await?;
+        info!("client start to request to server with frame: {}", cmd);
+        self.write_request(vec![cmd]).await?;
         info!("client start to read response from server");
-        self.conn.read_frame().await?;
-        Ok(())
+        match self.read_response().await? {
+            Frame::Ok => Ok(()),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
     }
 
+    /// Return `Ok(None)` only if the server responded the key is not found with `Frame::Null`
     pub async fn get(&mut self, key: String) -> Result<Option<String>> {
         let cmd = Frame::Get(key);
-        info!("client start to request to server with frame: {:?}", cmd);
-        self.conn.write_frame(cmd).await?;
+        info!("client start to request to server with frame: {}", cmd);
+        self.write_request(vec![cmd]).await?;
         info!("client start to read response from server");
-        if let Some(frame) = self.conn.read_frame().await? {
-            match frame {
-                Frame::Value(val) => {
-                    return Ok(Some(val));
-                }
-                Frame::Null => {
-                    return Ok(None);
-                }
-                Frame::Error(err) => {
-                    return Err(KvStoreErr::UnexceptErr(err));
-                }
-                _ => {
-                    return Err(KvStoreErr::UnexceptErr("invalid frame".to_owned()));
-                }
-            };
+        match self.read_response().await? {
+            Frame::Value(val) => Ok(Some(val)),
+            Frame::Null => Ok(None),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Get many keys in one round trip, by sending all requests before reading any response
+    ///
+    /// The server responds in request order, so the values are in the order of `keys`.
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let count = keys.len();
+        let cmds: Vec<Frame> = keys.into_iter().map(Frame::Get).collect();
+        info!("client start to pipeline {} get requests to server", count);
+        self.write_request(cmds).await?;
+        // read every response before checking them, to keep the connection in sync
+        let mut responses = Vec::with_capacity(count);
+        for _ in 0..count {
+            responses.push(self.read_response().await?);
+        }
+        responses
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Value(val) => Ok(Some(val)),
+                Frame::Null => Ok(None),
+                Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+                _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+            })
+            .collect()
+    }
+
+    /// Whether `key` exists, which the server answers without reading the value
+    pub async fn contains_key(&mut self, key: String) -> Result<bool> {
+        self.write_request(vec![Frame::ContainsKey(key)]).await?;
+        match self.read_response().await? {
+            Frame::Ok => Ok(true),
+            Frame::Null => Ok(false),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Whether each of `keys` exists, in the order of `keys`, asked in a single frame
+    /// which the server answers from its index without reading values
+    pub async fn exists_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let count = keys.len();
+        self.write_request(vec![Frame::ExistsMany(keys)]).await?;
+        match self.read_response().await? {
+            Frame::Bitmap(bitmap) => from_bitmap(&bitmap, count).ok_or_else(|| {
+                KvStoreErr::UnexceptErr("bitmap shorter than the keys asked for".to_owned())
+            }),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Add `delta` to the integer value of `key` and return the sum
+    pub async fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.increment_request(Frame::Increment(key, delta)).await
+    }
+
+    /// Like `increment`, sent with `request_id` so that the server applies it only once
+    /// however often it's retried with the same id, as long as it remembers the id.
+    /// Pick a fresh id, random for instance, for every increment meant to be applied.
+    pub async fn increment_with_request_id(
+        &mut self,
+        key: String,
+        delta: i64,
+        request_id: u64,
+    ) -> Result<i64> {
+        let cmd = Frame::Request(request_id, Box::new(Frame::Increment(key, delta)));
+        self.increment_request(cmd).await
+    }
+
+    async fn increment_request(&mut self, cmd: Frame) -> Result<i64> {
+        self.write_request(vec![cmd]).await?;
+        match self.read_response().await? {
+            Frame::Value(val) => val
+                .parse()
+                .map_err(|_| KvStoreErr::UnexceptErr(format!("invalid integer: {}", val))),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
         }
-        Ok(None)
     }
 
+    /// Return `KvStoreErr::KeyNotFound` if the server reports the key doesn't exist
     pub async fn remove(&mut self, key: String) -> Result<()> {
+        // the engines report a missing key with the message of this error
+        let not_found = KvStoreErr::KeyNotFound(key.clone());
         let cmd = Frame::Remove(key);
-        self.conn.write_frame(cmd).await?;
-        if let Some(frame) = self.conn.read_frame().await? {
-            match frame {
-                Frame::Null => {
-                    return Ok(());
+        self.write_request(vec![cmd]).await?;
+        match self.read_response().await? {
+            Frame::Ok => Ok(()),
+            Frame::Error(err) if err == not_found.to_string() => Err(not_found),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Ask the server to start no new background compaction until `resume_compaction`
+    pub async fn pause_compaction(&mut self) -> Result<()> {
+        self.write_request(vec![Frame::PauseCompaction]).await?;
+        match self.read_response().await? {
+            Frame::Ok => Ok(()),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Let the server compact in the background again
+    pub async fn resume_compaction(&mut self) -> Result<()> {
+        self.write_request(vec![Frame::ResumeCompaction]).await?;
+        match self.read_response().await? {
+            Frame::Ok => Ok(()),
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        }
+    }
+
+    /// Watch the keys starting with `prefix`, the stream yields their sets and removes
+    /// from the time this returns until the connection closes
+    ///
+    /// The connection is dedicated to the watch. The stream yields an error and ends
+    /// if the server sends something else than an event.
+    pub async fn watch(mut self, prefix: String) -> Result<impl Stream<Item = Result<KeyEvent>>> {
+        self.subscribe(prefix).await?;
+        Ok(self.events())
+    }
+
+    /// Copy all live keys with their values to bootstrap a follower, return them with the
+    /// log position the copy was taken at and the stream of every write after the copy
+    ///
+    /// The connection is dedicated to the stream, like for `watch`.
+    #[allow(clippy::type_complexity)]
+    pub async fn full_sync(
+        mut self,
+    ) -> Result<(
+        Vec<(String, String)>,
+        (u64, u64),
+        impl Stream<Item = Result<KeyEvent>>,
+    )> {
+        self.write_request(vec![Frame::FullSync]).await?;
+        let mut entries = Vec::new();
+        let position = loop {
+            match self.read_response().await? {
+                Frame::Set(key, value) => entries.push((key, value)),
+                Frame::Position(file_id, offset) => break (file_id, offset),
+                Frame::Error(err) => return Err(KvStoreErr::UnexceptErr(err)),
+                _ => return Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+            }
+        };
+        // events come whenever the keys change, however long that takes
+        self.conn.set_read_timeout(None);
+        Ok((entries, position, self.events()))
+    }
+
+    /// Stream up to `limit` live keys with their values, the first ones in key order from one
+    /// consistent copy on the server, which may send fewer keys than asked for
+    ///
+    /// The client can send other requests once the stream ended.
+    pub async fn dump(
+        &mut self,
+        limit: u64,
+    ) -> Result<impl Stream<Item = Result<(String, String)>> + '_> {
+        self.write_request(vec![Frame::Dump(limit)]).await?;
+        Ok(self.items(|frame| match frame {
+            Frame::Set(key, value) => Some((key, value)),
+            _ => None,
+        }))
+    }
+
+    /// Stream every live key, which the server walks while writes go on
+    ///
+    /// The client can send other requests once the stream ended.
+    pub async fn keys(&mut self) -> Result<impl Stream<Item = Result<String>> + '_> {
+        self.write_request(vec![Frame::Keys]).await?;
+        Ok(self.items(|frame| match frame {
+            Frame::Value(key) => Some(key),
+            _ => None,
+        }))
+    }
+
+    /// The items the server sends in response to a listing request up to the `Ok` ending them,
+    /// `item` takes them out of their frames and refuses other frames
+    fn items<T: 'static>(
+        &mut self,
+        item: fn(Frame) -> Option<T>,
+    ) -> impl Stream<Item = Result<T>> + '_ {
+        stream::unfold(Some(self), move |client| async move {
+            let client = client?;
+            let frame = match client.read_response().await {
+                Ok(Frame::Ok) => return None,
+                Ok(Frame::Error(err)) => {
+                    return Some((Err(KvStoreErr::UnexceptErr(err)), None));
                 }
-                Frame::Error(err) => {
-                    return Err(KvStoreErr::UnexceptErr(err));
+                Ok(frame) => frame,
+                Err(err) => return Some((Err(err), None)),
+            };
+            match item(frame) {
+                Some(item) => Some((Ok(item), Some(client))),
+                None => Some((
+                    Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+                    None,
+                )),
+            }
+        })
+    }
+
+    /// The sets and removes the server sends on a watching connection
+    fn events(self) -> impl Stream<Item = Result<KeyEvent>> {
+        stream::unfold(Some(self), |client| async move {
+            let mut client = client?;
+            let event = match client.conn.read_frame().await {
+                Ok(Some(Frame::Set(key, value))) => KeyEvent::Set { key, value },
+                Ok(Some(Frame::Remove(key))) => KeyEvent::Removed { key },
+                Ok(None) => return None,
+                Ok(Some(Frame::Error(err))) => {
+                    return Some((Err(KvStoreErr::UnexceptErr(err)), None))
                 }
-                _ => {
-                    return Err(KvStoreErr::UnexceptErr("invalid frame".to_owned()));
+                Ok(Some(_)) => {
+                    return Some((
+                        Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+                        None,
+                    ))
                 }
+                Err(err) => return Some((Err(err), None)),
+            };
+            Some((Ok(event), Some(client)))
+        })
+    }
+
+    async fn subscribe(&mut self, prefix: String) -> Result<()> {
+        self.write_request(vec![Frame::Watch(prefix)]).await?;
+        match self.read_response().await? {
+            Frame::Ok => {
+                // events come whenever the keys change, however long that takes
+                self.conn.set_read_timeout(None);
+                Ok(())
             }
+            Frame::Error(err) => Err(KvStoreErr::UnexceptErr(err)),
+            _ => Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
         }
-        Ok(())
+    }
+
+    /// Offer the server to compress frames and return whether it agreed,
+    /// the frames of both sides are compressed from then on if it did
+    pub async fn negotiate_compression(&mut self) -> Result<bool> {
+        self.write_request(vec![Frame::Hello(COMPRESSION_FEATURE.to_owned())])
+            .await?;
+        let accepted = match self.read_response().await? {
+            Frame::Hello(features) => features
+                .split(',')
+                .any(|feature| feature == COMPRESSION_FEATURE),
+            Frame::Error(err) => return Err(KvStoreErr::UnexceptErr(err)),
+            _ => return Err(KvStoreErr::UnexceptErr("invalid frame".to_owned())),
+        };
+        self.conn.set_compression(accepted);
+        Ok(accepted)
+    }
+
+    async fn read_response(&mut self) -> Result<Frame> {
+        with_timeout(self.request_timeout, self.conn.read_frame())
+            .await?
+            .ok_or_else(|| KvStoreErr::UnexceptErr("connection closed by server".to_owned()))
+    }
+
+    async fn write_request(&mut self, frames: Vec<Frame>) -> Result<()> {
+        let frames = if self.trace_requests {
+            frames
+                .into_iter()
+                .map(|frame| {
+                    let trace_id = new_trace_id();
+                    info!("[trace {:016x}] client send a frame: {}", trace_id, frame);
+                    self.last_trace_id = Some(trace_id);
+                    Frame::Traced(trace_id, Box::new(frame))
+                })
+                .collect()
+        } else {
+            frames
+        };
+        with_timeout(self.request_timeout, self.conn.write_frames(frames)).await
+    }
+}
+
+/// A trace id unlike those of the other requests of this process, and likely of other processes
+fn new_trace_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // keyed randomly once per process
+    static HASHER: OnceLock<RandomState> = OnceLock::new();
+    HASHER
+        .get_or_init(RandomState::new)
+        .hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| KvStoreErr::Timeout)?,
+        None => future.await,
     }
 }
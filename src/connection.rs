@@ -1,8 +1,10 @@
 use std::io::Cursor;
+use std::time::Duration;
 
 use log::info;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
     net::TcpStream,
 };
 
@@ -10,20 +12,100 @@ use bytes::{Buf, BytesMut};
 
 use crate::{Frame, KvStoreErr, Result};
 
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+/// Reading a frame larger than this fails by default, rather than buffering it without bound
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// Options of the TCP sockets of the server and the client
+///
+/// `TCP_NODELAY` is set by default, as every request waits for its response
+/// and Nagle's algorithm would hold small requests back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small writes right away instead of batching them
+    pub nodelay: bool,
+    /// Probe an idle connection after this long to detect a dead peer, `None` disables keepalive
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set the options on a connected socket
+    pub fn apply(&self, socket: &TcpStream) -> Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(socket);
+        match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?,
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(())
+    }
+}
+
+/// Reads and writes frames on a socket, or any other byte stream
+///
+/// Once compression is enabled, frames are compressed when written and compressed frames are
+/// decompressed when read. Before that a compressed frame is an error, as the peer sent it
+/// without the two sides agreeing on compression.
+pub struct Connection<S = TcpStream> {
+    stream: BufWriter<S>,
     buffer: BytesMut,
+    compression: bool,
+    max_frame_bytes: usize,
+    read_timeout: Option<Duration>,
 }
 
-impl Connection {
-    pub fn new(socket: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(socket: S) -> Self {
+        // default 4kb buffer
+        Self::with_capacity(socket, 4 * 1024)
+    }
+
+    /// Create a connection whose read buffer and write buffer start with `capacity` bytes
+    pub fn with_capacity(socket: S, capacity: usize) -> Self {
         Connection {
-            stream: BufWriter::new(socket),
-            // default 4kb buffer
-            buffer: BytesMut::with_capacity(4 * 1024),
+            stream: BufWriter::with_capacity(capacity, socket),
+            buffer: BytesMut::with_capacity(capacity),
+            compression: false,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            read_timeout: None,
         }
     }
 
+    /// Compress the frames written and accept compressed frames read from now on,
+    /// which the peer must have agreed to
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Fail `read_frame` with `KvStoreErr::FrameTooLarge` once more than this many bytes
+    /// are buffered without completing a frame
+    pub fn set_max_frame_bytes(&mut self, max_frame_bytes: usize) {
+        self.max_frame_bytes = max_frame_bytes;
+    }
+
+    /// Fail `read_frame` with `KvStoreErr::Timeout` if the stream sends nothing for this long,
+    /// so a peer which stalls in the middle of a frame can't hang the reader
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
             // try to parse frame from buffer
@@ -31,9 +113,19 @@ impl Connection {
                 return Ok(Some(frame));
             }
             info!("parse frame fail, try to read from socket");
+            if self.buffer.len() > self.max_frame_bytes {
+                return Err(KvStoreErr::FrameTooLarge(self.max_frame_bytes));
+            }
             // if parse frame but get none, means that the buffer hasn't at least one completed frame
             // try to read stream from socket
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+            let read = self.stream.read_buf(&mut self.buffer);
+            let len = match self.read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read)
+                    .await
+                    .map_err(|_| KvStoreErr::Timeout)??,
+                None => read.await?,
+            };
+            if 0 == len {
                 if self.buffer.is_empty() {
                     info!("socket is empty");
                     return Ok(None);
@@ -46,11 +138,30 @@ impl Connection {
     }
 
     pub async fn write_frame(&mut self, frame: Frame) -> Result<()> {
-        frame.write(&mut self.stream).await?;
+        self.write_to_buffer(frame).await?;
         self.stream.flush().await?;
         Ok(())
     }
 
+    /// Write all frames and flush once, so they are sent together, the frames are taken
+    /// from `frames` as they are written
+    pub async fn write_frames(&mut self, frames: impl IntoIterator<Item = Frame>) -> Result<()> {
+        for frame in frames {
+            self.write_to_buffer(frame).await?;
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn write_to_buffer(&mut self, frame: Frame) -> Result<()> {
+        let frame = if self.compression {
+            frame.compress().await?
+        } else {
+            frame
+        };
+        frame.write(&mut self.stream).await
+    }
+
     fn parse_frame(&mut self) -> Result<Option<Frame>> {
         let mut buf = Cursor::new(&self.buffer[..]);
         // check if there are completed frames in buffer
@@ -63,7 +174,15 @@ impl Connection {
                 let frame = Frame::parse(&mut buf)?;
                 // move the cursor forward len units
                 self.buffer.advance(len);
-                Ok(Some(frame))
+                if let Frame::Compressed(_) = frame {
+                    if !self.compression {
+                        return Err(KvStoreErr::UnexceptErr(
+                            "compressed frame on a connection which didn't negotiate compression"
+                                .to_owned(),
+                        ));
+                    }
+                }
+                Ok(Some(frame.decompress(self.max_frame_bytes)?))
             }
             Err(KvStoreErr::IncompleteErr) => Ok(None),
             Err(err) => Err(err),
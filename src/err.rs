@@ -1,25 +1,60 @@
 use core::result;
-use failure::Fail;
 use std::{io, string::FromUtf8Error};
+use thiserror::Error;
 
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvStoreErr {
-    #[fail(display = "{}", _0)]
-    IOErr(#[cause] io::Error),
-    #[fail(display = "{}", _0)]
-    BincodeErr(#[cause] bincode::Error),
-    #[fail(display = "key {} not found", _0)]
+    #[error("{0}")]
+    IOErr(#[source] io::Error),
+    #[error("{0}")]
+    BincodeErr(#[source] bincode::Error),
+    #[error("key {0} not found")]
     KeyNotFound(String),
-    #[fail(display = "inner error: {}", _0)]
+    #[error("inner error: {0}")]
     InnerErr(String),
-    #[fail(display = "system error: {}", _0)]
-    SystemErr(#[cause] FromUtf8Error),
-    #[fail(display = "unexcept error: {}", _0)]
+    #[error("system error: {0}")]
+    SystemErr(#[source] FromUtf8Error),
+    #[error("unexcept error: {0}")]
     UnexceptErr(String),
-    #[fail(display = "incomplete frame")]
+    #[error("incomplete frame")]
     IncompleteErr,
-    #[fail(display = "sled error: {}", _0)]
-    SledErr(#[cause] sled::Error),
+    #[error("incomplete entry at offset {0}")]
+    IncompleteEntry(u64),
+    #[error("corrupt entry in file {0} at offset {1}")]
+    CorruptEntry(u64, u64),
+    #[error("active file {0} is {2} bytes long but its writer is at {1}")]
+    ActiveFileMismatch(u64, u64, u64),
+    #[error("value of key {0} is not an integer")]
+    NotAnInteger(String),
+    #[error("frame larger than {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("request timed out")]
+    Timeout,
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("the engine is a read-only replica")]
+    ReadOnly,
+    #[error("the index takes about {0} bytes, more than the {1} allowed")]
+    IndexTooLarge(u64, u64),
+    #[error("the store was written with {0} = {1}, but is opened with {2}")]
+    ConfigConflict(String, String, String),
+    #[error(
+        "value of key {key}, {v_size} bytes ending at {v_pos}, lies past the end of \
+         file {file_id} which is {file_len} bytes long"
+    )]
+    ValueOutOfBounds {
+        key: String,
+        file_id: u64,
+        v_pos: u64,
+        v_size: u64,
+        file_len: u64,
+    },
+    #[error("bulk load input isn't sorted, key {0} comes after {1}")]
+    UnsortedInput(String, String),
+    #[error("expected a data directory of the {expected} engine, found one of {found}")]
+    EngineMismatch { expected: String, found: String },
+    #[error("sled error: {0}")]
+    SledErr(#[source] sled::Error),
 }
 
 impl From<io::Error> for KvStoreErr {
@@ -1,6 +1,7 @@
-use crate::Result;
+use crate::{KvStoreErr, Result};
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -21,6 +22,10 @@ impl<F: Write + Seek> BufWriterWithPos<F> {
             flushed: file_end_pos,
         })
     }
+
+    pub fn get_ref(&self) -> &F {
+        self.writer.get_ref()
+    }
 }
 
 impl<F: Write + Seek> Write for BufWriterWithPos<F> {
@@ -51,22 +56,123 @@ pub struct BufReaderWithPos<F: Read + Seek> {
 
 impl<F: Read + Seek> BufReaderWithPos<F> {
     pub fn new(mut f: F) -> Result<Self> {
-        let offset = f.seek(SeekFrom::Current(0))?;
+        let offset = f.stream_position()?;
         Ok(BufReaderWithPos {
             reader: (BufReader::new(f)),
             pos: offset,
         })
     }
 
-    pub fn read_u64(&mut self) -> Option<u64> {
+    /// Read a big-endian u64, return `None` if the reader is already at the end
+    pub fn read_u64_be(&mut self) -> Result<Option<u64>> {
+        Ok(self.read_u64_bytes()?.map(u64::from_be_bytes))
+    }
+
+    /// Read a little-endian u64, return `None` if the reader is already at the end
+    // the formats of this crate are big-endian, this reads data written by other tools
+    #[allow(dead_code)]
+    pub fn read_u64_le(&mut self) -> Result<Option<u64>> {
+        Ok(self.read_u64_bytes()?.map(u64::from_le_bytes))
+    }
+
+    fn read_u64_bytes(&mut self) -> Result<Option<[u8; 8]>> {
         let mut buf: [u8; 8] = [0; 8];
-        if let Ok(len) = self.read(&mut buf) {
-            if len == 0 {
-                return None;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_retrying(&mut buf[filled..])? {
+                0 => break,
+                len => filled += len,
+            }
+        }
+        match filled {
+            0 => Ok(None),
+            8 => Ok(Some(buf)),
+            _ => Err(KvStoreErr::IncompleteEntry(self.pos)),
+        }
+    }
+
+    /// Read a LEB128 varint, return `None` if the reader is already at the end
+    pub fn read_varint(&mut self) -> Result<Option<u64>> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8; 1];
+            if self.read_retrying(&mut byte)? == 0 {
+                if shift == 0 {
+                    return Ok(None);
+                }
+                return Err(KvStoreErr::IncompleteEntry(self.pos));
+            }
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(KvStoreErr::UnexceptErr(format!(
+            "varint longer than 10 bytes at offset {}",
+            self.pos
+        )))
+    }
+
+    /// Read into `buf` like `read`, retrying the reads which were interrupted as `read_exact` does
+    fn read_retrying(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.read(buf) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                read => return Ok(read?),
+            }
+        }
+    }
+
+    /// Read a big-endian u32 in the middle of an entry
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.read_entry_bytes(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Read `len` bytes, fail with `IncompleteEntry` if the reader ends before that.
+    /// The buffer grows with what is read, so a corrupt length can't allocate it all at once.
+    pub fn read_entry_vec(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.by_ref().take(len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < len {
+            return Err(KvStoreErr::IncompleteEntry(self.pos));
+        }
+        Ok(buf)
+    }
+
+    /// Skip `len` bytes in the middle of an entry. A `len` reaching past the end of the reader
+    /// is corrupt, seeking by it would go back for a huge length or past the end, and replay
+    /// would loop or read garbage from there.
+    pub fn skip_entry_bytes(&mut self, len: u64) -> Result<()> {
+        let pos = self.pos;
+        let end = self.seek(SeekFrom::End(0))?;
+        match pos.checked_add(len) {
+            Some(target) if target <= end => {
+                self.seek(SeekFrom::Start(target))?;
+                Ok(())
+            }
+            _ => {
+                self.seek(SeekFrom::Start(pos))?;
+                Err(KvStoreErr::UnexceptErr(format!(
+                    "corrupt length {} at offset {}, only {} bytes are left",
+                    len,
+                    pos,
+                    end - pos
+                )))
+            }
+        }
+    }
+
+    /// Fill the whole `buf`, fail with `IncompleteEntry` if the reader ends before that
+    pub fn read_entry_bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                Err(KvStoreErr::IncompleteEntry(self.pos))
             }
-            return Some(u8_arr_to_u64(&buf));
+            Err(err) => Err(err.into()),
         }
-        None
     }
 }
 
@@ -86,13 +192,119 @@ impl<F: Read + Seek> Seek for BufReaderWithPos<F> {
     }
 }
 
-pub fn u8_arr_to_u64(arr: &[u8; 8]) -> u64 {
-    let mut ans: u64 = 0;
-    let mut offet = 56;
-    for num in arr {
-        let real_num: u64 = (*num as u64) << offet;
-        offet = offet - 8;
-        ans = ans + real_num;
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom};
+
+    use super::BufReaderWithPos;
+    use crate::KvStoreErr;
+
+    // Returns one byte per read, after an interrupted read every other time
+    struct Trickle {
+        inner: Cursor<Vec<u8>>,
+        interrupt: bool,
+    }
+
+    impl Trickle {
+        fn reader(bytes: Vec<u8>) -> BufReaderWithPos<Trickle> {
+            BufReaderWithPos::new(Trickle {
+                inner: Cursor::new(bytes),
+                interrupt: false,
+            })
+            .unwrap()
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(1);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for Trickle {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn read_u64_both_endians() {
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 1, 0];
+        let mut reader = BufReaderWithPos::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.read_u64_be().unwrap(), Some(0x0102030405060708));
+        assert_eq!(reader.read_u64_be().unwrap(), Some(256));
+        assert_eq!(reader.read_u64_be().unwrap(), None);
+
+        let mut reader = BufReaderWithPos::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.read_u64_le().unwrap(), Some(0x0807060504030201));
+        assert_eq!(reader.read_u64_le().unwrap(), Some(1 << 48));
+        assert_eq!(reader.read_u64_le().unwrap(), None);
+    }
+
+    #[test]
+    fn read_u64_incomplete() {
+        let mut reader = BufReaderWithPos::new(Cursor::new([0xff; 5])).unwrap();
+        assert!(matches!(
+            reader.read_u64_le(),
+            Err(KvStoreErr::IncompleteEntry(5))
+        ));
+    }
+
+    #[test]
+    fn read_through_short_and_interrupted_reads() {
+        let mut bytes = vec![0, 0, 0, 0, 0, 0, 1, 0];
+        bytes.extend_from_slice(&[0x80, 0x01]);
+        bytes.extend_from_slice(&[0, 0, 0, 7]);
+        bytes.extend(0..=255u8);
+        let mut reader = Trickle::reader(bytes);
+        assert_eq!(reader.read_u64_be().unwrap(), Some(256));
+        assert_eq!(reader.read_varint().unwrap(), Some(128));
+        assert_eq!(reader.read_u32().unwrap(), 7);
+        assert_eq!(
+            reader.read_entry_vec(256).unwrap(),
+            (0..=255u8).collect::<Vec<_>>()
+        );
+        assert_eq!(reader.read_u64_be().unwrap(), None);
+        assert_eq!(reader.read_varint().unwrap(), None);
+    }
+
+    #[test]
+    fn entry_cut_off_is_incomplete() {
+        let mut reader = Trickle::reader(vec![0, 0, 0]);
+        assert!(matches!(
+            reader.read_u32(),
+            Err(KvStoreErr::IncompleteEntry(3))
+        ));
+        let mut reader = Trickle::reader(vec![1, 2, 3]);
+        assert!(matches!(
+            reader.read_entry_vec(4),
+            Err(KvStoreErr::IncompleteEntry(3))
+        ));
+        let mut reader = Trickle::reader(vec![0xff; 3]);
+        assert!(matches!(
+            reader.read_u64_be(),
+            Err(KvStoreErr::IncompleteEntry(3))
+        ));
+    }
+
+    #[test]
+    fn skip_within_the_reader_only() {
+        let mut reader = BufReaderWithPos::new(Cursor::new(vec![0u8; 16])).unwrap();
+        reader.read_u32().unwrap();
+        reader.skip_entry_bytes(8).unwrap();
+        assert_eq!(reader.pos, 12);
+        for len in [5, u64::MAX, 1 << 63] {
+            assert!(matches!(
+                reader.skip_entry_bytes(len),
+                Err(KvStoreErr::UnexceptErr(_))
+            ));
+        }
+        reader.skip_entry_bytes(4).unwrap();
+        assert_eq!(reader.read_u64_be().unwrap(), None);
     }
-    ans
 }
@@ -0,0 +1,56 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+
+/// Append-only record of the sets and removes of an engine, kept apart from its log files
+///
+/// Lines are buffered, so recording doesn't wait on the disk. They reach the file once
+/// the buffer fills, on `flush` and when the log is dropped.
+pub struct AuditLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Record a set of `key`, or a remove with `removed`, by `client` if it's known
+    pub fn record(
+        &self,
+        time: SystemTime,
+        removed: bool,
+        key: &str,
+        client: Option<SocketAddr>,
+    ) -> Result<()> {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let op = if removed { "remove" } else { "set" };
+        let client = client.map_or_else(|| "-".to_owned(), |client| client.to_string());
+        // the key is quoted and escaped, so every record stays on one line
+        writeln!(
+            self.writer.lock().unwrap(),
+            "{} {} {:?} {}",
+            millis,
+            op,
+            key,
+            client
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
@@ -1,456 +1,3496 @@
 use crate::KvStoreErr;
 use crate::KvsEngine;
 use crate::Result;
+use crate::ScanPrefix;
 use dashmap::DashMap;
+use log::{debug, info, warn};
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
-use std::fs::{self, remove_file, rename, File, OpenOptions};
+use std::fmt;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedReceiver;
 
-use super::entry::HintEntry;
+use super::audit::AuditLog;
+use super::bloom::BloomFilter;
+use super::cache::ValueCache;
+use super::checkpoint::{self, Checkpoint};
+use super::config;
+use super::entry::padding_before;
+use super::entry::Footer;
 use super::entry::IndexEntry;
 use super::entry::LogEntry;
-use super::entry::SerializeToBytes;
+use super::entry::{
+    FileDigest, HintEncoder, HintEntry, EXPIRY_MARKER, FRONT_CODED_HINT_MARKER,
+    LEGACY_FRONT_CODED_HINT_MARKER,
+};
+use super::evict::Evictor;
+use super::index::{FileEntry, KeyIndex};
+use super::install::{self, Rename};
+use super::lock::LockStripes;
+use super::options::{
+    BitcaskOptions, BulkLoadOpts, CorruptionPolicy, DuplicateKeys, EmptyMergedFiles, EntryFormat,
+    GarbageAccounting, MergeSchedule,
+};
+use super::secondary::SecondaryIndex;
+use super::store::{BlockFile, BlockStore};
+use super::watch::{FullSync, KeyEvent, Watchers};
 use crate::io::{BufReaderWithPos, BufWriterWithPos};
 
 const DELETED_CODE: u8 = 255;
-const DEFAULT_LOG_FILE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
-const DEFAULT_MERGE_TRIGGER_THRESHOLD: u64 = 1024 * 1024 * 1024;
-const DEFAULT_WRITE_FLUSH_INTERVAL: u64 = 4 * 1024 * 1024;
+// file in the log directory which scrub moves corrupt entries to
+const QUARANTINE_FILE_NAME: &str = "quarantine.bad";
+const NO_FILE: u64 = u64::MAX;
 
+type LogWriter = BufWriterWithPos<Box<dyn BlockFile>>;
+type LogReader = BufReaderWithPos<Box<dyn BlockFile>>;
+// a key with its index entry where a merge found it and where the merge wrote it,
+// or `None` where the merge dropped it as expired
+type EntryMove = (Vec<u8>, IndexEntry, Option<IndexEntry>);
+
+/// Log-structured key value store, clones share the same store
+///
+/// Writes are linearizable: they are appended to the log and applied to the index in one
+/// total order under the writer lock, so a `get` which starts after a `set` or `remove`
+/// returned sees it on every thread, and the index always agrees with the log replayed on open.
 #[derive(Clone)]
 pub struct BitcaskEngine {
-    index: Arc<DashMap<String, IndexEntry>>,
-    base_dir: Arc<PathBuf>,
+    index: Arc<KeyIndex<IndexEntry>>,
+    dirs: Arc<DataDirs>,
     active_file_id: Arc<AtomicU64>,
-    active_file_writer: Arc<Mutex<BufWriterWithPos<File>>>,
-    file_reader: Arc<DashMap<u64, BufReaderWithPos<File>>>,
+    active_file_writer: Arc<Mutex<LogWriter>>,
+    // footer of the active file so far, kept under the writer lock when `seal_footer` asks for
+    // footers, `None` once the file holds bytes written before the engine took it over
+    active_file_digest: Arc<Mutex<Option<FileDigest>>>,
+    file_reader: Arc<DashMap<u64, LogReader>>,
     useless_value_bytes: Arc<AtomicU64>,
-    log_file_max_bytes: u64,
-    merge_trigger_threshold: u64,
+    // bytes written by merges and bytes of log files they reclaimed, since open
+    merge_bytes_written: Arc<AtomicU64>,
+    merge_bytes_reclaimed: Arc<AtomicU64>,
+    // serialize read-modify-write operations on the same key
+    key_locks: Arc<LockStripes>,
+    // only one merge runs at a time
+    merge_lock: Arc<Mutex<()>>,
+    // held by reads through the index, and exclusively by a merge while it moves the merged
+    // files in place of the old ones, so no read finds the contents of another file at an entry
+    files_swap: Arc<RwLock<()>>,
+    // whether a triggered merge waits for its turn on the merge worker or pool
+    merge_queued: Arc<AtomicBool>,
+    // whether triggered and scheduled merges are held off by `pause_compaction`
+    compaction_paused: Arc<AtomicBool>,
+    // the file sealed by the last rotation, until its garbage is checked, or `NO_FILE`
+    sealed_file: Arc<AtomicU64>,
+    secondary_index: Option<Arc<SecondaryIndex>>,
+    // keys which may be in the index, swapped for a rebuilt filter under the writer lock
+    key_filter: Option<Arc<RwLock<BloomFilter>>>,
+    // gets the filter answered without the index
+    filtered_misses: Arc<AtomicU64>,
+    value_cache: Option<Arc<ValueCache>>,
+    watchers: Arc<Watchers>,
+    // live bytes and eviction order, once the store is capped by `max_live_bytes`
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    // runs the merges triggered by writes, unless they go to the merge pool
+    merge_worker: Option<Arc<MergeWorker>>,
+    // stops the scheduled merges once the last clone of the engine is dropped
+    _merge_schedule: Option<Arc<ScheduleStop>>,
+    // how far a read-only replica replayed each log file
+    replica: Option<Arc<Mutex<BTreeMap<u64, ReplayedFile>>>>,
+    // stops the refreshes of a replica once the last clone of the engine is dropped
+    _refresh_schedule: Option<Arc<ScheduleStop>>,
+    audit_log: Option<Arc<AuditLog>>,
+    options: Arc<BitcaskOptions>,
+}
+
+/// Dropping this disconnects a background thread of the engine, which then exits
+struct ScheduleStop {
+    _sender: mpsc::Sender<()>,
+}
+
+/// Dropping this stops the thread which runs the merges triggered by writes,
+/// after waiting for the merge it's running to finish
+struct MergeWorker {
+    sender: Option<mpsc::Sender<MergeTrigger>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for MergeWorker {
+    fn drop(&mut self) {
+        // the thread holds a clone of the engine without the worker, so it isn't joining itself
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Why a write triggered a merge
+enum MergeTrigger {
+    Threshold {
+        useless_value_bytes: u64,
+        threshold: u64,
+    },
+    SealedFileGarbage {
+        file_id: u64,
+        ratio: f64,
+        max_ratio: f64,
+    },
+}
+
+impl fmt::Display for MergeTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeTrigger::Threshold {
+                useless_value_bytes,
+                threshold,
+            } => write!(
+                f,
+                "useless value bytes {} crossed the merge threshold {}",
+                useless_value_bytes, threshold
+            ),
+            MergeTrigger::SealedFileGarbage {
+                file_id,
+                ratio,
+                max_ratio,
+            } => write!(
+                f,
+                "sealed log file {} is {:.2} garbage, at least {:.2}",
+                file_id, ratio, max_ratio
+            ),
+        }
+    }
+}
+
+/// How much of a log file a replica replayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ReplayedFile {
+    // length of the replayed prefix of the log file
+    log_len: u64,
+    // length of the hint file the log file was loaded from instead
+    hint_len: Option<u64>,
+}
+
+/// Directories the log files and the hint files are kept in
+struct DataDirs {
+    log_dir: PathBuf,
+    hint_dir: PathBuf,
+}
+
+/// What a merge would do, reported by `BitcaskEngine::merge_dry_run`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeEstimate {
+    /// Ids of the log files which would be rewritten
+    pub files_to_rewrite: Vec<u64>,
+    /// Total bytes of the log files which would be rewritten
+    pub input_bytes: u64,
+    /// Estimated total bytes of the merged log files
+    pub estimated_output_bytes: u64,
+    /// Estimated bytes of log files reclaimed by the merge
+    pub reclaimable_bytes: u64,
+}
+
+/// What a merge did, returned by `BitcaskEngine::merge_report`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Number of old log files which were merged
+    pub files_merged: u64,
+    /// Number of merged log files written in their place
+    pub files_produced: u64,
+    /// Entries of the old log files copied into the merged files
+    pub entries_kept: u64,
+    /// Entries of the old log files left out, since they were overwritten or removed
+    pub entries_dropped: u64,
+    /// Bytes of log files reclaimed by the merge
+    pub bytes_reclaimed: u64,
+    /// How long the merge took, not counting the wait for another merge to finish
+    pub duration: Duration,
+}
+
+/// What `BitcaskEngine::scrub` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Number of live keys whose entries were checked
+    pub scanned_keys: u64,
+    /// Keys whose entries don't match their checksum, sorted
+    pub corrupt_keys: Vec<String>,
+    /// Sealed log files that don't match the entry count or checksum in their footers, sorted,
+    /// see `BitcaskOptions::seal_footer`
+    pub corrupt_files: Vec<u64>,
+    /// Whether the corrupt entries were moved to the quarantine file and their keys removed
+    pub quarantined: bool,
+}
+
+/// Where `BitcaskEngine::get_with_source` found a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValueSource {
+    /// The value cache, enabled by `BitcaskOptions::value_cache_bytes` or
+    /// `BitcaskOptions::value_cache_entries`
+    Cache,
+    /// A log file
+    Disk,
+}
+
+/// What `BitcaskEngine::get_with_tombstone` knows about a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key has this value
+    Present(String),
+    /// The key was removed and its tombstone is still in the log files
+    Deleted,
+    /// The key never existed, or its tombstone was dropped by a merge
+    Absent,
+}
+
+/// A log file of a `BitcaskEngine`, reported by `BitcaskEngine::log_files`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFileInfo {
+    pub file_id: u64,
+    /// Bytes of the file, including what is still buffered for the active file
+    pub size: u64,
+    /// Whether new entries are appended to this file
+    pub is_active: bool,
+    /// Whether a hint file was written for this file by a merge
+    pub has_hint: bool,
+    /// Bytes of the file which aren't part of a live entry and would be reclaimed by a merge
+    pub dead_bytes: u64,
+    /// Entries recorded in the footer of a file sealed with `BitcaskOptions::seal_footer`
+    pub footer_entries: Option<u64>,
+}
+
+/// Counters of a `BitcaskEngine` since it was opened, reported by `BitcaskEngine::stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Total bytes of the log and hint files written by merges
+    pub merge_bytes_written: u64,
+    /// Total bytes of log files reclaimed by merges
+    pub merge_bytes_reclaimed: u64,
+    /// Gets of missing keys the Bloom filter answered without looking in the index
+    pub filtered_misses: u64,
+    /// Rough bytes of memory the index takes, as `BitcaskOptions::max_index_bytes` counts them
+    pub index_bytes_estimate: u64,
+    /// Garbage in the log files the next merge may reclaim, as
+    /// `BitcaskOptions::garbage_accounting` counts it
+    pub garbage_bytes: u64,
+}
+
+impl EngineStats {
+    /// Bytes written by merges per byte they reclaimed, 0 before anything was reclaimed
+    pub fn merge_write_amplification(&self) -> f64 {
+        if self.merge_bytes_reclaimed == 0 {
+            return 0.0;
+        }
+        self.merge_bytes_written as f64 / self.merge_bytes_reclaimed as f64
+    }
 }
 
 impl KvsEngine for BitcaskEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
-        let key_bytes = key.as_bytes();
-        let value_bytes = value.as_bytes();
-        let k_size = key_bytes.len() as u64;
-        let v_size = value_bytes.len() as u64;
-        let log_entry = LogEntry {
-            k_size: k_size,
-            v_size: v_size,
-            key: Vec::from(key_bytes),
-            value: Vec::from(value_bytes),
+        self.set_with_flags(key, value, 0)
+    }
+
+    fn set_from(&self, key: String, value: String, client: Option<SocketAddr>) -> Result<()> {
+        self.set_entry(key.into_bytes(), value.into_bytes(), 0, 0, client)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_flags(key)?.map(|(value, _)| value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_from(key, None)
+    }
+
+    fn remove_from(&self, key: String, client: Option<SocketAddr>) -> Result<()> {
+        self.remove_entry(key.as_bytes(), client)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_entry(key, value, 0, 0, None)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_from_source(key)?.map(|(value, _, _)| value))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.remove_entry(key, None)
+    }
+
+    /// Hold off the merges triggered by writes and the scheduled ones, a running merge
+    /// finishes and `merge` still merges when called
+    fn pause_compaction(&self) {
+        self.compaction_paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Let merges be triggered again, and merge right away if writes crossed the
+    /// threshold while paused
+    fn resume_compaction(&self) {
+        self.compaction_paused.store(false, Ordering::SeqCst);
+        self.merge_if_needed();
+    }
+
+    /// Looked up in the index alone, without reading any value
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.contains_live_key(key.as_bytes()))
+    }
+
+    /// Looked up in the index alone, like `contains_key`
+    fn exists_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        Ok(keys
+            .iter()
+            .map(|key| self.contains_live_key(key.as_bytes()))
+            .collect())
+    }
+
+    /// A point-in-time snapshot: the index is walked under the writer lock, so every write
+    /// happened either before the snapshot or after it. Writes wait for the walk, use
+    /// `for_each_key` to go through the keys without holding them up.
+    /// Keys which aren't utf-8 are converted lossily.
+    fn keys(&self) -> Result<Vec<String>> {
+        let _writer = self.active_file_writer.lock().unwrap();
+        let mut keys = Vec::with_capacity(self.len());
+        self.for_each_key(|key| keys.push(key.to_owned()));
+        Ok(keys)
+    }
+
+    /// Not a snapshot, see the inherent `for_each_key`
+    fn for_each_key(&self, f: &mut dyn FnMut(&str)) -> Result<()> {
+        BitcaskEngine::for_each_key(self, f);
+        Ok(())
+    }
+
+    fn key_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::with_capacity(self.len());
+        self.index.for_each(|key, entry| {
+            if !self.is_expired(entry) {
+                keys.push(key.to_vec());
+            }
+        });
+        Ok(keys)
+    }
+
+    /// The matching keys are collected from the index up front, the values are read as the
+    /// iterator goes, so a key removed or expired meanwhile is skipped and a later set is seen.
+    /// Keys and values which aren't utf-8 are converted lossily, like for `keys`.
+    fn scan_prefix<'a>(&'a self, prefix: String) -> Result<ScanPrefix<'a>> {
+        let mut keys = Vec::new();
+        self.index.for_each(|key, entry| {
+            if key.starts_with(prefix.as_bytes()) && !self.is_expired(entry) {
+                keys.push(key.to_vec());
+            }
+        });
+        Ok(Box::new(keys.into_iter().filter_map(
+            |key| match self.get_from_source(&key) {
+                Ok(Some((value, _, _))) => Some(Ok((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            },
+        )))
+    }
+
+    /// Atomic with the other writes of the key
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        BitcaskEngine::increment(self, key, delta)
+    }
+
+    /// Evictions are removes too. The entries a replica picks up on refresh aren't sent.
+    fn watch(&self, prefix: String) -> Result<UnboundedReceiver<KeyEvent>> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    /// The keys are listed and the events subscribed to under the writer lock, so every write
+    /// is either in the copy or an event.
+    fn full_sync(&self) -> Result<FullSync> {
+        let writer = self.active_file_writer.lock().unwrap();
+        let position = (self.active_file_id.load(Ordering::SeqCst), writer.pos);
+        let events = self.watchers.subscribe(String::new());
+        let keys = self.key_bytes()?;
+        drop(writer);
+        Ok(FullSync {
+            keys,
+            position,
+            events,
+        })
+    }
+
+    /// The index is copied under the writer lock, and merges wait until the values are read.
+    /// Keys and values which aren't utf-8 are converted lossily, like for `keys`.
+    fn dump(&self, limit: usize) -> Result<Vec<(String, String)>> {
+        let _guard = self.merge_lock.lock().unwrap();
+        let mut index_entries = {
+            let mut writer = self.active_file_writer.lock().unwrap();
+            writer.flush()?;
+            self.snapshot_index()
+        };
+        index_entries.retain(|(_, index_entry)| !self.is_expired(index_entry));
+        index_entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        index_entries.truncate(limit);
+        self.read_live_entries(index_entries)
+    }
+}
+
+impl BitcaskEngine {
+    fn store(&self) -> &dyn BlockStore {
+        self.options.block_store.as_ref()
+    }
+
+    fn garbage_bytes(&self, key: &[u8], v_size: u64) -> u64 {
+        garbage_bytes(&self.options, key, v_size)
+    }
+
+    /// Whether the entry expired by the clock of the engine
+    fn is_expired(&self, entry: &IndexEntry) -> bool {
+        entry.expire_at != 0 && entry.expire_at <= unix_millis(self.options.clock.now())
+    }
+
+    /// Whether `key` is in the index and hasn't expired
+    fn contains_live_key(&self, key: &[u8]) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|entry| !self.is_expired(&entry))
+    }
+
+    /// Set the value of `key` together with opaque flags which are returned by `get_with_flags`
+    pub fn set_with_flags(&self, key: String, value: String, flags: u32) -> Result<()> {
+        self.set_entry(key.into_bytes(), value.into_bytes(), flags, 0, None)
+    }
+
+    /// Set the value of `key` until `at`, from then on `key` reads as absent until it's set
+    /// again, and the next merge drops it. A time which already passed expires it right away.
+    pub fn set_expire_at(&self, key: String, value: String, at: SystemTime) -> Result<()> {
+        self.set_entry(
+            key.into_bytes(),
+            value.into_bytes(),
+            0,
+            unix_millis(at),
+            None,
+        )
+    }
+
+    /// Set the value of `key` for `ttl` from now by the clock of the engine, like `set_expire_at`
+    /// A `ttl` too large for a point in time fails.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let at = self
+            .options
+            .clock
+            .now()
+            .checked_add(ttl)
+            .ok_or_else(|| KvStoreErr::UnexceptErr(format!("ttl {:?} is too large", ttl)))?;
+        self.set_expire_at(key, value, at)
+    }
+
+    /// Write `value` for `key`, expiring at `expire_at` unix milliseconds unless it's 0
+    fn set_entry(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expire_at: u64,
+        client: Option<SocketAddr>,
+    ) -> Result<()> {
+        let log_entry = LogEntry::new(key, value, flags).expiring_at(expire_at);
+        {
+            let _guard = self.key_locks.lock(&log_entry.key);
+            self.write_value(&log_entry, client)?;
+        }
+        self.merge_if_needed();
+        self.evict_if_needed(Some(&log_entry.key))
+    }
+
+    /// Append the value entry `log_entry` and point the index at it,
+    /// the caller holds the lock stripe of its key
+    fn write_value(&self, log_entry: &LogEntry, client: Option<SocketAddr>) -> Result<()> {
+        let key = &log_entry.key;
+        let old_entry = self.write_and_flush(log_entry, client, |file_id, pos| {
+            // generate index entry
+            let index_entry = IndexEntry {
+                file_id,
+                v_pos: pos,
+                v_size: log_entry.v_size,
+                flags: log_entry.flags,
+                expire_at: log_entry.expire_at,
+            };
+            self.update_value_indexes(key, Some(&log_entry.value));
+            self.index.insert(key.clone(), index_entry)
+        })?;
+        if let Some(old_entry) = old_entry {
+            self.useless_value_bytes
+                .fetch_add(self.garbage_bytes(key, old_entry.v_size), Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn remove_entry(&self, key: &[u8], client: Option<SocketAddr>) -> Result<()> {
+        let guard = self.key_locks.lock(key);
+        // find in index, unless the tombstone is written for absent keys too
+        if !self.options.always_tombstone_on_remove && !self.contains_live_key(key) {
+            // not exists
+            return Err(KvStoreErr::KeyNotFound(
+                String::from_utf8_lossy(key).into_owned(),
+            ));
+        }
+        // write new log entry as remove
+        let log_entry = LogEntry::new(key.to_vec(), [DELETED_CODE; 1].to_vec(), 0);
+        let removed = self.write_and_flush(&log_entry, client, |_, _| {
+            self.update_value_indexes(key, None);
+            self.index.remove(key)
+        })?;
+        drop(guard);
+        // the tombstone is garbage too, a merge drops it once the key is gone from the index
+        let mut useless_value_bytes = self.garbage_bytes(key, 1);
+        if let Some(old_index_entry) = removed {
+            useless_value_bytes += self.garbage_bytes(key, old_index_entry.v_size);
+        }
+        self.useless_value_bytes
+            .fetch_add(useless_value_bytes, Ordering::SeqCst);
+        self.merge_if_needed();
+
+        Ok(())
+    }
+
+    /// Get the value of `key` together with the flags it was set with
+    pub fn get_with_flags(&self, key: String) -> Result<Option<(String, u32)>> {
+        match self.get_from_source(key.as_bytes())? {
+            Some((value, flags, _)) => Ok(Some((String::from_utf8(value)?, flags))),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value of `key` together with where it was found, to tune the value cache
+    pub fn get_with_source(&self, key: &str) -> Result<Option<(String, ValueSource)>> {
+        match self.get_from_source(key.as_bytes())? {
+            Some((value, _, source)) => Ok(Some((String::from_utf8(value)?, source))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_from_source(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u32, ValueSource)>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        // taken before the index entry, so a merge meanwhile keeps the value out of the cache
+        let generation = self.value_cache.as_ref().map(|cache| cache.generation());
+        let _files = self.files_swap.read().unwrap();
+        // find in index
+        // copy the entry out, so no index shard is locked while flushing the writer
+        if let Some(index_entry) = self.index.get(key) {
+            if self.is_expired(&index_entry) {
+                self.remove_expired(key, &index_entry);
+                return Ok(None);
+            }
+            if let Some(evictor) = &self.evictor {
+                evictor.lock().unwrap().touch(key);
+            }
+            if let Some(cache) = &self.value_cache {
+                if let Some(value) = cache.get(key, &index_entry) {
+                    return Ok(Some((value, index_entry.flags, ValueSource::Cache)));
+                }
+            }
+            self.flush_value(&index_entry)?;
+            if let Some(mut reader) = self.file_reader.get_mut(&index_entry.file_id) {
+                let value =
+                    read_checked_value(&mut reader, self.options.entry_format, key, &index_entry)?;
+                if let (Some(cache), Some(generation)) = (&self.value_cache, generation) {
+                    cache.insert(key, &index_entry, &value, generation);
+                }
+                Ok(Some((value, index_entry.flags, ValueSource::Disk)))
+            } else {
+                Err(KvStoreErr::InnerErr("get file reader".to_string()))
+            }
+        } else {
+            // not exists
+            Ok(None)
+        }
+    }
+
+    /// Add `delta` to the integer value of `key` and return the new value,
+    /// a missing key counts as 0
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let new_value = self.read_modify_write(&key, |current| {
+            let current = match current {
+                Some(value) => value
+                    .parse::<i64>()
+                    .map_err(|_| KvStoreErr::NotAnInteger(key.clone()))?,
+                None => 0,
+            };
+            let new_value = current.checked_add(delta).ok_or_else(|| {
+                KvStoreErr::UnexceptErr(format!("increment of key {} overflows", key))
+            })?;
+            Ok((Some(new_value.to_string()), new_value))
+        })?;
+        Ok(new_value)
+    }
+
+    /// Set `key` to `value` if its value is `expected`, `None` expecting it to be missing,
+    /// and return whether it was set
+    pub fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<&str>,
+        value: String,
+    ) -> Result<bool> {
+        self.read_modify_write(&key, |current| {
+            if current.as_deref() == expected {
+                Ok((Some(value), true))
+            } else {
+                Ok((None, false))
+            }
+        })
+    }
+
+    /// Append `suffix` to the value of `key`, a missing key counts as empty,
+    /// and return the length of the new value in bytes
+    pub fn append(&self, key: String, suffix: &str) -> Result<usize> {
+        self.read_modify_write(&key, |current| {
+            let mut value = current.unwrap_or_default();
+            value.push_str(suffix);
+            let len = value.len();
+            Ok((Some(value), len))
+        })
+    }
+
+    /// Read the value of `key` and write what `f` makes of it, if anything, with the lock
+    /// stripe of the key held, so no other write of the key lands in between.
+    /// The written value has no flags and doesn't expire, like one written by `set`.
+    fn read_modify_write<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(Option<String>) -> Result<(Option<String>, T)>,
+    ) -> Result<T> {
+        let (written, result) = {
+            let _guard = self.key_locks.lock(key.as_bytes());
+            let (new_value, result) = f(self.get(key.to_owned())?)?;
+            match new_value {
+                Some(value) => {
+                    let log_entry = LogEntry::new(key.into(), value.into_bytes(), 0);
+                    self.write_value(&log_entry, None)?;
+                    (true, result)
+                }
+                None => (false, result),
+            }
+        };
+        if written {
+            self.merge_if_needed();
+            self.evict_if_needed(Some(key.as_bytes()))?;
+        }
+        Ok(result)
+    }
+
+    /// Swap the values of two keys, together with their flags, in one write
+    ///
+    /// A missing key swaps as no value, so the other key is removed. The stripes of both keys
+    /// are held from the reads to the write, so concurrent sets, removes and increments of
+    /// either key happen entirely before or after the swap.
+    pub fn swap(&self, key_a: String, key_b: String) -> Result<()> {
+        if key_a == key_b {
+            return Ok(());
+        }
+        let _guards = self
+            .key_locks
+            .lock_all(&[key_a.as_bytes(), key_b.as_bytes()]);
+        let mut writer = self.active_file_writer.lock().unwrap();
+        // both values are read under the writer lock, so no write can slip in before the swap
+        writer.flush()?;
+        let index_a = self.index.get(key_a.as_bytes());
+        let index_b = self.index.get(key_b.as_bytes());
+        let value_a = index_a
+            .map(|entry| self.read_value(key_a.as_bytes(), &entry))
+            .transpose()?;
+        let value_b = index_b
+            .map(|entry| self.read_value(key_b.as_bytes(), &entry))
+            .transpose()?;
+
+        let mut log_entries = Vec::new();
+        for (key, old, new) in [
+            (&key_a, &index_a, index_b.zip(value_b)),
+            (&key_b, &index_b, index_a.zip(value_a)),
+        ] {
+            match new {
+                Some((index_entry, value)) => log_entries.push(
+                    LogEntry::new(key.as_bytes().to_vec(), value, index_entry.flags)
+                        .expiring_at(index_entry.expire_at),
+                ),
+                // the key gets no value, which only needs a tombstone if it has one now
+                None if old.is_some() => log_entries.push(LogEntry::new(
+                    key.as_bytes().to_vec(),
+                    [DELETED_CODE; 1].to_vec(),
+                    0,
+                )),
+                None => {}
+            }
+        }
+        let (file_id, positions) = self.append_locked(&mut writer, &log_entries, None)?;
+        let mut useless_value_bytes = 0;
+        for (log_entry, pos) in log_entries.iter().zip(positions) {
+            let key = &log_entry.key;
+            let old_entry = if log_entry.value == [DELETED_CODE] {
+                useless_value_bytes += self.garbage_bytes(key, 1);
+                self.update_value_indexes(key, None);
+                self.index.remove(key)
+            } else {
+                self.update_value_indexes(key, Some(&log_entry.value));
+                self.index.insert(
+                    key.clone(),
+                    IndexEntry {
+                        file_id,
+                        v_pos: pos,
+                        v_size: log_entry.v_size,
+                        flags: log_entry.flags,
+                        expire_at: log_entry.expire_at,
+                    },
+                )
+            };
+            useless_value_bytes +=
+                old_entry.map_or(0, |entry| self.garbage_bytes(key, entry.v_size));
+        }
+        drop(writer);
+        self.useless_value_bytes
+            .fetch_add(useless_value_bytes, Ordering::SeqCst);
+        self.merge_if_needed();
+        Ok(())
+    }
+
+    /// Keys whose values the secondary index extractor maps to `term`, sorted,
+    /// always empty unless the engine was opened with `BitcaskOptions::secondary_index`
+    pub fn find_by_value(&self, term: &str) -> Vec<String> {
+        self.secondary_index
+            .as_ref()
+            .map(|secondary_index| secondary_index.find(term))
+            .unwrap_or_default()
+    }
+
+    /// Keys matching the glob `pattern` with their values, sorted by key.
+    /// `*` matches any run of characters and `?` any single one.
+    ///
+    /// Every key of the index is matched against the pattern, so this takes time linear
+    /// in the number of keys however few match. Keys which aren't utf-8 are matched and
+    /// listed like `for_each_key` passes them.
+    pub fn scan_glob(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut keys: Vec<(String, Vec<u8>)> = self
+            .snapshot_index()
+            .into_iter()
+            .map(|(key, _)| (String::from_utf8_lossy(&key).into_owned(), key))
+            .filter(|(text, _)| glob_match(&pattern, text))
+            .collect();
+        keys.sort();
+        let mut entries = Vec::with_capacity(keys.len());
+        for (text, key) in keys {
+            // skip keys removed since the snapshot
+            if let Some((value, _, _)) = self.get_from_source(&key)? {
+                entries.push((text, String::from_utf8(value)?));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Keep the secondary index, the eviction order, the Bloom filter and the value cache
+    /// in line with the new value of `key`, and tell its watchers
+    ///
+    /// The watchers and the secondary index deal in strings: they get keys which aren't
+    /// utf-8 lossily converted, and the secondary index leaves out values which aren't utf-8.
+    fn update_value_indexes(&self, key: &[u8], value: Option<&[u8]>) {
+        let text_key = String::from_utf8_lossy(key);
+        let text_value = value.map(String::from_utf8_lossy);
+        self.watchers.notify(&text_key, text_value.as_deref());
+        if let Some(cache) = &self.value_cache {
+            cache.remove(key);
+        }
+        if let (Some(filter), Some(_)) = (&self.key_filter, value) {
+            filter.read().unwrap().insert(key);
+        }
+        if let Some(secondary_index) = &self.secondary_index {
+            match value.map(std::str::from_utf8) {
+                Some(Ok(value)) => secondary_index.insert(&text_key, value),
+                _ => secondary_index.remove(&text_key),
+            }
+        }
+        if let Some(evictor) = &self.evictor {
+            let bytes = value.map(|value| (key.len() + value.len()) as u64);
+            evictor.lock().unwrap().update(key, bytes);
+        }
+    }
+
+    /// Whether `key` gets past the Bloom filter, a key which doesn't is surely missing
+    fn may_contain(&self, key: &[u8]) -> bool {
+        let filter = match &self.key_filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        let may_contain = filter.read().unwrap().may_contain(key);
+        if !may_contain {
+            self.filtered_misses.fetch_add(1, Ordering::SeqCst);
+        }
+        may_contain
+    }
+
+    /// Build the Bloom filter again from the live keys, which forgets the removed ones
+    fn rebuild_key_filter(&self) {
+        let filter = match &self.key_filter {
+            Some(filter) => filter,
+            None => return,
         };
-        // serialize to bytes
-        let buf: Vec<u8> = log_entry.serialize();
-        let (file_id, pos) = self.write_and_flush(&buf)?;
-        // generate index entry
-        let index_entry = IndexEntry {
-            file_id: file_id,
-            v_pos: pos,
-            v_size: value_bytes.len() as u64,
+        // no write can insert a key the new filter misses meanwhile
+        let _writer = self.active_file_writer.lock().unwrap();
+        let mut live_keys = 0;
+        self.index.for_each(|_, _| live_keys += 1);
+        let capacity = self.options.bloom_filter_keys.unwrap_or(0);
+        let rebuilt = BloomFilter::with_capacity(capacity.max(live_keys * 2));
+        self.index.for_each(|key, _| rebuilt.insert(key));
+        *filter.write().unwrap() = rebuilt;
+    }
+
+    /// Remove the keys `max_live_bytes` evicts until the store fits, other than `keep`
+    fn evict_if_needed(&self, keep: Option<&[u8]>) -> Result<()> {
+        let (evictor, max_live_bytes) = match (&self.evictor, self.options.max_live_bytes) {
+            (Some(evictor), Some(max_live_bytes)) => (evictor, max_live_bytes),
+            _ => return Ok(()),
         };
-        if let Some(old_entry) = self.index.insert(key, index_entry) {
+        loop {
+            let victim = {
+                let evictor = evictor.lock().unwrap();
+                if evictor.live_bytes() <= max_live_bytes {
+                    return Ok(());
+                }
+                match evictor.victim(keep) {
+                    Some(victim) => victim,
+                    None => return Ok(()),
+                }
+            };
+            match self.remove_bytes(&victim) {
+                Ok(()) => {}
+                // removed meanwhile, make sure it isn't picked again
+                Err(KvStoreErr::KeyNotFound(_)) => evictor.lock().unwrap().update(&victim, None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Tell a removed key from one which never existed, by its tombstone
+    ///
+    /// Removed keys are dropped from the index, so a miss scans the log files newest first,
+    /// which is slow on a large store.
+    pub fn get_with_tombstone(&self, key: &str) -> Result<KeyState> {
+        if let Some(value) = self.get(key.to_owned())? {
+            return Ok(KeyState::Present(value));
+        }
+        self.flush()?;
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        for id in ids.iter().rev() {
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, *id, "log")?;
+            let mut last_entry_deleted = None;
+            loop {
+                let log_entry = match read_log_entry(&mut reader, self.options.entry_format) {
+                    Ok(Some((log_entry, _))) => log_entry,
+                    Ok(None) => break,
+                    // a write to the active file still in progress
+                    Err(KvStoreErr::IncompleteEntry(_)) if ids.last() == Some(id) => break,
+                    Err(err) => return Err(err),
+                };
+                if log_entry.key == key.as_bytes() {
+                    last_entry_deleted = Some(log_entry.value == [DELETED_CODE]);
+                }
+            }
+            match last_entry_deleted {
+                Some(true) => return Ok(KeyState::Deleted),
+                // a value written since the key was looked up
+                Some(false) => return Ok(KeyState::Absent),
+                None => {}
+            }
+        }
+        Ok(KeyState::Absent)
+    }
+
+    /// Read `len` bytes of the value of `key` starting at `offset`,
+    /// the range is cut at the end of the value
+    pub fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        let _files = self.files_swap.read().unwrap();
+        // copy the entry out, so no index shard is locked while flushing the writer
+        if let Some(index_entry) = self.index.get(key.as_bytes()) {
+            self.flush_value(&index_entry)?;
+            let offset = offset.min(index_entry.v_size);
+            let len = len.min(index_entry.v_size - offset);
+            if let Some(mut reader) = self.file_reader.get_mut(&index_entry.file_id) {
+                let bytes =
+                    read_value_bytes(&mut reader, key.as_bytes(), &index_entry, offset, len)?;
+                Ok(Some(bytes))
+            } else {
+                Err(KvStoreErr::InnerErr("get file reader".to_string()))
+            }
+        } else {
+            // not exists
+            Ok(None)
+        }
+    }
+
+    /// Drop the expired index entry of `key` unless a write replaced it meanwhile, so the key
+    /// stops counting as live before a merge drops its entry. Replicas leave their index
+    /// to the primary's log.
+    fn remove_expired(&self, key: &[u8], expired: &IndexEntry) {
+        if self.check_writable().is_err() {
+            return;
+        }
+        let _writer = self.active_file_writer.lock().unwrap();
+        let unchanged = self
+            .index
+            .get(key)
+            .is_some_and(|entry| entry.file_id == expired.file_id && entry.v_pos == expired.v_pos);
+        if unchanged {
+            self.update_value_indexes(key, None);
+            self.index.remove(key);
             self.useless_value_bytes
-                .fetch_add(old_entry.v_size, Ordering::SeqCst);
-            if self.useless_value_bytes.load(Ordering::SeqCst) > self.merge_trigger_threshold {
-                self.merge()?;
+                .fetch_add(self.garbage_bytes(key, expired.v_size), Ordering::SeqCst);
+        }
+    }
+
+    /// Make sure the value of the entry is readable from its file
+    fn flush_value(&self, index_entry: &IndexEntry) -> Result<()> {
+        if index_entry.file_id == self.active_file_id.load(Ordering::SeqCst) {
+            // the value may still be buffered in the active file writer
+            let mut writer = self.active_file_writer.lock().unwrap();
+            if writer.flushed < index_entry.v_pos {
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the values of the index entries which haven't expired, converted lossily to strings
+    /// along with their keys, the caller keeps merges from moving them meanwhile
+    fn read_live_entries(
+        &self,
+        index_entries: Vec<(Vec<u8>, IndexEntry)>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::with_capacity(index_entries.len());
+        for (key, index_entry) in index_entries {
+            if self.is_expired(&index_entry) {
+                continue;
+            }
+            let value = self.read_value(&key, &index_entry)?;
+            entries.push((
+                String::from_utf8_lossy(&key).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ));
+        }
+        Ok(entries)
+    }
+
+    /// Read the value an index entry points to, which must have been flushed already
+    fn read_value(&self, key: &[u8], index_entry: &IndexEntry) -> Result<Vec<u8>> {
+        let mut reader = self
+            .file_reader
+            .get_mut(&index_entry.file_id)
+            .ok_or_else(|| KvStoreErr::InnerErr("get file reader".to_string()))?;
+        read_checked_value(&mut reader, self.options.entry_format, key, index_entry)
+    }
+
+    /// File id and end of the last write handed to the file system, which a tail of the log
+    /// can read up to, unlike writes still buffered in the active file writer
+    pub fn durable_position(&self) -> (u64, u64) {
+        let writer = self.active_file_writer.lock().unwrap();
+        (self.active_file_id.load(Ordering::SeqCst), writer.flushed)
+    }
+
+    /// Bytes written to the active file but still buffered, which a crash of the process loses,
+    /// at most `BitcaskOptions::write_flush_bytes` plus the size of the last write
+    pub fn pending_flush_bytes(&self) -> u64 {
+        let writer = self.active_file_writer.lock().unwrap();
+        writer.pos - writer.flushed
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = self.active_file_writer.lock().unwrap();
+        writer.flush()?;
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the active file and write the index to a checkpoint file in the hint directory,
+    /// so that the next `open` loads it and replays only the writes after it
+    ///
+    /// The checkpoint is consistent with the log: no write lands between the flush and the copy
+    /// of the index. A merge moves the entries it points to, so it removes the checkpoint.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.check_writable()?;
+        // merges and bulk loads move files around, which would make the checkpoint stale
+        let _guard = self.merge_lock.lock().unwrap();
+        let mut writer = self.active_file_writer.lock().unwrap();
+        writer.flush()?;
+        let checkpoint = Checkpoint {
+            file_id: self.active_file_id.load(Ordering::SeqCst),
+            offset: writer.pos,
+            garbage_bytes: self.useless_value_bytes.load(Ordering::SeqCst),
+            entries: self.snapshot_index(),
+        };
+        drop(writer);
+        checkpoint::write(self.store(), &self.dirs.hint_dir, &checkpoint)
+    }
+
+    /// Check that the active file on disk ends where its writer is and that its last entry is intact
+    ///
+    /// Fails with `ActiveFileMismatch` when the file was truncated or appended to behind our back.
+    pub fn check_active_file(&self) -> Result<()> {
+        let mut writer = self.active_file_writer.lock().unwrap();
+        writer.flush()?;
+        let file_id = self.active_file_id.load(Ordering::SeqCst);
+        let size = self
+            .store()
+            .open_read(&log_path(&self.dirs, file_id, "log"))?
+            .size()?;
+        if size != writer.pos {
+            return Err(KvStoreErr::ActiveFileMismatch(file_id, writer.pos, size));
+        }
+        let mut reader = gen_buf_reader(self.store(), &self.dirs, file_id, "log")?;
+        let mut offset = 0;
+        while let Some((log_entry, pos)) = read_log_entry(&mut reader, self.options.entry_format)? {
+            if pos == writer.pos && !log_entry.is_intact() {
+                return Err(KvStoreErr::CorruptEntry(file_id, offset));
+            }
+            offset = pos;
+        }
+        if offset != writer.pos {
+            return Err(KvStoreErr::ActiveFileMismatch(file_id, writer.pos, offset));
+        }
+        Ok(())
+    }
+
+    /// Append `buf` to the active file and update the index with its file id and end position,
+    /// both under the writer lock so the index changes in the same order as the log
+    fn write_and_flush<R>(
+        &self,
+        log_entry: &LogEntry,
+        client: Option<SocketAddr>,
+        update_index: impl FnOnce(u64, u64) -> R,
+    ) -> Result<R> {
+        let mut writer = self.active_file_writer.lock().unwrap();
+        let (file_id, positions) =
+            self.append_locked(&mut writer, std::slice::from_ref(log_entry), client)?;
+        Ok(update_index(file_id, positions[0]))
+    }
+
+    /// Append the entries to the active file in one write, rotating it first if they don't fit,
+    /// and return the file id and the end position of each entry
+    ///
+    /// The entries are recorded in the audit log as written by `client`.
+    fn append_locked(
+        &self,
+        writer: &mut LogWriter,
+        log_entries: &[LogEntry],
+        client: Option<SocketAddr>,
+    ) -> Result<(u64, Vec<u64>)> {
+        self.check_writable()?;
+        let entries_bytes_at = |mut pos: u64| {
+            let mut buf = Vec::new();
+            let mut positions = Vec::with_capacity(log_entries.len());
+            for log_entry in log_entries {
+                buf.append(&mut self.entry_bytes_at(pos + buf.len() as u64, log_entry));
+                positions.push(pos + buf.len() as u64);
+            }
+            pos += buf.len() as u64;
+            (buf, positions, pos)
+        };
+        let (mut buf, mut positions, mut end) = entries_bytes_at(writer.pos);
+        let mut now_file_id = self.active_file_id.load(Ordering::SeqCst);
+        if end > self.options.log_file_max_bytes {
+            // seal the active file, a failure leaves it active
+            writer.flush()?;
+            if self.options.seal_footer {
+                let len = writer.pos;
+                if let Err(err) = self.append_footer(writer, now_file_id) {
+                    self.reset_active_writer(writer, now_file_id, len)?;
+                    return Err(err);
+                }
+            }
+            if self.options.sync_on_rotation {
+                writer.get_ref().sync()?;
+            }
+            // check out new active file writer
+            self.active_file_id.fetch_add(1, Ordering::SeqCst);
+            *self.active_file_digest.lock().unwrap() = None;
+            if self.options.rotation_merge_garbage_ratio.is_some() {
+                self.sealed_file.store(now_file_id, Ordering::SeqCst);
+            }
+            now_file_id += 1;
+            *writer = gen_file_writer_with_pos(self.store(), &self.dirs, now_file_id, "log")?;
+            self.restart_digest(writer);
+            if self.options.sync_on_rotation {
+                self.store().sync_dir(&self.dirs.log_dir)?;
+            }
+            self.file_reader.insert(
+                now_file_id,
+                gen_buf_reader(self.store(), &self.dirs, now_file_id, "log")?,
+            );
+            (buf, positions, end) = entries_bytes_at(writer.pos);
+        }
+        let start = writer.pos;
+        if let Err(err) = append_entry(writer, &buf, self.options.write_flush_bytes) {
+            // don't leave a torn entry for the next write to be appended after
+            self.reset_active_writer(writer, now_file_id, start)?;
+            return Err(err);
+        }
+        debug_assert_eq!(writer.pos, end);
+        if self.options.seal_footer {
+            if let Some(digest) = self.active_file_digest.lock().unwrap().as_mut() {
+                digest.update(&buf, log_entries.len() as u64);
+            }
+        }
+        for log_entry in log_entries {
+            self.audit(
+                &String::from_utf8_lossy(&log_entry.key),
+                log_entry.value == [DELETED_CODE],
+                client,
+            );
+        }
+        Ok((now_file_id, positions))
+    }
+
+    /// Record a set or remove of `key` in the audit log, if there is one
+    ///
+    /// The write is already in the log file by now, so a failure to record it is only logged.
+    fn audit(&self, key: &str, removed: bool, client: Option<SocketAddr>) {
+        if let Some(audit_log) = &self.audit_log {
+            let now = self.options.clock.now();
+            if let Err(err) = audit_log.record(now, removed, key, client) {
+                warn!(
+                    "failed to record the write of key {} in the audit log: {}",
+                    key, err
+                );
+            }
+        }
+    }
+
+    /// Serialize the entry to be written at `pos`, after the padding which aligns its value
+    fn entry_bytes_at(&self, pos: u64, log_entry: &LogEntry) -> Vec<u8> {
+        let format = self.options.entry_format;
+        let mut buf = padding_before(format, pos, log_entry, self.options.value_alignment);
+        buf.append(&mut format.encode(log_entry));
+        buf
+    }
+
+    /// Append the footer of the active file about to be sealed, reading the file back
+    /// to count its entries and checksum it
+    fn append_footer(&self, writer: &mut LogWriter, file_id: u64) -> Result<()> {
+        let digest = self.active_file_digest.lock().unwrap().clone();
+        let footer = match digest {
+            Some(digest) => digest.footer(),
+            // the file was opened or rewritten as it was, read it back once
+            None => {
+                let mut reader = gen_buf_reader(self.store(), &self.dirs, file_id, "log")?;
+                let mut entries = 0;
+                while read_log_entry(&mut reader, self.options.entry_format)?.is_some() {
+                    entries += 1;
+                }
+                Footer {
+                    entries,
+                    crc: log_file_checksum(self.store(), &self.dirs, file_id, writer.pos)?,
+                }
+            }
+        };
+        writer.write_all(&footer.encode(self.options.entry_format))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Keep the footer of the active file anew after its writer was replaced,
+    /// which is only possible when the file starts out empty
+    fn restart_digest(&self, writer: &LogWriter) {
+        *self.active_file_digest.lock().unwrap() =
+            (self.options.seal_footer && writer.pos == 0).then(FileDigest::default);
+    }
+
+    /// Replace the active file writer, dropping what it buffers and truncating the file to `len`
+    fn reset_active_writer(&self, writer: &mut LogWriter, file_id: u64, len: u64) -> Result<()> {
+        let path = log_path(&self.dirs, file_id, "log");
+        // dropping the old writer flushes what it still buffers, so truncate after that
+        let old_writer = std::mem::replace(
+            writer,
+            BufWriterWithPos::new(self.store().open_append(&path)?)?,
+        );
+        drop(old_writer);
+        let file = self.store().open_append(&path)?;
+        if file.size()? > len {
+            file.set_len(len)?;
+        }
+        *writer = gen_file_writer_with_pos(self.store(), &self.dirs, file_id, "log")?;
+        Ok(())
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<BitcaskEngine> {
+        Self::open_with_options(path, BitcaskOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: BitcaskOptions,
+    ) -> Result<BitcaskEngine> {
+        let path_buf: PathBuf = path.into();
+        let dirs = DataDirs {
+            hint_dir: options.hint_dir.clone().unwrap_or_else(|| path_buf.clone()),
+            log_dir: path_buf,
+        };
+        let store = options.block_store.as_ref();
+        if !options.read_only {
+            store.create_dir_all(&dirs.log_dir)?;
+            store.create_dir_all(&dirs.hint_dir)?;
+        }
+        config::check_or_mark_engine(
+            store,
+            &dirs.log_dir,
+            config::BITCASK_ENGINE,
+            options.read_only,
+        )?;
+        // the files may have been written with a layout the options no longer agree with
+        config::check_or_record(store, &dirs.log_dir, &options)?;
+        if !options.read_only {
+            finish_install(store, &dirs)?;
+        }
+        let log_id_list = get_all_sorted_log_file_id(store, &dirs.log_dir)?;
+        let index = Arc::new(KeyIndex::new(options.index_kind, options.entry_format));
+        let file_reader: DashMap<u64, LogReader> = DashMap::new();
+        let mut useless_value_bytes: u64 = 0;
+        // a replica replays the files in `refresh` below, and never truncates them
+        let replayed_on_open: &[u64] = if options.read_only { &[] } else { &log_id_list };
+        let checkpoint = match checkpoint::read(store, &dirs.hint_dir)? {
+            Some(checkpoint) if !options.read_only && log_id_list.contains(&checkpoint.file_id) => {
+                // the file may have lost the end the checkpoint covers
+                let log_len = store
+                    .open_read(&log_path(&dirs, checkpoint.file_id, "log"))?
+                    .size()?;
+                (log_len >= checkpoint.offset).then_some(checkpoint)
+            }
+            _ => None,
+        };
+        let mut replay_from = 0;
+        if let Some(checkpoint) = &checkpoint {
+            for (key, entry) in &checkpoint.entries {
+                index.insert(key.clone(), *entry);
+            }
+            useless_value_bytes += checkpoint.garbage_bytes;
+        }
+        for id in replayed_on_open {
+            let mut reader = gen_buf_reader(store, &dirs, *id, "log")?;
+            // the checkpoint covers the files before its active file, and that one up to its offset
+            if let Some(checkpoint) = &checkpoint {
+                if *id < checkpoint.file_id {
+                    file_reader.insert(*id, reader);
+                    continue;
+                }
+                replay_from = if *id == checkpoint.file_id {
+                    checkpoint.offset
+                } else {
+                    0
+                };
+            }
+            let hint_file_path = log_path(&dirs, *id, "hint");
+            let loaded_from_hint = replay_from == 0
+                && store.exists(&hint_file_path)
+                && load_from_hint_file(
+                    *id,
+                    &mut gen_buf_reader(store, &dirs, *id, "hint")?,
+                    index.clone(),
+                    &options,
+                    store.open_read(&log_path(&dirs, *id, "log"))?.size()?,
+                )?;
+            if !loaded_from_hint {
+                let active = log_id_list.last() == Some(id);
+                let (useless, valid_len) = load_from_log_file(
+                    *id,
+                    &mut reader,
+                    index.clone(),
+                    &options,
+                    replay_from,
+                    active,
+                )?;
+                useless_value_bytes += useless;
+                if active {
+                    // drop the half-written tail so that new entries are appended after the valid prefix
+                    let file = store.open_append(&log_path(&dirs, *id, "log"))?;
+                    if file.size()? > valid_len {
+                        file.set_len(valid_len)?;
+                    }
+                }
+            }
+            file_reader.insert(*id, reader);
+        }
+        let active_file_writer: LogWriter;
+        let active_file_id;
+        if options.read_only {
+            // the writer of a replica only tracks the size of the active file
+            active_file_id = log_id_list.last().copied().unwrap_or(0);
+            active_file_writer =
+                BufWriterWithPos::new(store.open_read(&log_path(&dirs, active_file_id, "log"))?)?;
+        } else if log_id_list.is_empty() {
+            // now data is empty
+            // create first log file
+            active_file_id = 0;
+            active_file_writer = gen_file_writer_with_pos(store, &dirs, active_file_id, "log")?;
+            file_reader.insert(
+                active_file_id,
+                gen_buf_reader(store, &dirs, active_file_id, "log")?,
+            );
+        } else {
+            active_file_id = *log_id_list.last().unwrap();
+            active_file_writer = gen_file_writer_with_pos(store, &dirs, active_file_id, "log")?;
+        }
+
+        let kv = BitcaskEngine {
+            index: index.clone(),
+            dirs: Arc::new(dirs),
+            active_file_id: Arc::new(AtomicU64::new(active_file_id)),
+            // a file the engine starts empty is written by it alone
+            active_file_digest: Arc::new(Mutex::new(
+                (options.seal_footer && active_file_writer.pos == 0).then(FileDigest::default),
+            )),
+            active_file_writer: Arc::new(Mutex::new(active_file_writer)),
+            file_reader: Arc::new(file_reader),
+            useless_value_bytes: Arc::new(AtomicU64::new(useless_value_bytes)),
+            merge_bytes_written: Arc::new(AtomicU64::new(0)),
+            merge_bytes_reclaimed: Arc::new(AtomicU64::new(0)),
+            key_locks: Arc::new(LockStripes::default()),
+            merge_lock: Arc::new(Mutex::new(())),
+            files_swap: Arc::new(RwLock::new(())),
+            merge_queued: Arc::new(AtomicBool::new(false)),
+            compaction_paused: Arc::new(AtomicBool::new(false)),
+            sealed_file: Arc::new(AtomicU64::new(NO_FILE)),
+            secondary_index: options
+                .secondary_index
+                .clone()
+                .map(|extract| Arc::new(SecondaryIndex::new(extract))),
+            key_filter: options
+                .bloom_filter_keys
+                .map(|keys| Arc::new(RwLock::new(BloomFilter::with_capacity(keys)))),
+            filtered_misses: Arc::new(AtomicU64::new(0)),
+            watchers: Arc::new(Watchers::default()),
+            value_cache: (options.value_cache_bytes.is_some()
+                || options.value_cache_entries.is_some())
+            .then(|| {
+                Arc::new(ValueCache::new(
+                    options.value_cache_bytes.unwrap_or(u64::MAX),
+                    options.value_cache_entries.unwrap_or(usize::MAX),
+                ))
+            }),
+            evictor: options
+                .max_live_bytes
+                .map(|_| Arc::new(Mutex::new(Evictor::new(options.eviction_policy)))),
+            merge_worker: None,
+            _merge_schedule: None,
+            replica: options
+                .read_only
+                .then(|| Arc::new(Mutex::new(BTreeMap::new()))),
+            _refresh_schedule: None,
+            audit_log: match &options.audit_log {
+                Some(path) if !options.read_only => Some(Arc::new(AuditLog::open(path)?)),
+                _ => None,
+            },
+            options: Arc::new(options),
+        };
+        kv.refresh()?;
+        if let Some(max_index_bytes) = kv.options.max_index_bytes {
+            let estimate = kv.index.memory_estimate();
+            if estimate > max_index_bytes {
+                return Err(KvStoreErr::IndexTooLarge(estimate, max_index_bytes));
+            }
+        }
+        // a replica built its filter in refresh
+        if kv.replica.is_none() {
+            kv.rebuild_key_filter();
+        }
+        if let Some(evictor) = &kv.evictor {
+            // the order of the writes is the order of the entries in the log files
+            let mut entries = kv.snapshot_index();
+            entries.sort_by_key(|(_, entry)| (entry.file_id, entry.v_pos));
+            let mut evictor = evictor.lock().unwrap();
+            for (key, entry) in entries {
+                evictor.update(&key, Some(key.len() as u64 + entry.v_size));
+            }
+        }
+        if let Some(secondary_index) = &kv.secondary_index {
+            for (key, _) in kv.snapshot_index() {
+                if let Some(value) = kv.get_bytes(&key)? {
+                    if let Ok(value) = std::str::from_utf8(&value) {
+                        secondary_index.insert(&String::from_utf8_lossy(&key), value);
+                    }
+                }
+            }
+        }
+        if kv.options.check_active_file_on_open {
+            kv.check_active_file()?;
+        }
+        let mut kv = kv;
+        if !kv.options.read_only && kv.options.merge_pool.is_none() {
+            kv.merge_worker = Some(Arc::new(kv.start_merge_worker()?));
+        }
+        if let Some(schedule) = kv.options.merge_schedule {
+            kv._merge_schedule = Some(Arc::new(kv.start_merge_schedule(schedule)?));
+        }
+        if let (true, Some(interval)) = (kv.options.read_only, kv.options.refresh_interval) {
+            kv._refresh_schedule = Some(Arc::new(kv.start_refresh_schedule(interval)?));
+        }
+        Ok(kv)
+    }
+
+    /// Pick up what the engine writing to the directory of this read-only replica wrote since
+    /// the last refresh: the tail appended to the active file and new log files.
+    /// Does nothing on an engine which isn't a replica.
+    ///
+    /// Once a merge rewrote files the replica already read, all files are replayed again.
+    /// A refresh in the middle of a merge may see some files missing, the next one catches up.
+    pub fn refresh(&self) -> Result<()> {
+        let replica = match &self.replica {
+            Some(replica) => replica,
+            None => return Ok(()),
+        };
+        let mut replayed = replica.lock().unwrap();
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        let mut sizes = BTreeMap::new();
+        for id in &ids {
+            let hint_path = log_path(&self.dirs, *id, "hint");
+            let hint_len = match self.store().exists(&hint_path) {
+                true => Some(self.store().open_read(&hint_path)?.size()?),
+                false => None,
+            };
+            sizes.insert(
+                *id,
+                (self.files_size([(*id, "log")].into_iter())?, hint_len),
+            );
+        }
+        let rewritten = replayed.iter().any(|(id, file)| match sizes.get(id) {
+            Some((log_len, hint_len)) => *log_len < file.log_len || *hint_len != file.hint_len,
+            None => true,
+        });
+
+        if rewritten {
+            // replay everything into a new index, then bring the index in line with it
+            let index = Arc::new(KeyIndex::new(
+                self.options.index_kind,
+                self.options.entry_format,
+            ));
+            let mut replayed_again = BTreeMap::new();
+            for id in &ids {
+                let file = self.replay_file(&index, *id, ReplayedFile::default())?;
+                replayed_again.insert(*id, file);
+            }
+            let mut stale_keys = Vec::new();
+            self.index.for_each(|key, _| {
+                if !index.contains_key(key) {
+                    stale_keys.push(key.to_vec());
+                }
+            });
+            for key in stale_keys {
+                self.index.remove(&key);
+            }
+            index.for_each(|key, entry| {
+                self.index.insert(key.to_vec(), *entry);
+            });
+            self.file_reader.retain(|id, _| sizes.contains_key(id));
+            // the rewritten files may hold other values where cached ones were
+            if let Some(cache) = &self.value_cache {
+                cache.clear();
+            }
+            for id in &ids {
+                self.file_reader
+                    .insert(*id, gen_buf_reader(self.store(), &self.dirs, *id, "log")?);
+            }
+            *replayed = replayed_again;
+        } else {
+            for id in &ids {
+                let from = replayed.get(id).copied();
+                if from.is_none() {
+                    self.file_reader
+                        .insert(*id, gen_buf_reader(self.store(), &self.dirs, *id, "log")?);
+                }
+                let file = self.replay_file(&self.index, *id, from.unwrap_or_default())?;
+                replayed.insert(*id, file);
+            }
+        }
+
+        // follow the active file of the writing engine
+        if let Some(active_file_id) = ids.last() {
+            let mut writer = self.active_file_writer.lock().unwrap();
+            *writer = BufWriterWithPos::new(self.store().open_read(&log_path(
+                &self.dirs,
+                *active_file_id,
+                "log",
+            ))?)?;
+            self.active_file_id.store(*active_file_id, Ordering::SeqCst);
+        }
+        drop(replayed);
+        self.rebuild_key_filter();
+        Ok(())
+    }
+
+    /// Fail with `KvStoreErr::ReadOnly` on a replica
+    fn check_writable(&self) -> Result<()> {
+        match self.replica {
+            Some(_) => Err(KvStoreErr::ReadOnly),
+            None => Ok(()),
+        }
+    }
+
+    /// Replay the log file `id` into `index` from where `from` left off, for a replica
+    fn replay_file(
+        &self,
+        index: &Arc<KeyIndex<IndexEntry>>,
+        id: u64,
+        from: ReplayedFile,
+    ) -> Result<ReplayedFile> {
+        let hint_path = log_path(&self.dirs, id, "hint");
+        if self.store().exists(&hint_path) {
+            // a file with hints is sealed, so it's loaded only once
+            if from.hint_len.is_some() {
+                return Ok(from);
+            }
+            let hint_len = self.files_size([(id, "hint")].into_iter())?;
+            let log_len = self.files_size([(id, "log")].into_iter())?;
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, id, "hint")?;
+            if load_from_hint_file(id, &mut reader, index.clone(), &self.options, log_len)? {
+                return Ok(ReplayedFile {
+                    log_len,
+                    hint_len: Some(hint_len),
+                });
+            }
+        }
+        // an entry still being written is read again by the next refresh, in whichever file
+        let mut reader = gen_buf_reader(self.store(), &self.dirs, id, "log")?;
+        let (_, valid_len) = load_from_log_file(
+            id,
+            &mut reader,
+            index.clone(),
+            &self.options,
+            from.log_len,
+            true,
+        )?;
+        Ok(ReplayedFile {
+            log_len: valid_len,
+            hint_len: None,
+        })
+    }
+
+    /// Spawn the thread which refreshes a replica every `interval`, like the merge schedule
+    fn start_refresh_schedule(&self, interval: Duration) -> Result<ScheduleStop> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let engine = self.clone();
+        thread::Builder::new()
+            .name("bitcask-refresh".to_owned())
+            .spawn(move || loop {
+                match receiver.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if let Err(err) = engine.refresh() {
+                            warn!("refresh of the replica failed: {}", err);
+                        }
+                    }
+                    _ => return,
+                }
+            })?;
+        Ok(ScheduleStop { _sender: sender })
+    }
+
+    /// Number of live keys
+    ///
+    /// A tombstone removes its key from the index, whether it's written by `remove`, for an
+    /// absent key with `always_tombstone_on_remove`, by `swap` or by an eviction, or replayed
+    /// from a log file. Merges drop tombstones, so hint files hold none. The index thus holds
+    /// only live keys but the expired ones, which `len`, `for_each_key`, `keys` and `scan_glob`
+    /// all leave out. While keys with an expiry are in the index, `len` goes through it.
+    pub fn len(&self) -> usize {
+        if !self.index.has_expiring() {
+            return self.index.len();
+        }
+        let mut len = 0;
+        self.index.for_each(|_, entry| {
+            if !self.is_expired(entry) {
+                len += 1;
+            }
+        });
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rough bytes the index takes in memory: the key bytes, an `IndexEntry` per key
+    /// and the overhead of the map's slots, which takes going through the whole index
+    pub fn index_memory_estimate(&self) -> u64 {
+        self.index.memory_estimate()
+    }
+
+    /// Call `f` with every live key, in no particular order, without collecting them
+    ///
+    /// This isn't a snapshot like `keys`: the index is walked a shard at a time while writes
+    /// go on. A key which lives through the whole walk is visited exactly once, a key set or
+    /// removed meanwhile may or may not be. `f` runs while a shard of the index is locked,
+    /// so it must not write to the store. A key which isn't utf-8, set with `set_bytes`,
+    /// is passed lossily converted.
+    pub fn for_each_key(&self, mut f: impl FnMut(&str)) {
+        self.index.for_each(|key, entry| {
+            if !self.is_expired(entry) {
+                f(&String::from_utf8_lossy(key))
+            }
+        });
+    }
+
+    /// List the log files sorted by id, with their sizes and how much of them is dead
+    pub fn log_files(&self) -> Result<Vec<LogFileInfo>> {
+        let (active_file_id, active_file_size) = {
+            let writer = self.active_file_writer.lock().unwrap();
+            (self.active_file_id.load(Ordering::SeqCst), writer.pos)
+        };
+        let mut files = Vec::new();
+        for file_id in get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)? {
+            let is_active = file_id == active_file_id;
+            let (size, footer) = if is_active {
+                (active_file_size, None)
+            } else {
+                let size = self
+                    .store()
+                    .open_read(&log_path(&self.dirs, file_id, "log"))?
+                    .size()?;
+                let mut reader = gen_buf_reader(self.store(), &self.dirs, file_id, "log")?;
+                (
+                    size,
+                    Footer::read(&mut reader, self.options.entry_format, size)?,
+                )
+            };
+            files.push(LogFileInfo {
+                file_id,
+                size,
+                is_active,
+                has_hint: self.store().exists(&log_path(&self.dirs, file_id, "hint")),
+                dead_bytes: size,
+                footer_entries: footer.map(|footer| footer.entries),
+            });
+        }
+        // whatever isn't taken by the entry of a live key is dead
+        self.index.for_each(|key, entry| {
+            let live_bytes = entry.v_pos - entry_start(self.options.entry_format, key, entry);
+            if let Ok(pos) = files.binary_search_by_key(&entry.file_id, |file| file.file_id) {
+                files[pos].dead_bytes = files[pos].dead_bytes.saturating_sub(live_bytes);
+            }
+        });
+        Ok(files)
+    }
+
+    /// Ids of the log files the engine keeps a reader of, sorted
+    pub fn reader_file_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .file_reader
+            .iter()
+            .map(|reader| *reader.key())
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Keep a reader of exactly the `.log` files in the directory: drop the readers of files
+    /// which are gone, open the missing ones and release the spare capacity of the reader map.
+    /// Return the ids of the dropped readers, sorted.
+    pub fn prune_readers(&self) -> Result<Vec<u64>> {
+        // merges move the files around
+        let _guard = self.merge_lock.lock().unwrap();
+        self.prune_readers_exclusive()
+    }
+
+    /// Prune the readers while holding the merge lock
+    fn prune_readers_exclusive(&self) -> Result<Vec<u64>> {
+        // no rotation may add a file meanwhile
+        let _writer = self.active_file_writer.lock().unwrap();
+        let ids: BTreeSet<u64> = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?
+            .into_iter()
+            .collect();
+        let mut pruned = Vec::new();
+        self.file_reader.retain(|id, _| {
+            if !ids.contains(id) {
+                pruned.push(*id);
+            }
+            ids.contains(id)
+        });
+        for id in &ids {
+            if !self.file_reader.contains_key(id) {
+                self.file_reader
+                    .insert(*id, gen_buf_reader(self.store(), &self.dirs, *id, "log")?);
+            }
+        }
+        self.file_reader.shrink_to_fit();
+        if !pruned.is_empty() {
+            debug!("pruned the readers of gone log files {:?}", pruned);
+        }
+        pruned.sort_unstable();
+        Ok(pruned)
+    }
+
+    /// Ids of the sealed log files which hold no entry of a live key and no tombstone, sorted
+    ///
+    /// Nothing reads them anymore, so they can be removed along with their hint files without
+    /// merging the other files. Files with tombstones are left out, as replaying the older
+    /// files without them would bring the removed keys back.
+    pub fn orphaned_files(&self) -> Result<Vec<u64>> {
+        // merges move the entries between files
+        let _guard = self.merge_lock.lock().unwrap();
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        // read after listing, so a file a rotation sealed meanwhile counts as active,
+        // as entries may still have been written to it
+        let active_file_id = self.active_file_id.load(Ordering::SeqCst);
+        let mut live_files = BTreeSet::new();
+        self.index.for_each(|_, entry| {
+            live_files.insert(entry.file_id);
+        });
+        let mut orphaned_files = Vec::new();
+        for id in ids {
+            if id >= active_file_id || live_files.contains(&id) {
+                continue;
+            }
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, id, "log")?;
+            let mut has_tombstone = false;
+            while let Some((log_entry, _)) = read_log_entry(&mut reader, self.options.entry_format)?
+            {
+                if log_entry.value == [DELETED_CODE] {
+                    has_tombstone = true;
+                    break;
+                }
+            }
+            if !has_tombstone {
+                orphaned_files.push(id);
+            }
+        }
+        Ok(orphaned_files)
+    }
+
+    /// Scan the files a merge would rewrite and estimate its payoff, without modifying anything
+    pub fn merge_dry_run(&self) -> Result<MergeEstimate> {
+        // a merge would remove the files while they are scanned
+        let _guard = self.merge_lock.lock().unwrap();
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        let old_log_file_ids = &ids[..ids.len().saturating_sub(1)];
+        let mut estimate = MergeEstimate {
+            files_to_rewrite: old_log_file_ids.to_vec(),
+            ..Default::default()
+        };
+        for id in old_log_file_ids {
+            let log_file_path = log_path(&self.dirs, *id, "log");
+            estimate.input_bytes += self.store().open_read(&log_file_path)?.size()?;
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, *id, "log")?;
+            while let Some((log_entry, pos)) =
+                read_log_entry(&mut reader, self.options.entry_format)?
+            {
+                if let Some(value) = self.index.get(&log_entry.key) {
+                    if value.file_id == *id && value.v_pos == pos {
+                        // this log is up to date and would be kept
+                        estimate.estimated_output_bytes +=
+                            self.options.entry_format.encode(&log_entry).len() as u64;
+                    }
+                }
+            }
+        }
+        estimate.reclaimable_bytes = estimate
+            .input_bytes
+            .saturating_sub(estimate.estimated_output_bytes);
+        Ok(estimate)
+    }
+
+    pub fn merge(&self) -> Result<()> {
+        self.merge_report().map(|_| ())
+    }
+
+    /// Merge like `merge`, and report what the merge did
+    pub fn merge_report(&self) -> Result<MergeReport> {
+        let pool = match &self.options.merge_pool {
+            Some(pool) => pool,
+            None => {
+                let _guard = self.merge_lock.lock().unwrap();
+                return self.merge_exclusive();
+            }
+        };
+        // wait for the merge to get a turn on the pool
+        let (sender, receiver) = mpsc::channel();
+        let engine = self.clone();
+        pool.execute(move || {
+            let _guard = engine.merge_lock.lock().unwrap();
+            let _ = sender.send(engine.merge_exclusive());
+        });
+        receiver
+            .recv()
+            .map_err(|_| KvStoreErr::InnerErr("merge pool dropped the merge".to_owned()))?
+    }
+
+    /// Spawn the thread which merges on `schedule`, it holds a clone of the engine
+    /// without the returned stop, so dropping every other clone stops it
+    fn start_merge_schedule(&self, schedule: MergeSchedule) -> Result<ScheduleStop> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let engine = self.clone();
+        thread::Builder::new()
+            .name("bitcask-merge-schedule".to_owned())
+            .spawn(move || loop {
+                let delay = schedule.next_delay(engine.options.clock.now());
+                match receiver.recv_timeout(delay) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if engine.compaction_paused.load(Ordering::SeqCst) {
+                            info!("compaction is paused, skip the scheduled merge");
+                            continue;
+                        }
+                        if let Err(err) = engine.merge() {
+                            warn!("scheduled merge failed: {}", err);
+                        }
+                    }
+                    _ => return,
+                }
+            })?;
+        Ok(ScheduleStop { _sender: sender })
+    }
+
+    /// Spawn the thread which runs the merges `merge_if_needed` queues, it holds a clone of
+    /// the engine without the returned worker, so dropping every other clone stops it
+    fn start_merge_worker(&self) -> Result<MergeWorker> {
+        let (sender, receiver) = mpsc::channel::<MergeTrigger>();
+        let engine = self.clone();
+        let thread = thread::Builder::new()
+            .name("bitcask-merge".to_owned())
+            .spawn(move || {
+                for trigger in receiver {
+                    engine.run_queued_merge(trigger);
+                }
+            })?;
+        Ok(MergeWorker {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue a merge once the useless value bytes grow beyond the threshold,
+    /// unless one is already queued or compaction is paused
+    ///
+    /// The merge runs on the merge worker, or the merge pool when there is one, so the write
+    /// which triggered it doesn't wait for it, and a failed merge is only logged.
+    fn merge_if_needed(&self) {
+        if self.compaction_paused.load(Ordering::SeqCst) {
+            return;
+        }
+        let trigger = match self.merge_trigger() {
+            Some(trigger) => trigger,
+            None => return,
+        };
+        // queue one merge at a time
+        if self.merge_queued.swap(true, Ordering::SeqCst) {
+            debug!("merge threshold crossed while a merge is queued");
+            return;
+        }
+        if let Some(pool) = &self.options.merge_pool {
+            let engine = self.clone();
+            pool.execute(move || engine.run_queued_merge(trigger));
+            return;
+        }
+        let sender = self
+            .merge_worker
+            .as_ref()
+            .and_then(|worker| worker.sender.as_ref());
+        match sender {
+            Some(sender) => {
+                if let Err(mpsc::SendError(trigger)) = sender.send(trigger) {
+                    warn!("merge worker stopped, merge on the writing thread");
+                    self.run_queued_merge(trigger);
+                }
+            }
+            // the worker's own clone of the engine, or a read-only replica
+            None => self.run_queued_merge(trigger),
+        }
+    }
+
+    /// Run a merge `merge_if_needed` queued, unless it's no longer needed by its turn
+    fn run_queued_merge(&self, trigger: MergeTrigger) {
+        self.merge_queued.store(false, Ordering::SeqCst);
+        // resuming triggers the merge again
+        if self.compaction_paused.load(Ordering::SeqCst) {
+            return;
+        }
+        let _guard = self.merge_lock.lock().unwrap();
+        // a merge which ran meanwhile may have cleaned up below the threshold
+        if matches!(trigger, MergeTrigger::Threshold { .. })
+            && self.useless_value_bytes.load(Ordering::SeqCst)
+                <= self.options.merge_trigger_threshold
+        {
+            return;
+        }
+        self.run_triggered_merge(&trigger);
+    }
+
+    /// Why the last write should trigger a merge, if it should
+    fn merge_trigger(&self) -> Option<MergeTrigger> {
+        let useless_value_bytes = self.useless_value_bytes.load(Ordering::SeqCst);
+        if useless_value_bytes > self.options.merge_trigger_threshold {
+            return Some(MergeTrigger::Threshold {
+                useless_value_bytes,
+                threshold: self.options.merge_trigger_threshold,
+            });
+        }
+        let sealed_file = self.sealed_file.swap(NO_FILE, Ordering::SeqCst);
+        match (sealed_file, self.options.rotation_merge_garbage_ratio) {
+            (NO_FILE, _) | (_, None) => None,
+            (file_id, Some(max_ratio)) => {
+                let ratio = match self.garbage_ratio(file_id) {
+                    Ok(ratio) => ratio,
+                    Err(err) => {
+                        warn!(
+                            "failed to find the garbage of log file {}: {}",
+                            file_id, err
+                        );
+                        return None;
+                    }
+                };
+                (ratio >= max_ratio).then_some(MergeTrigger::SealedFileGarbage {
+                    file_id,
+                    ratio,
+                    max_ratio,
+                })
+            }
+        }
+    }
+
+    /// Fraction of the log file `file_id` which isn't taken by the entry of a live key
+    fn garbage_ratio(&self, file_id: u64) -> Result<f64> {
+        let size = self.files_size([(file_id, "log")].into_iter())?;
+        if size == 0 {
+            return Ok(0.0);
+        }
+        let live_bytes = self.index.live_bytes(file_id);
+        Ok(size.saturating_sub(live_bytes) as f64 / size as f64)
+    }
+
+    /// Run the merge a write triggered while holding the merge lock, and log why and how it went
+    fn run_triggered_merge(&self, trigger: &MergeTrigger) {
+        info!("{}, start merging", trigger);
+        match self.merge_exclusive() {
+            Ok(report) => info!(
+                "triggered merge reclaimed {} bytes in {:?}, useless value bytes now {}",
+                report.bytes_reclaimed,
+                report.duration,
+                self.useless_value_bytes.load(Ordering::SeqCst)
+            ),
+            Err(err) => warn!("merge triggered by a write failed: {}", err),
+        }
+    }
+
+    /// Merge while holding the merge lock
+    fn merge_exclusive(&self) -> Result<MergeReport> {
+        self.check_writable()?;
+        let start = Instant::now();
+        let mut retries = 0;
+        loop {
+            match self.merge_once() {
+                Err(KvStoreErr::Cancelled) => return Err(KvStoreErr::Cancelled),
+                Err(err) if retries < self.options.merge_retries => {
+                    retries += 1;
+                    warn!(
+                        "merge failed: {}, retry {}/{}",
+                        err, retries, self.options.merge_retries
+                    );
+                }
+                Ok(report) => {
+                    if report.files_merged > 0 {
+                        self.refresh_after_merge()?;
+                    }
+                    return Ok(MergeReport {
+                        duration: start.elapsed(),
+                        ..report
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Bring what is derived from the log files up to date once a merge replaced some
+    fn refresh_after_merge(&self) -> Result<()> {
+        self.rebuild_key_filter();
+        if let Some(cache) = &self.value_cache {
+            cache.clear();
+        }
+        if self.options.prune_readers_on_merge {
+            self.prune_readers_exclusive()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the live entries of the sealed log file `file_id` into a new file of the same id
+    /// which replaces it, leaving the other files as they are
+    ///
+    /// Like a merge of that one file, it drops the dead entries and gets a hint file.
+    /// A file without live entries goes away unless `empty_merged_files` keeps it.
+    /// A file after the oldest one is refused while it holds tombstones or entries with an
+    /// expiry, as replaying the older files without them would bring back their values.
+    pub fn rewrite_file(&self, file_id: u64) -> Result<()> {
+        self.check_writable()?;
+        let _guard = self.merge_lock.lock().unwrap();
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        if !ids[..ids.len().saturating_sub(1)].contains(&file_id) {
+            return Err(KvStoreErr::UnexceptErr(format!(
+                "log file {} isn't a sealed log file",
+                file_id
+            )));
+        }
+        if ids[0] != file_id && self.shadows_older_files(file_id)? {
+            return Err(KvStoreErr::UnexceptErr(format!(
+                "log file {} holds tombstones or expiring entries, only the oldest log file \
+                 can drop them",
+                file_id
+            )));
+        }
+        // the live entries take no more room than the file did, should they still not fit
+        // the rewrite fails rather than spill over into the next file
+        let report = self.merge_files(&[file_id], file_id, Some(file_id + 1))?;
+        self.refresh_after_merge()?;
+        info!(
+            "rewrote log file {}, kept {} entries and reclaimed {} bytes",
+            file_id, report.entries_kept, report.bytes_reclaimed
+        );
+        Ok(())
+    }
+
+    /// Whether the log file holds a tombstone or the entry of a key with an expiry, which a
+    /// rewrite would drop while older files may still hold values of those keys
+    fn shadows_older_files(&self, file_id: u64) -> Result<bool> {
+        // an entry which hasn't expired yet may have by the time the rewrite reads it
+        let mut expiring = false;
+        self.index.for_each(|_, entry| {
+            expiring |= entry.file_id == file_id && entry.expire_at != 0;
+        });
+        if expiring {
+            return Ok(true);
+        }
+        let mut reader = gen_buf_reader(self.store(), &self.dirs, file_id, "log")?;
+        while let Some((log_entry, _)) = read_log_entry(&mut reader, self.options.entry_format)? {
+            if log_entry.value == [DELETED_CODE] {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Merge into a single active file if `single_file_merge` asks for it and the live data
+    /// fits, otherwise merge the sealed files
+    fn merge_once(&self) -> Result<MergeReport> {
+        // an install which failed to put the original files back is only completed on open,
+        // another merge would write over the files it still has to move in place
+        if install::pending(self.store(), &self.dirs.log_dir) {
+            return Err(KvStoreErr::UnexceptErr(
+                "a merge install is unfinished, reopen the store to complete it".to_owned(),
+            ));
+        }
+        if self.options.single_file_merge {
+            if let Some(report) = self.merge_into_one_file()? {
+                return Ok(report);
+            }
+        }
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        let old_log_file_ids = &ids[..ids.len().saturating_sub(1)];
+        if old_log_file_ids.is_empty() {
+            // only the active file exists, renaming merged files would clobber it
+            return Ok(MergeReport::default());
+        }
+        self.merge_files(old_log_file_ids, 0, None)
+    }
+
+    /// Write the live entries of every log file into a new file 0, which replaces them all
+    /// and becomes the active file. Return `None` and leave the files as they were
+    /// when the live entries don't fit in one file.
+    ///
+    /// The active file gets no hint file, as the hint would miss the later writes.
+    fn merge_into_one_file(&self) -> Result<Option<MergeReport>> {
+        // the files are small enough to keep reads waiting until the merged one replaces them,
+        // and no write may land in the files being replaced
+        let _files = self.files_swap.write().unwrap();
+        let mut writer = self.active_file_writer.lock().unwrap();
+        writer.flush()?;
+        let ids = get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)?;
+        // in log order, so that replaying the file puts the keys in the same eviction order
+        let (expired, mut entries): (Vec<_>, Vec<_>) = self
+            .snapshot_index()
+            .into_iter()
+            .partition(|(_, entry)| self.is_expired(entry));
+        entries.sort_by_key(|(_, entry)| (entry.file_id, entry.v_pos));
+        let positions = match self.write_single_file(&entries) {
+            Ok(Some(positions)) => positions,
+            written => {
+                self.remove_merge_temp_files()?;
+                return written.map(|_| None);
+            }
+        };
+        let mut report = MergeReport {
+            files_merged: ids.len() as u64,
+            files_produced: 1,
+            entries_kept: entries.len() as u64,
+            ..Default::default()
+        };
+        for id in &ids {
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, *id, "log")?;
+            while read_log_entry(&mut reader, self.options.entry_format)?.is_some() {
+                report.entries_dropped += 1;
+            }
+        }
+        report.entries_dropped = report.entries_dropped.saturating_sub(report.entries_kept);
+        let input_bytes = self.files_size(ids.iter().map(|id| (*id, "log")))?;
+        let output_bytes = self.files_size([(0, "log.temp")].into_iter())?;
+
+        // the checkpoint points into the files about to be replaced
+        checkpoint::remove(self.store(), &self.dirs.hint_dir)?;
+        // back up the old files, then move the merged file in place
+        let mut renames = self.backup_renames(&ids);
+        renames.push((0, "log.temp".to_owned(), "log".to_owned()));
+        self.install(&renames)?;
+        self.remove_backups(&ids)?;
+
+        // continue in the merged file
+        self.file_reader.clear();
+        self.file_reader
+            .insert(0, gen_buf_reader(self.store(), &self.dirs, 0, "log")?);
+        *writer = gen_file_writer_with_pos(self.store(), &self.dirs, 0, "log")?;
+        self.restart_digest(&writer);
+        self.active_file_id.store(0, Ordering::SeqCst);
+        self.sealed_file.store(NO_FILE, Ordering::SeqCst);
+        for (key, _) in expired {
+            self.update_value_indexes(&key, None);
+            self.index.remove(&key);
+        }
+        for ((key, index_entry), v_pos) in entries.into_iter().zip(positions) {
+            self.index.insert(
+                key,
+                IndexEntry {
+                    file_id: 0,
+                    v_pos,
+                    ..index_entry
+                },
+            );
+        }
+        self.useless_value_bytes.store(0, Ordering::SeqCst);
+        report.bytes_reclaimed = input_bytes.saturating_sub(output_bytes);
+        self.merge_bytes_written
+            .fetch_add(output_bytes, Ordering::SeqCst);
+        self.merge_bytes_reclaimed
+            .fetch_add(report.bytes_reclaimed, Ordering::SeqCst);
+        Ok(Some(report))
+    }
+
+    /// Write the entries into the temp log file 0, return where each value ends,
+    /// or `None` once they don't fit in one file
+    fn write_single_file(&self, entries: &[(Vec<u8>, IndexEntry)]) -> Result<Option<Vec<u64>>> {
+        let mut log_writer = gen_file_writer_with_pos(self.store(), &self.dirs, 0, "log.temp")?;
+        let mut positions = Vec::with_capacity(entries.len());
+        for (key, index_entry) in entries {
+            self.options.cancel_token.check()?;
+            // the active file writer is flushed and locked by the caller
+            let log_entry = self.read_flushed_entry(key, index_entry)?;
+            let buf = self.entry_bytes_at(log_writer.pos, &log_entry);
+            if log_writer.pos + buf.len() as u64 > self.options.log_file_max_bytes {
+                return Ok(None);
+            }
+            log_writer.write_all(&buf)?;
+            positions.push(log_writer.pos);
+        }
+        log_writer.flush()?;
+        Ok(Some(positions))
+    }
+
+    /// Counters of this engine since it was opened, and the size of its index,
+    /// which takes going through the whole index
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            merge_bytes_written: self.merge_bytes_written.load(Ordering::SeqCst),
+            merge_bytes_reclaimed: self.merge_bytes_reclaimed.load(Ordering::SeqCst),
+            filtered_misses: self.filtered_misses.load(Ordering::SeqCst),
+            index_bytes_estimate: self.index_memory_estimate(),
+            garbage_bytes: self.useless_value_bytes.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Merge the old log files once, the original files are left intact if this fails
+    ///
+    /// The merged files take the ids from `first_id`, failing when they reach `id_limit`.
+    fn merge_files(
+        &self,
+        old_log_file_ids: &[u64],
+        first_id: u64,
+        id_limit: Option<u64>,
+    ) -> Result<MergeReport> {
+        let mut report = MergeReport {
+            files_merged: old_log_file_ids.len() as u64,
+            ..Default::default()
+        };
+        let written = self
+            .write_merged_ranges(old_log_file_ids, first_id, id_limit, &mut report)
+            .and_then(|(merged_ids, moves, reclaimed_bytes)| {
+                let merged_ids = self.remove_empty_merged_files(merged_ids)?;
+                let input_bytes =
+                    self.files_size(old_log_file_ids.iter().map(|id| (*id, "log")))?;
+                let output_bytes = self.files_size(
+                    merged_ids
+                        .iter()
+                        .flat_map(|id| [(*id, "log.temp"), (*id, "hint.temp")]),
+                )?;
+                let output_log_bytes =
+                    self.files_size(merged_ids.iter().map(|id| (*id, "log.temp")))?;
+                Ok((
+                    merged_ids,
+                    moves,
+                    reclaimed_bytes,
+                    output_bytes,
+                    input_bytes.saturating_sub(output_log_bytes),
+                ))
+            });
+        let (merged_ids, moves, reclaimed_bytes, output_bytes, reclaimed_file_bytes) = match written
+        {
+            Ok(written) => written,
+            Err(err) => {
+                // leave the original files intact
+                self.remove_merge_temp_files()?;
+                return Err(err);
+            }
+        };
+
+        // no read may look at the files until the index points into the merged ones,
+        // and no write may change the index entries being moved
+        let files_guard = self.files_swap.write().unwrap();
+        let writer_guard = self.active_file_writer.lock().unwrap();
+
+        // the checkpoint points into the files about to be replaced
+        checkpoint::remove(self.store(), &self.dirs.hint_dir)?;
+        // back up the old files, then move the merged files in place
+        let mut renames = self.backup_renames(old_log_file_ids);
+        for &id in &merged_ids {
+            renames.push((id, "log.temp".to_owned(), "log".to_owned()));
+            renames.push((id, "hint.temp".to_owned(), "hint".to_owned()));
+        }
+        self.install(&renames)?;
+        // remove the readers of old log files which are gone, and read the merged ones
+        for id in old_log_file_ids {
+            if !merged_ids.contains(id) {
+                self.file_reader.remove(id);
             }
         }
-        Ok(())
+        for &id in &merged_ids {
+            let log_reader = gen_buf_reader(self.store(), &self.dirs, id, "log")?;
+            self.file_reader.insert(id, log_reader);
+        }
+        // keys written while merging keep their newer entries
+        for (key, old_entry, new_entry) in moves {
+            if let Some(entry) = self.index.get(&key) {
+                if entry.file_id == old_entry.file_id && entry.v_pos == old_entry.v_pos {
+                    match new_entry {
+                        Some(new_entry) => {
+                            self.index.insert(key, new_entry);
+                        }
+                        None => {
+                            self.update_value_indexes(&key, None);
+                            self.index.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+        drop(writer_guard);
+        drop(files_guard);
+
+        // the files loaded from hints on open were never counted
+        let _ =
+            self.useless_value_bytes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bytes| {
+                    Some(bytes.saturating_sub(reclaimed_bytes))
+                });
+        self.merge_bytes_written
+            .fetch_add(output_bytes, Ordering::SeqCst);
+        self.merge_bytes_reclaimed
+            .fetch_add(reclaimed_file_bytes, Ordering::SeqCst);
+        report.files_produced = merged_ids.len() as u64;
+        report.bytes_reclaimed = reclaimed_file_bytes;
+
+        self.remove_backups(old_log_file_ids)?;
+        Ok(report)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // find in index
-        if let Some(index_entry) = self.index.get(&key) {
-            if let Some(mut reader) = self.file_reader.get_mut(&index_entry.file_id) {
-                reader.seek(SeekFrom::Start(index_entry.v_pos - index_entry.v_size))?;
-                let mut taker = reader.value_mut().take(index_entry.v_size);
-                let mut buf: [u8; 255] = [0; 255];
-                taker.read(&mut buf[..])?;
-                Ok(Some(String::from_utf8(
-                    buf[..(index_entry.v_size as usize)].to_vec(),
-                )?))
-            } else {
-                Err(KvStoreErr::InnerErr("get file reader".to_string()))
+    /// The renames backing up the log files with the ids and their hint files
+    fn backup_renames(&self, ids: &[u64]) -> Vec<Rename> {
+        let mut renames = Vec::new();
+        for &id in ids {
+            renames.push((id, "log".to_owned(), "log.old".to_owned()));
+            if self.store().exists(&log_path(&self.dirs, id, "hint")) {
+                renames.push((id, "hint".to_owned(), "hint.old".to_owned()));
             }
-        } else {
-            // not exists
-            Ok(None)
         }
+        renames
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        // find in index
-        if self.index.contains_key(&key) {
-            // write new log entry as remove
-            let log_entry = LogEntry {
-                k_size: key.as_bytes().len() as u64,
-                v_size: 1,
-                key: key.as_bytes().to_vec(),
-                value: [DELETED_CODE; 1].to_vec(),
-            };
-            let buf = log_entry.serialize();
-            self.write_and_flush(&buf)?;
-            if let Some((_, old_index_entry)) = self.index.remove(&key) {
-                self.useless_value_bytes
-                    .fetch_add(old_index_entry.v_size + 1, Ordering::SeqCst);
-                if self.useless_value_bytes.load(Ordering::SeqCst) > self.merge_trigger_threshold {
-                    self.merge()?;
+    /// Do the renames moving the files of a merge in place, listing them in the install file
+    /// first, so that an open after a crash completes them. A failed rename puts the original
+    /// files back and removes the merged ones.
+    fn install(&self, renames: &[Rename]) -> Result<()> {
+        let path = |id: u64, extension: &str| log_path(&self.dirs, id, extension);
+        if let Err(err) = install::write(self.store(), &self.dirs.log_dir, renames) {
+            self.remove_merge_temp_files()?;
+            return Err(err);
+        }
+        for (done, (id, from, to)) in renames.iter().enumerate() {
+            if let Err(err) = self.store().rename(&path(*id, from), &path(*id, to)) {
+                // put the original files back, and only then forget the install,
+                // which would otherwise be completed on open
+                for (id, from, to) in renames[..done].iter().rev() {
+                    self.store().rename(&path(*id, to), &path(*id, from))?;
                 }
+                install::remove(self.store(), &self.dirs.log_dir)?;
+                self.remove_merge_temp_files()?;
+                return Err(err);
             }
-
-            Ok(())
-        } else {
-            // not exists
-            Err(KvStoreErr::KeyNotFound(key))
         }
+        install::remove(self.store(), &self.dirs.log_dir)
     }
-}
 
-impl BitcaskEngine {
-    pub fn flush(&self) -> Result<()> {
-        let mut writer = self.active_file_writer.lock().unwrap();
-        writer.flush()?;
+    /// Remove the backups of the log files with the ids and their hint files
+    fn remove_backups(&self, ids: &[u64]) -> Result<()> {
+        for &id in ids {
+            self.store().remove(&log_path(&self.dirs, id, "log.old"))?;
+            let hint_backup_path = log_path(&self.dirs, id, "hint.old");
+            if self.store().exists(&hint_backup_path) {
+                self.store().remove(&hint_backup_path)?;
+            }
+        }
         Ok(())
     }
 
-    fn write_and_flush(&self, buf: &[u8]) -> Result<(u64, u64)> {
-        let size = buf.len() as u64;
-        let mut writer = self.active_file_writer.lock().unwrap();
-        let mut now_file_id = self.active_file_id.load(Ordering::SeqCst);
-        if writer.pos + size > self.log_file_max_bytes {
-            // check out new active file writer
-            self.active_file_id.fetch_add(1, Ordering::SeqCst);
-            now_file_id += 1;
-            writer.flush()?;
-            *writer = gen_file_writer_with_pos(
-                &self.base_dir,
-                now_file_id,
-                "log",
-                &mut opt_create_r_w(),
-            )?;
-            self.file_reader.insert(
-                now_file_id,
-                gen_buf_reader(&self.base_dir, now_file_id, "log", &mut opt_open_r())?,
+    /// Read the entry of every live key and report the keys whose entries are corrupt
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        self.scrub_entries(false)
+    }
+
+    /// Like `scrub`, but also append the corrupt entries to a quarantine file in the log directory
+    /// and remove their keys, the next merge then drops the corrupt entries from the log files
+    pub fn scrub_and_quarantine(&self) -> Result<ScrubReport> {
+        self.scrub_entries(true)
+    }
+
+    fn scrub_entries(&self, quarantine: bool) -> Result<ScrubReport> {
+        if quarantine {
+            self.check_writable()?;
+        }
+        // merges move the entries between files
+        let _guard = self.merge_lock.lock().unwrap();
+        let entries = self.snapshot_index();
+        let mut report = ScrubReport {
+            scanned_keys: entries.len() as u64,
+            quarantined: quarantine,
+            ..Default::default()
+        };
+        let mut quarantine_writer = None;
+        let mut corrupt_keys = Vec::new();
+        for (key, index_entry) in entries {
+            if matches!(self.read_indexed_entry(&key, &index_entry), Ok(log_entry) if log_entry.is_intact())
+            {
+                continue;
+            }
+            let entry_start = entry_start(self.options.entry_format, &key, &index_entry);
+            warn!(
+                "corrupt entry of key {} in file {} at offset {}",
+                String::from_utf8_lossy(&key),
+                index_entry.file_id,
+                entry_start
             );
+            if quarantine {
+                let mut raw = vec![0; (index_entry.v_pos - entry_start) as usize];
+                let mut reader = self
+                    .file_reader
+                    .get_mut(&index_entry.file_id)
+                    .ok_or_else(|| KvStoreErr::InnerErr("get file reader".to_string()))?;
+                reader.seek(SeekFrom::Start(entry_start))?;
+                reader.read_entry_bytes(&mut raw)?;
+                if quarantine_writer.is_none() {
+                    quarantine_writer = Some(BufWriterWithPos::new(
+                        self.store()
+                            .open_append(&self.dirs.log_dir.join(QUARANTINE_FILE_NAME))?,
+                    )?);
+                }
+                if let Some(writer) = quarantine_writer.as_mut() {
+                    writer.write_all(&raw)?;
+                }
+            }
+            corrupt_keys.push(key);
         }
-        writer.write(buf)?;
-        if writer.pos - writer.flushed >= DEFAULT_WRITE_FLUSH_INTERVAL {
+        if let Some(mut writer) = quarantine_writer {
             writer.flush()?;
+            for key in &corrupt_keys {
+                self.remove_bytes(key)?;
+            }
+        }
+        report.corrupt_keys = corrupt_keys
+            .iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect();
+        report.corrupt_keys.sort();
+        // the active file gets its footer when it rotates
+        let active_file_id = self.active_file_id.load(Ordering::SeqCst);
+        for id in get_all_sorted_log_file_id(self.store(), &self.dirs.log_dir)? {
+            if id < active_file_id
+                && !footer_matches(self.store(), &self.dirs, id, self.options.entry_format)?
+            {
+                warn!("log file {} doesn't match its footer", id);
+                report.corrupt_files.push(id);
+            }
         }
-        Ok((now_file_id, writer.pos))
+        Ok(report)
     }
 
-    pub fn open(path: impl Into<PathBuf>) -> Result<BitcaskEngine> {
-        let path_buf: PathBuf = path.into();
-        fs::create_dir_all(path_buf.as_path())?;
-        let log_id_list = get_all_sorted_log_file_id(path_buf.as_path())?;
-        let index: Arc<DashMap<String, IndexEntry>> = Arc::new(DashMap::new());
-        let file_reader: DashMap<u64, BufReaderWithPos<File>> = DashMap::new();
-        let mut useless_value_bytes: u64 = 0;
-        for id in &log_id_list {
-            let mut reader = gen_buf_reader(&path_buf, *id, "log", &mut opt_open_r())?;
-            let hint_file_path = log_path(&path_buf, *id, "hint");
-            if hint_file_path.exists() {
-                load_from_hint_file(
-                    *id,
-                    &mut gen_buf_reader(&path_buf, *id, "hint", &mut opt_open_r())?,
-                    index.clone(),
-                )?;
-            } else {
-                useless_value_bytes += load_from_log_file(*id, &mut reader, index.clone())?;
+    /// Write a compacted copy of all live data into `target_dir`, which must not hold log files yet
+    ///
+    /// The store keeps serving meanwhile, the copy holds every key as of when it was read.
+    /// `target_dir` can then be opened as a store of its own.
+    pub fn compact_into(&self, target_dir: impl Into<PathBuf>) -> Result<()> {
+        let target_dir: PathBuf = target_dir.into();
+        let dirs = DataDirs {
+            log_dir: target_dir.clone(),
+            hint_dir: target_dir,
+        };
+        self.store().create_dir_all(&dirs.log_dir)?;
+        if !get_all_sorted_log_file_id(self.store(), &dirs.log_dir)?.is_empty() {
+            return Err(KvStoreErr::UnexceptErr(format!(
+                "{:?} already holds log files",
+                dirs.log_dir
+            )));
+        }
+        if let Err(err) = self.write_compacted_files(&dirs) {
+            // don't leave a partial copy which could be opened
+            for path in self.store().list(&dirs.log_dir)? {
+                let extension = path.extension();
+                if extension == Some("log".as_ref()) || extension == Some("hint".as_ref()) {
+                    self.store().remove(&path)?;
+                }
             }
-            file_reader.insert(*id, reader);
+            return Err(err);
         }
-        let active_file_writer: BufWriterWithPos<File>;
-        let active_file_id;
-        if log_id_list.len() == 0 {
-            // now data is empty
-            // create first log file
-            active_file_id = 0;
-            active_file_writer =
-                gen_file_writer_with_pos(&path_buf, active_file_id, "log", &mut opt_create_r_w())?;
-            file_reader.insert(
-                active_file_id,
-                gen_buf_reader(&path_buf, active_file_id, "log", &mut opt_open_r())?,
-            );
+        // the copy is laid out like this store
+        config::check_or_mark_engine(self.store(), &dirs.log_dir, config::BITCASK_ENGINE, false)?;
+        config::record(self.store(), &dirs.log_dir, &self.options)
+    }
+
+    fn write_compacted_files(&self, dirs: &DataDirs) -> Result<()> {
+        // merges move the entries between files
+        let _guard = self.merge_lock.lock().unwrap();
+        let mut entries = self.snapshot_index();
+        // read the files sequentially
+        entries.sort_by_key(|(_, index_entry)| (index_entry.file_id, index_entry.v_pos));
+        let mut sealed_files = SealedFilesWriter::new(self, dirs, 0, "")?;
+        for (key, index_entry) in entries {
+            self.options.cancel_token.check()?;
+            if self.is_expired(&index_entry) {
+                continue;
+            }
+            let log_entry = self.read_indexed_entry(&key, &index_entry)?;
+            sealed_files.write(&log_entry)?;
+        }
+        let file_id = sealed_files.finish()?;
+        // an empty active file without hints, so the copy can be written to once opened
+        gen_file_writer_with_pos(self.store(), dirs, file_id + 1, "log")?.flush()?;
+        Ok(())
+    }
+
+    /// Load many entries at once, written straight into new sealed log and hint files
+    /// instead of one by one through the active file
+    ///
+    /// Later entries of a key win over earlier ones, and all of them over the current values.
+    /// The files are written under temporary names and renamed in place once complete,
+    /// so a failed load leaves the store as it was.
+    pub fn bulk_load(&self, entries: impl Iterator<Item = (String, String)>) -> Result<()> {
+        self.bulk_load_with_opts(entries, BulkLoadOpts::default())
+    }
+
+    /// Like `bulk_load`, with `opts` deciding whether the input must come sorted and which
+    /// entry of a duplicate key is loaded
+    pub fn bulk_load_with_opts(
+        &self,
+        entries: impl Iterator<Item = (String, String)>,
+        opts: BulkLoadOpts,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let mut entries: Vec<(String, String)> = entries.collect();
+        if opts.require_sorted {
+            if let Some(pair) = entries.windows(2).find(|pair| pair[1].0 < pair[0].0) {
+                return Err(KvStoreErr::UnsortedInput(
+                    pair[1].0.clone(),
+                    pair[0].0.clone(),
+                ));
+            }
         } else {
-            let active_id = log_id_list.get(log_id_list.len() - 1).unwrap();
-            active_file_id = *active_id;
-            active_file_writer =
-                gen_file_writer_with_pos(&path_buf, active_file_id, "log", &mut opt_open_r_w())?;
+            // the sort is stable, so the entries of a key stay in input order
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        match opts.duplicates {
+            DuplicateKeys::KeepFirst => entries.dedup_by(|a, b| a.0 == b.0),
+            DuplicateKeys::KeepLast => {
+                // after reversing the first entry of every key is its last one
+                entries.reverse();
+                entries.dedup_by(|a, b| a.0 == b.0);
+                entries.reverse();
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
         }
 
-        let kv = BitcaskEngine {
-            index: index.clone(),
-            base_dir: Arc::new(path_buf),
-            active_file_id: Arc::new(AtomicU64::new(active_file_id)),
-            active_file_writer: Arc::new(Mutex::new(active_file_writer)),
-            file_reader: Arc::new(file_reader),
-            useless_value_bytes: Arc::new(AtomicU64::new(useless_value_bytes)),
-            log_file_max_bytes: DEFAULT_LOG_FILE_MAX_BYTES,
-            merge_trigger_threshold: DEFAULT_MERGE_TRIGGER_THRESHOLD,
+        let keys: Vec<&[u8]> = entries.iter().map(|(key, _)| key.as_bytes()).collect();
+        let key_guards = self.key_locks.lock_all(&keys);
+        // merges move files around, and no write may land between the loaded files
+        let merge_guard = self.merge_lock.lock().unwrap();
+        let mut writer = self.active_file_writer.lock().unwrap();
+        let first_id = self.active_file_id.load(Ordering::SeqCst) + 1;
+        let (last_id, positions) = match self.write_bulk_files(&entries, first_id) {
+            Ok(written) => written,
+            Err(err) => {
+                self.remove_merge_temp_files()?;
+                return Err(err);
+            }
         };
-        Ok(kv)
+        let mut renames = Vec::new();
+        for id in first_id..=last_id {
+            for extension in ["hint", "log"] {
+                renames.push((
+                    log_path(&self.dirs, id, &format!("{}.temp", extension)),
+                    log_path(&self.dirs, id, extension),
+                ));
+            }
+        }
+        for (done, (from, to)) in renames.iter().enumerate() {
+            if let Err(err) = self.store().rename(from, to) {
+                for (from, to) in renames[..done].iter().rev() {
+                    self.store().rename(to, from)?;
+                }
+                self.remove_merge_temp_files()?;
+                return Err(err);
+            }
+        }
+
+        // continue in a new active file after the loaded ones
+        writer.flush()?;
+        for id in first_id..=last_id {
+            self.file_reader
+                .insert(id, gen_buf_reader(self.store(), &self.dirs, id, "log")?);
+        }
+        let active_file_id = last_id + 1;
+        *writer = gen_file_writer_with_pos(self.store(), &self.dirs, active_file_id, "log")?;
+        self.restart_digest(&writer);
+        self.file_reader.insert(
+            active_file_id,
+            gen_buf_reader(self.store(), &self.dirs, active_file_id, "log")?,
+        );
+        self.active_file_id.store(active_file_id, Ordering::SeqCst);
+
+        let mut useless_value_bytes = 0;
+        for ((key, value), (file_id, v_pos)) in entries.into_iter().zip(positions) {
+            self.update_value_indexes(key.as_bytes(), Some(value.as_bytes()));
+            let index_entry = IndexEntry {
+                file_id,
+                v_pos,
+                v_size: value.len() as u64,
+                flags: 0,
+                expire_at: 0,
+            };
+            self.audit(&key, false, None);
+            if let Some(old_entry) = self.index.insert(key.clone().into_bytes(), index_entry) {
+                useless_value_bytes += self.garbage_bytes(key.as_bytes(), old_entry.v_size);
+            }
+        }
+        self.useless_value_bytes
+            .fetch_add(useless_value_bytes, Ordering::SeqCst);
+        drop(writer);
+        drop(merge_guard);
+        drop(key_guards);
+        self.evict_if_needed(None)
     }
 
-    pub fn merge(&self) -> Result<()> {
-        let ids = get_all_sorted_log_file_id(&self.base_dir)?;
-        let old_log_file_ids = &ids[..ids.len() - 1];
-        let mut merged_log_file_id = 0;
+    /// Write the entries into temp log and hint files from `first_id` on,
+    /// return the id of the last file and where each value ends
+    fn write_bulk_files(
+        &self,
+        entries: &[(String, String)],
+        first_id: u64,
+    ) -> Result<(u64, Vec<(u64, u64)>)> {
+        let mut sealed_files = SealedFilesWriter::new(self, &self.dirs, first_id, ".temp")?;
+        let mut positions = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            self.options.cancel_token.check()?;
+            let log_entry = LogEntry::new(key.as_bytes().to_vec(), value.as_bytes().to_vec(), 0);
+            positions.push(sealed_files.write(&log_entry)?);
+        }
+        Ok((sealed_files.finish()?, positions))
+    }
+
+    /// Copy of all entries of the index
+    fn snapshot_index(&self) -> Vec<(Vec<u8>, IndexEntry)> {
+        let mut entries = Vec::new();
+        self.index
+            .for_each(|key, entry| entries.push((key.to_vec(), *entry)));
+        entries
+    }
+
+    /// Read the whole log entry of `key`, which the index entry points to
+    fn read_indexed_entry(&self, key: &[u8], index_entry: &IndexEntry) -> Result<LogEntry> {
+        self.flush_value(index_entry)?;
+        self.read_flushed_entry(key, index_entry)
+    }
+
+    /// Like `read_indexed_entry`, for an entry no longer buffered in the active file writer
+    fn read_flushed_entry(&self, key: &[u8], index_entry: &IndexEntry) -> Result<LogEntry> {
+        let entry_start = entry_start(self.options.entry_format, key, index_entry);
+        let mut reader = self
+            .file_reader
+            .get_mut(&index_entry.file_id)
+            .ok_or_else(|| KvStoreErr::InnerErr("get file reader".to_string()))?;
+        reader.seek(SeekFrom::Start(entry_start))?;
+        match read_log_entry(&mut reader, self.options.entry_format)? {
+            Some((log_entry, pos)) if pos == index_entry.v_pos && log_entry.key == key => {
+                Ok(log_entry)
+            }
+            _ => Err(KvStoreErr::CorruptEntry(index_entry.file_id, entry_start)),
+        }
+    }
+
+    /// Write the live entries of the old log files into merged files, split into ranges of
+    /// files which are merged in parallel when `merge_threads` asks for it. Return the ids of
+    /// the merged files, the index entries to move into them and the reclaimed value bytes.
+    fn write_merged_ranges(
+        &self,
+        old_log_file_ids: &[u64],
+        first_id: u64,
+        id_limit: Option<u64>,
+        report: &mut MergeReport,
+    ) -> Result<(Vec<u64>, Vec<EntryMove>, u64)> {
+        let threads = self
+            .options
+            .merge_threads
+            .clamp(1, old_log_file_ids.len().max(1));
+        let ranges: Vec<&[u64]> = old_log_file_ids
+            .chunks(old_log_file_ids.len().div_ceil(threads).max(1))
+            .collect();
+        // the merged files of a range take the ids from its first old file up to the first
+        // old file of the next range, so the ranges never write the same file
+        let bounds = |i: usize| {
+            let range_first_id = if i == 0 { first_id } else { ranges[i][0] };
+            (
+                range_first_id,
+                ranges.get(i + 1).map_or(id_limit, |next| Some(next[0])),
+            )
+        };
+        let written: Vec<Result<_>> = if ranges.len() == 1 {
+            vec![self.write_merged_files(old_log_file_ids, first_id, id_limit, report)]
+        } else {
+            thread::scope(|scope| {
+                let handles: Vec<_> = ranges
+                    .iter()
+                    .enumerate()
+                    .map(|(i, range)| {
+                        let (first_id, id_limit) = bounds(i);
+                        scope.spawn(move || {
+                            let mut report = MergeReport::default();
+                            self.write_merged_files(range, first_id, id_limit, &mut report)
+                                .map(|written| (written, report))
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("merge thread panicked"))
+                    .map(|written| {
+                        written.map(|(written, range_report)| {
+                            report.entries_kept += range_report.entries_kept;
+                            report.entries_dropped += range_report.entries_dropped;
+                            written
+                        })
+                    })
+                    .collect()
+            })
+        };
+        let mut merged_ids = Vec::new();
+        let mut moves = Vec::new();
+        let mut reclaimed_bytes = 0;
+        for written in written {
+            let (ids, range_moves, range_reclaimed_bytes) = written?;
+            merged_ids.extend(ids);
+            moves.extend(range_moves);
+            reclaimed_bytes += range_reclaimed_bytes;
+        }
+        Ok((merged_ids, moves, reclaimed_bytes))
+    }
+
+    /// Write the live entries of the old log files into merged files with ids from `first_id`,
+    /// failing when the merged files reach `id_limit`
+    fn write_merged_files(
+        &self,
+        old_log_file_ids: &[u64],
+        first_id: u64,
+        id_limit: Option<u64>,
+        report: &mut MergeReport,
+    ) -> Result<(RangeInclusive<u64>, Vec<EntryMove>, u64)> {
+        let mut merged_log_file_id = first_id;
+        let mut moves = Vec::new();
+        let mut reclaimed_bytes = 0;
         let (mut log_writer, mut hint_writer) =
-            gen_merge_process_writer_pair(&self.base_dir, merged_log_file_id)?;
+            gen_merge_process_writer_pair(self.store(), &self.dirs, merged_log_file_id)?;
+        let mut hint_encoder = HintEncoder::new(
+            self.options.hint_prefix_compression,
+            self.options.entry_format,
+        );
 
         // merge old log files and generate merged old log files and hint files
         for id in old_log_file_ids {
-            let mut reader = gen_buf_reader(&self.base_dir, *id, "log", &mut opt_open_r())?;
-            while let Ok(Some((log_entry, pos))) = read_log_entry(&mut reader) {
-                if let Some(value) = self.index.get(&String::from_utf8(log_entry.key.clone())?) {
-                    // this log is up to date
-                    if value.file_id == *id && value.v_pos == pos {
-                        let log_vec = log_entry.serialize();
-                        if log_vec.len() as u64 + log_writer.pos > self.log_file_max_bytes {
+            let mut reader = gen_buf_reader(self.store(), &self.dirs, *id, "log")?;
+            while let Some((log_entry, pos)) =
+                read_log_entry(&mut reader, self.options.entry_format)?
+            {
+                self.options.cancel_token.check()?;
+                let key = log_entry.key.clone();
+                if let Some(value) = self.index.get(&key) {
+                    let up_to_date = value.file_id == *id && value.v_pos == pos;
+                    if up_to_date && self.is_expired(&value) {
+                        // the key leaves the index once the merged files are in place
+                        moves.push((key, value, None));
+                        reclaimed_bytes += self.garbage_bytes(&log_entry.key, log_entry.v_size);
+                        report.entries_dropped += 1;
+                    } else if up_to_date {
+                        // this log is up to date
+                        let mut log_vec = self.entry_bytes_at(log_writer.pos, &log_entry);
+                        if log_vec.len() as u64 + log_writer.pos > self.options.log_file_max_bytes {
                             // if log file size reach out log file max bytes
                             // flush
                             log_writer.flush()?;
                             hint_writer.flush()?;
                             merged_log_file_id += 1;
-                            (log_writer, hint_writer) =
-                                gen_merge_process_writer_pair(&self.base_dir, merged_log_file_id)?;
+                            if Some(merged_log_file_id) == id_limit {
+                                return Err(KvStoreErr::UnexceptErr(format!(
+                                    "merged files of log files {:?} run into file {}",
+                                    old_log_file_ids, merged_log_file_id
+                                )));
+                            }
+                            (log_writer, hint_writer) = gen_merge_process_writer_pair(
+                                self.store(),
+                                &self.dirs,
+                                merged_log_file_id,
+                            )?;
+                            hint_encoder = HintEncoder::new(
+                                self.options.hint_prefix_compression,
+                                self.options.entry_format,
+                            );
+                            log_vec = self.entry_bytes_at(log_writer.pos, &log_entry);
                         }
-                        log_writer.write(&log_entry.serialize())?;
+                        log_writer.write_all(&log_vec)?;
                         // write hint entry into hint file
                         let hint_entry = HintEntry {
                             k_size: log_entry.k_size,
                             v_size: log_entry.v_size,
                             v_pos: log_writer.pos,
+                            flags: log_entry.flags,
                             key: log_entry.key.clone(),
+                            expire_at: log_entry.expire_at,
+                        };
+                        hint_writer.write_all(&hint_encoder.encode(&hint_entry))?;
+                        let new_entry = IndexEntry {
+                            file_id: merged_log_file_id,
+                            v_pos: log_writer.pos,
+                            ..value
                         };
-                        hint_writer.write(&hint_entry.serialize())?;
+                        moves.push((key, value, Some(new_entry)));
+                        report.entries_kept += 1;
                     } else {
                         // this log has been expired
-                        self.useless_value_bytes
-                            .fetch_sub(log_entry.v_size, Ordering::SeqCst);
+                        reclaimed_bytes += self.garbage_bytes(&key, log_entry.v_size);
+                        report.entries_dropped += 1;
                     }
                 } else {
                     // this log has been deleted
-                    self.useless_value_bytes.fetch_sub(1, Ordering::SeqCst);
+                    reclaimed_bytes += self.garbage_bytes(&key, log_entry.v_size);
+                    report.entries_dropped += 1;
                 }
             }
         }
         log_writer.flush()?;
         hint_writer.flush()?;
+        Ok((first_id..=merged_log_file_id, moves, reclaimed_bytes))
+    }
 
-        // remove old log files and reader
-        for id in old_log_file_ids {
-            // remove reader
-            self.file_reader.remove(id);
-            let path = log_path(&self.base_dir, *id, "log");
-            remove_file(&path)?;
-        }
-
-        // update
-        for id in 0..=merged_log_file_id {
-            // rename log file and hint file
-            let temp_log_file_path = log_path(&self.base_dir, id, "log.temp");
-            let log_file_path = log_path(&self.base_dir, id, "log");
-            rename(&temp_log_file_path, &log_file_path)?;
-            let temp_hint_file_path = log_path(&self.base_dir, id, "hint.temp");
-            let hint_file_path = log_path(&self.base_dir, id, "hint");
-            rename(&temp_hint_file_path, &hint_file_path)?;
-
-            // add merged log file reader in mem
-            let log_reader = gen_buf_reader(&self.base_dir, id, "log", &mut opt_open_r())?;
-            self.file_reader.insert(id, log_reader);
+    /// Remove the temp files of the merged files which got no entry and return the ids of
+    /// the others, keeping one empty file if they're all empty and `empty_merged_files` says so
+    fn remove_empty_merged_files(&self, merged_ids: Vec<u64>) -> Result<Vec<u64>> {
+        let mut kept = Vec::with_capacity(merged_ids.len());
+        let mut empty = Vec::new();
+        for id in merged_ids {
+            if self.files_size([(id, "log.temp")].into_iter())? == 0 {
+                empty.push(id);
+            } else {
+                kept.push(id);
+            }
+        }
+        if kept.is_empty() && self.options.empty_merged_files == EmptyMergedFiles::KeepOne {
+            // the first one, so the kept file takes the same id whatever the merge threads
+            kept = empty.drain(..empty.len().min(1)).collect();
+        }
+        for id in empty {
+            self.store().remove(&log_path(&self.dirs, id, "log.temp"))?;
+            self.store()
+                .remove(&log_path(&self.dirs, id, "hint.temp"))?;
+        }
+        Ok(kept)
+    }
+
+    /// Total bytes of the files with the given ids and extensions
+    fn files_size(&self, files: impl Iterator<Item = (u64, &'static str)>) -> Result<u64> {
+        let mut size = 0;
+        for (id, extension) in files {
+            size += self
+                .store()
+                .open_read(&log_path(&self.dirs, id, extension))?
+                .size()?;
+        }
+        Ok(size)
+    }
 
-            // update index by loading hint file
-            let mut reader = gen_buf_reader(&self.base_dir, id, "hint", &mut opt_open_r())?;
-            load_from_hint_file(id, &mut reader, self.index.clone())?;
+    /// Remove the temp files left by an aborted merge
+    fn remove_merge_temp_files(&self) -> Result<()> {
+        for dir in [&self.dirs.log_dir, &self.dirs.hint_dir] {
+            for path in self.store().list(dir)? {
+                if path.extension() == Some("temp".as_ref()) {
+                    self.store().remove(&path)?;
+                }
+            }
         }
         Ok(())
     }
 }
 
-fn opt_create_r_w() -> OpenOptions {
-    OpenOptions::new()
-        .append(true)
-        .create(true)
-        .read(true)
-        .write(true)
-        .to_owned()
+/// Writes entries into log files and their hint files, moving on to the next pair of files
+/// whenever the log file would grow beyond the max size
+struct SealedFilesWriter<'a> {
+    engine: &'a BitcaskEngine,
+    dirs: &'a DataDirs,
+    // appended to the extensions of the files, to write them under temporary names
+    suffix: &'static str,
+    file_id: u64,
+    log_writer: LogWriter,
+    hint_writer: LogWriter,
+    hint_encoder: HintEncoder,
+}
+
+impl<'a> SealedFilesWriter<'a> {
+    fn new(
+        engine: &'a BitcaskEngine,
+        dirs: &'a DataDirs,
+        first_id: u64,
+        suffix: &'static str,
+    ) -> Result<Self> {
+        let store = engine.store();
+        Ok(SealedFilesWriter {
+            engine,
+            dirs,
+            suffix,
+            file_id: first_id,
+            log_writer: gen_file_writer_with_pos(store, dirs, first_id, &format!("log{}", suffix))?,
+            hint_writer: gen_file_writer_with_pos(
+                store,
+                dirs,
+                first_id,
+                &format!("hint{}", suffix),
+            )?,
+            hint_encoder: HintEncoder::new(
+                engine.options.hint_prefix_compression,
+                engine.options.entry_format,
+            ),
+        })
+    }
+
+    /// Append the entry, return the id of its file and the end of its value
+    fn write(&mut self, log_entry: &LogEntry) -> Result<(u64, u64)> {
+        let mut log_vec = self.engine.entry_bytes_at(self.log_writer.pos, log_entry);
+        if self.log_writer.pos > 0
+            && log_vec.len() as u64 + self.log_writer.pos > self.engine.options.log_file_max_bytes
+        {
+            self.log_writer.flush()?;
+            self.hint_writer.flush()?;
+            *self = SealedFilesWriter::new(self.engine, self.dirs, self.file_id + 1, self.suffix)?;
+            log_vec = self.engine.entry_bytes_at(self.log_writer.pos, log_entry);
+        }
+        self.log_writer.write_all(&log_vec)?;
+        let hint_entry = HintEntry {
+            k_size: log_entry.k_size,
+            v_size: log_entry.v_size,
+            v_pos: self.log_writer.pos,
+            flags: log_entry.flags,
+            key: log_entry.key.clone(),
+            expire_at: log_entry.expire_at,
+        };
+        self.hint_writer
+            .write_all(&self.hint_encoder.encode(&hint_entry))?;
+        Ok((self.file_id, self.log_writer.pos))
+    }
+
+    /// Flush the files and return the id of the last one
+    fn finish(mut self) -> Result<u64> {
+        self.log_writer.flush()?;
+        self.hint_writer.flush()?;
+        Ok(self.file_id)
+    }
+}
+
+/// Offset of the log entry of `key` in its file
+fn entry_start(format: EntryFormat, key: &[u8], index_entry: &IndexEntry) -> u64 {
+    index_entry.v_pos - index_entry.file_bytes(key.len() as u64, format)
+}
+
+impl FileEntry for IndexEntry {
+    fn file_id(&self) -> u64 {
+        self.file_id
+    }
+
+    fn file_bytes(&self, key_size: u64, format: EntryFormat) -> u64 {
+        self.v_size
+            + key_size
+            + format.header_size(key_size, self.v_size)
+            + format.expiry_size(self.expire_at)
+    }
+
+    fn expires(&self) -> bool {
+        self.expire_at != 0
+    }
+}
+
+/// Unix milliseconds of `time`, at least 1 since 0 stands for never expiring
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        })
+        .max(1)
+}
+
+/// Read `len` bytes from `offset` into the value of `key`, which `index_entry` points to
+///
+/// Fails with `ValueOutOfBounds` rather than returning a short value if the entry points
+/// past the end of its file, which only a corrupt index or a file truncated behind our back does.
+fn read_value_bytes(
+    reader: &mut LogReader,
+    key: &[u8],
+    index_entry: &IndexEntry,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let read = match index_entry.v_pos.checked_sub(index_entry.v_size) {
+        Some(value_start) => {
+            reader.seek(SeekFrom::Start(value_start + offset))?;
+            reader.read_entry_vec(len)
+        }
+        None => Err(KvStoreErr::IncompleteEntry(0)),
+    };
+    match read {
+        Err(KvStoreErr::IncompleteEntry(_)) => Err(KvStoreErr::ValueOutOfBounds {
+            key: String::from_utf8_lossy(key).into_owned(),
+            file_id: index_entry.file_id,
+            v_pos: index_entry.v_pos,
+            v_size: index_entry.v_size,
+            file_len: reader.seek(SeekFrom::End(0))?,
+        }),
+        read => read,
+    }
 }
 
-fn opt_open_r_w() -> OpenOptions {
-    OpenOptions::new().read(true).write(true).to_owned()
+/// Read the whole entry of `key` which `index_entry` points to and return its value
+///
+/// Fails with `CorruptEntry` if the entry doesn't match its checksum or isn't the one the index
+/// entry describes, and with `ValueOutOfBounds` if it lies past the end of its file.
+fn read_checked_value(
+    reader: &mut LogReader,
+    format: EntryFormat,
+    key: &[u8],
+    index_entry: &IndexEntry,
+) -> Result<Vec<u8>> {
+    let key_size = key.len() as u64;
+    let start = index_entry.v_pos.checked_sub(
+        index_entry.v_size
+            + key_size
+            + format.header_size(key_size, index_entry.v_size)
+            + format.expiry_size(index_entry.expire_at),
+    );
+    let decoded = match start {
+        Some(start) => {
+            reader.seek(SeekFrom::Start(start))?;
+            format.decode(reader)
+        }
+        None => Err(KvStoreErr::IncompleteEntry(0)),
+    };
+    match decoded {
+        Ok(Some(entry)) => {
+            if entry.is_intact()
+                && entry.key == key
+                && entry.expire_at == index_entry.expire_at
+                && reader.pos == index_entry.v_pos
+            {
+                Ok(entry.value)
+            } else {
+                Err(KvStoreErr::CorruptEntry(
+                    index_entry.file_id,
+                    start.unwrap_or_default(),
+                ))
+            }
+        }
+        Ok(None) | Err(KvStoreErr::IncompleteEntry(_)) => Err(KvStoreErr::ValueOutOfBounds {
+            key: String::from_utf8_lossy(key).into_owned(),
+            file_id: index_entry.file_id,
+            v_pos: index_entry.v_pos,
+            v_size: index_entry.v_size,
+            file_len: reader.seek(SeekFrom::End(0))?,
+        }),
+        Err(err) => Err(err),
+    }
 }
 
-fn opt_open_r() -> OpenOptions {
-    OpenOptions::new().read(true).to_owned()
+fn append_entry(writer: &mut LogWriter, buf: &[u8], flush_bytes: u64) -> Result<()> {
+    writer.write_all(buf)?;
+    if writer.pos - writer.flushed >= flush_bytes {
+        writer.flush()?;
+    }
+    Ok(())
 }
 
 fn gen_merge_process_writer_pair(
-    base_path: &Path,
+    store: &dyn BlockStore,
+    dirs: &DataDirs,
     id: u64,
-) -> Result<(BufWriterWithPos<File>, BufWriterWithPos<File>)> {
-    let log_writer = gen_file_writer_with_pos(base_path, id, "log.temp", &mut opt_create_r_w())?;
-    let hint_writer = gen_file_writer_with_pos(base_path, id, "hint.temp", &mut opt_create_r_w())?;
+) -> Result<(LogWriter, LogWriter)> {
+    let log_writer = gen_file_writer_with_pos(store, dirs, id, "log.temp")?;
+    let hint_writer = gen_file_writer_with_pos(store, dirs, id, "hint.temp")?;
     Ok((log_writer, hint_writer))
 }
 
 fn gen_file_writer_with_pos(
-    base_path: &Path,
+    store: &dyn BlockStore,
+    dirs: &DataDirs,
     id: u64,
     extension: &str,
-    opt: &mut OpenOptions,
-) -> Result<BufWriterWithPos<File>> {
-    Ok(BufWriterWithPos::new(
-        opt.open(log_path(base_path, id, extension))?,
-    )?)
+) -> Result<LogWriter> {
+    BufWriterWithPos::new(store.open_append(&log_path(dirs, id, extension))?)
 }
 
 fn gen_buf_reader(
-    base_path: &Path,
+    store: &dyn BlockStore,
+    dirs: &DataDirs,
     id: u64,
     extension: &str,
-    opt: &mut OpenOptions,
-) -> Result<BufReaderWithPos<File>> {
-    Ok(BufReaderWithPos::new(
-        opt.open(log_path(base_path, id, extension))?,
-    )?)
+) -> Result<LogReader> {
+    BufReaderWithPos::new(store.open_read(&log_path(dirs, id, extension))?)
+}
+
+/// Bytes of an entry of `key` with a value of `v_size` bytes which count as garbage once the entry
+/// is no longer live
+fn garbage_bytes(options: &BitcaskOptions, key: &[u8], v_size: u64) -> u64 {
+    match options.garbage_accounting {
+        GarbageAccounting::ValueBytes => v_size,
+        GarbageAccounting::EntryBytes => {
+            let key_size = key.len() as u64;
+            options.entry_format.header_size(key_size, v_size) + key_size + v_size
+        }
+    }
 }
 
 /// Load index entry and replay it to update index
-/// Return useless value bytes
+/// Return useless value bytes and the length of the valid prefix of the log file,
+/// the replay starts at offset `from`
+///
+/// A log entry which the file ends within is treated as the end of the file,
+/// since it's most likely the tail of a write interrupted by a crash. Any other error reading
+/// the file fails the replay, rather than dropping the entries after it.
+/// An entry which doesn't match its checksum is treated as `on_corruption` says, unless it
+/// ends an `active` file: a crash may have torn that write too, so replay stops before it.
 fn load_from_log_file(
     file_id: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: Arc<DashMap<String, IndexEntry>>,
-) -> Result<u64> {
-    reader.seek(SeekFrom::Start(0))?;
+    reader: &mut LogReader,
+    index: Arc<KeyIndex<IndexEntry>>,
+    options: &BitcaskOptions,
+    from: u64,
+    active: bool,
+) -> Result<(u64, u64)> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(from))?;
     let mut useless_value_bytes: u64 = 0;
-    while let Ok(Some((log_entry, pos))) = read_log_entry(reader) {
+    let mut valid_len: u64 = from;
+    loop {
+        options.cancel_token.check()?;
+        let (log_entry, pos) = match read_log_entry(reader, options.entry_format) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            // the file ends within the entry, a write torn by a crash
+            Err(err @ KvStoreErr::IncompleteEntry(_)) => {
+                warn!(
+                    "stop replaying log file {} at offset {}: {}",
+                    file_id, valid_len, err
+                );
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let entry_offset = valid_len;
+        if !log_entry.is_intact() {
+            if active && pos == file_len {
+                warn!(
+                    "stop replaying log file {} at offset {}: torn final entry",
+                    file_id, entry_offset
+                );
+                break;
+            }
+            valid_len = pos;
+            let err = KvStoreErr::UnexceptErr("checksum mismatch".to_owned());
+            handle_corrupt_entry(options.on_corruption, file_id, entry_offset, err)?;
+            continue;
+        }
+        valid_len = pos;
+        let key = log_entry.key;
         if log_entry.value.len() == 1 && log_entry.value[0] == DELETED_CODE {
             // this key mark as deleted
-            if let Some((_, old_entry)) = index.remove(&String::from_utf8(log_entry.key)?) {
-                // entry represents the deleted also occupy 1 bytes in value slot
-                useless_value_bytes += old_entry.v_size + 1;
+            // entry represents the deleted also occupy 1 bytes in value slot
+            useless_value_bytes += garbage_bytes(options, &key, 1);
+            if let Some(old_entry) = index.remove(&key) {
+                useless_value_bytes += garbage_bytes(options, &key, old_entry.v_size);
             }
         } else {
             // update it to index
-            let key = String::from_utf8(log_entry.key)?;
             if let Some(old_entry) = index.insert(
-                key,
+                key.clone(),
                 IndexEntry {
-                    file_id: file_id,
+                    file_id,
                     v_pos: pos,
                     v_size: log_entry.v_size,
+                    flags: log_entry.flags,
+                    expire_at: log_entry.expire_at,
                 },
             ) {
-                useless_value_bytes += old_entry.v_size;
+                useless_value_bytes += garbage_bytes(options, &key, old_entry.v_size);
             }
         }
     }
-    Ok(useless_value_bytes)
+    Ok((useless_value_bytes, valid_len))
 }
 
+/// Load the index entries of the log file `file_id`, which is `log_len` bytes long,
+/// from its hint file, return whether the hint file could be read to its end
+///
+/// Hint entries carry no checksum, so only an entry which points past the end of the log file
+/// is known to be corrupt, and is treated as `on_corruption` says. A hint file which can't be
+/// decoded fails too, unless `on_corruption` skips, then the log file is to be replayed instead.
 fn load_from_hint_file(
     file_id: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: Arc<DashMap<String, IndexEntry>>,
-) -> Result<()> {
+    reader: &mut LogReader,
+    index: Arc<KeyIndex<IndexEntry>>,
+    options: &BitcaskOptions,
+    log_len: u64,
+) -> Result<bool> {
     reader.seek(SeekFrom::Start(0))?;
-    while let Ok(Some(hint_entry)) = read_hint_entry(reader) {
-        let key = String::from_utf8(hint_entry.key)?;
+    // the keys of a front-coded hint file are decoded against the previous key
+    let mut prev_key = match reader.read_u64_be() {
+        Ok(Some(FRONT_CODED_HINT_MARKER | LEGACY_FRONT_CODED_HINT_MARKER)) => Some(Vec::new()),
+        _ => {
+            reader.seek(SeekFrom::Start(0))?;
+            None
+        }
+    };
+    let mut entry_offset = reader.pos;
+    loop {
+        let hint_entry = match read_hint_entry(reader, prev_key.as_mut(), options.entry_format) {
+            Ok(Some(hint_entry)) => hint_entry,
+            Ok(None) => return Ok(true),
+            // hint files are renamed into place once written, so even a cut off entry is corrupt
+            Err(err) => {
+                return match options.on_corruption {
+                    CorruptionPolicy::Fail => Err(KvStoreErr::CorruptEntry(file_id, entry_offset)),
+                    CorruptionPolicy::SkipAndContinue => {
+                        warn!(
+                            "hint file {} can't be read at offset {}: {}, replay the log file",
+                            file_id, entry_offset, err
+                        );
+                        Ok(false)
+                    }
+                };
+            }
+        };
+        options.cancel_token.check()?;
+        let offset = entry_offset;
+        entry_offset = reader.pos;
+        let entry_len = hint_entry.k_size.saturating_add(hint_entry.v_size);
+        if hint_entry.v_pos > log_len || hint_entry.v_pos < entry_len {
+            let err = KvStoreErr::UnexceptErr(format!(
+                "the entry ends at {} of a log file of {} bytes",
+                hint_entry.v_pos, log_len
+            ));
+            handle_corrupt_entry(options.on_corruption, file_id, offset, err)?;
+            continue;
+        }
         index.insert(
-            key,
+            hint_entry.key,
             IndexEntry {
-                file_id: file_id,
+                file_id,
                 v_pos: hint_entry.v_pos,
                 v_size: hint_entry.v_size,
+                flags: hint_entry.flags,
+                expire_at: hint_entry.expire_at,
             },
         );
     }
-    Ok(())
 }
 
-fn read_log_entry(reader: &mut BufReaderWithPos<File>) -> Result<Option<(LogEntry, u64)>> {
-    let k_size: u64;
-    if let Some(k_s) = reader.read_u64() {
-        k_size = k_s;
-    } else {
-        return Ok(None);
+/// CRC32 of the first `len` bytes of a log file
+fn log_file_checksum(store: &dyn BlockStore, dirs: &DataDirs, id: u64, len: u64) -> Result<u32> {
+    let mut file = Read::take(store.open_read(&log_path(dirs, id, "log"))?, len);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Whether a sealed log file matches the entry count and checksum in its footer, or has none.
+/// An entry that can't be read counts as a mismatch.
+fn footer_matches(
+    store: &dyn BlockStore,
+    dirs: &DataDirs,
+    file_id: u64,
+    format: EntryFormat,
+) -> Result<bool> {
+    let len = store.open_read(&log_path(dirs, file_id, "log"))?.size()?;
+    let mut reader = gen_buf_reader(store, dirs, file_id, "log")?;
+    let Some(footer) = Footer::read(&mut reader, format, len)? else {
+        return Ok(true);
+    };
+    let body_len = len - Footer::size(format);
+    if log_file_checksum(store, dirs, file_id, body_len)? != footer.crc {
+        return Ok(false);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    let mut entries = 0;
+    loop {
+        match read_log_entry(&mut reader, format) {
+            Ok(Some(_)) => entries += 1,
+            Ok(None) => break,
+            Err(_) => return Ok(false),
+        }
+    }
+    Ok(entries == footer.entries)
+}
+
+/// Fail or skip an entry which was read completely but can't be decoded
+fn handle_corrupt_entry(
+    on_corruption: CorruptionPolicy,
+    file_id: u64,
+    offset: u64,
+    err: KvStoreErr,
+) -> Result<()> {
+    match on_corruption {
+        CorruptionPolicy::Fail => Err(KvStoreErr::CorruptEntry(file_id, offset)),
+        CorruptionPolicy::SkipAndContinue => {
+            warn!(
+                "skip corrupt entry in file {} at offset {}: {}",
+                file_id, offset, err
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Whether `text` matches the glob `pattern` of `*` and `?` wildcards
+fn glob_match(pattern: &[char], text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the last `*` and where in the text its match ends so far, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the `*` match one more character
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Read the next log entry and the offset right after it
+fn read_log_entry(reader: &mut LogReader, format: EntryFormat) -> Result<Option<(LogEntry, u64)>> {
+    Ok(format
+        .decode(reader)?
+        .map(|log_entry| (log_entry, reader.pos)))
+}
+
+/// Read the next hint entry, `prev_key` is the key of the previous entry of a front-coded file
+fn read_hint_entry(
+    reader: &mut LogReader,
+    prev_key: Option<&mut Vec<u8>>,
+    format: EntryFormat,
+) -> Result<Option<HintEntry>> {
+    // the expiry record comes first, in place of the first size
+    let mut first_size = format.read_size(reader)?;
+    let mut expire_at = 0;
+    if first_size == Some(EXPIRY_MARKER) {
+        expire_at = reader
+            .read_u64_be()?
+            .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+        first_size = Some(
+            format
+                .read_size(reader)?
+                .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?,
+        );
+    }
+    let mut shared = 0;
+    if prev_key.is_some() {
+        if let Some(size) = first_size {
+            shared = size as usize;
+            first_size = format.read_size(reader)?;
+        } else {
+            return Ok(None);
+        }
     }
-    let v_size = reader.read_u64().unwrap();
-    let mut key_buf: [u8; 255] = [0; 255];
-    let mut taker = reader.take(k_size);
-    taker.read(&mut key_buf)?;
-    let mut value_buf: [u8; 255] = [0; 255];
-    let mut taker2 = reader.take(v_size);
-    taker2.read(&mut value_buf)?;
-    Ok(Some((
-        LogEntry {
-            k_size: k_size,
-            v_size: v_size,
-            key: key_buf[..(k_size as usize)].to_vec(),
-            value: value_buf[..(v_size as usize)].to_vec(),
-        },
-        reader.pos,
-    )))
-}
-
-fn read_hint_entry(reader: &mut BufReaderWithPos<File>) -> Result<Option<HintEntry>> {
     let k_size: u64;
-    if let Some(k_s) = reader.read_u64() {
+    if let Some(k_s) = first_size {
         k_size = k_s;
+    } else if prev_key.is_some() {
+        return Err(KvStoreErr::IncompleteEntry(reader.pos));
     } else {
         return Ok(None);
     }
 
-    let v_size = reader
-        .read_u64()
-        .expect(format!("error to read value size").as_str());
+    let v_size = format
+        .read_size(reader)?
+        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+
+    let v_pos = format
+        .read_size(reader)?
+        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
 
-    let v_pos = reader
-        .read_u64()
-        .expect(format!("error to read value position").as_str());
+    let flags = reader.read_u32()?;
 
-    let mut key_buf: [u8; 255] = [0; 255];
-    let mut taker = reader.take(k_size);
-    taker.read(&mut key_buf)?;
+    let mut key = reader.read_entry_vec(k_size)?;
+    if let Some(prev_key) = prev_key {
+        if shared > prev_key.len() {
+            return Err(KvStoreErr::UnexceptErr(format!(
+                "hint entry shares {} bytes with a key of {} bytes",
+                shared,
+                prev_key.len()
+            )));
+        }
+        key = [&prev_key[..shared], &key[..]].concat();
+        prev_key.clone_from(&key);
+    }
     Ok(Some(HintEntry {
-        k_size: k_size,
-        v_size: v_size,
-        v_pos: v_pos,
-        key: key_buf[..(k_size as usize)].to_vec(),
+        k_size: key.len() as u64,
+        v_size,
+        v_pos,
+        flags,
+        key,
+        expire_at,
     }))
 }
 
-fn log_path(base_path: &Path, id: u64, extension: &str) -> PathBuf {
-    base_path.join(format!("{}.{}", id, extension))
+/// Complete the renames of a merge install which a crash interrupted, then remove the backups
+/// of the replaced files, which are left over once the renames are all done
+///
+/// The merged files were written in full before the first rename, and each rename is done
+/// unless its file is already in place, so the install can be completed wherever it stopped.
+fn finish_install(store: &dyn BlockStore, dirs: &DataDirs) -> Result<()> {
+    if let Some(renames) = install::read(store, &dirs.log_dir)? {
+        warn!("complete the merge install interrupted by a crash");
+        let renames: Vec<_> = renames
+            .into_iter()
+            .map(|(id, from, to)| (log_path(dirs, id, &from), log_path(dirs, id, &to)))
+            .collect();
+        if let Some((from, _)) = renames
+            .iter()
+            .find(|(from, to)| !store.exists(from) && !store.exists(to))
+        {
+            return Err(KvStoreErr::UnexceptErr(format!(
+                "can't complete the merge install, {:?} is missing",
+                from
+            )));
+        }
+        for (from, to) in renames {
+            if store.exists(&from) && !store.exists(&to) {
+                store.rename(&from, &to)?;
+            }
+        }
+        install::remove(store, &dirs.log_dir)?;
+    }
+    for dir in [&dirs.log_dir, &dirs.hint_dir] {
+        for path in store.list(dir)? {
+            if path.extension() == Some("old".as_ref()) {
+                store.remove(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn log_path(dirs: &DataDirs, id: u64, extension: &str) -> PathBuf {
+    let dir = if extension.starts_with("hint") {
+        &dirs.hint_dir
+    } else {
+        &dirs.log_dir
+    };
+    dir.join(format!("{}.{}", id, extension))
 }
 
-fn get_all_sorted_log_file_id(path: &Path) -> Result<Vec<u64>> {
-    let mut log_list: Vec<u64> = fs::read_dir(path)?
-        .flat_map(|dir_entry| -> Result<_> { Ok(dir_entry?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
+fn get_all_sorted_log_file_id(store: &dyn BlockStore, path: &Path) -> Result<Vec<u64>> {
+    let mut log_list: Vec<u64> = store
+        .list(path)?
+        .iter()
+        .filter_map(|path| path.file_name().and_then(OsStr::to_str))
+        .filter(|name| name.ends_with(".log"))
+        .filter_map(|name| {
+            let id = parse_log_file_id(name);
+            if id.is_none() {
+                warn!("skip {} which isn't named like a log file", name);
+            }
+            id
         })
-        .flatten()
         .collect();
     log_list.sort();
     Ok(log_list)
 }
+
+/// Id of a log file named `<id>.log`, where the id is written the way `log_path` writes it,
+/// so that no two names map to the same id
+fn parse_log_file_id(file_name: &str) -> Option<u64> {
+    let digits = file_name.strip_suffix(".log")?;
+    let id = digits.parse::<u64>().ok()?;
+    (id.to_string() == digits).then_some(id)
+}
@@ -0,0 +1,47 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// about 1% false positives at the capacity of the filter
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+/// Bloom filter of keys, which tells for sure that a key was never inserted
+///
+/// Keys can't be taken out, so the filter of a store is rebuilt from time to time
+/// to forget the removed ones. Inserts and lookups take no lock.
+pub struct BloomFilter {
+    hasher: RandomState,
+    bits: Vec<AtomicU64>,
+}
+
+impl BloomFilter {
+    /// An empty filter with about 1% false positives once it holds `capacity` keys
+    pub fn with_capacity(capacity: usize) -> Self {
+        let words = (capacity.max(1) * BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            hasher: RandomState::new(),
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn insert(&self, key: &[u8]) {
+        for bit in self.bits_of(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// Whether `key` may have been inserted, `false` means it never was
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::SeqCst) & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing, the halves of one hash make all the probes
+    fn bits_of(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use super::entry::IndexEntry;
+
+/// Values read from the log files, the least recently read go first once the cache
+/// holds more than its bytes or its entries
+///
+/// A value is only served for the index entry it was read at, so a value replaced
+/// meanwhile is never served. Merges move entries around, and the cache starts over after each.
+pub struct ValueCache {
+    max_bytes: u64,
+    max_entries: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    bytes: u64,
+    // bumped by `clear`, so reads which started before don't fill the cache
+    generation: u64,
+    entries: HashMap<Vec<u8>, Cached>,
+    // keys by the tick of their last read, the first one goes first
+    order: BTreeMap<u64, Vec<u8>>,
+    next_tick: u64,
+}
+
+struct Cached {
+    tick: u64,
+    file_id: u64,
+    v_pos: u64,
+    value: Vec<u8>,
+}
+
+impl ValueCache {
+    pub fn new(max_bytes: u64, max_entries: usize) -> Self {
+        ValueCache {
+            max_bytes,
+            max_entries,
+            inner: Mutex::new(CacheInner {
+                bytes: 0,
+                generation: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_tick: 0,
+            }),
+        }
+    }
+
+    /// Take before reading a value to `insert`
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// The value of `key` if it was cached for `index_entry`
+    pub fn get(&self, key: &[u8], index_entry: &IndexEntry) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let tick = inner.tick();
+        let cached = inner.entries.get_mut(key)?;
+        if (cached.file_id, cached.v_pos) != (index_entry.file_id, index_entry.v_pos) {
+            return None;
+        }
+        let old_tick = std::mem::replace(&mut cached.tick, tick);
+        let value = cached.value.clone();
+        inner.order.remove(&old_tick);
+        inner.order.insert(tick, key.to_vec());
+        Some(value)
+    }
+
+    /// Cache the value of `key` read at `index_entry`, unless the cache was cleared
+    /// since `generation` was taken
+    pub fn insert(&self, key: &[u8], index_entry: &IndexEntry, value: &[u8], generation: u64) {
+        let bytes = (key.len() + value.len()) as u64;
+        if bytes > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        inner.remove(key);
+        let tick = inner.tick();
+        inner.entries.insert(
+            key.to_vec(),
+            Cached {
+                tick,
+                file_id: index_entry.file_id,
+                v_pos: index_entry.v_pos,
+                value: value.to_vec(),
+            },
+        );
+        inner.order.insert(tick, key.to_vec());
+        inner.bytes += bytes;
+        while inner.bytes > self.max_bytes || inner.entries.len() > self.max_entries {
+            let victim = match inner.order.values().next() {
+                Some(victim) => victim.clone(),
+                None => break,
+            };
+            inner.remove(&victim);
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.bytes = 0;
+        inner.entries.clear();
+        inner.order.clear();
+    }
+}
+
+impl CacheInner {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.order.remove(&cached.tick);
+            self.bytes -= (key.len() + cached.value.len()) as u64;
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+}
@@ -0,0 +1,80 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::entry::IndexEntry;
+use super::store::BlockStore;
+use crate::Result;
+
+/// File in the hint directory which holds the last checkpoint of the index
+pub const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+
+/// The index as it was once the log files up to `offset` in file `file_id` were written
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    /// The active file when the checkpoint was taken
+    pub file_id: u64,
+    /// How much of the active file the checkpoint covers
+    pub offset: u64,
+    /// Bytes of garbage in the covered files
+    pub garbage_bytes: u64,
+    pub entries: Vec<(Vec<u8>, IndexEntry)>,
+}
+
+/// Write `checkpoint` into `dir`, replacing the last one in one rename
+///
+/// The file is the bincode of the checkpoint followed by its CRC32, big-endian.
+pub fn write(store: &dyn BlockStore, dir: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let mut bytes = bincode::serialize(checkpoint)?;
+    let crc = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    let temp_path = dir.join(format!("{}.temp", CHECKPOINT_FILE_NAME));
+    if store.exists(&temp_path) {
+        store.remove(&temp_path)?;
+    }
+    let mut file = store.open_append(&temp_path)?;
+    file.write_all(&bytes)?;
+    file.flush()?;
+    file.sync()?;
+    drop(file);
+    store.rename(&temp_path, &dir.join(CHECKPOINT_FILE_NAME))
+}
+
+/// The checkpoint in `dir`, `None` if there is none, it doesn't match its checksum
+/// or it can't be decoded
+pub fn read(store: &dyn BlockStore, dir: &Path) -> Result<Option<Checkpoint>> {
+    let path = dir.join(CHECKPOINT_FILE_NAME);
+    if !store.exists(&path) {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    store.open_read(&path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 4 {
+        warn!("ignore the checkpoint of {} bytes", bytes.len());
+        return Ok(None);
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body).to_be_bytes() != crc {
+        warn!("ignore the checkpoint which doesn't match its checksum");
+        return Ok(None);
+    }
+    match bincode::deserialize(body) {
+        Ok(checkpoint) => Ok(Some(checkpoint)),
+        Err(err) => {
+            // taken by a version which laid out the index entries differently
+            warn!("ignore the checkpoint which can't be decoded: {}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// Remove the checkpoint in `dir`, if there is one
+pub fn remove(store: &dyn BlockStore, dir: &Path) -> Result<()> {
+    let path = dir.join(CHECKPOINT_FILE_NAME);
+    if store.exists(&path) {
+        store.remove(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use std::time::SystemTime;
+
+/// Source of the wall clock time of an engine, replace it to control time in tests
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> SystemTime;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::options::{BitcaskOptions, EntryFormat};
+use super::store::BlockStore;
+use crate::{KvStoreErr, Result};
+
+/// File in the log directory which records the settings the files were written with
+pub const CONFIG_FILE_NAME: &str = "config";
+/// File in the data directory which names the engine that created it
+pub const ENGINE_FILE_NAME: &str = "engine";
+pub const BITCASK_ENGINE: &str = "kvs";
+pub const SLED_ENGINE: &str = "sled";
+
+// bumped whenever the layout of the log or hint files changes
+const FORMAT_VERSION: u32 = 1;
+
+/// Settings which decide how the log and hint files are laid out, as `name = value` pairs
+fn settings(options: &BitcaskOptions) -> Vec<(&'static str, String)> {
+    vec![
+        ("format_version", FORMAT_VERSION.to_string()),
+        (
+            "codec",
+            match options.entry_format {
+                EntryFormat::Fixed => "default",
+                EntryFormat::Varint => "varint",
+            }
+            .to_owned(),
+        ),
+        ("byte_order", "big_endian".to_owned()),
+        ("value_alignment", options.value_alignment.to_string()),
+        (
+            "hint_prefix_compression",
+            options.hint_prefix_compression.to_string(),
+        ),
+    ]
+}
+
+/// Check the settings of `options` against the config file in `dir`, and record them there
+/// if the store has no config file yet or it misses some of them
+///
+/// Fails with `KvStoreErr::ConfigConflict` on the first setting which differs. Log files
+/// without a config file were written before there was one, with the default codec.
+/// A read-only replica only checks.
+pub fn check_or_record(store: &dyn BlockStore, dir: &Path, options: &BitcaskOptions) -> Result<()> {
+    let passed = settings(options);
+    let path = dir.join(CONFIG_FILE_NAME);
+    let mut recorded = 0;
+    if store.exists(&path) {
+        let mut text = String::new();
+        store.open_read(&path)?.read_to_string(&mut text)?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (name, stored) = line.split_once('=').ok_or_else(|| {
+                KvStoreErr::UnexceptErr(format!("malformed line {:?} in the config file", line))
+            })?;
+            let (name, stored) = (name.trim(), stored.trim());
+            match passed.iter().find(|(setting, _)| *setting == name) {
+                Some((_, value)) if value == stored => recorded += 1,
+                Some((_, value)) => {
+                    return Err(KvStoreErr::ConfigConflict(
+                        name.to_owned(),
+                        stored.to_owned(),
+                        value.clone(),
+                    ))
+                }
+                None => {
+                    return Err(KvStoreErr::UnexceptErr(format!(
+                        "unknown setting {} in the config file",
+                        name
+                    )))
+                }
+            }
+        }
+    } else if options.entry_format != EntryFormat::Fixed && has_log_files(store, dir)? {
+        let (_, codec) = passed.iter().find(|(name, _)| *name == "codec").unwrap();
+        return Err(KvStoreErr::ConfigConflict(
+            "codec".to_owned(),
+            "default".to_owned(),
+            codec.clone(),
+        ));
+    }
+    if recorded < passed.len() && !options.read_only {
+        record(store, dir, options)?;
+    }
+    Ok(())
+}
+
+/// Write the settings of `options` into the config file in `dir`, replacing it in one rename
+pub fn record(store: &dyn BlockStore, dir: &Path, options: &BitcaskOptions) -> Result<()> {
+    let text: String = settings(options)
+        .into_iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect();
+    replace_file(store, dir, CONFIG_FILE_NAME, &text)
+}
+
+/// Check that `dir` holds the data of `engine`, and mark it as such if no engine marked it yet
+///
+/// Fails with `KvStoreErr::EngineMismatch` if another engine created it. An unmarked directory
+/// which holds files is only taken for the data of bitcask if some of them are log files, and
+/// for that of another engine if none are. A read-only replica only checks.
+pub fn check_or_mark_engine(
+    store: &dyn BlockStore,
+    dir: &Path,
+    engine: &str,
+    read_only: bool,
+) -> Result<()> {
+    let path = dir.join(ENGINE_FILE_NAME);
+    if store.exists(&path) {
+        let mut found = String::new();
+        store.open_read(&path)?.read_to_string(&mut found)?;
+        let found = found.trim();
+        if found != engine {
+            return Err(KvStoreErr::EngineMismatch {
+                expected: engine.to_owned(),
+                found: found.to_owned(),
+            });
+        }
+    } else {
+        check_unmarked(store, dir, engine)?;
+        if !read_only {
+            replace_file(store, dir, ENGINE_FILE_NAME, &format!("{}\n", engine))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the files of a directory written before engines marked their directories,
+/// bitcask keeps its entries in log files and the other engines don't
+fn check_unmarked(store: &dyn BlockStore, dir: &Path, engine: &str) -> Result<()> {
+    let temp_name = format!("{}.temp", ENGINE_FILE_NAME);
+    let names: Vec<String> = store
+        .list(dir)?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str().map(str::to_owned))
+        .filter(|name| *name != temp_name)
+        .collect();
+    let has_logs = names.iter().any(|name| name.ends_with(".log"));
+    if engine == BITCASK_ENGINE && !names.is_empty() && !has_logs {
+        return Err(KvStoreErr::UnexceptErr(format!(
+            "{:?} holds files but no log files of bitcask",
+            dir
+        )));
+    }
+    if engine != BITCASK_ENGINE && has_logs {
+        return Err(KvStoreErr::EngineMismatch {
+            expected: engine.to_owned(),
+            found: BITCASK_ENGINE.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+fn has_log_files(store: &dyn BlockStore, dir: &Path) -> Result<bool> {
+    Ok(store
+        .list(dir)?
+        .iter()
+        .any(|path| path.extension().is_some_and(|ext| ext == "log")))
+}
+
+/// Write `text` into the file `name` in `dir`, replacing it in one rename
+fn replace_file(store: &dyn BlockStore, dir: &Path, name: &str, text: &str) -> Result<()> {
+    let temp_path = dir.join(format!("{}.temp", name));
+    if store.exists(&temp_path) {
+        store.remove(&temp_path)?;
+    }
+    let mut file = store.open_append(&temp_path)?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+    drop(file);
+    store.rename(&temp_path, &dir.join(name))
+}
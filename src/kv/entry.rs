@@ -1,18 +1,314 @@
+use std::io::{Read, Seek, SeekFrom};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use super::options::EntryFormat;
+use crate::io::BufReaderWithPos;
+use crate::{KvStoreErr, Result};
+
+/// Bytes of a log entry before its key: key size, value size, flags and checksum
+pub const LOG_ENTRY_HEADER_SIZE: u64 = 8 + 8 + 4 + 4;
+/// Key size which marks a padding record: `marker | length | length bytes of padding`,
+/// readers skip it. The length is a big-endian u64 in both formats.
+pub const PADDING_MARKER: u64 = u64::MAX;
+/// Key size which marks the footer of a sealed log file: `marker | entry count | crc`,
+/// with the count a big-endian u64 and the crc a big-endian CRC32 of every byte before the
+/// footer. Readers stop at it.
+pub const FOOTER_MARKER: u64 = u64::MAX - 1;
+/// Key size which marks an expiry record: `marker | expire_at`, with `expire_at` a big-endian
+/// u64 of unix milliseconds. It belongs to the entry right after it, in log and hint files alike,
+/// and entries which never expire go without one, so files written before expiries still load.
+pub const EXPIRY_MARKER: u64 = u64::MAX - 2;
+/// First 8 bytes of a front-coded hint file, whose entries are
+/// `shared prefix size | suffix size | v_size | v_pos | flags | suffix`,
+/// with the key made of the first bytes of the previous key and the suffix.
+/// It differs from the markers of log records, so no record can be taken for it.
+pub const FRONT_CODED_HINT_MARKER: u64 = u64::MAX - 3;
+/// What `FRONT_CODED_HINT_MARKER` was before it got a value of its own, still read so that
+/// front-coded hint files written back then load
+pub const LEGACY_FRONT_CODED_HINT_MARKER: u64 = PADDING_MARKER;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct IndexEntry {
     pub file_id: u64,
     pub v_pos: u64,
     pub v_size: u64,
+    pub flags: u32,
+    /// Unix milliseconds from which the entry reads as absent, 0 if it never expires
+    pub expire_at: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct LogEntry {
     pub k_size: u64,
     pub v_size: u64,
+    pub flags: u32,
+    /// Checksum of the other fields, as written in the log
+    pub crc: u32,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
+    /// Unix milliseconds from which the entry reads as absent, 0 if it never expires
+    pub expire_at: u64,
+}
+
+impl LogEntry {
+    pub fn new(key: Vec<u8>, value: Vec<u8>, flags: u32) -> Self {
+        let mut entry = LogEntry {
+            k_size: key.len() as u64,
+            v_size: value.len() as u64,
+            flags,
+            crc: 0,
+            key,
+            value,
+            expire_at: 0,
+        };
+        entry.crc = entry.checksum();
+        entry
+    }
+
+    /// The entry, expiring at `expire_at` unix milliseconds instead
+    pub fn expiring_at(mut self, expire_at: u64) -> Self {
+        self.expire_at = expire_at;
+        self.crc = self.checksum();
+        self
+    }
+
+    /// CRC32 of the sizes, flags, key and value, and of the expiry if the entry has one,
+    /// so entries which never expire keep the checksum they had before expiries
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.k_size.to_be_bytes());
+        hasher.update(&self.v_size.to_be_bytes());
+        hasher.update(&self.flags.to_be_bytes());
+        hasher.update(&self.key);
+        hasher.update(&self.value);
+        if self.expire_at != 0 {
+            hasher.update(&self.expire_at.to_be_bytes());
+        }
+        hasher.finalize()
+    }
+
+    /// Whether the entry matches the checksum it was written with
+    pub fn is_intact(&self) -> bool {
+        self.crc == self.checksum()
+    }
+}
+
+/// Writes and reads log entries in one format, so the two sides can't drift apart
+pub trait EntryCodec {
+    /// Bytes of `entry` in a log file
+    fn encode(entry: &LogEntry) -> Vec<u8>;
+    /// Read the next entry, skipping padding records, `None` if the reader is at the end
+    fn decode<R: Read + Seek>(reader: &mut BufReaderWithPos<R>) -> Result<Option<LogEntry>>;
+}
+
+/// The format of the log files: `k_size | v_size | flags | crc | key | value`,
+/// with big-endian numbers
+pub struct DefaultCodec;
+
+impl EntryCodec for DefaultCodec {
+    fn encode(entry: &LogEntry) -> Vec<u8> {
+        let mut buf: Vec<u8> =
+            Vec::with_capacity((LOG_ENTRY_HEADER_SIZE + entry.k_size + entry.v_size) as usize);
+        buf.append(&mut EntryFormat::Fixed.expiry_record(entry.expire_at));
+        buf.extend_from_slice(&entry.k_size.to_be_bytes());
+        buf.extend_from_slice(&entry.v_size.to_be_bytes());
+        buf.extend_from_slice(&entry.flags.to_be_bytes());
+        buf.extend_from_slice(&entry.crc.to_be_bytes());
+        buf.extend_from_slice(&entry.key);
+        buf.extend_from_slice(&entry.value);
+        buf
+    }
+
+    fn decode<R: Read + Seek>(reader: &mut BufReaderWithPos<R>) -> Result<Option<LogEntry>> {
+        let mut expire_at = 0;
+        let k_size = loop {
+            match reader.read_u64_be()? {
+                None => return Ok(None),
+                Some(PADDING_MARKER) => {
+                    // skip the padding which aligns the value of the next entry
+                    let len = reader
+                        .read_u64_be()?
+                        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+                    reader.skip_entry_bytes(len)?;
+                }
+                Some(FOOTER_MARKER) => return Ok(None),
+                Some(EXPIRY_MARKER) => {
+                    expire_at = reader
+                        .read_u64_be()?
+                        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+                }
+                Some(k_size) => break k_size,
+            }
+        };
+        let v_size = reader
+            .read_u64_be()?
+            .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+        let flags = reader.read_u32()?;
+        let crc = reader.read_u32()?;
+        let key = reader.read_entry_vec(k_size)?;
+        let value = reader.read_entry_vec(v_size)?;
+        Ok(Some(LogEntry {
+            k_size,
+            v_size,
+            flags,
+            crc,
+            key,
+            value,
+            expire_at,
+        }))
+    }
+}
+
+/// The log format of `EntryFormat::Varint`: `k_size | v_size | flags | crc | key | value`,
+/// with the sizes as LEB128 varints and the rest as in `DefaultCodec`
+pub struct VarintCodec;
+
+impl EntryCodec for VarintCodec {
+    fn encode(entry: &LogEntry) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(
+            (EntryFormat::Varint.header_size(entry.k_size, entry.v_size)
+                + entry.k_size
+                + entry.v_size) as usize,
+        );
+        buf.append(&mut EntryFormat::Varint.expiry_record(entry.expire_at));
+        put_varint(&mut buf, entry.k_size);
+        put_varint(&mut buf, entry.v_size);
+        buf.extend_from_slice(&entry.flags.to_be_bytes());
+        buf.extend_from_slice(&entry.crc.to_be_bytes());
+        buf.extend_from_slice(&entry.key);
+        buf.extend_from_slice(&entry.value);
+        buf
+    }
+
+    fn decode<R: Read + Seek>(reader: &mut BufReaderWithPos<R>) -> Result<Option<LogEntry>> {
+        let mut expire_at = 0;
+        let k_size = loop {
+            match reader.read_varint()? {
+                None => return Ok(None),
+                Some(PADDING_MARKER) => {
+                    let len = reader
+                        .read_u64_be()?
+                        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+                    reader.skip_entry_bytes(len)?;
+                }
+                Some(FOOTER_MARKER) => return Ok(None),
+                Some(EXPIRY_MARKER) => {
+                    expire_at = reader
+                        .read_u64_be()?
+                        .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+                }
+                Some(k_size) => break k_size,
+            }
+        };
+        let v_size = reader
+            .read_varint()?
+            .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+        let flags = reader.read_u32()?;
+        let crc = reader.read_u32()?;
+        let key = reader.read_entry_vec(k_size)?;
+        let value = reader.read_entry_vec(v_size)?;
+        Ok(Some(LogEntry {
+            k_size,
+            v_size,
+            flags,
+            crc,
+            key,
+            value,
+            expire_at,
+        }))
+    }
+}
+
+/// Append `value` to `buf` as a LEB128 varint
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Bytes `value` takes as a LEB128 varint
+pub fn varint_len(value: u64) -> u64 {
+    (64 - u64::from(value.leading_zeros())).max(1).div_ceil(7)
+}
+
+impl EntryFormat {
+    /// Bytes of `entry` in a log file of this format
+    pub fn encode(self, entry: &LogEntry) -> Vec<u8> {
+        match self {
+            EntryFormat::Fixed => DefaultCodec::encode(entry),
+            EntryFormat::Varint => VarintCodec::encode(entry),
+        }
+    }
+
+    /// Read the next entry of a log file of this format
+    pub fn decode<R: Read + Seek>(
+        self,
+        reader: &mut BufReaderWithPos<R>,
+    ) -> Result<Option<LogEntry>> {
+        match self {
+            EntryFormat::Fixed => DefaultCodec::decode(reader),
+            EntryFormat::Varint => VarintCodec::decode(reader),
+        }
+    }
+
+    /// Bytes of the header of a log entry with a key of `k_size` and a value of `v_size` bytes
+    pub fn header_size(self, k_size: u64, v_size: u64) -> u64 {
+        match self {
+            EntryFormat::Fixed => LOG_ENTRY_HEADER_SIZE,
+            EntryFormat::Varint => varint_len(k_size) + varint_len(v_size) + 4 + 4,
+        }
+    }
+
+    /// Expiry record to write before an entry which expires at `expire_at`,
+    /// empty for one which never expires
+    pub fn expiry_record(self, expire_at: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if expire_at != 0 {
+            self.put_size(&mut buf, EXPIRY_MARKER);
+            buf.extend_from_slice(&expire_at.to_be_bytes());
+        }
+        buf
+    }
+
+    /// Bytes of the expiry record before an entry which expires at `expire_at`
+    pub fn expiry_size(self, expire_at: u64) -> u64 {
+        self.expiry_record(expire_at).len() as u64
+    }
+
+    /// Read a size of a hint entry, `None` if the reader is already at the end
+    pub fn read_size<R: Read + Seek>(
+        self,
+        reader: &mut BufReaderWithPos<R>,
+    ) -> Result<Option<u64>> {
+        match self {
+            EntryFormat::Fixed => reader.read_u64_be(),
+            EntryFormat::Varint => reader.read_varint(),
+        }
+    }
+
+    fn put_size(self, buf: &mut Vec<u8>, value: u64) {
+        match self {
+            EntryFormat::Fixed => buf.extend_from_slice(&value.to_be_bytes()),
+            EntryFormat::Varint => put_varint(buf, value),
+        }
+    }
+
+    /// Bytes of `entry` in a hint file of this format
+    fn serialize_hint(self, entry: &HintEntry) -> Vec<u8> {
+        if self == EntryFormat::Fixed {
+            return SerializeToBytes::serialize(entry);
+        }
+        let mut buf = Vec::with_capacity(3 * 10 + 4 + entry.key.len());
+        put_varint(&mut buf, entry.k_size);
+        put_varint(&mut buf, entry.v_size);
+        put_varint(&mut buf, entry.v_pos);
+        buf.extend_from_slice(&entry.flags.to_be_bytes());
+        buf.extend_from_slice(&entry.key);
+        buf
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,32 +316,309 @@ pub struct HintEntry {
     pub k_size: u64,
     pub v_size: u64,
     pub v_pos: u64,
+    pub flags: u32,
     pub key: Vec<u8>,
+    /// Unix milliseconds from which the entry reads as absent, 0 if it never expires
+    pub expire_at: u64,
 }
 
-pub trait SerializeToBytes {
-    fn serialize(&self) -> Vec<u8>;
+/// Serializes the hint entries of one hint file, front-coding the keys if enabled
+pub struct HintEncoder {
+    front_coding: bool,
+    format: EntryFormat,
+    prev_key: Option<Vec<u8>>,
 }
 
-impl SerializeToBytes for LogEntry {
-    fn serialize(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> =
-            Vec::with_capacity(8 + 8 + self.k_size as usize + self.v_size as usize);
-        buf.append(&mut self.k_size.to_be_bytes().to_vec());
-        buf.append(&mut self.v_size.to_be_bytes().to_vec());
-        buf.append(&mut self.key.clone());
-        buf.append(&mut self.value.clone());
+impl HintEncoder {
+    pub fn new(front_coding: bool, format: EntryFormat) -> Self {
+        HintEncoder {
+            front_coding,
+            format,
+            prev_key: None,
+        }
+    }
+
+    /// Bytes to append to the hint file for `entry`, starting with the marker for the first one
+    pub fn encode(&mut self, entry: &HintEntry) -> Vec<u8> {
+        if !self.front_coding {
+            let mut buf = self.format.expiry_record(entry.expire_at);
+            buf.append(&mut self.format.serialize_hint(entry));
+            return buf;
+        }
+        let mut buf = Vec::new();
+        let shared = match &self.prev_key {
+            Some(prev_key) => prev_key
+                .iter()
+                .zip(&entry.key)
+                .take_while(|(a, b)| a == b)
+                .count(),
+            None => {
+                buf.append(&mut FRONT_CODED_HINT_MARKER.to_be_bytes().to_vec());
+                0
+            }
+        };
+        buf.append(&mut self.format.expiry_record(entry.expire_at));
+        self.format.put_size(&mut buf, shared as u64);
+        let suffix_entry = HintEntry {
+            k_size: (entry.key.len() - shared) as u64,
+            v_size: entry.v_size,
+            v_pos: entry.v_pos,
+            flags: entry.flags,
+            key: entry.key[shared..].to_vec(),
+            expire_at: entry.expire_at,
+        };
+        buf.append(&mut self.format.serialize_hint(&suffix_entry));
+        self.prev_key = Some(entry.key.clone());
+        buf
+    }
+}
+
+/// Padding record to write at `pos`, so that the value of `entry` written after it in a log file
+/// of `format` starts at a multiple of `alignment`, empty if the value is aligned already
+pub fn padding_before(format: EntryFormat, pos: u64, entry: &LogEntry, alignment: u64) -> Vec<u8> {
+    let entry_prefix = format.expiry_size(entry.expire_at)
+        + format.header_size(entry.k_size, entry.v_size)
+        + entry.k_size;
+    let misalignment = |record_size: u64| (pos + record_size + entry_prefix) % alignment;
+    if alignment <= 1 || misalignment(0) == 0 {
+        return Vec::new();
+    }
+    let mut buf = Vec::new();
+    format.put_size(&mut buf, PADDING_MARKER);
+    let record_size = buf.len() as u64 + 8;
+    let len = (alignment - misalignment(record_size)) % alignment;
+    buf.append(&mut len.to_be_bytes().to_vec());
+    buf.resize((record_size + len) as usize, 0);
+    buf
+}
+
+/// What the footer of a sealed log file records about the bytes before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub entries: u64,
+    pub crc: u32,
+}
+
+impl Footer {
+    /// Bytes of the footer in a log file of `format`
+    pub fn encode(&self, format: EntryFormat) -> Vec<u8> {
+        let mut buf = Vec::new();
+        format.put_size(&mut buf, FOOTER_MARKER);
+        buf.extend_from_slice(&self.entries.to_be_bytes());
+        buf.extend_from_slice(&self.crc.to_be_bytes());
         buf
     }
+
+    /// Bytes of a footer in a log file of `format`
+    pub fn size(format: EntryFormat) -> u64 {
+        Footer { entries: 0, crc: 0 }.encode(format).len() as u64
+    }
+
+    /// Read the footer at the end of a log file of `len` bytes, `None` if it has none
+    pub fn read<R: Read + Seek>(
+        reader: &mut BufReaderWithPos<R>,
+        format: EntryFormat,
+        len: u64,
+    ) -> Result<Option<Footer>> {
+        let Some(start) = len.checked_sub(Footer::size(format)) else {
+            return Ok(None);
+        };
+        reader.seek(SeekFrom::Start(start))?;
+        if format.read_size(reader)? != Some(FOOTER_MARKER) {
+            return Ok(None);
+        }
+        let entries = reader
+            .read_u64_be()?
+            .ok_or(KvStoreErr::IncompleteEntry(reader.pos))?;
+        let crc = reader.read_u32()?;
+        Ok(Some(Footer { entries, crc }))
+    }
+}
+
+/// Checksum and entry count of what was written to a log file from its start, kept while
+/// writing so that sealing the file doesn't read it back
+#[derive(Clone, Default)]
+pub struct FileDigest {
+    crc: crc32fast::Hasher,
+    entries: u64,
+}
+
+impl FileDigest {
+    /// Account for `bytes` appended to the file, which hold `entries` entries
+    pub fn update(&mut self, bytes: &[u8], entries: u64) {
+        self.crc.update(bytes);
+        self.entries += entries;
+    }
+
+    pub fn footer(&self) -> Footer {
+        Footer {
+            entries: self.entries,
+            crc: self.crc.clone().finalize(),
+        }
+    }
+}
+
+pub trait SerializeToBytes {
+    fn serialize(&self) -> Vec<u8>;
 }
 
 impl SerializeToBytes for HintEntry {
     fn serialize(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::with_capacity(8 + 8 + 8 + self.k_size as usize);
+        let mut buf: Vec<u8> = Vec::with_capacity(8 + 8 + 8 + 4 + self.k_size as usize);
         buf.append(&mut self.k_size.to_be_bytes().to_vec());
         buf.append(&mut self.v_size.to_be_bytes().to_vec());
         buf.append(&mut self.v_pos.to_be_bytes().to_vec());
+        buf.append(&mut self.flags.to_be_bytes().to_vec());
         buf.append(&mut self.key.clone());
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::{thread_rng, Rng};
+
+    use super::{
+        padding_before, put_varint, varint_len, DefaultCodec, EntryCodec, LogEntry, VarintCodec,
+        EXPIRY_MARKER, FOOTER_MARKER, FRONT_CODED_HINT_MARKER, PADDING_MARKER,
+    };
+    use crate::io::BufReaderWithPos;
+    use crate::kv::options::EntryFormat;
+    use crate::KvStoreErr;
+
+    // mostly short, with some far beyond what a single byte of length would hold
+    fn random_bytes(rng: &mut impl Rng) -> Vec<u8> {
+        let len = match rng.gen_range(0..10) {
+            0 => rng.gen_range(256..100_000),
+            _ => rng.gen_range(0..256),
+        };
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    // some of them expiring
+    fn random_entry(rng: &mut impl Rng) -> LogEntry {
+        let entry = LogEntry::new(random_bytes(rng), random_bytes(rng), rng.gen());
+        match rng.gen_range(0..4) {
+            0 => entry.expiring_at(rng.gen_range(1..u64::MAX)),
+            _ => entry,
+        }
+    }
+
+    #[test]
+    fn decode_encoded_entries() {
+        let mut rng = thread_rng();
+        for format in [EntryFormat::Fixed, EntryFormat::Varint] {
+            let entries: Vec<LogEntry> = (0..200).map(|_| random_entry(&mut rng)).collect();
+            let mut buf = Vec::new();
+            for entry in &entries {
+                // padding records between the entries are skipped
+                let alignment = rng.gen_range(1..64);
+                buf.append(&mut padding_before(
+                    format,
+                    buf.len() as u64,
+                    entry,
+                    alignment,
+                ));
+                let value_pos = buf.len() as u64
+                    + format.expiry_size(entry.expire_at)
+                    + format.header_size(entry.k_size, entry.v_size)
+                    + entry.k_size;
+                assert_eq!(value_pos % alignment, 0);
+                buf.append(&mut format.encode(entry));
+            }
+
+            let mut reader = BufReaderWithPos::new(Cursor::new(buf)).unwrap();
+            for entry in &entries {
+                let decoded = format.decode(&mut reader).unwrap();
+                assert_eq!(decoded.as_ref(), Some(entry));
+                assert!(decoded.unwrap().is_intact());
+            }
+            assert_eq!(format.decode(&mut reader).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn varint_entries_are_smaller() {
+        let entry = LogEntry::new(b"key".to_vec(), b"value".to_vec(), 0);
+        let encoded = VarintCodec::encode(&entry);
+        assert_eq!(encoded.len(), 1 + 1 + 4 + 4 + 3 + 5);
+        assert_eq!(
+            encoded.len() as u64,
+            EntryFormat::Varint.header_size(3, 5) + 3 + 5
+        );
+        assert!(encoded.len() < DefaultCodec::encode(&entry).len());
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let values = [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ];
+        let mut buf = Vec::new();
+        for value in values {
+            let before = buf.len();
+            put_varint(&mut buf, value);
+            assert_eq!((buf.len() - before) as u64, varint_len(value));
+        }
+        let mut reader = BufReaderWithPos::new(Cursor::new(buf)).unwrap();
+        for value in values {
+            assert_eq!(reader.read_varint().unwrap(), Some(value));
+        }
+        assert_eq!(reader.read_varint().unwrap(), None);
+
+        // a varint cut short is incomplete rather than the end of the file
+        let mut reader = BufReaderWithPos::new(Cursor::new(vec![0x80])).unwrap();
+        assert!(reader.read_varint().is_err());
+    }
+
+    #[test]
+    fn decode_truncated_entry() {
+        let entry = LogEntry::new(b"key".to_vec(), b"value".to_vec(), 0);
+        let mut buf = DefaultCodec::encode(&entry);
+        buf.truncate(buf.len() - 1);
+        let mut reader = BufReaderWithPos::new(Cursor::new(buf)).unwrap();
+        assert!(DefaultCodec::decode(&mut reader).is_err());
+    }
+
+    #[test]
+    fn decode_corrupt_padding_length() {
+        let entry = LogEntry::new(b"key".to_vec(), b"value".to_vec(), 0);
+        for format in [EntryFormat::Fixed, EntryFormat::Varint] {
+            // one length would seek back to the start of the padding, the other past the end
+            for len in [u64::MAX - 7, 1 << 40] {
+                let mut buf = Vec::new();
+                format.put_size(&mut buf, PADDING_MARKER);
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.append(&mut format.encode(&entry));
+                let mut reader = BufReaderWithPos::new(Cursor::new(buf)).unwrap();
+                assert!(matches!(
+                    format.decode(&mut reader),
+                    Err(KvStoreErr::UnexceptErr(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn markers_are_distinct() {
+        let markers = [
+            PADDING_MARKER,
+            FOOTER_MARKER,
+            EXPIRY_MARKER,
+            FRONT_CODED_HINT_MARKER,
+        ];
+        for (i, marker) in markers.iter().enumerate() {
+            assert!(!markers[i + 1..].contains(marker));
+        }
+    }
+}
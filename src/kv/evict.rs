@@ -0,0 +1,70 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::options::EvictionPolicy;
+
+/// Bytes of the live keys and the order to evict them in, for a store with `max_live_bytes`
+pub struct Evictor {
+    policy: EvictionPolicy,
+    live_bytes: u64,
+    // the tick of the last write, or use under LRU, of every key and its bytes
+    keys: HashMap<Vec<u8>, (u64, u64)>,
+    // keys by their tick, the first one goes first
+    order: BTreeMap<u64, Vec<u8>>,
+    next_tick: u64,
+}
+
+impl Evictor {
+    pub fn new(policy: EvictionPolicy) -> Self {
+        Evictor {
+            policy,
+            live_bytes: 0,
+            keys: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+
+    /// Record that `key` now takes `bytes` with its value, or is gone with `None`
+    pub fn update(&mut self, key: &[u8], bytes: Option<u64>) {
+        if let Some((tick, old_bytes)) = self.keys.remove(key) {
+            self.order.remove(&tick);
+            self.live_bytes -= old_bytes;
+        }
+        if let Some(bytes) = bytes {
+            let tick = self.tick();
+            self.keys.insert(key.to_vec(), (tick, bytes));
+            self.order.insert(tick, key.to_vec());
+            self.live_bytes += bytes;
+        }
+    }
+
+    /// Record a read of `key`, which only keeps it longer under LRU
+    pub fn touch(&mut self, key: &[u8]) {
+        if self.policy != EvictionPolicy::Lru {
+            return;
+        }
+        let tick = self.tick();
+        if let Some((old_tick, _)) = self.keys.get_mut(key) {
+            let key = self.order.remove(old_tick).unwrap_or_else(|| key.to_vec());
+            *old_tick = tick;
+            self.order.insert(tick, key);
+        }
+    }
+
+    /// The key to evict next, other than `keep`
+    pub fn victim(&self, keep: Option<&[u8]>) -> Option<Vec<u8>> {
+        self.order
+            .values()
+            .find(|key| Some(key.as_slice()) != keep)
+            .cloned()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+}
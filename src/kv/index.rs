@@ -0,0 +1,213 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::RwLock;
+
+use dashmap::DashMap;
+
+use super::options::{EntryFormat, IndexKind};
+
+// few shards, as each one costs a lock and the spare capacity of its map
+const SHARDS: usize = 16;
+
+/// An entry in a log file, whose bytes are live while the index holds it
+pub trait FileEntry: Copy {
+    fn file_id(&self) -> u64;
+    /// Bytes the entry of a key of `key_size` bytes takes in a log file of `format`
+    fn file_bytes(&self, key_size: u64, format: EntryFormat) -> u64;
+    /// Whether the entry has an expiry, after which its key is no longer live
+    fn expires(&self) -> bool {
+        false
+    }
+}
+
+/// Keys to the entries of their latest values, in the map picked by `IndexKind`,
+/// with the live bytes of each log file and the entries with an expiry counted as entries
+/// come and go
+pub struct KeyIndex<V> {
+    map: Map<V>,
+    format: EntryFormat,
+    // signed, as the old entry an insert returns may be subtracted before it is added
+    live_bytes: DashMap<u64, i64>,
+    expiring: AtomicI64,
+}
+
+enum Map<V> {
+    Dash(DashMap<Vec<u8>, V>),
+    Sharded(ShardedMap<V>),
+}
+
+impl<V: FileEntry> KeyIndex<V> {
+    pub fn new(kind: IndexKind, format: EntryFormat) -> Self {
+        let map = match kind {
+            IndexKind::DashMap => Map::Dash(DashMap::new()),
+            IndexKind::ShardedHashMap => Map::Sharded(ShardedMap::new()),
+        };
+        KeyIndex {
+            map,
+            format,
+            live_bytes: DashMap::new(),
+            expiring: AtomicI64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<V> {
+        match &self.map {
+            Map::Dash(map) => map.get(key).map(|entry| *entry),
+            Map::Sharded(map) => map.shard(key).read().unwrap().get(key).copied(),
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert the entry of `key`, return its old one
+    pub fn insert(&self, key: Vec<u8>, value: V) -> Option<V> {
+        let key_size = key.len() as u64;
+        self.count(key_size, &value, 1);
+        let old = match &self.map {
+            Map::Dash(map) => map.insert(key, value),
+            Map::Sharded(map) => map.shard(&key).write().unwrap().insert(key, value),
+        };
+        if let Some(old) = &old {
+            self.count(key_size, old, -1);
+        }
+        old
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.map {
+            Map::Dash(map) => map.len(),
+            Map::Sharded(map) => map
+                .shards
+                .iter()
+                .map(|shard| shard.read().unwrap().len())
+                .sum(),
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Option<V> {
+        let old = match &self.map {
+            Map::Dash(map) => map.remove(key).map(|(_, value)| value),
+            Map::Sharded(map) => map.shard(key).write().unwrap().remove(key),
+        };
+        if let Some(old) = &old {
+            self.count(key.len() as u64, old, -1);
+        }
+        old
+    }
+
+    /// Bytes of the log file `file_id` taken by the entries in the index
+    pub fn live_bytes(&self, file_id: u64) -> u64 {
+        self.live_bytes
+            .get(&file_id)
+            .map_or(0, |bytes| (*bytes).max(0) as u64)
+    }
+
+    /// Whether an entry in the index has an expiry
+    pub fn has_expiring(&self) -> bool {
+        self.expiring.load(Ordering::SeqCst) > 0
+    }
+
+    fn count(&self, key_size: u64, entry: &V, sign: i64) {
+        if entry.expires() {
+            self.expiring.fetch_add(sign, Ordering::SeqCst);
+        }
+        let file_id = entry.file_id();
+        *self.live_bytes.entry(file_id).or_insert(0) +=
+            sign * entry.file_bytes(key_size, self.format) as i64;
+        // files go away with their last entry, keep the counters of the others only
+        self.live_bytes.remove_if(&file_id, |_, bytes| *bytes == 0);
+    }
+
+    /// Rough bytes the keys and their entries take in memory, counting the spare slots of the maps
+    pub fn memory_estimate(&self) -> u64 {
+        let mut keys = 0;
+        let mut key_bytes = 0;
+        self.for_each(|key, _| {
+            keys += 1;
+            key_bytes += key.len();
+        });
+        // both maps keep a control byte per slot and fill at most 7/8 of the slots
+        let slot_bytes = size_of::<Vec<u8>>() + size_of::<V>() + 1;
+        (key_bytes + keys * slot_bytes * 8 / 7) as u64
+    }
+
+    /// Call `f` with every key and its entry, a shard at a time is locked while doing so
+    pub fn for_each(&self, mut f: impl FnMut(&[u8], &V)) {
+        match &self.map {
+            Map::Dash(map) => map.iter().for_each(|entry| f(entry.key(), entry.value())),
+            Map::Sharded(map) => {
+                for shard in &map.shards {
+                    for (key, value) in shard.read().unwrap().iter() {
+                        f(key, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A fixed number of plain hash maps, each behind its own lock
+pub struct ShardedMap<V> {
+    hasher: RandomState,
+    shards: Vec<RwLock<HashMap<Vec<u8>, V>>>,
+}
+
+impl<V> ShardedMap<V> {
+    fn new() -> Self {
+        ShardedMap {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<HashMap<Vec<u8>, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileEntry, KeyIndex};
+    use crate::kv::options::{EntryFormat, IndexKind};
+
+    #[derive(Clone, Copy)]
+    struct Entry {
+        file_id: u64,
+        len: u64,
+    }
+
+    impl FileEntry for Entry {
+        fn file_id(&self) -> u64 {
+            self.file_id
+        }
+
+        fn file_bytes(&self, key_size: u64, _format: EntryFormat) -> u64 {
+            key_size + self.len
+        }
+    }
+
+    #[test]
+    fn count_live_bytes_per_file() {
+        for kind in [IndexKind::DashMap, IndexKind::ShardedHashMap] {
+            let index = KeyIndex::new(kind, EntryFormat::Fixed);
+            let entry = |file_id, len| Entry { file_id, len };
+            index.insert(b"a".to_vec(), entry(0, 10));
+            index.insert(b"bb".to_vec(), entry(0, 20));
+            assert_eq!(index.live_bytes(0), 11 + 22);
+            // an overwrite moves the bytes to the file of the new entry
+            index.insert(b"a".to_vec(), entry(1, 5));
+            assert_eq!(index.live_bytes(0), 22);
+            assert_eq!(index.live_bytes(1), 6);
+            index.remove(b"bb");
+            index.remove(b"missing");
+            assert_eq!(index.live_bytes(0), 0);
+            assert_eq!(index.live_bytes(1), 6);
+            assert_eq!(index.live_bytes.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::store::BlockStore;
+use crate::{KvStoreErr, Result};
+
+/// File in the log directory which lists the renames of a merge being installed
+pub const INSTALL_FILE_NAME: &str = "install";
+
+/// A rename of the log or hint file with the id from one extension to another
+pub type Rename = (u64, String, String);
+
+/// Write the renames of an install into `dir` in one rename, before any of them is done
+///
+/// The file is the bincode of the renames followed by its CRC32, big-endian.
+pub fn write(store: &dyn BlockStore, dir: &Path, renames: &[Rename]) -> Result<()> {
+    let mut bytes = bincode::serialize(renames)?;
+    let crc = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    let temp_path = dir.join(format!("{}.temp", INSTALL_FILE_NAME));
+    if store.exists(&temp_path) {
+        store.remove(&temp_path)?;
+    }
+    let mut file = store.open_append(&temp_path)?;
+    file.write_all(&bytes)?;
+    file.flush()?;
+    file.sync()?;
+    drop(file);
+    store.rename(&temp_path, &dir.join(INSTALL_FILE_NAME))?;
+    store.sync_dir(dir)
+}
+
+/// The renames of the install a crash interrupted in `dir`, `None` if there is none
+///
+/// The file is renamed into place whole, so one which doesn't match its checksum is corrupt.
+pub fn read(store: &dyn BlockStore, dir: &Path) -> Result<Option<Vec<Rename>>> {
+    if !pending(store, dir) {
+        return Ok(None);
+    }
+    let path = dir.join(INSTALL_FILE_NAME);
+    let mut bytes = Vec::new();
+    store.open_read(&path)?.read_to_end(&mut bytes)?;
+    let corrupt = || KvStoreErr::UnexceptErr(format!("corrupt merge install file {:?}", path));
+    if bytes.len() < 4 {
+        return Err(corrupt());
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body).to_be_bytes() != crc {
+        return Err(corrupt());
+    }
+    Ok(Some(bincode::deserialize(body)?))
+}
+
+/// Whether an install in `dir` is listed but not finished
+pub fn pending(store: &dyn BlockStore, dir: &Path) -> bool {
+    store.exists(&dir.join(INSTALL_FILE_NAME))
+}
+
+/// Remove the install file in `dir`, if there is one
+pub fn remove(store: &dyn BlockStore, dir: &Path) -> Result<()> {
+    let path = dir.join(INSTALL_FILE_NAME);
+    if store.exists(&path) {
+        store.remove(&path)?;
+    }
+    Ok(())
+}
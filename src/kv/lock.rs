@@ -0,0 +1,49 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+const DEFAULT_STRIPE_COUNT: usize = 64;
+
+/// A fixed array of mutexes, a key is guarded by the mutex its hash falls into
+///
+/// Every write of a key holds its mutex, so a read-modify-write operation holding it sees no
+/// other write of the key between its read and its write. Operations on different keys
+/// seldom contend, while those on the same key are serialized.
+pub struct LockStripes {
+    locks: Vec<Mutex<()>>,
+}
+
+impl LockStripes {
+    pub fn new(stripe_count: usize) -> Self {
+        LockStripes {
+            locks: (0..stripe_count.max(1)).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.locks[self.stripe(key)].lock().unwrap()
+    }
+
+    /// Lock the stripes of all `keys`, in stripe order so that two callers can't deadlock
+    pub fn lock_all(&self, keys: &[&[u8]]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys.iter().map(|key| self.stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.locks[stripe].lock().unwrap())
+            .collect()
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.locks.len() as u64) as usize
+    }
+}
+
+impl Default for LockStripes {
+    fn default() -> Self {
+        LockStripes::new(DEFAULT_STRIPE_COUNT)
+    }
+}
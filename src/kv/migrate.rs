@@ -0,0 +1,19 @@
+use super::KvsEngine;
+use crate::Result;
+
+/// Copy every live key of `src` to `dst`, return how many keys were copied
+///
+/// The keys are listed first and their values read one by one, so a key removed from `src`
+/// in between is skipped. Keys which `dst` already holds are overwritten, the others are kept.
+/// Keys and values are copied as bytes, a `dst` which only stores strings refuses those
+/// that aren't utf-8.
+pub fn migrate(src: &dyn KvsEngine, dst: &dyn KvsEngine) -> Result<u64> {
+    let mut migrated = 0;
+    for key in src.key_bytes()? {
+        if let Some(value) = src.get_bytes(&key)? {
+            dst.set_bytes(key, value)?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
@@ -1,10 +1,147 @@
+mod audit;
 pub mod bitcask;
+mod bloom;
+mod cache;
+pub mod cancel;
+mod checkpoint;
+pub mod clock;
+mod config;
 mod entry;
-mod sled;
-use super::Result;
+mod evict;
+mod index;
+mod install;
+mod lock;
+pub mod migrate;
+pub mod options;
+pub mod pool;
+pub mod secondary;
+pub mod sled;
+pub mod store;
+pub mod watch;
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use self::watch::{FullSync, KeyEvent};
+use super::{KvStoreErr, Result};
+
+/// The key/value pairs `KvsEngine::scan_prefix` goes through
+pub type ScanPrefix<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
 
 pub trait KvsEngine: Sync + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Like `set`, for keys and values which may not be utf-8,
+    /// engines which only store strings refuse those that aren't
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set(String::from_utf8(key)?, String::from_utf8(value)?)
+    }
+
+    /// Like `get`, for keys and values which may not be utf-8
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.get(String::from_utf8(key.to_vec())?)?;
+        Ok(value.map(String::into_bytes))
+    }
+
+    /// Like `remove`, for keys which may not be utf-8
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.remove(String::from_utf8(key.to_vec())?)
+    }
+
+    /// Like `set`, on behalf of `client`, for engines which record who writes
+    fn set_from(&self, key: String, value: String, _client: Option<SocketAddr>) -> Result<()> {
+        self.set(key, value)
+    }
+
+    /// Like `remove`, on behalf of `client`, for engines which record who writes
+    fn remove_from(&self, key: String, _client: Option<SocketAddr>) -> Result<()> {
+        self.remove(key)
+    }
+
+    /// Start no new background compaction until `resume_compaction`,
+    /// for engines which compact in the background
+    fn pause_compaction(&self) {}
+
+    /// Let background compaction start again after `pause_compaction`
+    fn resume_compaction(&self) {}
+
+    /// Whether `key` exists, engines which can tell without reading the value do so
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Whether each of `keys` exists, in the order of `keys`
+    fn exists_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter().map(|key| self.contains_key(key)).collect()
+    }
+
+    /// The live keys, in no particular order
+    fn keys(&self) -> Result<Vec<String>> {
+        Err(KvStoreErr::UnexceptErr(
+            "the engine doesn't support listing keys".to_owned(),
+        ))
+    }
+
+    /// Like `keys`, for keys which may not be utf-8
+    fn key_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.keys()?.into_iter().map(String::into_bytes).collect())
+    }
+
+    /// Call `f` with every live key, in no particular order, without collecting them first
+    /// where the engine can. Unlike `keys` this needn't be a snapshot.
+    fn for_each_key(&self, f: &mut dyn FnMut(&str)) -> Result<()> {
+        for key in self.keys()? {
+            f(&key);
+        }
+        Ok(())
+    }
+
+    /// The live keys starting with `prefix` with their values, in no particular order,
+    /// an empty prefix matching every key
+    ///
+    /// The values are read as the iterator goes, a key removed meanwhile is skipped.
+    fn scan_prefix<'a>(&'a self, prefix: String) -> Result<ScanPrefix<'a>> {
+        let keys = self.keys()?.into_iter();
+        Ok(Box::new(
+            keys.filter(move |key| key.starts_with(&prefix))
+                .filter_map(|key| match self.get(key.clone()) {
+                    Ok(Some(value)) => Some(Ok((key, value))),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err)),
+                }),
+        ))
+    }
+
+    /// Add `delta` to the integer value of `key` and return the sum, a missing key counts as 0
+    fn increment(&self, _key: String, _delta: i64) -> Result<i64> {
+        Err(KvStoreErr::UnexceptErr(
+            "the engine doesn't support increments".to_owned(),
+        ))
+    }
+
+    /// Receive an event for every later set and remove of the keys starting with `prefix`,
+    /// in the order they happen
+    fn watch(&self, _prefix: String) -> Result<UnboundedReceiver<KeyEvent>> {
+        Err(KvStoreErr::UnexceptErr(
+            "the engine doesn't support watching keys".to_owned(),
+        ))
+    }
+
+    /// Copy up to `limit` live keys with their values consistently, the first ones in key order,
+    /// to look into the store while debugging
+    fn dump(&self, _limit: usize) -> Result<Vec<(String, String)>> {
+        Err(KvStoreErr::UnexceptErr(
+            "the engine doesn't support dumps".to_owned(),
+        ))
+    }
+
+    /// List the live keys consistently, to bootstrap a follower which reads their values
+    /// and then applies the events of the writes after the copy
+    fn full_sync(&self) -> Result<FullSync> {
+        Err(KvStoreErr::UnexceptErr(
+            "the engine doesn't support full syncs".to_owned(),
+        ))
+    }
 }
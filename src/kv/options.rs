@@ -0,0 +1,281 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cancel::CancellationToken;
+use super::clock::{Clock, SystemClock};
+use super::pool::MergePool;
+use super::secondary::ValueExtractor;
+use super::store::{BlockStore, FileStore};
+
+/// What replay does with an entry which is complete but can't be decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Fail to open the store, so that someone can investigate
+    #[default]
+    Fail,
+    /// Log the entry and skip it
+    SkipAndContinue,
+}
+
+/// Which map the index of `BitcaskEngine` keeps its keys in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A `DashMap`, for the most concurrent reads and writes
+    #[default]
+    DashMap,
+    /// A few hash maps behind `RwLock`s, which take less memory per key
+    /// but let fewer threads use the index at once
+    ShardedHashMap,
+}
+
+/// Which keys go first once the store holds more than `BitcaskOptions::max_live_bytes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The key which was least recently set or read
+    #[default]
+    Lru,
+    /// The key which was least recently set
+    Fifo,
+}
+
+/// What `BitcaskOptions::merge_trigger_threshold` counts of the entries which are no longer live
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GarbageAccounting {
+    /// Only their values, a tombstone counts as one byte
+    #[default]
+    ValueBytes,
+    /// Their headers, keys and values, all of which a merge reclaims
+    EntryBytes,
+}
+
+/// Which entry of a key `BitcaskEngine::bulk_load_with_opts` keeps when the input holds
+/// several
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// The last one in the input
+    #[default]
+    KeepLast,
+    /// The first one in the input
+    KeepFirst,
+}
+
+/// How `BitcaskEngine::bulk_load_with_opts` treats its input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulkLoadOpts {
+    /// Fail with `KvStoreErr::UnsortedInput` unless the keys come in order, rather than
+    /// sorting them. Entries of the same key may follow each other either way.
+    pub require_sorted: bool,
+    /// Which entry of a key given more than once is loaded
+    pub duplicates: DuplicateKeys,
+}
+
+/// What a merge does with the merged files it wrote no entry to, which happens when every
+/// entry of the files it merges is dead
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyMergedFiles {
+    /// Remove them, so a merge of nothing but dead entries leaves only the files it didn't merge
+    #[default]
+    Remove,
+    /// Keep one empty sealed file with its empty hint file when the merge wrote no entry at all,
+    /// so a merge always produces a file
+    KeepOne,
+}
+
+/// How the sizes in the log and hint files are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryFormat {
+    /// Big-endian u64s, 24 bytes of header per log entry
+    #[default]
+    Fixed,
+    /// LEB128 varints, which take a single byte for sizes below 128 and so shrink the files
+    /// of small records, down to 10 bytes of header per log entry
+    Varint,
+}
+
+/// When a background thread merges, on top of the merges triggered by writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeSchedule {
+    /// Every this long, counting from open
+    Every(Duration),
+    /// Once a day, this long after midnight UTC
+    DailyAt(Duration),
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+impl MergeSchedule {
+    /// How long from `now` until the next merge
+    pub fn next_delay(&self, now: SystemTime) -> Duration {
+        match *self {
+            MergeSchedule::Every(interval) => interval,
+            MergeSchedule::DailyAt(time) => {
+                let day = Duration::from_secs(SECONDS_PER_DAY);
+                let since_midnight = Duration::from_nanos(
+                    (now.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_nanos()
+                        % day.as_nanos()) as u64,
+                );
+                let time = Duration::from_nanos((time.as_nanos() % day.as_nanos()) as u64);
+                if time > since_midnight {
+                    time - since_midnight
+                } else {
+                    day - since_midnight + time
+                }
+            }
+        }
+    }
+}
+
+const DEFAULT_LOG_FILE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MERGE_TRIGGER_THRESHOLD: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MERGE_RETRIES: u32 = 2;
+const DEFAULT_WRITE_FLUSH_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Options to open a `BitcaskEngine` with
+#[derive(Clone)]
+pub struct BitcaskOptions {
+    /// Max bytes of a log file, the active file is rotated once it would grow beyond this
+    pub log_file_max_bytes: u64,
+    /// Whether rotating the active file syncs the file it seals and the directory the new one
+    /// is created in, so a crash can't lose the tail of a sealed file. Writes between
+    /// rotations are only handed to the file system.
+    pub sync_on_rotation: bool,
+    /// Append a footer with the entry count and a checksum to each log file sealed at
+    /// rotation. `BitcaskEngine::scrub` checks the sealed files against their footers and
+    /// reports those that don't match. Merged files get hint files instead.
+    pub seal_footer: bool,
+    /// Hand writes to the file system once this many bytes are buffered, 0 flushes every
+    /// write. A crash of the process loses what is buffered, which
+    /// `BitcaskEngine::pending_flush_bytes` reports.
+    pub write_flush_bytes: u64,
+    /// Merge is triggered once the useless value bytes grow beyond this
+    pub merge_trigger_threshold: u64,
+    /// How much of an overwritten or removed entry counts towards `merge_trigger_threshold`
+    pub garbage_accounting: GarbageAccounting,
+    /// When the active file rotates, merge right away if the file it sealed is at least
+    /// this fraction garbage, rather than letting garbage pile up until the threshold.
+    /// Finding the garbage of the file goes through the whole index.
+    pub rotation_merge_garbage_ratio: Option<f64>,
+    /// Whether a merge compacts every log file, the active one included, into a single file
+    /// which becomes the active file, when the live data fits in one. Writes wait for such
+    /// a merge, which suits small stores. Merges of larger stores rewrite the sealed files.
+    pub single_file_merge: bool,
+    /// How many threads a merge of the sealed files splits the files between, each compacting
+    /// a range of files into merged files of its own. The keys overwritten in another range are
+    /// dropped, as the index points at their newer entries.
+    pub merge_threads: usize,
+    /// How many times a failed merge is retried, the original files are kept when all attempts fail
+    pub merge_retries: u32,
+    /// What a merge does with the merged files which got no entry
+    pub empty_merged_files: EmptyMergedFiles,
+    /// Whether a merge which rewrote files prunes the readers to the log files left and shrinks
+    /// the reader map, as `BitcaskEngine::prune_readers` does
+    pub prune_readers_on_merge: bool,
+    /// Merge on this schedule in a background thread, which stops once the engine is dropped
+    pub merge_schedule: Option<MergeSchedule>,
+    /// Pool to run merges on, share one between engines to bound how many merge at once.
+    /// By default the merges triggered by writes run on a background thread of the engine,
+    /// and `merge` runs on the calling thread.
+    pub merge_pool: Option<MergePool>,
+    /// Directory for the hint files, defaults to the directory of the log files
+    pub hint_dir: Option<PathBuf>,
+    /// How the log and hint files encode sizes, a store keeps the format it was created with
+    pub entry_format: EntryFormat,
+    /// Values start at a multiple of this many bytes in the log files, 1 disables the padding
+    pub value_alignment: u64,
+    /// Whether merges front-code the keys of the hint files they write, storing each key as the
+    /// size of the prefix it shares with the previous key plus the rest, which shrinks the hint
+    /// files of keys with long common prefixes. The log files keep whole keys for random reads.
+    pub hint_prefix_compression: bool,
+    /// Whether `remove` writes a tombstone for a key which doesn't exist instead of failing,
+    /// so that the delete still reaches whoever follows the log
+    pub always_tombstone_on_remove: bool,
+    /// Whether `open` runs `BitcaskEngine::check_active_file` before returning the engine
+    pub check_active_file_on_open: bool,
+    /// Remove keys once the keys and values of the live keys take more than this many bytes,
+    /// which makes the store a bounded cache. Writes evict other keys until the store fits.
+    pub max_live_bytes: Option<u64>,
+    /// Which keys `max_live_bytes` evicts first
+    pub eviction_policy: EvictionPolicy,
+    /// Map to keep the index of the keys in
+    pub index_kind: IndexKind,
+    /// Fail to open with `KvStoreErr::IndexTooLarge` if the index of the keys in the files
+    /// would take about more than this many bytes of memory, instead of running out of memory
+    pub max_index_bytes: Option<u64>,
+    /// Cache up to this many bytes of the keys and values read from the log files,
+    /// the least recently read go first
+    pub value_cache_bytes: Option<u64>,
+    /// Cache up to this many values read from the log files, the least recently read go
+    /// first. With `value_cache_bytes` too, values go once the cache exceeds either.
+    pub value_cache_entries: Option<usize>,
+    /// Keep a Bloom filter of the keys, sized for this many, so that gets of missing keys
+    /// mostly skip the index. Removed keys stay in the filter until a merge rebuilds it,
+    /// for at least twice the live keys.
+    pub bloom_filter_keys: Option<usize>,
+    /// Index the keys by the term this extracts from their values, for `find_by_value`.
+    /// Every live value is read on open to build it, and it's kept in memory.
+    pub secondary_index: Option<ValueExtractor>,
+    /// What to do with a corrupt entry in a log or hint file
+    pub on_corruption: CorruptionPolicy,
+    /// Backend to keep the log and hint files in
+    pub block_store: Arc<dyn BlockStore>,
+    /// Wall clock time of the engine, which the merge schedule follows
+    pub clock: Arc<dyn Clock>,
+    /// Open a read-only replica of a directory another engine writes to, which picks up
+    /// the new entries of that engine on `BitcaskEngine::refresh`. That engine must have
+    /// opened the directory first, and writes and merges on the replica fail.
+    pub read_only: bool,
+    /// How often a read-only replica refreshes in a background thread,
+    /// which stops once the engine is dropped
+    pub refresh_interval: Option<Duration>,
+    /// Append a line to this file for every set and remove, apart from the log files, as
+    /// `<unix millis> <set|remove> <quoted key> <client address or ->`. Lines are buffered,
+    /// `BitcaskEngine::flush` writes them out.
+    pub audit_log: Option<PathBuf>,
+    /// Once cancelled, the replay in open and merges abort and leave the files as they were
+    pub cancel_token: CancellationToken,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        BitcaskOptions {
+            log_file_max_bytes: DEFAULT_LOG_FILE_MAX_BYTES,
+            sync_on_rotation: false,
+            write_flush_bytes: DEFAULT_WRITE_FLUSH_BYTES,
+            seal_footer: false,
+            merge_trigger_threshold: DEFAULT_MERGE_TRIGGER_THRESHOLD,
+            garbage_accounting: GarbageAccounting::default(),
+            rotation_merge_garbage_ratio: None,
+            single_file_merge: false,
+            merge_threads: 1,
+            merge_retries: DEFAULT_MERGE_RETRIES,
+            empty_merged_files: EmptyMergedFiles::default(),
+            prune_readers_on_merge: true,
+            merge_schedule: None,
+            merge_pool: None,
+            hint_dir: None,
+            entry_format: EntryFormat::default(),
+            value_alignment: 1,
+            hint_prefix_compression: false,
+            always_tombstone_on_remove: false,
+            check_active_file_on_open: false,
+            max_live_bytes: None,
+            eviction_policy: EvictionPolicy::default(),
+            index_kind: IndexKind::default(),
+            max_index_bytes: None,
+            value_cache_bytes: None,
+            value_cache_entries: None,
+            bloom_filter_keys: None,
+            secondary_index: None,
+            on_corruption: CorruptionPolicy::default(),
+            block_store: Arc::new(FileStore),
+            clock: Arc::new(SystemClock),
+            read_only: false,
+            refresh_interval: None,
+            audit_log: None,
+            cancel_token: CancellationToken::new(),
+        }
+    }
+}
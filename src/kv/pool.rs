@@ -0,0 +1,47 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::warn;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads which merges run on
+///
+/// Clones share the same threads, so engines opened with clones of one pool run at most
+/// that many merges at once, while the other merges wait in line.
+/// The threads exit once every clone is dropped.
+#[derive(Clone)]
+pub struct MergePool {
+    sender: Sender<Job>,
+}
+
+impl MergePool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || run_jobs(receiver));
+        }
+        MergePool { sender }
+    }
+
+    /// Run `job` on one of the threads once it is free
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if self.sender.send(Box::new(job)).is_err() {
+            warn!("merge pool has no threads left, the job is dropped");
+        }
+    }
+}
+
+fn run_jobs(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // only hold the lock while waiting, so the other threads can take the next job
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use dashmap::DashMap;
+
+/// Maps a value to the term it is indexed by in a secondary index
+pub type ValueExtractor = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Keys by the term extracted from their values, kept next to the index of `BitcaskEngine`
+pub struct SecondaryIndex {
+    extract: ValueExtractor,
+    // the term of every indexed key, to find its old bucket when its value changes
+    terms: DashMap<String, String>,
+    buckets: DashMap<String, BTreeSet<String>>,
+    // moving a key between buckets touches two shards, so updates are serialized
+    update_lock: Mutex<()>,
+}
+
+impl SecondaryIndex {
+    pub fn new(extract: ValueExtractor) -> Self {
+        SecondaryIndex {
+            extract,
+            terms: DashMap::new(),
+            buckets: DashMap::new(),
+            update_lock: Mutex::new(()),
+        }
+    }
+
+    /// Index `key` by the term of its new value, out of the bucket of its old value
+    pub fn insert(&self, key: &str, value: &str) {
+        let term = (self.extract)(value);
+        let _guard = self.update_lock.lock().unwrap();
+        if let Some(old_term) = self.terms.insert(key.to_owned(), term.clone()) {
+            self.remove_from_bucket(&old_term, key);
+        }
+        self.buckets.entry(term).or_default().insert(key.to_owned());
+    }
+
+    pub fn remove(&self, key: &str) {
+        let _guard = self.update_lock.lock().unwrap();
+        if let Some((_, old_term)) = self.terms.remove(key) {
+            self.remove_from_bucket(&old_term, key);
+        }
+    }
+
+    /// Keys indexed by `term`, sorted
+    pub fn find(&self, term: &str) -> Vec<String> {
+        self.buckets
+            .get(term)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_from_bucket(&self, term: &str, key: &str) {
+        self.buckets.remove_if_mut(term, |_, keys| {
+            keys.remove(key);
+            keys.is_empty()
+        });
+    }
+}
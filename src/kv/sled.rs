@@ -2,14 +2,21 @@ use std::path::PathBuf;
 
 use sled::Db;
 
-use crate::{KvStoreErr, KvsEngine, Result};
+use super::config;
+use super::store::{BlockStore, FileStore};
+use crate::{KvStoreErr, KvsEngine, Result, ScanPrefix};
 
-struct SledEngine {
+/// Engine keeping its keys in a sled database, flushed after every set and remove
+pub struct SledEngine {
     kv: Db,
 }
+
 impl SledEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let path_buf: PathBuf = path.into();
+        // sled must not write into the directory of another engine
+        FileStore.create_dir_all(&path_buf)?;
+        config::check_or_mark_engine(&FileStore, &path_buf, config::SLED_ENGINE, false)?;
         if let Ok(kv) = sled::open(&path_buf) {
             Ok(SledEngine { kv })
         } else {
@@ -32,13 +39,51 @@ impl KvsEngine for SledEngine {
         if let Ok(Some(val)) = self.kv.get(key) {
             return Ok(Some(String::from_utf8(val.to_vec())?));
         }
-        return Ok(None);
+        Ok(None)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        if let None = self.kv.remove(&key)? {
+        if self.kv.remove(&key)?.is_none() {
             return Err(KvStoreErr::KeyNotFound(key));
         }
-        return Ok(());
+        self.kv.flush()?;
+        Ok(())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.kv.contains_key(key)?)
+    }
+
+    fn exists_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| Ok(self.kv.contains_key(key)?))
+            .collect()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.kv
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    /// Walks sled's keys in order as it goes
+    fn for_each_key(&self, f: &mut dyn FnMut(&str)) -> Result<()> {
+        for key in self.kv.iter().keys() {
+            f(&String::from_utf8(key?.to_vec())?);
+        }
+        Ok(())
+    }
+
+    /// Delegated to sled, which walks the keys in order and reads the values as it goes
+    fn scan_prefix<'a>(&'a self, prefix: String) -> Result<ScanPrefix<'a>> {
+        Ok(Box::new(self.kv.scan_prefix(prefix).map(|entry| {
+            let (key, value) = entry?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        })))
     }
 }
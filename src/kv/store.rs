@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{KvStoreErr, Result};
+
+/// A file opened from a `BlockStore`
+pub trait BlockFile: Read + Write + Seek + Send + Sync {
+    /// Current size of the file in bytes
+    fn size(&self) -> Result<u64>;
+    /// Truncate or extend the file to `len` bytes
+    fn set_len(&self, len: u64) -> Result<()>;
+    /// Make what was written to the file durable, for stores which can lose writes on a crash
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Backend which the bitcask engine stores its log and hint files in
+pub trait BlockStore: Send + Sync + 'static {
+    /// Open an existing file for reading
+    fn open_read(&self, path: &Path) -> Result<Box<dyn BlockFile>>;
+    /// Open a file for appending, create it if it doesn't exist
+    fn open_append(&self, path: &Path) -> Result<Box<dyn BlockFile>>;
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+    fn remove(&self, path: &Path) -> Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    /// Paths of all files directly under `dir`
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>>;
+    /// Make the files created in and renamed into `dir` durable
+    fn sync_dir(&self, _dir: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Store backed by the local file system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileStore;
+
+impl BlockFile for File {
+    fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        File::set_len(self, len)?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.sync_data()?;
+        Ok(())
+    }
+}
+
+impl BlockStore for FileStore {
+    fn open_read(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        Ok(Box::new(OpenOptions::new().read(true).open(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .read(true)
+                .open(path)?,
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .flat_map(|dir_entry| -> Result<_> { Ok(dir_entry?.path()) })
+            .filter(|path| path.is_file())
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+type MemoryFileData = Arc<Mutex<Vec<u8>>>;
+
+/// Store keeping all files in memory, clones share the same files
+///
+/// Useful for tests and for deployments which don't need persistence.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    files: Arc<Mutex<HashMap<PathBuf, MemoryFileData>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn open(&self, path: &Path, create: bool) -> Result<MemoryFile> {
+        let mut files = self.files.lock().unwrap();
+        let data = match files.get(path) {
+            Some(data) => data.clone(),
+            None if create => files.entry(path.to_path_buf()).or_default().clone(),
+            None => return Err(not_found(path)),
+        };
+        Ok(MemoryFile {
+            data,
+            pos: 0,
+            append: create,
+        })
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn open_read(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        Ok(Box::new(self.open(path, false)?))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        Ok(Box::new(self.open(path, true)?))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+}
+
+struct MemoryFile {
+    data: MemoryFileData,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        if self.append {
+            self.pos = data.len() as u64;
+        }
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl BlockFile for MemoryFile {
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        self.data.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> KvStoreErr {
+    KvStoreErr::IOErr(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} not found", path),
+    ))
+}
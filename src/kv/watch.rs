@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// A change to a watched key, see `BitcaskEngine::watch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// The key was set to this value
+    Set { key: String, value: String },
+    /// The key was removed
+    Removed { key: String },
+}
+
+impl KeyEvent {
+    pub fn key(&self) -> &str {
+        match self {
+            KeyEvent::Set { key, .. } | KeyEvent::Removed { key } => key,
+        }
+    }
+}
+
+/// The live keys as of one point in the log, and the events of every write after it,
+/// see `KvsEngine::full_sync`
+pub struct FullSync {
+    /// The keys to read the values of, in batches so that the copy needn't fit in memory.
+    /// A value read after the copy was taken may be newer, the events bring it up to date.
+    pub keys: Vec<Vec<u8>>,
+    /// Id of the active file and offset in it the copy was taken at
+    pub position: (u64, u64),
+    pub events: UnboundedReceiver<KeyEvent>,
+}
+
+/// Senders of the events of the keys with each watched prefix
+#[derive(Default)]
+pub struct Watchers {
+    watchers: Mutex<Vec<(String, UnboundedSender<KeyEvent>)>>,
+}
+
+impl Watchers {
+    pub fn subscribe(&self, prefix: String) -> UnboundedReceiver<KeyEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.watchers.lock().unwrap().push((prefix, sender));
+        receiver
+    }
+
+    /// Send the new value of `key`, or `None` once removed, to whoever watches it,
+    /// and forget the watchers which dropped their receivers
+    pub fn notify(&self, key: &str, value: Option<&str>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.is_empty() {
+            return;
+        }
+        let event = match value {
+            Some(value) => KeyEvent::Set {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            },
+            None => KeyEvent::Removed {
+                key: key.to_owned(),
+            },
+        };
+        watchers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
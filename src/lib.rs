@@ -9,10 +9,30 @@ mod io;
 mod kv;
 mod protocol;
 mod server;
+#[cfg(feature = "sync-server")]
+mod sync_server;
 
-pub use client::Client;
+pub use client::{Client, ClientBuilder};
+pub use connection::{Connection, SocketOptions};
 pub use err::{KvStoreErr, Result};
-pub use kv::bitcask::BitcaskEngine;
-pub use kv::KvsEngine;
-pub use protocol::Frame;
-pub use server::Server;
+pub use kv::bitcask::{
+    BitcaskEngine, EngineStats, KeyState, LogFileInfo, MergeEstimate, MergeReport, ScrubReport,
+    ValueSource,
+};
+pub use kv::cancel::CancellationToken;
+pub use kv::clock::{Clock, SystemClock};
+pub use kv::migrate::migrate;
+pub use kv::options::{
+    BitcaskOptions, BulkLoadOpts, CorruptionPolicy, DuplicateKeys, EmptyMergedFiles, EntryFormat,
+    EvictionPolicy, GarbageAccounting, IndexKind, MergeSchedule,
+};
+pub use kv::pool::MergePool;
+pub use kv::secondary::ValueExtractor;
+pub use kv::sled::SledEngine;
+pub use kv::store::{BlockFile, BlockStore, FileStore, MemoryStore};
+pub use kv::watch::{FullSync, KeyEvent};
+pub use kv::{KvsEngine, ScanPrefix};
+pub use protocol::{Frame, COMPRESSION_FEATURE};
+pub use server::{AccessList, Server, ServerBuilder};
+#[cfg(feature = "sync-server")]
+pub use sync_server::SyncServer;
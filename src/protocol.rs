@@ -1,149 +1,454 @@
 use bytes::Buf;
-use std::io::Cursor;
-use tokio::{
-    io::{AsyncWriteExt, BufWriter},
-    net::TcpStream,
-};
+use std::fmt;
+use std::io::{Cursor, Read};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{KvStoreErr, Result};
 
 /// Command frame, to request and respond in c/s
 ///
-/// Frame's format in stream: `%command%`
+/// Frame's format in stream: the code of the frame as one byte, then each of its fields
+/// as its length in 4 bytes big endian followed by its bytes, so keys and values may hold
+/// any character. Numbers are fields of 8 bytes big endian.
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Frame {
     /// Set key value command.
-    /// Frame's format in stream: `%0key#value%`
+    /// Code 0, fields: key, value
     Set(String, String),
     /// Get key command.
-    /// Frame's format in stream: `%1key%`
+    /// Code 1, fields: key
     Get(String),
     /// Remove key command.
-    /// Frame's format in stream: `%2key%`
+    /// Code 2, fields: key
     Remove(String),
     /// Respond to client with value.
-    /// Frame's format in stream: `%3value%`
+    /// Code 3, fields: value
     Value(String),
     /// Respond to client with error message.
-    /// Frame's format in stream: `%4error_msg%`
+    /// Code 4, fields: error_msg
     Error(String),
-    /// Respond to client with null.
-    /// Frame's format in stream: `%5%`
+    /// Respond to client with null, means the key is not found.
+    /// Code 5, no fields
     Null,
+    /// Acknowledge a successful set or remove.
+    /// Code 6, no fields
+    Ok,
+    /// Offer transport features to the server, or accept some of them in its response,
+    /// as a comma separated list.
+    /// Code 7, fields: features
+    Hello(String),
+    /// Another frame compressed with zstd.
+    /// Code 8, fields: compressed frame
+    Compressed(Vec<u8>),
+    /// Ask the server to start no new background compaction until resumed.
+    /// Code 9, no fields
+    PauseCompaction,
+    /// Ask the server to resume background compaction.
+    /// Code 10, no fields
+    ResumeCompaction,
+    /// Watch the keys starting with a prefix. The server acknowledges with `Ok` and then sends
+    /// a `Set` or `Remove` for every change to the keys, until the connection closes.
+    /// Code 11, fields: prefix
+    Watch(String),
+    /// Ask which of the keys exist, answered with a `Bitmap`.
+    /// Code 12, fields: the keys, each as its length in 4 bytes big endian followed by its bytes
+    ExistsMany(Vec<String>),
+    /// Respond to `ExistsMany` with one bit per key in request order, set if the key exists,
+    /// starting from the lowest bit of the first byte.
+    /// Code 13, fields: bitmap
+    Bitmap(Vec<u8>),
+    /// Add a delta to the integer value of a key, answered with a `Value` of the sum.
+    /// Code 14, fields: key, delta
+    Increment(String, i64),
+    /// Another frame sent with an id, which the server answers only once: a retry with an id
+    /// it still remembers gets the first response again rather than being applied twice.
+    /// Ids are kept apart per client address, one sent again with another frame gets an `Error`.
+    /// Code 15, fields: id, frame
+    Request(u64, Box<Frame>),
+    /// Ask for all live keys to bootstrap a follower. The server sends a `Set` for every key,
+    /// then a `Position`, and then a `Set` or `Remove` for every later write like for `Watch`.
+    /// Code 16, no fields
+    FullSync,
+    /// End a `FullSync` copy with the log file id and offset it was taken at.
+    /// Code 17, fields: file_id, offset
+    Position(u64, u64),
+    /// Ask whether a key exists without reading its value,
+    /// answered with `Ok` if it does and `Null` if it doesn't.
+    /// Code 18, fields: key
+    ContainsKey(String),
+    /// Another frame sent with a trace id picked by the client, which both sides log
+    /// along with the frame so that one request can be followed through both logs.
+    /// Code 19, fields: trace id, frame
+    Traced(u64, Box<Frame>),
+    /// Ask for up to a limit of live keys with their values, to look into a live server.
+    /// The server sends a `Set` for every key, the first ones in key order from one
+    /// consistent copy, and then an `Ok`. It may send fewer keys than asked for.
+    /// Code 20, fields: limit
+    Dump(u64),
+    /// Ask for every live key. The server sends a `Value` of every key as it walks them while
+    /// writes go on, and then an `Ok`.
+    /// Code 21, no fields
+    Keys,
+}
+
+/// Transport feature to compress frames with zstd, negotiated with `Frame::Hello`
+pub const COMPRESSION_FEATURE: &str = "zstd";
+
+// bytes of the length of a field
+const FIELD_LENGTH_BYTES: usize = 4;
+
+// characters of a key or value shown by `Display` before the rest is cut off
+const DISPLAY_MAX_CHARS: usize = 32;
+// keys of an `ExistsMany` shown by `Display`
+const DISPLAY_MAX_KEYS: usize = 4;
+
+/// Quote the text, cut off after `DISPLAY_MAX_CHARS` characters
+fn display_text(text: &str) -> String {
+    let mut chars = text.chars();
+    let shown: String = chars.by_ref().take(DISPLAY_MAX_CHARS).collect();
+    if chars.next().is_some() {
+        format!("{:?}...", shown)
+    } else {
+        format!("{:?}", shown)
+    }
+}
+
+/// One line summary of the frame for logs, long keys and values are cut off
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Set(key, value) => {
+                write!(f, "set {} {}", display_text(key), display_text(value))
+            }
+            Frame::Get(key) => write!(f, "get {}", display_text(key)),
+            Frame::Remove(key) => write!(f, "remove {}", display_text(key)),
+            Frame::Value(value) => write!(f, "value {}", display_text(value)),
+            Frame::Error(msg) => write!(f, "error {}", display_text(msg)),
+            Frame::Null => write!(f, "null"),
+            Frame::Ok => write!(f, "ok"),
+            Frame::Hello(features) => write!(f, "hello {}", display_text(features)),
+            Frame::Compressed(bytes) => write!(f, "compressed {} bytes", bytes.len()),
+            Frame::PauseCompaction => write!(f, "pause compaction"),
+            Frame::ResumeCompaction => write!(f, "resume compaction"),
+            Frame::Watch(prefix) => write!(f, "watch {}", display_text(prefix)),
+            Frame::ExistsMany(keys) => {
+                write!(f, "exists {} keys [", keys.len())?;
+                for (i, key) in keys.iter().take(DISPLAY_MAX_KEYS).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", display_text(key))?;
+                }
+                if keys.len() > DISPLAY_MAX_KEYS {
+                    write!(f, ", ...")?;
+                }
+                write!(f, "]")
+            }
+            Frame::Bitmap(bitmap) => write!(f, "bitmap {} bytes", bitmap.len()),
+            Frame::Increment(key, delta) => {
+                write!(f, "increment {} by {}", display_text(key), delta)
+            }
+            Frame::Request(id, frame) => write!(f, "request {}: {}", id, frame),
+            Frame::FullSync => write!(f, "full sync"),
+            Frame::Position(file_id, offset) => {
+                write!(f, "position {} at {}", file_id, offset)
+            }
+            Frame::ContainsKey(key) => write!(f, "contains {}", display_text(key)),
+            Frame::Traced(trace_id, frame) => write!(f, "trace {:016x}: {}", trace_id, frame),
+            Frame::Dump(limit) => write!(f, "dump {} keys", limit),
+            Frame::Keys => write!(f, "keys"),
+        }
+    }
 }
 
 impl Frame {
-    pub async fn write(&self, writer: &mut BufWriter<TcpStream>) -> Result<()> {
-        // write start separtor %
-        writer.write_u8(b'%').await?;
+    /// Compress this frame, unless compressing doesn't make it smaller
+    pub async fn compress(self) -> Result<Frame> {
+        if let Frame::Compressed(_) = self {
+            return Ok(self);
+        }
+        let raw = self.to_bytes();
+        let compressed = zstd::encode_all(&raw[..], 0)?;
+        if 1 + FIELD_LENGTH_BYTES + compressed.len() < raw.len() {
+            Ok(Frame::Compressed(compressed))
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Decompress a `Frame::Compressed`, other frames are returned as they are
+    ///
+    /// Fails with `KvStoreErr::FrameTooLarge` once the frame decompresses to more than
+    /// `max_frame_bytes`, so a small compressed frame can't make the reader allocate
+    /// what a plain frame couldn't.
+    pub fn decompress(self, max_frame_bytes: usize) -> Result<Frame> {
         match self {
-            Self::Set(key, value) => {
-                // write code
-                writer.write_u8(0).await?;
+            Frame::Compressed(compressed) => {
+                let mut raw = Vec::new();
+                zstd::stream::read::Decoder::new(&compressed[..])?
+                    .take(max_frame_bytes as u64 + 1)
+                    .read_to_end(&mut raw)?;
+                if raw.len() > max_frame_bytes {
+                    return Err(KvStoreErr::FrameTooLarge(max_frame_bytes));
+                }
+                let mut buf = Cursor::new(&raw[..]);
+                Frame::check(&mut buf)?;
+                buf.set_position(0);
+                match Frame::parse(&mut buf)? {
+                    Frame::Compressed(_) => Err(KvStoreErr::UnexceptErr(
+                        "compressed frame nested in a compressed frame".to_owned(),
+                    )),
+                    frame => Ok(frame),
+                }
+            }
+            frame => Ok(frame),
+        }
+    }
 
-                // write key
-                writer.write(key.as_bytes()).await?;
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
 
-                // write #
-                writer.write_u8(b'#').await?;
+    /// The bytes of this frame in a stream, for writers which aren't async
+    ///
+    /// A field can't be longer than `u32::MAX` bytes, far beyond what a connection reads.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Self::Set(key, value) => {
+                // write code
+                buf.push(0);
 
-                // write value
-                writer.write(value.as_bytes()).await?;
+                // write key and value
+                put_field(&mut buf, key.as_bytes());
+                put_field(&mut buf, value.as_bytes());
             }
             Self::Get(key) => {
                 // write code
-                writer.write_u8(1).await?;
+                buf.push(1);
 
                 // write key
-                writer.write(key.as_bytes()).await?;
+                put_field(&mut buf, key.as_bytes());
             }
             Self::Remove(key) => {
                 // write code
-                writer.write_u8(2).await?;
+                buf.push(2);
 
                 // write key
-                writer.write(key.as_bytes()).await?;
+                put_field(&mut buf, key.as_bytes());
             }
             Self::Value(value) => {
                 // write code
-                writer.write_u8(3).await?;
+                buf.push(3);
 
                 // write value
-                writer.write(value.as_bytes()).await?;
+                put_field(&mut buf, value.as_bytes());
             }
             Self::Error(msg) => {
                 // write code
-                writer.write_u8(4).await?;
+                buf.push(4);
 
                 // write value
-                writer.write(msg.as_bytes()).await?;
+                put_field(&mut buf, msg.as_bytes());
             }
             Self::Null => {
                 // write code
-                writer.write_u8(5).await?;
+                buf.push(5);
+            }
+            Self::Ok => {
+                // write code
+                buf.push(6);
+            }
+            Self::Hello(features) => {
+                // write code
+                buf.push(7);
+
+                // write features
+                put_field(&mut buf, features.as_bytes());
+            }
+            Self::Compressed(compressed) => {
+                // write code
+                buf.push(8);
+
+                // write compressed frame
+                put_field(&mut buf, compressed);
+            }
+            Self::PauseCompaction => {
+                // write code
+                buf.push(9);
+            }
+            Self::ResumeCompaction => {
+                // write code
+                buf.push(10);
+            }
+            Self::Watch(prefix) => {
+                // write code
+                buf.push(11);
+
+                // write prefix
+                put_field(&mut buf, prefix.as_bytes());
+            }
+            Self::ExistsMany(keys) => {
+                // write code
+                buf.push(12);
+
+                // write keys
+                let mut field = Vec::new();
+                for key in keys {
+                    put_field(&mut field, key.as_bytes());
+                }
+                put_field(&mut buf, &field);
+            }
+            Self::Bitmap(bitmap) => {
+                // write code
+                buf.push(13);
+
+                // write bitmap
+                put_field(&mut buf, bitmap);
+            }
+            Self::Increment(key, delta) => {
+                // write code
+                buf.push(14);
+
+                // write key and delta
+                put_field(&mut buf, key.as_bytes());
+                put_field(&mut buf, &delta.to_be_bytes());
+            }
+            Self::Request(id, frame) => {
+                // write code
+                buf.push(15);
+
+                // write id and frame
+                put_field(&mut buf, &id.to_be_bytes());
+                put_field(&mut buf, &frame.to_bytes());
+            }
+            Self::FullSync => {
+                // write code
+                buf.push(16);
+            }
+            Self::Position(file_id, offset) => {
+                // write code
+                buf.push(17);
+
+                // write file id and offset
+                put_field(&mut buf, &file_id.to_be_bytes());
+                put_field(&mut buf, &offset.to_be_bytes());
+            }
+            Self::ContainsKey(key) => {
+                // write code
+                buf.push(18);
+
+                // write key
+                put_field(&mut buf, key.as_bytes());
+            }
+            Self::Traced(trace_id, frame) => {
+                // write code
+                buf.push(19);
+
+                // write trace id and frame
+                put_field(&mut buf, &trace_id.to_be_bytes());
+                put_field(&mut buf, &frame.to_bytes());
+            }
+            Self::Dump(limit) => {
+                // write code
+                buf.push(20);
+
+                // write limit
+                put_field(&mut buf, &limit.to_be_bytes());
+            }
+            Self::Keys => {
+                // write code
+                buf.push(21);
             }
         }
-        // write end separtor %
-        writer.write_u8(b'%').await?;
-        Ok(())
+        buf
     }
 
     pub fn parse(buf: &mut Cursor<&[u8]>) -> Result<Frame> {
-        // get start separator
-        let _ = get_u8(buf)?;
         let code: u8 = get_u8(buf)?;
         match code {
             0 => {
-                let key_buf = get_until_target_char(buf, b'#').unwrap();
-                let key = String::from_utf8(key_buf.to_vec())?;
-                let value_buf = get_until_target_char(buf, b'%').unwrap();
-                let value = String::from_utf8(value_buf.to_vec())?;
+                let key = get_text(buf)?;
+                let value = get_text(buf)?;
                 Ok(Self::Set(key, value))
             }
-            1 => {
-                let key_buf = get_until_target_char(buf, b'%').unwrap();
-                let key = String::from_utf8(key_buf.to_vec())?;
-                Ok(Self::Get(key))
+            1 => Ok(Self::Get(get_text(buf)?)),
+            2 => Ok(Self::Remove(get_text(buf)?)),
+            3 => Ok(Self::Value(get_text(buf)?)),
+            4 => Ok(Self::Error(get_text(buf)?)),
+            5 => Ok(Self::Null),
+            6 => Ok(Self::Ok),
+            7 => Ok(Self::Hello(get_text(buf)?)),
+            8 => Ok(Self::Compressed(get_field(buf)?.to_vec())),
+            9 => Ok(Self::PauseCompaction),
+            10 => Ok(Self::ResumeCompaction),
+            11 => Ok(Self::Watch(get_text(buf)?)),
+            12 => {
+                let mut field = Cursor::new(get_field(buf)?);
+                let mut keys = Vec::new();
+                while field.has_remaining() {
+                    // the frame is complete, so a key cut off is malformed
+                    let key = get_field(&mut field).map_err(|_| {
+                        KvStoreErr::UnexceptErr("truncated key in exists frame".to_owned())
+                    })?;
+                    keys.push(String::from_utf8(key.to_vec())?);
+                }
+                Ok(Self::ExistsMany(keys))
             }
-            2 => {
-                let key_buf = get_until_target_char(buf, b'%').unwrap();
-                let key = String::from_utf8(key_buf.to_vec())?;
-                Ok(Self::Remove(key))
+            13 => Ok(Self::Bitmap(get_field(buf)?.to_vec())),
+            14 => {
+                let key = get_text(buf)?;
+                let delta = get_number(buf)? as i64;
+                Ok(Self::Increment(key, delta))
             }
-            3 => {
-                let key_buf = get_until_target_char(buf, b'%').unwrap();
-                let key = String::from_utf8(key_buf.to_vec())?;
-                Ok(Self::Value(key))
+            15 => {
+                let id = get_number(buf)?;
+                let inner = get_inner_frame(buf, &[15, 19], "request")?;
+                Ok(Self::Request(id, Box::new(inner)))
             }
-            4 => {
-                let msg_buf = get_until_target_char(buf, b'%').unwrap();
-                let msg = String::from_utf8(msg_buf.to_vec())?;
-                Ok(Self::Error(msg))
+            16 => Ok(Self::FullSync),
+            17 => {
+                let file_id = get_number(buf)?;
+                let offset = get_number(buf)?;
+                Ok(Self::Position(file_id, offset))
             }
-            5 => {
-                let _ = get_until_target_char(buf, b'%').unwrap();
-                Ok(Self::Null)
+            18 => Ok(Self::ContainsKey(get_text(buf)?)),
+            19 => {
+                let trace_id = get_number(buf)?;
+                // a traced request may carry an id too
+                let inner = get_inner_frame(buf, &[19], "traced")?;
+                Ok(Self::Traced(trace_id, Box::new(inner)))
             }
+            20 => Ok(Self::Dump(get_number(buf)?)),
+            21 => Ok(Self::Keys),
             _ => Err(KvStoreErr::UnexceptErr(
                 "server receive unkown frame".to_owned(),
             )),
         }
     }
 
+    /// Move past the next frame, `KvStoreErr::IncompleteErr` if the buffer ends before it does
     pub fn check(buf: &mut Cursor<&[u8]>) -> Result<()> {
-        // get start separator
-        let start_separtor = get_u8(buf)?;
-        if start_separtor != b'%' {
-            return Err(KvStoreErr::UnexceptErr(
-                "server receive wrong format frame".to_owned(),
-            ));
-        }
-        // get end separtor
-        if let Some(_) = get_until_target_char(buf, b'%') {
-            return Ok(());
+        let code = get_u8(buf)?;
+        let fields = field_count(code).ok_or_else(|| {
+            KvStoreErr::UnexceptErr("server receive wrong format frame".to_owned())
+        })?;
+        for _ in 0..fields {
+            get_field(buf)?;
         }
-        return Err(KvStoreErr::IncompleteErr);
+        Ok(())
+    }
+}
+
+/// How many fields the frames with `code` have, `None` for an unknown code
+fn field_count(code: u8) -> Option<usize> {
+    match code {
+        5 | 6 | 9 | 10 | 16 | 21 => Some(0),
+        1 | 2 | 3 | 4 | 7 | 8 | 11 | 12 | 13 | 18 | 20 => Some(1),
+        0 | 14 | 15 | 17 | 19 => Some(2),
+        _ => None,
     }
 }
 
@@ -155,14 +460,80 @@ fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8> {
     Ok(src.get_u8())
 }
 
-fn get_until_target_char<'a>(buf: &mut Cursor<&'a [u8]>, char: u8) -> Option<&'a [u8]> {
+fn put_field(buf: &mut Vec<u8>, field: &[u8]) {
+    let len = u32::try_from(field.len()).expect("frame field longer than u32::MAX bytes");
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+/// The next field of the frame
+fn get_field<'a>(buf: &mut Cursor<&'a [u8]>) -> Result<&'a [u8]> {
+    if buf.remaining() < FIELD_LENGTH_BYTES {
+        return Err(KvStoreErr::IncompleteErr);
+    }
+    let len = buf.get_u32() as usize;
+    if buf.remaining() < len {
+        return Err(KvStoreErr::IncompleteErr);
+    }
     let start = buf.position() as usize;
-    let end = buf.get_ref().len();
-    for index in start..end {
-        if buf.get_ref()[index] == char {
-            buf.set_position((index + 1) as u64);
-            return Some(&buf.get_ref()[start..index]);
-        }
+    buf.advance(len);
+    Ok(&buf.get_ref()[start..start + len])
+}
+
+/// The next field of the frame, which must be utf-8
+fn get_text(buf: &mut Cursor<&[u8]>) -> Result<String> {
+    Ok(String::from_utf8(get_field(buf)?.to_vec())?)
+}
+
+/// The frame carried by the next field of a `name` frame, which may not be one of `refused`
+fn get_inner_frame(buf: &mut Cursor<&[u8]>, refused: &[u8], name: &str) -> Result<Frame> {
+    let body = get_field(buf)?;
+    // refused before parsing, so that nesting can't recurse deeply
+    if body.first().is_some_and(|code| refused.contains(code)) {
+        return Err(KvStoreErr::UnexceptErr(format!(
+            "frame {} nested in a {} frame",
+            body[0], name
+        )));
+    }
+    let mut inner = Cursor::new(body);
+    Frame::check(&mut inner)
+        .map_err(|_| KvStoreErr::UnexceptErr(format!("truncated frame in {} frame", name)))?;
+    if inner.position() as usize != body.len() {
+        return Err(KvStoreErr::UnexceptErr(format!(
+            "trailing bytes in {} frame",
+            name
+        )));
+    }
+    inner.set_position(0);
+    Frame::parse(&mut inner)
+}
+
+/// The next field of the frame, which must be a number
+fn get_number(buf: &mut Cursor<&[u8]>) -> Result<u64> {
+    let field = get_field(buf)?;
+    let bytes: [u8; 8] = field
+        .try_into()
+        .map_err(|_| KvStoreErr::UnexceptErr(format!("number field of {} bytes", field.len())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Pack one bit per flag into the bytes of a `Frame::Bitmap`
+pub fn to_bitmap(flags: &[bool]) -> Vec<u8> {
+    let mut bitmap = vec![0; flags.len().div_ceil(8)];
+    for (i, _) in flags.iter().enumerate().filter(|(_, flag)| **flag) {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+    bitmap
+}
+
+/// The first `len` flags of the bytes of a `Frame::Bitmap`, `None` if it holds fewer bits
+pub fn from_bitmap(bitmap: &[u8], len: usize) -> Option<Vec<bool>> {
+    if bitmap.len() < len.div_ceil(8) {
+        return None;
     }
-    None
+    Some(
+        (0..len)
+            .map(|i| bitmap[i / 8] & (1 << (i % 8)) != 0)
+            .collect(),
+    )
 }
@@ -1,48 +1,295 @@
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log::{error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::OnceCell;
 
-use crate::{connection::Connection, Frame, KvStoreErr, KvsEngine, Result};
+use crate::{
+    connection::{Connection, SocketOptions},
+    protocol::to_bitmap,
+    Frame, KeyEvent, KvStoreErr, KvsEngine, Result, COMPRESSION_FEATURE,
+};
+
+// how long a refused peer gets to read the error before its connection is dropped
+const REFUSE_LINGER: Duration = Duration::from_secs(1);
+// how many ids of `Frame::Request`s a server remembers the responses to by default,
+// and for how long
+const DEFAULT_MAX_REQUEST_IDS: usize = 10_000;
+const DEFAULT_REQUEST_ID_AGE: Duration = Duration::from_secs(10 * 60);
+// most keys a dump sends, however many the client asks for
+const MAX_DUMP_KEYS: u64 = 100_000;
+// keys a full sync reads the values of at a time
+const FULL_SYNC_BATCH_KEYS: usize = 1000;
+// keys a key listing sends at a time
+const LIST_BATCH_KEYS: usize = 1000;
+
+/// Which peers a server serves, by their IP address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessList {
+    /// Only these addresses
+    Allow(Vec<IpAddr>),
+    /// Every address but these
+    Deny(Vec<IpAddr>),
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // an IPv4 peer of a dual stack socket shows up as an IPv4-mapped IPv6 address
+        let ip = ip.to_canonical();
+        match self {
+            AccessList::Allow(ips) => ips.contains(&ip),
+            AccessList::Deny(ips) => !ips.contains(&ip),
+        }
+    }
+}
+
+/// Responses to the requests sent with an id, shared by all connections of a server so that
+/// a request retried on a new connection isn't applied again
+///
+/// Ids are picked by the clients, so each client address has ids of its own, and a request
+/// whose id was sent before with another request is refused rather than answered with the
+/// response to that one. The oldest ids are forgotten once there are more than `max_ids`,
+/// or once they are older than `max_age`. A retry after that is applied again.
+struct RequestIds {
+    max_ids: usize,
+    max_age: Duration,
+    inner: Mutex<RequestIdsInner>,
+}
+
+// a request id of a client
+type RequestKey = (Option<IpAddr>, u64);
+
+struct RequestIdsInner {
+    // the hash of the first request with the id, and its response which the others wait for
+    responses: HashMap<RequestKey, (u64, Arc<OnceCell<Frame>>)>,
+    // ids by the time they were first seen, the oldest first
+    seen: VecDeque<(Instant, RequestKey)>,
+}
+
+impl RequestIds {
+    fn new(max_ids: usize, max_age: Duration) -> Self {
+        RequestIds {
+            max_ids,
+            max_age,
+            inner: Mutex::new(RequestIdsInner {
+                responses: HashMap::new(),
+                seen: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// The response to `request` sent by `client` with `id`, left empty for the first request
+    /// to fill, `None` if the id was sent before with another request
+    fn response(
+        &self,
+        client: Option<IpAddr>,
+        id: u64,
+        request: &Frame,
+    ) -> Option<Arc<OnceCell<Frame>>> {
+        let key = (client, id);
+        let mut hasher = DefaultHasher::new();
+        request.to_bytes().hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        while let Some(&(seen, old_key)) = inner.seen.front() {
+            if now.duration_since(seen) < self.max_age {
+                break;
+            }
+            inner.seen.pop_front();
+            inner.responses.remove(&old_key);
+        }
+        if let Some((first_hash, response)) = inner.responses.get(&key) {
+            return (*first_hash == hash).then(|| response.clone());
+        }
+        let response = Arc::new(OnceCell::new());
+        if self.max_ids > 0 {
+            if inner.seen.len() == self.max_ids {
+                if let Some((_, old_key)) = inner.seen.pop_front() {
+                    inner.responses.remove(&old_key);
+                }
+            }
+            inner.seen.push_back((now, key));
+            inner.responses.insert(key, (hash, response.clone()));
+        }
+        Some(response)
+    }
+}
 
 pub struct Server<D: KvsEngine> {
     tcp: TcpListener,
     kv: Arc<D>,
+    command_timeout: Option<Duration>,
+    socket_options: SocketOptions,
+    access_list: Option<AccessList>,
+    request_ids: Arc<RequestIds>,
 }
 
-impl<D: KvsEngine> Server<D> {
-    pub async fn start(tcp: TcpListener, kv: Arc<D>) -> Result<Self> {
-        let mut server = Server { tcp, kv };
+/// Builder to tune a `Server` before starting it
+///
+/// No command timeout and no access list are set by default, and the responses to requests
+/// sent with an id are remembered for 10000 ids for 10 minutes.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    command_timeout: Option<Duration>,
+    socket_options: SocketOptions,
+    access_list: Option<AccessList>,
+    max_request_ids: usize,
+    request_id_age: Duration,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            command_timeout: None,
+            socket_options: SocketOptions::default(),
+            access_list: None,
+            max_request_ids: DEFAULT_MAX_REQUEST_IDS,
+            request_id_age: DEFAULT_REQUEST_ID_AGE,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer an engine operation which takes longer than this with a `Frame::Error`,
+    /// so a stuck engine doesn't leave clients hanging
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Options of the accepted sockets, instead of the defaults
+    pub fn socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Answer connections from peers `access_list` doesn't permit with a `Frame::Error`
+    /// and close them before anything they send is handled
+    pub fn access_list(mut self, access_list: AccessList) -> Self {
+        self.access_list = Some(access_list);
+        self
+    }
+
+    /// Remember the responses to requests sent with an id for up to `max_ids` ids
+    /// and `max_age` each
+    pub fn request_ids(mut self, max_ids: usize, max_age: Duration) -> Self {
+        self.max_request_ids = max_ids;
+        self.request_id_age = max_age;
+        self
+    }
+
+    /// Serve `kv` to the connections accepted on `tcp`, which only returns on an error
+    pub async fn start<D: KvsEngine>(self, tcp: TcpListener, kv: Arc<D>) -> Result<Server<D>> {
+        let mut server = Server {
+            tcp,
+            kv,
+            command_timeout: self.command_timeout,
+            socket_options: self.socket_options,
+            access_list: self.access_list,
+            request_ids: Arc::new(RequestIds::new(self.max_request_ids, self.request_id_age)),
+        };
         server.run().await?;
         Ok(server)
     }
+}
+
+impl<D: KvsEngine> Server<D> {
+    pub async fn start(tcp: TcpListener, kv: Arc<D>) -> Result<Self> {
+        ServerBuilder::new().start(tcp, kv).await
+    }
 
     pub async fn run(&mut self) -> Result<()> {
         info!("server start to receive connection from client");
         // receive connection
-        while let (socket, _) = self.tcp.accept().await? {
+        loop {
+            let (socket, peer) = self.tcp.accept().await?;
+            if let Some(access_list) = &self.access_list {
+                if !access_list.permits(peer.ip()) {
+                    warn!("refuse the connection from {}", peer);
+                    tokio::spawn(refuse(socket, peer));
+                    continue;
+                }
+            }
             info!("server receive a connection from: {:?}", socket);
+            if let Err(err) = self.socket_options.apply(&socket) {
+                warn!("failed to set the socket options: {:?}", err);
+            }
             let mut handler = Handler::new(socket, self.kv.clone());
+            handler.command_timeout = self.command_timeout;
+            handler.request_ids = self.request_ids.clone();
             tokio::spawn(async move {
                 if let Err(err) = handler.handle().await {
                     error!("handler handle error: {:?}", err);
                 }
             });
         }
-        Ok(())
     }
 }
 
+/// A `Set` of each of `keys` which is still live, a key or value the frames can't carry fails
+fn read_batch<D: KvsEngine>(kv: &D, keys: Vec<Vec<u8>>) -> Result<Vec<Frame>> {
+    let mut frames = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(value) = kv.get_bytes(&key)? {
+            frames.push(Frame::Set(
+                String::from_utf8(key)?,
+                String::from_utf8(value)?,
+            ));
+        }
+    }
+    Ok(frames)
+}
+
+/// Tell a peer it isn't allowed and close its connection
+async fn refuse(socket: TcpStream, peer: SocketAddr) {
+    let mut stream = BufWriter::new(socket);
+    let frame = Frame::Error(format!("connections from {} are not allowed", peer.ip()));
+    if frame.write(&mut stream).await.is_err() || stream.flush().await.is_err() {
+        return;
+    }
+    let _ = stream.get_mut().shutdown().await;
+    // drop what the peer sent meanwhile, closing with it unread would reset the connection
+    // and could lose the error on the way
+    let _ = tokio::time::timeout(REFUSE_LINGER, async {
+        let mut buf = [0; 1024];
+        while let Ok(1..) = stream.read(&mut buf).await {}
+    })
+    .await;
+}
+
 pub struct Handler<D: KvsEngine> {
     conn: Connection,
     kv: Arc<D>,
+    command_timeout: Option<Duration>,
+    // the client, which the engine gets to know who writes
+    peer: Option<SocketAddr>,
+    request_ids: Arc<RequestIds>,
 }
 
 impl<D: KvsEngine> Handler<D> {
     pub fn new(socket: TcpStream, kv: Arc<D>) -> Self {
         Handler {
+            peer: socket.peer_addr().ok(),
             conn: Connection::new(socket),
             kv,
+            command_timeout: None,
+            request_ids: Arc::new(RequestIds::new(
+                DEFAULT_MAX_REQUEST_IDS,
+                DEFAULT_REQUEST_ID_AGE,
+            )),
         }
     }
 
@@ -50,33 +297,123 @@ impl<D: KvsEngine> Handler<D> {
         // keep reading frame from socket, and write response to socket
         info!("handler start to handler requests from client");
         loop {
-            if let Some(frame) = self.conn.read_frame().await? {
+            match self.conn.read_frame().await? {
                 // receive a frame
-                self.deal(frame).await?;
+                Some(frame) => self.deal(frame).await?,
+                None => {
+                    info!("client closed the connection");
+                    return Ok(());
+                }
             }
         }
     }
 
     pub async fn deal(&mut self, frame: Frame) -> Result<()> {
-        info!("handler read a frame: {:?} from socket", frame);
+        // a traced frame is dealt with like the frame it carries, logged with its trace id
+        let (trace, frame) = match frame {
+            Frame::Traced(trace_id, frame) => (format!("[trace {:016x}] ", trace_id), *frame),
+            frame => (String::new(), frame),
+        };
+        info!("{}handler read a frame: {} from socket", trace, frame);
         let resp = match frame {
+            Frame::Hello(features) => {
+                // accept the features we support, the response itself isn't compressed yet
+                let compression = features
+                    .split(',')
+                    .any(|feature| feature == COMPRESSION_FEATURE);
+                let accepted = if compression { COMPRESSION_FEATURE } else { "" };
+                self.conn
+                    .write_frame(Frame::Hello(accepted.to_owned()))
+                    .await?;
+                self.conn.set_compression(compression);
+                return Ok(());
+            }
+            Frame::Watch(prefix) => return self.watch(prefix).await,
+            Frame::FullSync => return self.full_sync().await,
+            Frame::Dump(limit) => return self.dump(limit).await,
+            Frame::Keys => return self.keys().await,
+            // a retry waits for the first request with the id and gets its response
+            Frame::Request(id, request) => {
+                let client = self.peer.map(|peer| peer.ip().to_canonical());
+                match self.request_ids.response(client, id, &request) {
+                    Some(response) => response
+                        .get_or_try_init(|| self.execute(*request))
+                        .await?
+                        .clone(),
+                    None => Frame::Error(format!(
+                        "request id {} was sent before with another request",
+                        id
+                    )),
+                }
+            }
+            frame => self.execute(frame).await?,
+        };
+        info!("{}handler write a frame: {} to client", trace, resp);
+        // write resp
+        self.conn.write_frame(resp).await?;
+        Ok(())
+    }
+
+    /// Apply a request to the engine and return the response to it
+    async fn execute(&self, frame: Frame) -> Result<Frame> {
+        Ok(match frame {
             Frame::Set(key, value) => {
-                if let Err(err) = self.kv.set(key, value) {
+                let peer = self.peer;
+                if let Err(err) = self.call(move |kv| kv.set_from(key, value, peer)).await {
                     Frame::Error(err.to_string())
                 } else {
-                    Frame::Null
+                    Frame::Ok
                 }
             }
-            Frame::Get(key) => match self.kv.get(key) {
+            Frame::Get(key) => match self.call(move |kv| kv.get(key)).await {
                 Ok(Some(val)) => Frame::Value(val),
                 Ok(None) => Frame::Null,
                 Err(err) => Frame::Error(err.to_string()),
             },
             Frame::Remove(key) => {
-                if let Err(err) = self.kv.remove(key) {
+                let peer = self.peer;
+                if let Err(err) = self.call(move |kv| kv.remove_from(key, peer)).await {
                     Frame::Error(err.to_string())
                 } else {
-                    Frame::Null
+                    Frame::Ok
+                }
+            }
+            Frame::Increment(key, delta) => {
+                match self.call(move |kv| kv.increment(key, delta)).await {
+                    Ok(value) => Frame::Value(value.to_string()),
+                    Err(err) => Frame::Error(err.to_string()),
+                }
+            }
+            Frame::ContainsKey(key) => match self.call(move |kv| kv.contains_key(key)).await {
+                Ok(true) => Frame::Ok,
+                Ok(false) => Frame::Null,
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::ExistsMany(keys) => match self.call(move |kv| kv.exists_many(keys)).await {
+                Ok(exists) => Frame::Bitmap(to_bitmap(&exists)),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::PauseCompaction => {
+                let pause = |kv: &D| {
+                    kv.pause_compaction();
+                    Ok(())
+                };
+                if let Err(err) = self.call(pause).await {
+                    Frame::Error(err.to_string())
+                } else {
+                    Frame::Ok
+                }
+            }
+            // resuming may merge right away, which happens off the async runtime too
+            Frame::ResumeCompaction => {
+                let resume = |kv: &D| {
+                    kv.resume_compaction();
+                    Ok(())
+                };
+                if let Err(err) = self.call(resume).await {
+                    Frame::Error(err.to_string())
+                } else {
+                    Frame::Ok
                 }
             }
             _ => {
@@ -84,10 +421,147 @@ impl<D: KvsEngine> Handler<D> {
                 warn!("{}", msg);
                 return Err(KvStoreErr::UnexceptErr(msg));
             }
+        })
+    }
+
+    /// Send the changes to the keys starting with `prefix`, the connection takes
+    /// no more requests from then on
+    async fn watch(&mut self, prefix: String) -> Result<()> {
+        let events = match self.kv.watch(prefix) {
+            Ok(events) => events,
+            Err(err) => return self.conn.write_frame(Frame::Error(err.to_string())).await,
+        };
+        self.conn.write_frame(Frame::Ok).await?;
+        self.send_events(events).await
+    }
+
+    /// Send a copy of all live keys, the position it was taken at and then the changes
+    /// to the keys, the connection takes no more requests from then on
+    async fn full_sync(&mut self) -> Result<()> {
+        let sync = match self.call(|kv| kv.full_sync()).await {
+            Ok(sync) => sync,
+            Err(err) => return self.conn.write_frame(Frame::Error(err.to_string())).await,
+        };
+        info!("full sync of {} keys", sync.keys.len());
+        let mut keys = sync.keys.into_iter();
+        loop {
+            let batch: Vec<Vec<u8>> = keys.by_ref().take(FULL_SYNC_BATCH_KEYS).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let frames = match self.call(move |kv| read_batch(kv, batch)).await {
+                Ok(frames) => frames,
+                Err(err) => return self.conn.write_frame(Frame::Error(err.to_string())).await,
+            };
+            self.conn.write_frames(frames).await?;
+        }
+        self.conn
+            .write_frame(Frame::Position(sync.position.0, sync.position.1))
+            .await?;
+        self.send_events(sync.events).await
+    }
+
+    /// Send a copy of up to `limit` keys with their values and then an `Ok`
+    async fn dump(&mut self, limit: u64) -> Result<()> {
+        let limit = limit.min(MAX_DUMP_KEYS) as usize;
+        let entries = match self.call(move |kv| kv.dump(limit)).await {
+            Ok(entries) => entries,
+            Err(err) => return self.conn.write_frame(Frame::Error(err.to_string())).await,
         };
-        info!("handler write a frame: {:?} to client", resp);
-        // write resp
-        self.conn.write_frame(resp).await?;
-        Ok(())
+        info!("dump of {} keys", entries.len());
+        let frames = entries
+            .into_iter()
+            .map(|(key, value)| Frame::Set(key, value))
+            .chain([Frame::Ok]);
+        self.conn.write_frames(frames).await
+    }
+
+    /// Send every live key and then an `Ok`
+    ///
+    /// The keys are sent in batches while the engine walks them with `for_each_key`, so they
+    /// are never all in memory and the listing isn't a snapshot. The walk waits for the client
+    /// to take a batch before it goes on, holding up the writes to the part of the index it's in.
+    async fn keys(&mut self) -> Result<()> {
+        let (sender, mut batches) = mpsc::channel(1);
+        let kv = self.kv.clone();
+        let walk = tokio::task::spawn_blocking(move || {
+            let mut batch = Vec::with_capacity(LIST_BATCH_KEYS);
+            // the client is gone, the rest of the walk sends nothing
+            let mut closed = false;
+            kv.for_each_key(&mut |key| {
+                if closed {
+                    return;
+                }
+                batch.push(key.to_owned());
+                if batch.len() == LIST_BATCH_KEYS {
+                    let full = mem::replace(&mut batch, Vec::with_capacity(LIST_BATCH_KEYS));
+                    closed = sender.blocking_send(full).is_err();
+                }
+            })?;
+            if !closed && !batch.is_empty() {
+                let _ = sender.blocking_send(batch);
+            }
+            Ok(())
+        });
+        let mut listed = 0;
+        while let Some(batch) = batches.recv().await {
+            listed += batch.len();
+            self.conn
+                .write_frames(batch.into_iter().map(Frame::Value))
+                .await?;
+        }
+        let walked: Result<()> = walk
+            .await
+            .map_err(|err| KvStoreErr::InnerErr(err.to_string()))?;
+        if let Err(err) = walked {
+            return self.conn.write_frame(Frame::Error(err.to_string())).await;
+        }
+        info!("list {} keys", listed);
+        self.conn.write_frame(Frame::Ok).await
+    }
+
+    /// Send every event as a `Set` or `Remove` until the client closes the connection
+    async fn send_events(&mut self, mut events: UnboundedReceiver<KeyEvent>) -> Result<()> {
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let frame = match event {
+                        Some(KeyEvent::Set { key, value }) => Frame::Set(key, value),
+                        Some(KeyEvent::Removed { key }) => Frame::Remove(key),
+                        // the engine is gone
+                        None => return Ok(()),
+                    };
+                    self.conn.write_frame(frame).await?;
+                }
+                frame = self.conn.read_frame() => match frame? {
+                    None => return Ok(()),
+                    Some(frame) => {
+                        return Err(KvStoreErr::UnexceptErr(format!(
+                            "frame received on a watching connection: {:?}",
+                            frame
+                        )))
+                    }
+                },
+            }
+        }
+    }
+
+    /// Run an engine operation off the async runtime, bounded by the command timeout
+    ///
+    /// An operation which timed out keeps running in the background, only its result is dropped.
+    async fn call<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&D) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let kv = self.kv.clone();
+        let task = tokio::task::spawn_blocking(move || op(&kv));
+        let joined = match self.command_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, task).await.map_err(|_| {
+                warn!("engine operation timed out after {:?}", timeout);
+                KvStoreErr::Timeout
+            })?,
+            None => task.await,
+        };
+        joined.map_err(|err| KvStoreErr::InnerErr(err.to_string()))?
     }
 }
@@ -0,0 +1,165 @@
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use log::{error, info, warn};
+
+use crate::{
+    connection::DEFAULT_MAX_FRAME_BYTES, protocol::to_bitmap, Frame, KvStoreErr, KvsEngine, Result,
+};
+
+/// Server which handles every connection on a thread of its own with blocking engine calls,
+/// speaking the same frames as `Server` without an async runtime
+///
+/// It suits engines whose calls block on disk most of the time. Compression is never agreed
+/// to, and keys can't be watched or fully synced.
+pub struct SyncServer<D: KvsEngine> {
+    tcp: TcpListener,
+    kv: Arc<D>,
+}
+
+impl<D: KvsEngine> SyncServer<D> {
+    /// Serve the connections of `tcp`, only returns once accepting a connection fails
+    pub fn start(tcp: TcpListener, kv: Arc<D>) -> Result<Self> {
+        let server = SyncServer { tcp, kv };
+        server.run()?;
+        Ok(server)
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("sync server start to receive connection from client");
+        loop {
+            let (socket, peer) = self.tcp.accept()?;
+            info!("sync server receive a connection from: {}", peer);
+            // every request waits for its response, so don't hold small responses back
+            if let Err(err) = socket.set_nodelay(true) {
+                warn!("failed to set the socket options: {:?}", err);
+            }
+            let mut handler = SyncHandler {
+                stream: socket,
+                buffer: Vec::new(),
+                kv: self.kv.clone(),
+                peer: Some(peer),
+            };
+            thread::spawn(move || {
+                if let Err(err) = handler.handle() {
+                    error!("handler handle error: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+struct SyncHandler<D: KvsEngine> {
+    stream: TcpStream,
+    // bytes read from the stream which don't make a whole frame yet
+    buffer: Vec<u8>,
+    kv: Arc<D>,
+    peer: Option<SocketAddr>,
+}
+
+impl<D: KvsEngine> SyncHandler<D> {
+    fn handle(&mut self) -> Result<()> {
+        while let Some(frame) = self.read_frame()? {
+            let (trace, frame) = match frame {
+                Frame::Traced(trace_id, frame) => (format!("[trace {:016x}] ", trace_id), *frame),
+                frame => (String::new(), frame),
+            };
+            info!("{}handler read a frame: {} from socket", trace, frame);
+            let resp = self.deal(frame)?;
+            info!("{}handler write a frame: {} to client", trace, resp);
+            self.stream.write_all(&resp.to_bytes())?;
+        }
+        info!("client closed the connection");
+        Ok(())
+    }
+
+    fn deal(&self, frame: Frame) -> Result<Frame> {
+        let acked = |result: Result<()>| match result {
+            Ok(()) => Frame::Ok,
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(match frame {
+            // no transport feature is supported
+            Frame::Hello(_) => Frame::Hello(String::new()),
+            Frame::Set(key, value) => acked(self.kv.set_from(key, value, self.peer)),
+            Frame::Get(key) => match self.kv.get(key) {
+                Ok(Some(val)) => Frame::Value(val),
+                Ok(None) => Frame::Null,
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::Remove(key) => acked(self.kv.remove_from(key, self.peer)),
+            Frame::ContainsKey(key) => match self.kv.contains_key(key) {
+                Ok(true) => Frame::Ok,
+                Ok(false) => Frame::Null,
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::ExistsMany(keys) => match self.kv.exists_many(keys) {
+                Ok(exists) => Frame::Bitmap(to_bitmap(&exists)),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::PauseCompaction => {
+                self.kv.pause_compaction();
+                Frame::Ok
+            }
+            Frame::ResumeCompaction => {
+                self.kv.resume_compaction();
+                Frame::Ok
+            }
+            Frame::Increment(key, delta) => match self.kv.increment(key, delta) {
+                Ok(value) => Frame::Value(value.to_string()),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Frame::Watch(_) => Frame::Error("the sync server can't watch keys".to_owned()),
+            Frame::FullSync => Frame::Error("the sync server can't stream a full sync".to_owned()),
+            Frame::Dump(_) => Frame::Error("the sync server can't stream a dump".to_owned()),
+            Frame::Keys => Frame::Error("the sync server can't stream keys".to_owned()),
+            Frame::Request(..) => {
+                Frame::Error("the sync server doesn't take requests with ids".to_owned())
+            }
+            _ => {
+                let msg = format!("unexcept frame received: {:?}", frame);
+                warn!("{}", msg);
+                return Err(KvStoreErr::UnexceptErr(msg));
+            }
+        })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let mut buf = Cursor::new(&self.buffer[..]);
+            match Frame::check(&mut buf) {
+                Ok(()) => {
+                    let len = buf.position() as usize;
+                    buf.set_position(0);
+                    let frame = Frame::parse(&mut buf)?;
+                    self.buffer.drain(..len);
+                    // the hello answer offers no features, so compression is never agreed on
+                    if let Frame::Compressed(_) = frame {
+                        return Err(KvStoreErr::UnexceptErr(
+                            "the sync server doesn't take compressed frames".to_owned(),
+                        ));
+                    }
+                    return Ok(Some(frame));
+                }
+                Err(KvStoreErr::IncompleteErr) => {}
+                Err(err) => return Err(err),
+            }
+            if self.buffer.len() > DEFAULT_MAX_FRAME_BYTES {
+                return Err(KvStoreErr::FrameTooLarge(DEFAULT_MAX_FRAME_BYTES));
+            }
+            let mut chunk = [0; 4 * 1024];
+            let len = self.stream.read(&mut chunk)?;
+            if len == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(KvStoreErr::UnexceptErr(
+                    "read an uncompleted frame".to_owned(),
+                ));
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+; ModuleID = 'probe4.f51004f11f9b7ff5-cgu.0'
+source_filename = "probe4.f51004f11f9b7ff5-cgu.0"
+target datalayout = "e-m:e-p270:32:32-p271:32:32-p272:64:64-i64:64-i128:128-f80:128-n8:16:32:64-S128"
+target triple = "x86_64-unknown-linux-gnu"
+
+; probe4::probe
+; Function Attrs: nonlazybind uwtable
+define void @_ZN6probe45probe17h3f511780d68fe224E() unnamed_addr #0 {
+start:
+  %0 = alloca [4 x i8], align 4
+  store i32 1, ptr %0, align 4
+  %_0.i = load i32, ptr %0, align 4
+  ret void
+}
+
+; Function Attrs: nocallback nofree nosync nounwind speculatable willreturn memory(none)
+declare i32 @llvm.cttz.i32(i32, i1 immarg) #1
+
+attributes #0 = { nonlazybind uwtable "probe-stack"="inline-asm" "target-cpu"="x86-64" }
+attributes #1 = { nocallback nofree nosync nounwind speculatable willreturn memory(none) }
+
+!llvm.module.flags = !{!0, !1}
+!llvm.ident = !{!2}
+
+!0 = !{i32 8, !"PIC Level", i32 2}
+!1 = !{i32 2, !"RtLibUseGOT", i32 1}
+!2 = !{!"rustc version 1.95.0 (59807616e 2026-04-14)"}
@@ -0,0 +1,980 @@
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use kvs::{
+    AccessList, BitcaskEngine, BitcaskOptions, Client, ClientBuilder, Frame, KeyEvent, KvStoreErr,
+    KvsEngine, Result, Server, ServerBuilder, SledEngine, SocketOptions,
+};
+use socket2::SockRef;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+// Start a fake server which answers the first request with `resp`
+async fn serve_once(resp: Frame) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = BufWriter::new(socket);
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        resp.write(&mut stream).await.unwrap();
+        stream.flush().await.unwrap();
+    });
+    addr
+}
+
+// Start a fake server which answers gets after a simulated network latency,
+// every read from the socket which completes some frames costs one round trip
+async fn serve_with_latency(latency: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let round_trips = Arc::new(AtomicUsize::new(0));
+    let counter = round_trips.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = BufWriter::new(socket);
+        let mut buffer = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let len = stream.read(&mut buf).await.unwrap();
+            if len == 0 {
+                return;
+            }
+            buffer.extend_from_slice(&buf[..len]);
+            let mut responses = Vec::new();
+            loop {
+                let mut cursor = Cursor::new(&buffer[..]);
+                if Frame::check(&mut cursor).is_err() {
+                    break;
+                }
+                let frame_len = cursor.position() as usize;
+                cursor.set_position(0);
+                let resp = match Frame::parse(&mut cursor).unwrap() {
+                    Frame::Get(key) if key == "missing" => Frame::Null,
+                    Frame::Get(key) => Frame::Value(format!("value-{}", key)),
+                    _ => Frame::Error("unexpected frame".to_owned()),
+                };
+                responses.push(resp);
+                buffer.drain(..frame_len);
+            }
+            if responses.is_empty() {
+                continue;
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(latency).await;
+            for resp in responses {
+                resp.write(&mut stream).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+        }
+    });
+    (addr, round_trips)
+}
+
+// Engine whose operations on keys starting with "slow" hang for a while, like on a stuck disk
+struct SlowEngine {
+    inner: BitcaskEngine,
+    delay: Duration,
+}
+
+impl SlowEngine {
+    fn stall(&self, key: &str) {
+        if key.starts_with("slow") {
+            std::thread::sleep(self.delay);
+        }
+    }
+}
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.stall(&key);
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.stall(&key);
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.stall(&key);
+        self.inner.remove(key)
+    }
+}
+
+async fn client_to(addr: SocketAddr) -> Client {
+    Client::new(TcpStream::connect(addr).await.unwrap())
+}
+
+#[tokio::test]
+async fn set_acked_by_ok() {
+    let mut client = client_to(serve_once(Frame::Ok).await).await;
+    assert!(client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn set_surfaces_error() {
+    let mut client = client_to(serve_once(Frame::Error("disk full".to_owned())).await).await;
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("disk full"));
+}
+
+#[tokio::test]
+async fn set_not_acked_by_null() {
+    let mut client = client_to(serve_once(Frame::Null).await).await;
+    assert!(client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn get_value() {
+    let mut client = client_to(serve_once(Frame::Value("value1".to_owned())).await).await;
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn get_miss_by_null() {
+    let mut client = client_to(serve_once(Frame::Null).await).await;
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn get_surfaces_error() {
+    let mut client = client_to(serve_once(Frame::Error("io error".to_owned())).await).await;
+    let err = client.get("key1".to_owned()).await.unwrap_err();
+    assert!(err.to_string().contains("io error"));
+}
+
+#[tokio::test]
+async fn get_not_a_miss_when_connection_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // close the connection without responding
+        let _ = listener.accept().await.unwrap();
+    });
+    let mut client = client_to(addr).await;
+    assert!(client.get("key1".to_owned()).await.is_err());
+}
+
+#[tokio::test]
+async fn client_access_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await.unwrap(), None);
+    client.remove("key1".to_owned()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(KvStoreErr::KeyNotFound(key)) if key == "key1"
+    ));
+
+    client
+        .set("key2".to_owned(), "value2".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .get_many(vec!["key1".to_owned(), "key2".to_owned()])
+            .await
+            .unwrap(),
+        vec![None, Some("value2".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn dump_and_keys_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let mut expected = Vec::new();
+    for key_id in 0..20 {
+        kv.set(format!("key{:02}", key_id), format!("old{}", key_id))
+            .unwrap();
+        kv.set(format!("key{:02}", key_id), format!("value{}", key_id))
+            .unwrap();
+        expected.push((format!("key{:02}", key_id), format!("value{}", key_id)));
+    }
+    kv.remove("key07".to_owned()).unwrap();
+    expected.remove(7);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    let dumped: Vec<(String, String)> = client
+        .dump(100)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect()
+        .await;
+    assert_eq!(dumped, expected);
+
+    // the limit keeps the first keys
+    let dumped: Vec<(String, String)> = client
+        .dump(5)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect()
+        .await;
+    assert_eq!(dumped, expected[..5]);
+
+    // the connection takes other requests once the dump ended
+    assert_eq!(
+        client.get("key03".to_owned()).await.unwrap(),
+        Some("value3".to_owned())
+    );
+
+    let mut keys: Vec<String> = client
+        .keys()
+        .await
+        .unwrap()
+        .map(|key| key.unwrap())
+        .collect()
+        .await;
+    keys.sort();
+    let expected_keys: Vec<String> = expected.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, expected_keys);
+    assert_eq!(client.get("key07".to_owned()).await.unwrap(), None);
+}
+
+// A key listing sends every key, however many the store holds
+#[tokio::test]
+async fn keys_listing_sends_every_key() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    for i in (0..100_010).rev() {
+        kv.set(format!("key{:06}", i), String::new()).unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    let mut keys: Vec<String> = client
+        .keys()
+        .await
+        .unwrap()
+        .map(|key| key.unwrap())
+        .collect()
+        .await;
+    keys.sort_unstable();
+    let expected: Vec<String> = (0..100_010).map(|i| format!("key{:06}", i)).collect();
+    assert_eq!(keys, expected);
+    // the connection takes requests again
+    assert_eq!(
+        client.get("key000000".to_owned()).await.unwrap(),
+        Some(String::new())
+    );
+}
+
+// Both engines report removing a missing key the same way over the network
+#[tokio::test]
+async fn remove_missing_key_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bitcask_addr = {
+        let kv = BitcaskEngine::open(temp_dir.path().join("bitcask")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::start(listener, Arc::new(kv)));
+        addr
+    };
+    let sled_addr = {
+        let kv = SledEngine::open(temp_dir.path().join("sled")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::start(listener, Arc::new(kv)));
+        addr
+    };
+    for addr in [bitcask_addr, sled_addr] {
+        let mut client = client_to(addr).await;
+        assert!(matches!(
+            client.remove("missing key".to_owned()).await,
+            Err(KvStoreErr::KeyNotFound(key)) if key == "missing key"
+        ));
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        client.remove("key1".to_owned()).await.unwrap();
+        assert!(matches!(
+            client.remove("key1".to_owned()).await,
+            Err(KvStoreErr::KeyNotFound(_))
+        ));
+    }
+
+    // other errors stay unexpected ones
+    let addr = serve_once(Frame::Error("disk full".to_owned())).await;
+    assert!(matches!(
+        client_to(addr).await.remove("key1".to_owned()).await,
+        Err(KvStoreErr::UnexceptErr(err)) if err == "disk full"
+    ));
+}
+
+// An increment retried after its response was lost is applied once, as long as the server
+// remembers its request id for the client
+#[tokio::test]
+async fn retried_increment_applied_once() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        ServerBuilder::new()
+            .request_ids(2, Duration::from_secs(60))
+            .start(listener, Arc::new(kv)),
+    );
+
+    // the connection goes away before the response arrives
+    let request = Frame::Request(7, Box::new(Frame::Increment("counter".to_owned(), 5)));
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&request.to_bytes()).await.unwrap();
+    drop(socket);
+
+    let mut client = client_to(addr).await;
+    for _ in 0..2 {
+        assert_eq!(
+            client
+                .increment_with_request_id("counter".to_owned(), 5, 7)
+                .await
+                .unwrap(),
+            5
+        );
+    }
+    assert_eq!(
+        client.get("counter".to_owned()).await.unwrap(),
+        Some("5".to_owned())
+    );
+    // other ids and increments without an id are applied
+    assert_eq!(
+        client
+            .increment_with_request_id("counter".to_owned(), 5, 8)
+            .await
+            .unwrap(),
+        10
+    );
+    // an id sent before with another request is refused
+    assert!(client
+        .increment_with_request_id("counter".to_owned(), 6, 8)
+        .await
+        .is_err());
+    assert_eq!(client.increment("counter".to_owned(), 1).await.unwrap(), 11);
+
+    // only the last 2 ids are remembered
+    client
+        .increment_with_request_id("counter".to_owned(), 1, 9)
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .increment_with_request_id("counter".to_owned(), 5, 7)
+            .await
+            .unwrap(),
+        17
+    );
+    // another client address has ids of its own
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+    let mut other = Client::new(socket.connect(addr).await.unwrap());
+    assert_eq!(
+        other
+            .increment_with_request_id("counter".to_owned(), 1, 9)
+            .await
+            .unwrap(),
+        18
+    );
+    assert!(client
+        .increment("missing".to_owned(), i64::MAX)
+        .await
+        .is_ok());
+    assert!(client.increment("missing".to_owned(), 1).await.is_err());
+}
+
+#[tokio::test]
+async fn nul_bytes_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    client
+        .set("key\0suffix".to_owned(), "\0value\0".to_owned())
+        .await
+        .unwrap();
+    client.set("\0".to_owned(), "\0".to_owned()).await.unwrap();
+    assert_eq!(
+        client.get("key\0suffix".to_owned()).await.unwrap(),
+        Some("\0value\0".to_owned())
+    );
+    assert_eq!(
+        client.get("\0".to_owned()).await.unwrap(),
+        Some("\0".to_owned())
+    );
+    assert_eq!(client.get("key".to_owned()).await.unwrap(), None);
+    client.remove("key\0suffix".to_owned()).await.unwrap();
+    assert_eq!(client.get("key\0suffix".to_owned()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn separators_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    client
+        .set("a%b#c".to_owned(), "b%c\n#%\n".to_owned())
+        .await
+        .unwrap();
+    client.set("%".to_owned(), "#".to_owned()).await.unwrap();
+    assert_eq!(
+        client.get("a%b#c".to_owned()).await.unwrap(),
+        Some("b%c\n#%\n".to_owned())
+    );
+    assert_eq!(
+        client.get("%".to_owned()).await.unwrap(),
+        Some("#".to_owned())
+    );
+    assert_eq!(client.get("a".to_owned()).await.unwrap(), None);
+    assert_eq!(client.increment("#".to_owned(), -2).await.unwrap(), -2);
+}
+
+#[tokio::test]
+async fn exists_many_in_request_order() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    for i in (0..20).step_by(3) {
+        kv.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    kv.remove("key9".to_owned()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = client_to(addr).await;
+    // more keys than fit in a byte, in no order and with repeats
+    let ids = [19, 0, 3, 9, 1, 18, 18, 7, 6, 12, 15, 2, 4];
+    let keys: Vec<String> = ids.iter().map(|i| format!("key{}", i)).collect();
+    let expected: Vec<bool> = ids.iter().map(|i| i % 3 == 0 && *i != 9).collect();
+    assert_eq!(client.exists_many(keys.clone()).await.unwrap(), expected);
+    assert!(client.contains_key("key3".to_owned()).await.unwrap());
+    assert!(!client.contains_key("key9".to_owned()).await.unwrap());
+    assert_eq!(
+        client.exists_many(Vec::new()).await.unwrap(),
+        Vec::<bool>::new()
+    );
+
+    // keys may hold the separators of the other frames
+    assert_eq!(
+        client
+            .exists_many(vec!["%".to_owned(), "key0#%".to_owned()])
+            .await
+            .unwrap(),
+        vec![false, false]
+    );
+
+    let mut client = ClientBuilder::new()
+        .compression(true)
+        .connect(addr)
+        .await
+        .unwrap();
+    assert_eq!(client.exists_many(keys).await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn exists_many_rejects_short_bitmap() {
+    let addr = serve_once(Frame::Bitmap(vec![0xff])).await;
+    let mut client = client_to(addr).await;
+    let keys = (0..9).map(|i| format!("key{}", i)).collect();
+    assert!(client.exists_many(keys).await.is_err());
+}
+
+#[tokio::test]
+async fn get_many_pipelines_requests() {
+    let keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+
+    let (addr, sequential_round_trips) = serve_with_latency(Duration::from_millis(20)).await;
+    let mut client = client_to(addr).await;
+    for key in &keys {
+        assert_eq!(
+            client.get(key.clone()).await.unwrap(),
+            Some(format!("value-{}", key))
+        );
+    }
+
+    let (addr, pipelined_round_trips) = serve_with_latency(Duration::from_millis(20)).await;
+    let mut client = client_to(addr).await;
+    let mut request = keys.clone();
+    request.insert(3, "missing".to_owned());
+    let mut values = client.get_many(request).await.unwrap();
+    assert_eq!(values.len(), keys.len() + 1);
+    assert_eq!(values[3], None);
+    values.remove(3);
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, Some(format!("value-{}", key)));
+    }
+
+    assert_eq!(sequential_round_trips.load(Ordering::SeqCst), keys.len());
+    assert!(pipelined_round_trips.load(Ordering::SeqCst) < keys.len());
+}
+
+#[tokio::test]
+async fn request_timeout_on_stalled_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // read the request but never respond
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        while socket.read(&mut buf).await.unwrap() > 0 {}
+    });
+    let mut client = ClientBuilder::new()
+        .connect_timeout(Duration::from_secs(5))
+        .request_timeout(Duration::from_millis(100))
+        .connect(addr)
+        .await
+        .unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), client.get("key1".to_owned()))
+        .await
+        .expect("the client should time out by itself");
+    assert!(matches!(result, Err(KvStoreErr::Timeout)));
+}
+
+#[tokio::test]
+async fn builder_with_small_buffers() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    // frames larger than the buffers still go through
+    let mut client = Client::builder()
+        .buffer_size(8)
+        .request_timeout(Duration::from_secs(5))
+        .connect(addr)
+        .await
+        .unwrap();
+    let value = "v".repeat(200);
+    client.set("key1".to_owned(), value.clone()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), Some(value));
+}
+
+#[tokio::test]
+async fn command_timeout_on_slow_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = SlowEngine {
+        inner: BitcaskEngine::open(temp_dir.path()).unwrap(),
+        delay: Duration::from_secs(2),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        ServerBuilder::new()
+            .command_timeout(Duration::from_millis(100))
+            .start(listener, Arc::new(kv)),
+    );
+
+    let mut client = client_to(addr).await;
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+
+    // the server answers with an error instead of waiting for the engine
+    let start = Instant::now();
+    let err = client.get("slow-key".to_owned()).await.unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{}", err);
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // the connection is still usable
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn negotiate_compression() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = Client::builder()
+        .compression(true)
+        .request_timeout(Duration::from_secs(5))
+        .connect(addr)
+        .await
+        .unwrap();
+    let value = "0123456789".repeat(20);
+    client.set("key1".to_owned(), value.clone()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), Some(value));
+    assert_eq!(client.get("key2".to_owned()).await.unwrap(), None);
+    assert!(client.negotiate_compression().await.unwrap());
+}
+
+#[tokio::test]
+async fn socket_options_applied() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // accept both clients, and keep the last socket to set options on
+    let accept = tokio::spawn(async move {
+        let _first = listener.accept().await.unwrap();
+        listener.accept().await.unwrap().0
+    });
+
+    // nodelay without keepalive by default
+    let client = Client::builder().connect(addr).await.unwrap();
+    let socket = SockRef::from(client.socket());
+    assert!(socket.nodelay().unwrap());
+    assert!(!socket.keepalive().unwrap());
+
+    let client = Client::builder()
+        .nodelay(false)
+        .keepalive(Duration::from_secs(60))
+        .connect(addr)
+        .await
+        .unwrap();
+    let socket = SockRef::from(client.socket());
+    assert!(!socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+
+    // what the server does to the sockets it accepts
+    let accepted = accept.await.unwrap();
+    let options = SocketOptions {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+    };
+    options.apply(&accepted).unwrap();
+    let socket = SockRef::from(&accepted);
+    assert!(socket.nodelay().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+}
+
+// Start a fake server which answers the first request with the raw `resp` and then stalls
+async fn serve_raw_and_stall(resp: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        let _ = socket.read(&mut buf).await.unwrap();
+        socket.write_all(&resp).await.unwrap();
+        while socket.read(&mut buf).await.unwrap() > 0 {}
+    });
+    addr
+}
+
+#[tokio::test]
+async fn read_timeout_on_partial_frame() {
+    // the start of a value frame without its end
+    let addr = serve_raw_and_stall(b"\x03\x00\x00\x00\x20partial val".to_vec()).await;
+    let mut client = ClientBuilder::new()
+        .read_timeout(Duration::from_millis(100))
+        .connect(addr)
+        .await
+        .unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), client.get("key1".to_owned()))
+        .await
+        .expect("the client should time out by itself");
+    assert!(matches!(result, Err(KvStoreErr::Timeout)));
+}
+
+#[tokio::test]
+async fn max_frame_bytes_on_endless_frame() {
+    let mut resp = b"\x03\x00\x01\x00\x00".to_vec();
+    resp.extend(vec![b'v'; 64 * 1024]);
+    let addr = serve_raw_and_stall(resp).await;
+    let mut client = ClientBuilder::new()
+        .max_frame_bytes(1024)
+        .read_timeout(Duration::from_secs(5))
+        .connect(addr)
+        .await
+        .unwrap();
+    let result = client.get("key1".to_owned()).await;
+    assert!(matches!(result, Err(KvStoreErr::FrameTooLarge(1024))));
+}
+
+// The server passes the address of the client to the engine, which records it in the audit log
+#[tokio::test]
+async fn audit_log_records_client_address() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_path = temp_dir.path().join("audit.log");
+    let options = BitcaskOptions {
+        audit_log: Some(audit_path.clone()),
+        ..Default::default()
+    };
+    let kv = BitcaskEngine::open_with_options(temp_dir.path().join("data"), options).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv.clone())));
+
+    let mut client = client_to(addr).await;
+    let client_addr = client.socket().local_addr().unwrap();
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    client.remove("key1".to_owned()).await.unwrap();
+    kv.flush().unwrap();
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let records: Vec<Vec<&str>> = audit
+        .lines()
+        .map(|line| line.split(' ').skip(1).collect())
+        .collect();
+    let client_addr = client_addr.to_string();
+    assert_eq!(
+        records,
+        [
+            ["set", "\"key1\"", &client_addr],
+            ["remove", "\"key1\"", &client_addr],
+        ]
+    );
+}
+
+// Compaction of the engine behind the server is paused and resumed over the protocol
+#[tokio::test]
+async fn pause_and_resume_compaction() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: 500,
+        ..Default::default()
+    };
+    let kv = BitcaskEngine::open_with_options(temp_dir.path(), options).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv.clone())));
+
+    let mut client = client_to(addr).await;
+    client.pause_compaction().await.unwrap();
+    for i in 0..200 {
+        client
+            .set(format!("key{}", i % 10), format!("value{:05}", i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(kv.stats().merge_bytes_written, 0);
+    client.resume_compaction().await.unwrap();
+    // the merge runs on the merge worker of the engine
+    let start = Instant::now();
+    while kv.stats().merge_bytes_reclaimed == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        client.get("key9".to_owned()).await.unwrap(),
+        Some("value00199".to_owned())
+    );
+}
+
+// A watching client sees the changes another client makes to the watched keys, in order
+#[tokio::test]
+async fn watch_prefix() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let events = client_to(addr)
+        .await
+        .watch("user:".to_owned())
+        .await
+        .unwrap();
+    let mut events = Box::pin(events);
+    let mut writer = client_to(addr).await;
+    writer
+        .set("user:1".to_owned(), "alice".to_owned())
+        .await
+        .unwrap();
+    writer
+        .set("group:1".to_owned(), "admins".to_owned())
+        .await
+        .unwrap();
+    writer
+        .set("user:2".to_owned(), "bob".to_owned())
+        .await
+        .unwrap();
+    writer.remove("user:1".to_owned()).await.unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap();
+        received.push(event.unwrap().unwrap());
+    }
+    assert_eq!(
+        received,
+        [
+            KeyEvent::Set {
+                key: "user:1".to_owned(),
+                value: "alice".to_owned()
+            },
+            KeyEvent::Set {
+                key: "user:2".to_owned(),
+                value: "bob".to_owned()
+            },
+            KeyEvent::Removed {
+                key: "user:1".to_owned()
+            },
+        ]
+    );
+}
+
+// A follower bootstrapped by a full sync and fed the events after it holds the leader's keys
+#[tokio::test]
+async fn full_sync_bootstraps_follower() {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(leader_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut writer = client_to(addr).await;
+    for i in 0..10 {
+        writer
+            .set(format!("key{}", i), i.to_string())
+            .await
+            .unwrap();
+    }
+    writer.remove("key3".to_owned()).await.unwrap();
+
+    let (mut entries, position, events) = client_to(addr).await.full_sync().await.unwrap();
+    entries.sort();
+    let expected: Vec<_> = (0..10)
+        .filter(|i| *i != 3)
+        .map(|i| (format!("key{}", i), i.to_string()))
+        .collect();
+    assert_eq!(entries, expected);
+    // the copy covers the whole log written so far
+    let log_len = std::fs::metadata(leader_dir.path().join("0.log"))
+        .unwrap()
+        .len();
+    assert_eq!(position, (0, log_len));
+
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower = BitcaskEngine::open(follower_dir.path()).unwrap();
+    for (key, value) in entries {
+        follower.set(key, value).unwrap();
+    }
+    writer
+        .set("key3".to_owned(), "again".to_owned())
+        .await
+        .unwrap();
+    writer.remove("key5".to_owned()).await.unwrap();
+    let mut events = Box::pin(events);
+    for _ in 0..2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap();
+        match event.unwrap().unwrap() {
+            KeyEvent::Set { key, value } => follower.set(key, value).unwrap(),
+            KeyEvent::Removed { key } => follower.remove(key).unwrap(),
+        }
+    }
+
+    let mut reader = client_to(addr).await;
+    for i in 0..10 {
+        let key = format!("key{}", i);
+        assert_eq!(
+            follower.get(key.clone()).unwrap(),
+            reader.get(key).await.unwrap()
+        );
+    }
+}
+
+// A full sync larger than a batch sends every key, and fails on one the frames can't carry
+#[tokio::test]
+async fn full_sync_in_batches() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = Arc::new(BitcaskEngine::open(temp_dir.path()).unwrap());
+    for i in 0..2500 {
+        kv.set(format!("key{:04}", i), i.to_string()).unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, kv.clone()));
+
+    let (mut entries, _, _) = client_to(addr).await.full_sync().await.unwrap();
+    entries.sort();
+    let expected: Vec<_> = (0..2500)
+        .map(|i| (format!("key{:04}", i), i.to_string()))
+        .collect();
+    assert_eq!(entries, expected);
+
+    kv.set_bytes(vec![0xFF], b"value".to_vec()).unwrap();
+    assert!(client_to(addr).await.full_sync().await.is_err());
+}
+
+#[tokio::test]
+async fn access_list_refuses_peers() {
+    let serve = |access_list: AccessList| async move {
+        let temp_dir = TempDir::new().unwrap();
+        let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            ServerBuilder::new()
+                .access_list(access_list)
+                .start(listener, Arc::new(kv)),
+        );
+        (addr, temp_dir)
+    };
+    let loopback = "127.0.0.1".parse().unwrap();
+
+    let (addr, _temp_dir) = serve(AccessList::Deny(vec![loopback])).await;
+    let mut client = client_to(addr).await;
+    let err = client.get("key1".to_owned()).await.unwrap_err();
+    assert!(err.to_string().contains("not allowed"), "{}", err);
+
+    let (addr, _temp_dir) = serve(AccessList::Allow(vec![loopback])).await;
+    let mut client = client_to(addr).await;
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+}
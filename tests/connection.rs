@@ -0,0 +1,127 @@
+use kvs::{Connection, Frame, KvStoreErr};
+use tokio::io::{duplex, AsyncReadExt};
+
+#[tokio::test]
+async fn compressed_round_trip() {
+    let (client, server) = duplex(64 * 1024);
+    let mut client = Connection::new(client);
+    let mut server = Connection::new(server);
+    client.set_compression(true);
+    server.set_compression(true);
+
+    let value = "repetitive value ".repeat(1000);
+    client
+        .write_frames(vec![
+            Frame::Set("key1".to_owned(), value.clone()),
+            Frame::Get("key1".to_owned()),
+            Frame::Ok,
+        ])
+        .await
+        .unwrap();
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Set(key, v)) if key == "key1" && v == value
+    ));
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Get(key)) if key == "key1"
+    ));
+    assert!(matches!(
+        server.read_frame().await.unwrap(),
+        Some(Frame::Ok)
+    ));
+
+    server
+        .write_frame(Frame::Value(value.clone()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::Value(v)) if v == value
+    ));
+}
+
+#[tokio::test]
+async fn large_repetitive_value_compresses() {
+    let value = "repetitive value ".repeat(1000);
+    let wire_bytes = |compression: bool| {
+        let value = value.clone();
+        async move {
+            let (client, mut server) = duplex(64 * 1024);
+            let mut client = Connection::new(client);
+            client.set_compression(compression);
+            client.write_frame(Frame::Value(value)).await.unwrap();
+            drop(client);
+            let mut bytes = Vec::new();
+            server.read_to_end(&mut bytes).await.unwrap();
+            bytes
+        }
+    };
+
+    let plain = wire_bytes(false).await;
+    let compressed = wire_bytes(true).await;
+    assert_eq!(plain.len(), value.len() + 5);
+    assert!(compressed.len() * 10 < plain.len(), "{}", compressed.len());
+
+    // and the compressed bytes decode back to the frame
+    let (mut writer, reader) = duplex(64 * 1024);
+    tokio::io::AsyncWriteExt::write_all(&mut writer, &compressed)
+        .await
+        .unwrap();
+    drop(writer);
+    let mut conn = Connection::new(reader);
+    conn.set_compression(true);
+    assert!(matches!(
+        conn.read_frame().await.unwrap(),
+        Some(Frame::Value(v)) if v == value
+    ));
+    assert!(conn.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn small_frames_stay_uncompressed() {
+    let (client, mut server) = duplex(1024);
+    let mut client = Connection::new(client);
+    client.set_compression(true);
+    client
+        .write_frame(Frame::Get("key1".to_owned()))
+        .await
+        .unwrap();
+    drop(client);
+    let mut bytes = Vec::new();
+    server.read_to_end(&mut bytes).await.unwrap();
+    assert_eq!(bytes, b"\x01\x00\x00\x00\x04key1");
+}
+
+#[tokio::test]
+async fn compressed_frame_larger_than_allowed() {
+    let value = "a".repeat(2 * 1024 * 1024);
+    let (client, server) = duplex(64 * 1024);
+    let mut client = Connection::new(client);
+    let mut server = Connection::new(server);
+    client.set_compression(true);
+    server.set_compression(true);
+    server.set_max_frame_bytes(1024 * 1024);
+
+    // a few kilobytes on the wire, more than the limit once decompressed
+    client.write_frame(Frame::Value(value)).await.unwrap();
+    assert!(matches!(
+        server.read_frame().await,
+        Err(KvStoreErr::FrameTooLarge(limit)) if limit == 1024 * 1024
+    ));
+}
+
+#[tokio::test]
+async fn compressed_frame_without_negotiation() {
+    let (client, server) = duplex(64 * 1024);
+    let mut client = Connection::new(client);
+    let mut server = Connection::new(server);
+    client.set_compression(true);
+
+    let value = "repetitive value ".repeat(1000);
+    client.write_frame(Frame::Value(value)).await.unwrap();
+    assert!(matches!(
+        server.read_frame().await,
+        Err(KvStoreErr::UnexceptErr(_))
+    ));
+}
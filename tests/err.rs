@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::io;
+
+use kvs::KvStoreErr;
+
+fn assert_error<E: Error + Send + Sync + 'static>() {}
+
+#[test]
+fn implements_std_error() {
+    assert_error::<KvStoreErr>();
+    let err: Box<dyn Error + Send + Sync> = Box::new(KvStoreErr::KeyNotFound("key1".to_owned()));
+    assert_eq!(err.to_string(), "key key1 not found");
+    assert!(err.source().is_none());
+}
+
+#[test]
+fn source_is_the_underlying_error() {
+    let err = KvStoreErr::from(io::Error::other("disk full"));
+    let source = err.source().unwrap();
+    assert!(source.downcast_ref::<io::Error>().is_some());
+    assert_eq!(source.to_string(), "disk full");
+
+    let bincode_err = bincode::deserialize::<u64>(&[]).unwrap_err();
+    let err = KvStoreErr::from(bincode_err);
+    assert!(err.source().unwrap().is::<bincode::Error>());
+
+    let err = KvStoreErr::from(sled::Error::Unsupported("feature".to_owned()));
+    assert!(err.source().unwrap().is::<sled::Error>());
+
+    let utf8_err = String::from_utf8(vec![0xFF]).unwrap_err();
+    let err = KvStoreErr::from(utf8_err);
+    assert!(err.source().unwrap().is::<std::string::FromUtf8Error>());
+}
@@ -1,14 +1,273 @@
-use kvs::{BitcaskEngine, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    BitcaskEngine, BitcaskOptions, BlockFile, BlockStore, BulkLoadOpts, CancellationToken, Clock,
+    CorruptionPolicy, DuplicateKeys, EmptyMergedFiles, EngineStats, EntryFormat, EvictionPolicy,
+    FileStore, GarbageAccounting, IndexKind, KeyState, KvStoreErr, KvsEngine, LogFileInfo,
+    MemoryStore, MergePool, MergeReport, MergeSchedule, Result, ScrubReport, SledEngine,
+    ValueSource,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Names of the files in `dir` with the given extension
+fn files_with_extension(dir: &Path, extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+// Total bytes of the `.log` files in `dir`
+fn log_files_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// Wait until `done` holds, for what the background threads of the engine do
+fn wait_until(done: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Options which rotate the log files quickly and never merge automatically
+fn small_file_options() -> BitcaskOptions {
+    BitcaskOptions {
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: u64::MAX,
+        ..Default::default()
+    }
+}
+
+fn in_memory_opener() -> impl Fn() -> Result<BitcaskEngine> {
+    let memory_store = MemoryStore::new();
+    move || {
+        let options = BitcaskOptions {
+            block_store: Arc::new(memory_store.clone()),
+            ..Default::default()
+        };
+        BitcaskEngine::open_with_options("kvs", options)
+    }
+}
+
+type ReadWriteHook = Arc<dyn Fn(&Path, usize) -> io::Result<usize> + Send + Sync>;
+type PathHook = Arc<dyn Fn(&Path) + Send + Sync>;
+type SyncHook = Arc<dyn Fn(&Path, u64) + Send + Sync>;
+type RenameHook = Arc<dyn Fn(&Path, &Path) -> Result<()> + Send + Sync>;
+
+// Store delegating to `inner`, calling the hooks a test sets to watch or fail what the engine does
+#[derive(Clone)]
+struct HookStore {
+    inner: Arc<dyn BlockStore>,
+    // how many of the `len` bytes a read or a write of a file at the path gets to, or its error
+    read: Option<ReadWriteHook>,
+    write: Option<ReadWriteHook>,
+    // called with the path and the size of a file when it's synced
+    sync: Option<SyncHook>,
+    // called before a file is opened for appending
+    open_append: Option<PathHook>,
+    // called before a rename, which fails with its error
+    rename: Option<RenameHook>,
+    sync_dir: Option<PathHook>,
+}
+
+impl HookStore {
+    fn new(inner: impl BlockStore) -> Self {
+        HookStore {
+            inner: Arc::new(inner),
+            read: None,
+            write: None,
+            sync: None,
+            open_append: None,
+            rename: None,
+            sync_dir: None,
+        }
+    }
+
+    fn on_read(
+        self,
+        hook: impl Fn(&Path, usize) -> io::Result<usize> + Send + Sync + 'static,
+    ) -> Self {
+        HookStore {
+            read: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn on_write(
+        self,
+        hook: impl Fn(&Path, usize) -> io::Result<usize> + Send + Sync + 'static,
+    ) -> Self {
+        HookStore {
+            write: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn on_sync(self, hook: impl Fn(&Path, u64) + Send + Sync + 'static) -> Self {
+        HookStore {
+            sync: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn on_open_append(self, hook: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        HookStore {
+            open_append: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn on_rename(self, hook: impl Fn(&Path, &Path) -> Result<()> + Send + Sync + 'static) -> Self {
+        HookStore {
+            rename: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn on_sync_dir(self, hook: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        HookStore {
+            sync_dir: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    fn hooked(&self, path: &Path, file: Box<dyn BlockFile>) -> Box<dyn BlockFile> {
+        Box::new(HookFile {
+            inner: file,
+            path: path.to_path_buf(),
+            store: self.clone(),
+        })
+    }
+}
+
+impl BlockStore for HookStore {
+    fn open_read(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        Ok(self.hooked(path, self.inner.open_read(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn BlockFile>> {
+        if let Some(hook) = &self.open_append {
+            hook(path);
+        }
+        Ok(self.hooked(path, self.inner.open_append(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(hook) = &self.rename {
+            hook(from, to)?;
+        }
+        self.inner.rename(from, to)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        if let Some(hook) = &self.sync_dir {
+            hook(dir);
+        }
+        self.inner.sync_dir(dir)
+    }
+}
+
+// File of a `HookStore`, calling its file hooks
+struct HookFile {
+    inner: Box<dyn BlockFile>,
+    path: PathBuf,
+    store: HookStore,
+}
+
+impl io::Read for HookFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match &self.store.read {
+            Some(hook) => hook(&self.path, buf.len())?,
+            None => buf.len(),
+        };
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Write for HookFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match &self.store.write {
+            Some(hook) => hook(&self.path, buf.len())?,
+            None => buf.len(),
+        };
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for HookFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl BlockFile for HookFile {
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync(&self) -> Result<()> {
+        if let Some(hook) = &self.store.sync {
+            hook(&self.path, self.inner.size()?);
+        }
+        self.inner.sync()
+    }
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    check_get_stored_value(|| BitcaskEngine::open(temp_dir.path()))
+}
+
+#[test]
+fn get_stored_value_in_memory() -> Result<()> {
+    check_get_stored_value(in_memory_opener())
+}
+
+fn check_get_stored_value(open: impl Fn() -> Result<BitcaskEngine>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -18,7 +277,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -29,7 +288,16 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    check_overwrite_value(|| BitcaskEngine::open(temp_dir.path()))
+}
+
+#[test]
+fn overwrite_value_in_memory() -> Result<()> {
+    check_overwrite_value(in_memory_opener())
+}
+
+fn check_overwrite_value(open: impl Fn() -> Result<BitcaskEngine>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +306,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -50,14 +318,23 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    check_get_non_existent_value(|| BitcaskEngine::open(temp_dir.path()))
+}
+
+#[test]
+fn get_non_existent_value_in_memory() -> Result<()> {
+    check_get_non_existent_value(in_memory_opener())
+}
+
+fn check_get_non_existent_value(open: impl Fn() -> Result<BitcaskEngine>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -74,138 +351,4231 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
+    check_remove_key(|| BitcaskEngine::open(temp_dir.path()))
+}
+
+#[test]
+fn remove_key_in_memory() -> Result<()> {
+    check_remove_key(in_memory_opener())
+}
+
+fn check_remove_key(open: impl Fn() -> Result<BitcaskEngine>) -> Result<()> {
+    let store = open()?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Keys and values are length-prefixed on disk, so embedded NUL bytes must survive
+// the write, a reopen which replays the log, and a merge which goes through the hints
 #[test]
-fn compaction() -> Result<()> {
+fn keys_and_values_with_nul_bytes() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
-
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    let open = || BitcaskEngine::open_with_options(temp_dir.path(), small_file_options());
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        assert_eq!(store.get("\0".to_owned())?, Some("\0".to_owned()));
+        assert_eq!(
+            store.get("key\0suffix".to_owned())?,
+            Some("value\0\0suffix".to_owned())
+        );
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key\0".to_owned())?, None);
+        Ok(())
     };
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
-
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
-
-        drop(store);
-        // reopen and check content
-        let store = BitcaskEngine::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+    let store = open()?;
+    store.set("\0".to_owned(), "\0".to_owned())?;
+    store.set("key\0suffix".to_owned(), "value\0\0suffix".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    // rotate so the entries above are in sealed files which merge rewrites
+    for i in 0..100 {
+        store.set(format!("filler{}", i), "x".repeat(32))?;
     }
+    check(&store)?;
 
-    panic!("No compaction detected");
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    store.merge()?;
+    check(&store)?;
+    assert!(!files_with_extension(temp_dir.path(), "hint").is_empty());
+
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    Ok(())
 }
 
+// Should report exactly the keys whose values were corrupted, and quarantine them on request
 #[test]
-fn concurrent_set() -> Result<()> {
+fn scrub_corrupt_values() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = BitcaskEngine::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
     }
-    barrier.wait();
+    // overwritten entries aren't live, so their corruption doesn't matter
+    store.set("key0".to_owned(), "value10".to_owned())?;
+    assert_eq!(
+        store.scrub()?,
+        ScrubReport {
+            scanned_keys: 10,
+            ..Default::default()
+        }
+    );
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    // each entry takes 24 bytes of header, 4 bytes of key and 6 bytes of value
+    let log_file = temp_dir.path().join("0.log");
+    for entry_id in [0, 3, 7] {
+        corrupt_key_byte(&log_file, entry_id * 34 + 24 + 4);
     }
+    let report = store.scrub()?;
+    assert_eq!(
+        report.corrupt_keys,
+        vec!["key3".to_owned(), "key7".to_owned()]
+    );
+    assert!(!report.quarantined);
+    // scrub alone doesn't touch the data
+    assert!(store.get("key3".to_owned()).is_err());
+
+    let report = store.scrub_and_quarantine()?;
+    assert_eq!(
+        report.corrupt_keys,
+        vec!["key3".to_owned(), "key7".to_owned()]
+    );
+    assert!(report.quarantined);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("quarantine.bad"))?.len(),
+        2 * 34
+    );
+    assert_eq!(store.scrub()?.corrupt_keys, Vec::<String>::new());
 
-    // Open from disk again and check persistent data
     drop(store);
-    let store = BitcaskEngine::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    // the corrupt entries are still in the log, replay has to skip them
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        BitcaskOptions {
+            on_corruption: CorruptionPolicy::SkipAndContinue,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.scrub()?.scanned_keys, 8);
+    Ok(())
+}
+
+// Keys and values which aren't utf-8 survive a reopen and a merge, and the string API
+// refuses a value it can't return
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || BitcaskEngine::open_with_options(temp_dir.path(), small_file_options());
+    let store = open()?;
+    let key = vec![0xFF, 0x00, 0xFE];
+    let value = vec![0x80, 0x00, 0xC0, 0xFF];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"text".to_vec(), value.clone())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"plain")?, Some(b"value".to_vec()));
+    assert!(store.get("text".to_owned()).is_err());
+    assert_eq!(store.get_bytes(&[0xFF])?, None);
+    assert_eq!(store.keys()?.len(), 3);
+
+    for i in 0..100 {
+        store.set_bytes(vec![0xFE, i], vec![i; 32])?;
     }
+    store.remove_bytes(&[0xFE, 0])?;
+    assert!(matches!(
+        store.remove_bytes(&[0xFE, 0]),
+        Err(KvStoreErr::KeyNotFound(_))
+    ));
+    drop(store);
+    let store = open()?;
+    store.merge()?;
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get_bytes(&[0xFE, 0])?, None);
+    assert_eq!(store.get_bytes(&[0xFE, 99])?, Some(vec![99; 32]));
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len(), 102);
 
+    // an engine of strings only takes the bytes which are utf-8
+    let sled_store = SledEngine::open(temp_dir.path().join("sled"))?;
+    sled_store.set_bytes(b"key".to_vec(), b"value".to_vec())?;
+    assert_eq!(sled_store.get_bytes(b"key")?, Some(b"value".to_vec()));
+    assert!(sled_store.set_bytes(key.clone(), value).is_err());
+    sled_store.remove_bytes(b"key")?;
+    assert_eq!(sled_store.get("key".to_owned())?, None);
     Ok(())
 }
 
+// Flags are kept with the value through overwrites, a reopen and a merge
 #[test]
-fn concurrent_get() -> Result<()> {
+fn set_and_get_with_flags() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = BitcaskEngine::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    let open = || BitcaskEngine::open_with_options(temp_dir.path(), small_file_options());
+    let store = open()?;
+    store.set_with_flags("key1".to_owned(), "value1".to_owned(), 7)?;
+    store.set_with_flags("key2".to_owned(), "value2".to_owned(), 1)?;
+    store.set_with_flags("key2".to_owned(), "value3".to_owned(), u32::MAX)?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        assert_eq!(
+            store.get_with_flags("key1".to_owned())?,
+            Some(("value1".to_owned(), 7))
+        );
+        assert_eq!(
+            store.get_with_flags("key2".to_owned())?,
+            Some(("value3".to_owned(), u32::MAX))
+        );
+        assert_eq!(
+            store.get_with_flags("key3".to_owned())?,
+            Some(("value4".to_owned(), 0))
+        );
+        assert_eq!(store.get_with_flags("key4".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    for i in 0..40 {
+        store.set(format!("filler{}", i), "x".repeat(32))?;
     }
+    store.merge()?;
+    check(&store)?;
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    Ok(())
+}
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+// Should read back values far larger than a single read returns
+#[test]
+fn get_large_value() -> Result<()> {
+    let memory_store = MemoryStore::new();
+    let open = || {
+        let options = BitcaskOptions {
+            // every read returns at most a few bytes
+            block_store: Arc::new(
+                HookStore::new(memory_store.clone()).on_read(|_, len| Ok(len.min(7))),
+            ),
+            ..Default::default()
+        };
+        BitcaskEngine::open_with_options("kvs", options)
+    };
+    let store = open()?;
+    let value: String = (0..10 * 1024)
+        .map(|i| char::from(b' ' + (i % 95) as u8))
+        .collect();
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should replay keys and values longer than 255 bytes from the log and hint files
+#[test]
+fn large_keys_survive_reopen() -> Result<()> {
+    for hint_prefix_compression in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || BitcaskOptions {
+            hint_prefix_compression,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+        let key = |i: usize| format!("{}{}", "k".repeat(1000), i);
+        let value = |i: usize| format!("{}{}", i, "v".repeat(3000));
+        for i in 0..5 {
+            store.set(key(i), "old".to_owned())?;
+            store.set(key(i), value(i))?;
+        }
+        let check = |store: &BitcaskEngine| -> Result<()> {
+            for i in 0..5 {
+                assert_eq!(store.get(key(i))?, Some(value(i)));
             }
-        });
-        handles.push(handle);
-    }
-    for handle in handles {
-        handle.join().unwrap();
+            Ok(())
+        };
+        drop(store);
+        // replayed from the log files
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+        check(&store)?;
+
+        // replayed from the hint files of the merged files
+        store.merge()?;
+        assert!(!files_with_extension(temp_dir.path(), "hint").is_empty());
+        drop(store);
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+        check(&store)?;
     }
+    Ok(())
+}
 
-    // Open from disk again and check persistent data
-    drop(store);
+// Should read a slice of a value without the rest of it
+#[test]
+fn get_value_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = BitcaskEngine::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    let value: String = (0..10 * 1024)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    store.set("key1".to_owned(), value.clone())?;
+
+    assert_eq!(
+        store.get_range("key1", 5000, 100)?,
+        Some(value.as_bytes()[5000..5100].to_vec())
+    );
+    // the range is cut at the end of the value
+    assert_eq!(
+        store.get_range("key1", 10 * 1024 - 10, 100)?,
+        Some(value.as_bytes()[10 * 1024 - 10..].to_vec())
+    );
+    assert_eq!(store.get_range("key1", 20 * 1024, 100)?, Some(Vec::new()));
+    assert_eq!(store.get_range("key2", 0, 100)?, None);
+    Ok(())
+}
+
+// Overwrite the first byte of a key with a byte which is never valid utf-8
+fn corrupt_key_byte(path: &Path, offset: u64) {
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[0xFF]).unwrap();
+}
+
+fn corruption_options(on_corruption: CorruptionPolicy) -> BitcaskOptions {
+    BitcaskOptions {
+        on_corruption,
+        ..small_file_options()
     }
-    for handle in handles {
-        handle.join().unwrap();
+}
+
+// Should fail or skip a corrupt entry in the middle of a log file, as configured
+#[test]
+fn corrupt_log_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
     }
+    drop(store);
+    // each entry takes 24 bytes of sizes, flags and checksum, 4 bytes of key and 6 bytes of value
+    corrupt_key_byte(&temp_dir.path().join("0.log"), 34 + 24);
 
+    assert!(matches!(
+        BitcaskEngine::open_with_options(
+            temp_dir.path(),
+            corruption_options(CorruptionPolicy::Fail)
+        ),
+        Err(KvStoreErr::CorruptEntry(0, 34))
+    ));
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        corruption_options(CorruptionPolicy::SkipAndContinue),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    // the entries after the corrupt one are kept, new entries are appended after them
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        corruption_options(CorruptionPolicy::SkipAndContinue),
+    )?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Should check a value against its entry's checksum, and drop a torn final entry on open
+#[test]
+fn entry_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.flush()?;
+    // the last byte of each value, which is still valid utf-8
+    let log_file = temp_dir.path().join("0.log");
+    let flip_value_byte = |entry_id: u64| {
+        let mut file = OpenOptions::new().write(true).open(&log_file).unwrap();
+        file.seek(SeekFrom::Start(entry_id * 34 + 33)).unwrap();
+        file.write_all(b"x").unwrap();
+    };
+    flip_value_byte(1);
+    assert!(matches!(
+        store.get("key2".to_owned()),
+        Err(KvStoreErr::CorruptEntry(0, 34))
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // in the middle of the file the corrupt entry follows the corruption policy
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(KvStoreErr::CorruptEntry(0, 34))
+    ));
+    flip_value_byte(2);
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        BitcaskOptions {
+            on_corruption: CorruptionPolicy::SkipAndContinue,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // at the end of the active file it's a torn write, which is dropped
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    assert_eq!(fs::metadata(&log_file)?.len(), 3 * 34);
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        BitcaskOptions {
+            on_corruption: CorruptionPolicy::SkipAndContinue,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// The index alone tells whether a key exists, so a value gone bad on disk doesn't matter
+#[test]
+fn contains_key_reads_no_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    corrupt_key_byte(&temp_dir.path().join("0.log"), 28);
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.contains_key("key3".to_owned())?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    Ok(())
+}
+
+// Should scan the live keys under a prefix with their values, every key for an empty prefix
+#[test]
+fn scan_prefix() -> Result<()> {
+    let check = |store: &dyn KvsEngine| -> Result<()> {
+        for key_id in 0..3 {
+            store.set(format!("user:{}", key_id), format!("name{}", key_id))?;
+            store.set(format!("order:{}", key_id), format!("item{}", key_id))?;
+        }
+        store.remove("user:1".to_owned())?;
+        let scan = |prefix: &str| -> Result<Vec<(String, String)>> {
+            let mut entries = store
+                .scan_prefix(prefix.to_owned())?
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+            Ok(entries)
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            scan("user:")?,
+            pairs(&[("user:0", "name0"), ("user:2", "name2")])
+        );
+        assert_eq!(
+            scan("")?,
+            pairs(&[
+                ("order:0", "item0"),
+                ("order:1", "item1"),
+                ("order:2", "item2"),
+                ("user:0", "name0"),
+                ("user:2", "name2"),
+            ])
+        );
+        assert!(scan("nobody")?.is_empty());
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    check(&store)?;
+    // the values are read as the scan goes, so a key removed meanwhile is skipped
+    let mut scan = store.scan_prefix("order:".to_owned())?;
+    let (first, _) = scan.next().unwrap()?;
+    for key_id in 0..3 {
+        let key = format!("order:{}", key_id);
+        if key != first {
+            store.remove(key)?;
+        }
+    }
+    assert!(scan.next().is_none());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// Should write a footer into each sealed file, and flag a sealed file which doesn't match it on scrub
+#[test]
+fn sealed_file_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |on_corruption| BitcaskOptions {
+        seal_footer: true,
+        ..corruption_options(on_corruption)
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options(CorruptionPolicy::Fail))?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{:03}", i))?;
+    }
+    let files = store.log_files()?;
+    assert!(files.len() > 2);
+    let entry_size = (24 + "key000".len() + "value000".len()) as u64;
+    let check_footers = |files: &[LogFileInfo]| {
+        for file in files {
+            if file.is_active {
+                assert_eq!(file.footer_entries, None);
+            } else {
+                let entries = file.footer_entries.unwrap();
+                assert!(entries > 0);
+                // the footer takes a marker, the entry count and the checksum
+                assert_eq!(entries * entry_size + 8 + 8 + 4, file.size);
+            }
+        }
+    };
+    check_footers(&files);
+    drop(store);
+
+    // intact files open, and replay stops at the footers
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options(CorruptionPolicy::Fail))?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{:03}", i))?,
+            Some(format!("value{:03}", i))
+        );
+    }
+    // the active file taken over on open is sealed too, and the files after it
+    for i in 100..200 {
+        store.set(format!("key{:03}", i), format!("value{:03}", i))?;
+    }
+    check_footers(&store.log_files()?);
+    drop(store);
+    BitcaskEngine::open_with_options(temp_dir.path(), options(CorruptionPolicy::Fail))?;
+
+    let write_at = |offset: u64, bytes: &[u8]| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join("0.log"))
+            .unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    };
+
+    // a flipped value byte: replay skips the entry, and scrub flags the file
+    write_at(24 + "key000".len() as u64, b"V");
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        options(CorruptionPolicy::SkipAndContinue),
+    )?;
+    assert_eq!(store.get("key000".to_owned())?, None);
+    assert_eq!(store.get("key099".to_owned())?, Some("value099".to_owned()));
+    assert_eq!(store.scrub()?.corrupt_files, vec![0]);
+    drop(store);
+
+    // so is an entry count which doesn't match the file
+    write_at(24 + "key000".len() as u64, b"v");
+    let len = std::fs::metadata(temp_dir.path().join("0.log"))?.len();
+    let entries = (len - 8 - 8 - 4) / entry_size;
+    write_at(len - 8 - 4, &(entries + 1).to_be_bytes());
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options(CorruptionPolicy::Fail))?;
+    let report = store.scrub()?;
+    assert!(report.corrupt_keys.is_empty());
+    assert_eq!(report.corrupt_files, vec![0]);
+    Ok(())
+}
+
+// Should fail or skip a corrupt entry in the middle of a hint file, as configured
+#[test]
+fn corrupt_hint_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for i in 0..40 {
+        store.set(format!("filler{}", i), "x".repeat(32))?;
+    }
+    store.merge()?;
+    drop(store);
+    // each hint entry takes 24 bytes of sizes and position, 4 bytes of flags and 4 bytes of key,
+    // keys may be any bytes, so point the second entry far past the end of its log file
+    let mut hint_file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.hint"))?;
+    hint_file.seek(SeekFrom::Start(32 + 16))?;
+    hint_file.write_all(&[0xFF])?;
+    drop(hint_file);
+
+    assert!(matches!(
+        BitcaskEngine::open_with_options(
+            temp_dir.path(),
+            corruption_options(CorruptionPolicy::Fail)
+        ),
+        Err(KvStoreErr::CorruptEntry(0, 32))
+    ));
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        corruption_options(CorruptionPolicy::SkipAndContinue),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("filler0".to_owned())?, Some("x".repeat(32)));
+    Ok(())
+}
+
+// Should fail on a hint file which can't be decoded, or replay its log file, as configured
+#[test]
+fn undecodable_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for i in 0..40 {
+        store.set(format!("filler{}", i), "x".repeat(32))?;
+    }
+    store.merge()?;
+    drop(store);
+    // cut the hint file in the middle of its second entry
+    OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.hint"))?
+        .set_len(32 + 10)?;
+
+    assert!(matches!(
+        BitcaskEngine::open_with_options(
+            temp_dir.path(),
+            corruption_options(CorruptionPolicy::Fail)
+        ),
+        Err(KvStoreErr::CorruptEntry(0, 32))
+    ));
+    let store = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        corruption_options(CorruptionPolicy::SkipAndContinue),
+    )?;
+    for key_id in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("filler39".to_owned())?, Some("x".repeat(32)));
+    Ok(())
+}
+
+// Scans of the log files fail on an entry they can't decode, rather than ending the file there
+#[test]
+fn scans_fail_on_undecodable_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for i in 0..40 {
+        store.set(format!("filler{}", i), "x".repeat(32))?;
+    }
+    // a key size of key2 reaching far past the end of the sealed file
+    let mut log_file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.log"))?;
+    log_file.seek(SeekFrom::Start(34))?;
+    log_file.write_all(&[0x7F])?;
+    drop(log_file);
+
+    assert!(store.merge_dry_run().is_err());
+    assert!(store.get_with_tombstone("missing").is_err());
+    // the merge would have dropped the entries after key2
+    assert!(store.merge().is_err());
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A single file merge fails on an undecodable entry too, even one which is no longer live
+#[test]
+fn single_file_merge_fails_on_undecodable_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        single_file_merge: true,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // more than a file of writes, but few enough live entries to fit in one
+    for _ in 0..3 {
+        for i in 0..10 {
+            store.set(format!("filler{}", i), "x".repeat(32))?;
+        }
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut log_file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.log"))?;
+    log_file.seek(SeekFrom::Start(34))?;
+    log_file.write_all(&[0x7F])?;
+    drop(log_file);
+
+    assert!(store.merge().is_err());
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should fail with a descriptive error rather than a short value when the index points past
+// the end of a file
+#[test]
+fn value_out_of_bounds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    let log_path = temp_dir.path().join("0.log");
+    let len = fs::metadata(&log_path)?.len();
+    // cut the file in the middle of the value of key2
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    match store.get("key2".to_owned()) {
+        Err(KvStoreErr::ValueOutOfBounds {
+            key,
+            file_id,
+            v_pos,
+            v_size,
+            file_len,
+        }) => {
+            assert_eq!(key, "key2");
+            assert_eq!(file_id, 0);
+            assert_eq!(v_pos, len);
+            assert_eq!(v_size, 6);
+            assert_eq!(file_len, len - 3);
+        }
+        other => panic!("expected ValueOutOfBounds, got {:?}", other),
+    }
+    assert!(matches!(
+        store.get_range("key2", 0, 6),
+        Err(KvStoreErr::ValueOutOfBounds { .. })
+    ));
+    // the part of the value still in the file and the other values read fine
+    assert_eq!(store.get_range("key2", 0, 3)?, Some(b"val".to_vec()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should load the valid prefix of a log whose last entry was only partially written
+#[test]
+fn load_log_with_partial_final_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // simulate a crash in the middle of appending an entry: full key size, half of value size
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("0.log"))?;
+    file.write_all(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0])?;
+    drop(file);
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // new entries are appended after the valid prefix and survive another reopen
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A read error in the middle of a log file fails the open instead of dropping the entries after it
+#[test]
+fn read_error_fails_replay() -> Result<()> {
+    let memory_store = MemoryStore::new();
+    // while positive, the read of the log which fails, counting down
+    let failing_read = Arc::new(AtomicUsize::new(0));
+    let open = || {
+        let failing_read = failing_read.clone();
+        let block_store = HookStore::new(memory_store.clone()).on_read(move |path, len| {
+            if path.extension() == Some("log".as_ref())
+                && failing_read
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    == Ok(1)
+            {
+                return Err(io::Error::other("injected read failure"));
+            }
+            Ok(len.min(7))
+        });
+        let options = BitcaskOptions {
+            block_store: Arc::new(block_store),
+            ..Default::default()
+        };
+        BitcaskEngine::open_with_options("kvs", options)
+    };
+    let store = open()?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    failing_read.store(10, Ordering::SeqCst);
+    assert!(matches!(open(), Err(KvStoreErr::IOErr(_))));
+    let store = open()?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // small enough that the writes below rotate and merge the files
+    let options = BitcaskOptions {
+        log_file_max_bytes: 1024 * 1024,
+        merge_trigger_threshold: 1024 * 1024,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert!(store.increment("text".to_owned(), 1).is_err());
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..100 {
+                store.increment("shared".to_owned(), 1).unwrap();
+                store
+                    .increment(format!("key{}", (thread_id * 100 + i) % 10), 2)
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("shared".to_owned())?, Some("800".to_owned()));
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("160".to_owned()));
+    }
+    assert_eq!(store.increment("shared".to_owned(), -801)?, -1);
+    Ok(())
+}
+
+// Compare-and-set and append are atomic like increment, and a plain set never lands between
+// the read and the write of one of them
+#[test]
+fn concurrent_compare_and_set_and_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert!(store.compare_and_set("cas".to_owned(), None, "0".to_owned())?);
+    assert!(!store.compare_and_set("cas".to_owned(), None, "1".to_owned())?);
+    assert!(!store.compare_and_set("cas".to_owned(), Some("1"), "2".to_owned())?);
+    assert_eq!(store.get("cas".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.append("appended".to_owned(), "ab")?, 2);
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                // retry until no other thread got in between
+                loop {
+                    let current = store.get("cas".to_owned()).unwrap().unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    if store
+                        .compare_and_set("cas".to_owned(), Some(&current), next)
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+                store.append("appended".to_owned(), "x").unwrap();
+                store.increment("counter".to_owned(), 1).unwrap();
+            }
+        }));
+    }
+    // a set racing the increments is never lost to one which read the value before it
+    thread::sleep(Duration::from_millis(1));
+    store.set("counter".to_owned(), "1000000".to_owned())?;
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("cas".to_owned())?, Some("400".to_owned()));
+    assert_eq!(
+        store.get("appended".to_owned())?,
+        Some(format!("ab{}", "x".repeat(400)))
+    );
+    let counter: u64 = store.get("counter".to_owned())?.unwrap().parse().unwrap();
+    assert!((1_000_000..=1_000_400).contains(&counter));
+    Ok(())
+}
+
+// A get which starts after a set returned on another thread must see that set
+#[test]
+fn linearizable_set_then_get_across_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let (sender, receiver) = mpsc::channel();
+    let (ack_sender, ack_receiver) = mpsc::channel();
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..1000 {
+                store.set(format!("key{}", i % 10), format!("value{}", i))?;
+                if i % 3 == 0 {
+                    store.remove(format!("key{}", i % 10))?;
+                    sender.send((i, None)).unwrap();
+                } else {
+                    sender.send((i, Some(format!("value{}", i)))).unwrap();
+                }
+                // wait for the reader before the key is written again
+                ack_receiver.recv().unwrap();
+            }
+            Ok::<_, KvStoreErr>(())
+        })
+    };
+    for (i, expected) in receiver {
+        assert_eq!(store.get(format!("key{}", i % 10))?, expected);
+        ack_sender.send(()).unwrap();
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// Readers must never observe a value older than one they already read
+#[test]
+fn reads_never_go_backwards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                while last < 2000 {
+                    let value: u64 = store.get("counter".to_owned())?.unwrap().parse().unwrap();
+                    assert!(value >= last, "read {} after {}", value, last);
+                    last = value;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 1..=2000 {
+        store.set("counter".to_owned(), i.to_string())?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Racing writes to the same keys must leave the index in the order of the log,
+// so the values read before and after a reopen agree
+#[test]
+fn concurrent_overwrites_agree_with_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..200 {
+                    let key = format!("key{}", i % 4);
+                    if (thread_id + i) % 7 == 0 {
+                        let _ = store.remove(key);
+                    } else {
+                        store.set(key, format!("value{}_{}", thread_id, i))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let before: Vec<_> = (0..4)
+        .map(|i| store.get(format!("key{}", i)))
+        .collect::<Result<_>>()?;
+
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let after: Vec<_> = (0..4)
+        .map(|i| store.get(format!("key{}", i)))
+        .collect::<Result<_>>()?;
+    assert_eq!(before, after);
+    Ok(())
+}
+
+// Should copy exactly the live data into a new directory and leave the source as it was
+#[test]
+fn compact_into_new_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source_dir = temp_dir.path().join("source");
+    let target_dir = temp_dir.path().join("target");
+    let store = BitcaskEngine::open_with_options(&source_dir, small_file_options())?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in 0..5 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set_with_flags("key5".to_owned(), "flagged".to_owned(), 3)?;
+    // compact_into flushes the active file first
+    store.flush()?;
+    let source_files = files_with_extension(&source_dir, "log");
+    let source_size = log_files_size(&source_dir);
+
+    store.compact_into(&target_dir)?;
+    assert_eq!(files_with_extension(&source_dir, "log"), source_files);
+    assert_eq!(log_files_size(&source_dir), source_size);
+    assert!(log_files_size(&target_dir) < source_size);
+    // the source keeps serving
+    store.set("key6".to_owned(), "after".to_owned())?;
+    assert!(store.compact_into(&target_dir).is_err());
+
+    let copy = BitcaskEngine::open_with_options(&target_dir, small_file_options())?;
+    let check = |copy: &BitcaskEngine| -> Result<()> {
+        for key_id in 0..5 {
+            assert_eq!(copy.get(format!("key{}", key_id))?, None);
+        }
+        assert_eq!(
+            copy.get_with_flags("key5".to_owned())?,
+            Some(("flagged".to_owned(), 3))
+        );
+        for key_id in 6..20 {
+            assert_eq!(
+                copy.get(format!("key{}", key_id))?,
+                Some("value9".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check(&copy)?;
+    assert_eq!(copy.scrub()?.scanned_keys, 15);
+
+    // the copy is a store of its own
+    copy.set("key20".to_owned(), "value20".to_owned())?;
+    drop(copy);
+    let copy = BitcaskEngine::open_with_options(&target_dir, small_file_options())?;
+    check(&copy)?;
+    assert_eq!(copy.get("key20".to_owned())?, Some("value20".to_owned()));
+    assert_eq!(store.get("key20".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn merge_dry_run_estimates_reclaimed_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let size_before = log_files_size(temp_dir.path());
+    let estimate = store.merge_dry_run()?;
+    assert!(!estimate.files_to_rewrite.is_empty());
+    assert!(estimate.reclaimable_bytes > 0);
+    assert_eq!(
+        estimate.input_bytes,
+        estimate.estimated_output_bytes + estimate.reclaimable_bytes
+    );
+    // the dry run doesn't modify anything
+    assert_eq!(log_files_size(temp_dir.path()), size_before);
+    assert_eq!(store.merge_dry_run()?, estimate);
+
+    store.merge()?;
+    assert_eq!(
+        size_before - log_files_size(temp_dir.path()),
+        estimate.reclaimable_bytes
+    );
+    for key_id in 1..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    // with the log files removed behind the engine there is nothing to merge
+    for name in files_with_extension(temp_dir.path(), "log") {
+        fs::remove_file(temp_dir.path().join(name))?;
+    }
+    let estimate = store.merge_dry_run()?;
+    assert!(estimate.files_to_rewrite.is_empty());
+    assert_eq!(estimate.input_bytes, 0);
+    Ok(())
+}
+
+// A dry run during merges never finds the files it lists removed
+#[test]
+fn merge_dry_run_during_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let merger = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..20 {
+                for key_id in 0..50 {
+                    store.set(format!("key{}", key_id), format!("value{}", iter))?;
+                }
+                store.merge()?;
+            }
+            Ok(())
+        })
+    };
+    while !merger.is_finished() {
+        store.merge_dry_run()?;
+    }
+    merger.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn split_log_and_hint_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join("log");
+    let hint_dir = temp_dir.path().join("hint");
+    let options = || BitcaskOptions {
+        hint_dir: Some(hint_dir.clone()),
+        // every merged entry is overwritten later, keep a merged file to look at
+        empty_merged_files: EmptyMergedFiles::KeepOne,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(&log_dir, options())?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.merge()?;
+
+    assert!(!files_with_extension(&log_dir, "log").is_empty());
+    assert!(files_with_extension(&log_dir, "hint").is_empty());
+    assert!(!files_with_extension(&hint_dir, "hint").is_empty());
+    assert!(files_with_extension(&hint_dir, "log").is_empty());
+
+    drop(store);
+    let store = BitcaskEngine::open_with_options(&log_dir, options())?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn cancel_merge_and_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cancel_token = CancellationToken::new();
+    let options = BitcaskOptions {
+        cancel_token: cancel_token.clone(),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let log_files = files_with_extension(temp_dir.path(), "log");
+    let size = log_files_size(temp_dir.path());
+
+    cancel_token.cancel();
+    assert!(matches!(store.merge(), Err(KvStoreErr::Cancelled)));
+    // the original files are untouched and the temp files are cleaned up
+    assert_eq!(files_with_extension(temp_dir.path(), "log"), log_files);
+    assert_eq!(log_files_size(temp_dir.path()), size);
+    assert!(files_with_extension(temp_dir.path(), "temp").is_empty());
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    drop(store);
+    assert!(matches!(
+        BitcaskEngine::open_with_options(temp_dir.path(), options),
+        Err(KvStoreErr::Cancelled)
+    ));
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Store whose files share one budget of bytes which can still be written, like a disk filling up
+fn disk_full_store(inner: MemoryStore, budget: Arc<AtomicUsize>) -> HookStore {
+    HookStore::new(inner).on_write(move |_, len| {
+        let budget = budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |budget| {
+                Some(budget - budget.min(len))
+            })
+            .unwrap();
+        if budget == 0 {
+            return Err(io::Error::other("no space left on device"));
+        }
+        Ok(len.min(budget))
+    })
+}
+
+// A write failing half way must leave the index and the log as they were before it
+#[test]
+fn set_on_full_disk() -> Result<()> {
+    let memory_store = MemoryStore::new();
+    let budget = Arc::new(AtomicUsize::new(usize::MAX));
+    let open = || {
+        let options = BitcaskOptions {
+            block_store: Arc::new(disk_full_store(memory_store.clone(), budget.clone())),
+            ..Default::default()
+        };
+        BitcaskEngine::open_with_options("kvs", options)
+    };
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+
+    // the first write is torn after 10 bytes, the next ones fail right away,
+    // the values don't fit into the write buffer so they go to the file at once
+    let large_value = "x".repeat(64 * 1024);
+    budget.store(10, Ordering::SeqCst);
+    assert!(store.set("key1".to_owned(), large_value.clone()).is_err());
+    assert!(store.set("key3".to_owned(), large_value).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // once there is space again, new entries follow the last complete one
+    budget.store(usize::MAX, Ordering::SeqCst);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(store.scrub()?.corrupt_keys.is_empty());
+    Ok(())
+}
+
+// What a store recording syncs synced, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Synced {
+    // the path of the file and its size when synced
+    File(PathBuf, u64),
+    Dir(PathBuf),
+}
+
+// Store recording the syncs of its files and directories
+fn sync_recording_store(synced: Arc<Mutex<Vec<Synced>>>) -> HookStore {
+    let synced_dirs = synced.clone();
+    HookStore::new(MemoryStore::new())
+        .on_sync(move |path, size| {
+            synced
+                .lock()
+                .unwrap()
+                .push(Synced::File(path.to_path_buf(), size))
+        })
+        .on_sync_dir(move |dir| {
+            synced_dirs
+                .lock()
+                .unwrap()
+                .push(Synced::Dir(dir.to_path_buf()))
+        })
+}
+
+#[test]
+fn sync_on_rotation() -> Result<()> {
+    let write = |sync_on_rotation: bool| -> Result<(BitcaskEngine, Vec<Synced>)> {
+        let synced = Arc::new(Mutex::new(Vec::new()));
+        let options = BitcaskOptions {
+            block_store: Arc::new(sync_recording_store(synced.clone())),
+            sync_on_rotation,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options("kvs", options)?;
+        // a little more than fits in one file
+        for key_id in 0..40 {
+            store.set(format!("key{:02}", key_id), "value".to_owned())?;
+        }
+        let synced = synced.lock().unwrap().clone();
+        Ok((store, synced))
+    };
+
+    let (store, synced) = write(true)?;
+    let files = store.log_files()?;
+    assert_eq!(files.len(), 2);
+    // the whole sealed file, then the directory holding the new active file
+    assert_eq!(
+        synced,
+        vec![
+            Synced::File(PathBuf::from("kvs/0.log"), files[0].size),
+            Synced::Dir(PathBuf::from("kvs")),
+        ]
+    );
+
+    let (_, synced) = write(false)?;
+    assert!(synced.is_empty());
+    Ok(())
+}
+
+// Store failing the renames which move merged files in place, while `failures` is positive
+fn flaky_rename_store(inner: MemoryStore, failures: Arc<AtomicUsize>) -> HookStore {
+    HookStore::new(inner).on_rename(move |from, _| {
+        let installing = from.extension() == Some("temp".as_ref());
+        if installing
+            && failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+        {
+            return Err(KvStoreErr::IOErr(io::Error::other(
+                "injected rename failure",
+            )));
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn retry_failed_merge() -> Result<()> {
+    let memory_store = MemoryStore::new();
+    let failures = Arc::new(AtomicUsize::new(0));
+    let open = |merge_retries| {
+        let options = BitcaskOptions {
+            block_store: Arc::new(flaky_rename_store(memory_store.clone(), failures.clone())),
+            merge_retries,
+            // every merged entry is overwritten later, the merge still writes a file to rename
+            empty_merged_files: EmptyMergedFiles::KeepOne,
+            ..small_file_options()
+        };
+        BitcaskEngine::open_with_options("kvs", options)
+    };
+    let file_names = || -> Result<Vec<String>> {
+        let mut names: Vec<String> = memory_store
+            .list(Path::new("kvs"))?
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        Ok(names)
+    };
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for key_id in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value9".to_owned())
+            );
+        }
+        Ok(())
+    };
+
+    let store = open(0)?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let files = file_names()?;
+
+    // without retries the merge fails and the original files are put back
+    failures.store(1, Ordering::SeqCst);
+    assert!(store.merge().is_err());
+    assert_eq!(file_names()?, files);
+    check(&store)?;
+    drop(store);
+    let store = open(0)?;
+    check(&store)?;
+    drop(store);
+
+    // a transient failure is retried
+    failures.store(1, Ordering::SeqCst);
+    let store = open(1)?;
+    store.merge()?;
+    assert_eq!(failures.load(Ordering::SeqCst), 0);
+    let files = file_names()?;
+    assert!(files.iter().any(|name| name.ends_with(".hint")));
+    assert!(!files
+        .iter()
+        .any(|name| name.ends_with(".temp") || name.ends_with(".old")));
+    check(&store)?;
+    drop(store);
+    let store = open(0)?;
+    check(&store)?;
+    Ok(())
+}
+
+// A crash between the renames which install a merge is completed on the next open
+#[test]
+fn crash_during_merge_install() -> Result<()> {
+    for single_file_merge in [false, true] {
+        let mut crashed = 0;
+        for renames in 0.. {
+            let memory_store = MemoryStore::new();
+            let allowed = Arc::new(AtomicUsize::new(usize::MAX));
+            // every rename after the allowed ones fails, so does putting the files back
+            let allowed_renames = allowed.clone();
+            let store = HookStore::new(memory_store.clone()).on_rename(move |_, _| {
+                allowed_renames
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .map(|_| ())
+                    .map_err(|_| KvStoreErr::IOErr(io::Error::other("crashed")))
+            });
+            let options = BitcaskOptions {
+                single_file_merge,
+                ..small_file_options()
+            };
+            let engine = BitcaskEngine::open_with_options(
+                "kvs",
+                BitcaskOptions {
+                    block_store: Arc::new(store),
+                    ..options.clone()
+                },
+            )?;
+            for iter in 0..10 {
+                for key_id in 0..20 {
+                    engine.set(format!("key{}", key_id), format!("value{}", iter))?;
+                }
+            }
+            // what was written before the crash is in the files
+            engine.flush()?;
+            allowed.store(renames, Ordering::SeqCst);
+            let merged = engine.merge().is_ok();
+            // the process dies without cleaning up
+            std::mem::forget(engine);
+
+            let engine = BitcaskEngine::open_with_options(
+                "kvs",
+                BitcaskOptions {
+                    block_store: Arc::new(memory_store.clone()),
+                    ..options
+                },
+            )?;
+            for key_id in 0..20 {
+                assert_eq!(
+                    engine.get(format!("key{}", key_id))?,
+                    Some("value9".to_owned())
+                );
+            }
+            let leftovers: Vec<PathBuf> = memory_store
+                .list(Path::new("kvs"))?
+                .into_iter()
+                .filter(|path| {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    name.ends_with(".old") || name.ends_with(".temp") || name == "install"
+                })
+                .collect();
+            assert!(leftovers.is_empty(), "{:?}", leftovers);
+            if merged {
+                break;
+            }
+            crashed += 1;
+        }
+        assert!(crashed > 2);
+    }
+    Ok(())
+}
+
+// Backups left by an install which completed before a crash are removed on open
+#[test]
+fn remove_stale_merge_backups_on_open() -> Result<()> {
+    let memory_store = MemoryStore::new();
+    let options = BitcaskOptions {
+        block_store: Arc::new(memory_store.clone()),
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options("kvs", options.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let mut backup = memory_store.open_append(Path::new("kvs/7.log.old"))?;
+    backup.write_all(b"stale")?;
+    drop(backup);
+
+    let store = BitcaskEngine::open_with_options("kvs", options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(!memory_store.exists(Path::new("kvs/7.log.old")));
+    Ok(())
+}
+
+#[test]
+fn values_aligned_in_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_alignment: 8,
+        ..small_file_options()
+    };
+    let open = || BitcaskEngine::open_with_options(temp_dir.path(), options.clone());
+    let check_aligned = || {
+        let logs: Vec<Vec<u8>> = files_with_extension(temp_dir.path(), "log")
+            .iter()
+            .map(|name| fs::read(temp_dir.path().join(name)).unwrap())
+            .collect();
+        for key_id in 0..50 {
+            let value = format!("aligned-value-{:03}", key_id);
+            let offset = logs
+                .iter()
+                .find_map(|log| {
+                    log.windows(value.len())
+                        .position(|window| window == value.as_bytes())
+                })
+                .expect("value should be in a log file");
+            assert_eq!(offset % 8, 0, "{} starts at {}", value, offset);
+        }
+    };
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get("k".repeat(key_id % 7 + 1) + &key_id.to_string())?,
+                Some(format!("aligned-value-{:03}", key_id))
+            );
+        }
+        Ok(())
+    };
+
+    let store = open()?;
+    for key_id in 0..50 {
+        // keys of varying length shift the values around
+        let key = "k".repeat(key_id % 7 + 1) + &key_id.to_string();
+        store.set(key.clone(), "stale".to_owned())?;
+        store.set(key, format!("aligned-value-{:03}", key_id))?;
+    }
+    check(&store)?;
+    store.flush()?;
+    check_aligned();
+    drop(store);
+
+    let store = open()?;
+    check(&store)?;
+    store.merge()?;
+    check(&store)?;
+    check_aligned();
+    drop(store);
+
+    let store = open()?;
+    check(&store)?;
+    Ok(())
+}
+
+#[test]
+fn merge_write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.stats(), EngineStats::default());
+    assert_eq!(store.stats().merge_write_amplification(), 0.0);
+
+    // live entries in the old files, which the merge rewrites
+    for key_id in 0..10 {
+        store.set(format!("filler{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let before_merge = log_files_size(temp_dir.path());
+    store.merge()?;
+    let reclaimed = before_merge - log_files_size(temp_dir.path());
+
+    let stats = store.stats();
+    assert_eq!(stats.merge_bytes_reclaimed, reclaimed);
+    // most of the log was overwritten, merge writes far less than it reclaims
+    assert!(stats.merge_bytes_written > 0);
+    let amplification = stats.merge_write_amplification();
+    assert!(
+        amplification > 0.0 && amplification < 1.0,
+        "{}",
+        amplification
+    );
+
+    // the counters accumulate over merges
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".repeat(30))?;
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.merge()?;
+    assert!(store.stats().merge_bytes_written > stats.merge_bytes_written);
+    assert!(store.stats().merge_bytes_reclaimed > stats.merge_bytes_reclaimed);
+    Ok(())
+}
+
+#[test]
+fn always_tombstone_on_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        always_tombstone_on_remove: true,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    let size = log_files_size(temp_dir.path());
+
+    // a never seen key still gets a tombstone
+    store.remove("never-seen".to_owned())?;
+    store.flush()?;
+    let tombstone_size = log_files_size(temp_dir.path()) - size;
+    assert_eq!(tombstone_size, 24 + "never-seen".len() as u64 + 1);
+    let log = fs::read(temp_dir.path().join("0.log"))?;
+    assert!(log.windows(10).any(|window| window == b"never-seen"));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("never-seen".to_owned())?, None);
+    drop(store);
+
+    // by default removing an absent key fails and writes nothing
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let size = log_files_size(temp_dir.path());
+    assert!(matches!(
+        store.remove("never-seen".to_owned()),
+        Err(KvStoreErr::KeyNotFound(_))
+    ));
+    store.flush()?;
+    assert_eq!(log_files_size(temp_dir.path()), size);
+    Ok(())
+}
+
+#[test]
+fn check_active_file_flags_external_truncate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        check_active_file_on_open: true,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.check_active_file()?;
+
+    // truncate the active file behind the engine's back
+    let active_file = files_with_extension(temp_dir.path(), "log").pop().unwrap();
+    let file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join(&active_file))?;
+    let original = fs::read(temp_dir.path().join(&active_file))?;
+    let len = original.len() as u64;
+    file.set_len(len - 3)?;
+    assert!(matches!(
+        store.check_active_file(),
+        Err(KvStoreErr::ActiveFileMismatch(_, expected, actual)) if expected == len && actual == len - 3
+    ));
+
+    // appending behind its back is caught as well
+    file.set_len(len + 5)?;
+    assert!(matches!(
+        store.check_active_file(),
+        Err(KvStoreErr::ActiveFileMismatch(_, expected, actual)) if expected == len && actual == len + 5
+    ));
+
+    // the length is right again, but the tail of the last entry was zeroed
+    file.set_len(len)?;
+    assert!(matches!(
+        store.check_active_file(),
+        Err(KvStoreErr::CorruptEntry(_, _))
+    ));
+    fs::write(temp_dir.path().join(&active_file), &original)?;
+    store.check_active_file()?;
+    drop(store);
+
+    // replay drops the torn tail, so the check on open passes and the intact entries are kept
+    file.set_len(len - 3)?;
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.check_active_file()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn orphaned_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+
+    let orphaned = store.orphaned_files()?;
+    // the first file only holds dead entries, but its tombstone keeps "gone" removed
+    assert!(!orphaned.is_empty());
+    assert!(!orphaned.contains(&0));
+    for file in store.log_files()? {
+        if orphaned.contains(&file.file_id) {
+            assert!(!file.is_active);
+            assert_eq!(file.dead_bytes, file.size);
+        }
+    }
+
+    // removing them loses nothing
+    drop(store);
+    for id in &orphaned {
+        fs::remove_file(temp_dir.path().join(format!("{}.log", id)))?;
+    }
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert!(store.orphaned_files()?.is_empty());
+    Ok(())
+}
+
+// A merge of nothing but dead entries leaves no empty files behind, unless asked to keep one
+#[test]
+fn merge_all_garbage() -> Result<()> {
+    for empty_merged_files in [EmptyMergedFiles::Remove, EmptyMergedFiles::KeepOne] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || BitcaskOptions {
+            empty_merged_files,
+            merge_threads: 2,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+        for iter in 0..5 {
+            for key_id in 0..20 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        for key_id in 0..20 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        // the tombstones stay in the active file, which the merge leaves alone
+        let active_id = store.log_files()?.last().unwrap().file_id;
+        assert!(active_id > 3);
+        store.merge()?;
+
+        let expected_logs = match empty_merged_files {
+            EmptyMergedFiles::Remove => vec![format!("{}.log", active_id)],
+            EmptyMergedFiles::KeepOne => vec!["0.log".to_owned(), format!("{}.log", active_id)],
+        };
+        let expected_hints = match empty_merged_files {
+            EmptyMergedFiles::Remove => vec![],
+            EmptyMergedFiles::KeepOne => vec!["0.hint".to_owned()],
+        };
+        let check_files = || {
+            assert_eq!(files_with_extension(temp_dir.path(), "log"), expected_logs);
+            assert_eq!(
+                files_with_extension(temp_dir.path(), "hint"),
+                expected_hints
+            );
+            assert!(files_with_extension(temp_dir.path(), "temp").is_empty());
+            assert!(files_with_extension(temp_dir.path(), "old").is_empty());
+        };
+        check_files();
+        assert_eq!(store.reader_file_ids().len(), expected_logs.len());
+        assert!(store.is_empty());
+        drop(store);
+
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+        assert!(store.is_empty());
+        assert_eq!(store.get("key0".to_owned())?, None);
+        check_files();
+        store.set("key0".to_owned(), "again".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("again".to_owned()));
+    }
+    Ok(())
+}
+
+// The readers follow the log files through merges and files removed behind the engine's back
+#[test]
+fn prune_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let log_file_ids =
+        || -> Result<Vec<u64>> { Ok(store.log_files()?.iter().map(|file| file.file_id).collect()) };
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(store.reader_file_ids().len() > 3);
+    assert_eq!(store.reader_file_ids(), log_file_ids()?);
+
+    // many files merged into few
+    store.merge()?;
+    assert_eq!(store.reader_file_ids(), log_file_ids()?);
+    assert!(store.prune_readers()?.is_empty());
+
+    for iter in 20..40 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let orphaned = store.orphaned_files()?;
+    assert!(!orphaned.is_empty());
+    for id in &orphaned {
+        fs::remove_file(temp_dir.path().join(format!("{}.log", id)))?;
+    }
+    assert_eq!(store.prune_readers()?, orphaned);
+    assert_eq!(store.reader_file_ids(), log_file_ids()?);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value39".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Only the rewritten file changes, the others keep their bytes
+#[test]
+fn rewrite_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..3 {
+        store.set(format!("live{}", key_id), format!("value{}", key_id))?;
+    }
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let read_logs = || -> Vec<(String, Vec<u8>)> {
+        files_with_extension(temp_dir.path(), "log")
+            .into_iter()
+            .map(|name| {
+                let bytes = fs::read(temp_dir.path().join(&name)).unwrap();
+                (name, bytes)
+            })
+            .collect()
+    };
+    let before = read_logs();
+    assert!(before.len() > 3);
+
+    store.rewrite_file(0)?;
+    let after = read_logs();
+    assert_eq!(after.len(), before.len());
+    assert!(after[0].1.len() < before[0].1.len() / 2);
+    assert_eq!(after[1..], before[1..]);
+    assert_eq!(
+        files_with_extension(temp_dir.path(), "hint"),
+        vec!["0.hint"]
+    );
+    assert!(files_with_extension(temp_dir.path(), "temp").is_empty());
+
+    // the active file and missing files can't be rewritten
+    let active_id = store.log_files()?.last().unwrap().file_id;
+    assert!(store.rewrite_file(active_id).is_err());
+    assert!(store.rewrite_file(active_id + 1).is_err());
+
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for key_id in 0..3 {
+            assert_eq!(
+                store.get(format!("live{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value19".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        small_file_options(),
+    )?)
+}
+
+// A file after the oldest one keeps the tombstones and expiring entries which shadow older files
+#[test]
+fn rewrite_middle_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let active_file_id =
+        |store: &BitcaskEngine| -> Result<u64> { Ok(store.log_files()?.last().unwrap().file_id) };
+    let fill = |store: &BitcaskEngine| -> Result<()> {
+        let file_id = active_file_id(store)?;
+        let mut i = 0;
+        while active_file_id(store)? == file_id {
+            store.set(format!("filler{}", i % 4), "x".repeat(32))?;
+            i += 1;
+        }
+        Ok(())
+    };
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.set("expiring".to_owned(), "old".to_owned())?;
+    fill(&store)?;
+    let tombstone_file = active_file_id(&store)?;
+    store.remove("gone".to_owned())?;
+    fill(&store)?;
+    let expiring_file = active_file_id(&store)?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "new".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    fill(&store)?;
+    let plain_file = active_file_id(&store)?;
+    fill(&store)?;
+
+    assert!(store.rewrite_file(tombstone_file).is_err());
+    assert!(store.rewrite_file(expiring_file).is_err());
+    store.rewrite_file(plain_file)?;
+    drop(store);
+
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("expiring".to_owned())?, Some("new".to_owned()));
+    // the oldest file has nothing below it to shadow
+    store.rewrite_file(0)?;
+    Ok(())
+}
+
+#[test]
+fn list_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..10 {
+        store.set(format!("filler{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+
+    let check_sizes = |files: &[LogFileInfo]| {
+        let ids: Vec<u64> = files.iter().map(|file| file.file_id).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+        assert_eq!(
+            files.iter().filter(|file| file.is_active).count(),
+            1,
+            "{:?}",
+            files
+        );
+        assert!(files.last().unwrap().is_active);
+        assert_eq!(
+            files.iter().map(|file| file.size).sum::<u64>(),
+            log_files_size(temp_dir.path())
+        );
+    };
+
+    let files = store.log_files()?;
+    store.flush()?;
+    assert!(files.len() > 2);
+    check_sizes(&files);
+    assert!(files.iter().all(|file| !file.has_hint));
+    // the old files are mostly overwritten values
+    assert!(files[1].dead_bytes > files[1].size / 2);
+    let active_file_id = files.last().unwrap().file_id;
+
+    store.merge()?;
+    let files = store.log_files()?;
+    check_sizes(&files);
+    let (active, merged) = files.split_last().unwrap();
+    assert_eq!(active.file_id, active_file_id);
+    assert!(!active.has_hint);
+    assert!(!merged.is_empty());
+    for file in merged {
+        assert!(file.has_hint);
+        assert_eq!(file.dead_bytes, 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn hint_prefix_compression() -> Result<()> {
+    let key = |key_id: usize| format!("user:12345:sessions:2023-01-01:{:04}", key_id);
+    let hint_files_size = |dir: &Path| -> u64 {
+        files_with_extension(dir, "hint")
+            .iter()
+            .map(|name| fs::metadata(dir.join(name)).unwrap().len())
+            .sum()
+    };
+    let merged_hint_size = |hint_prefix_compression: bool| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            hint_prefix_compression,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store.set(key(key_id), format!("value{}", iter))?;
+            }
+        }
+        store.merge()?;
+        drop(store);
+
+        // reopen from the hint files
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(key(key_id))?, Some("value2".to_owned()));
+        }
+        assert_eq!(store.get(key(100))?, None);
+        Ok(hint_files_size(temp_dir.path()))
+    };
+
+    let plain = merged_hint_size(false)?;
+    let front_coded = merged_hint_size(true)?;
+    assert!(plain > 0);
+    assert!(front_coded < plain * 3 / 4, "{} vs {}", front_coded, plain);
+    Ok(())
+}
+
+#[test]
+fn config_conflict_on_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compressed = BitcaskOptions {
+        hint_prefix_compression: true,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), compressed.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("config").exists());
+
+    let result = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options());
+    match result {
+        Err(KvStoreErr::ConfigConflict(setting, stored, passed)) => {
+            assert_eq!(setting, "hint_prefix_compression");
+            assert_eq!(stored, "true");
+            assert_eq!(passed, "false");
+        }
+        other => panic!("expected a config conflict, got {:?}", other.map(|_| ())),
+    }
+
+    // the same settings open fine
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), compressed)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Log files from before the config file have the default codec
+#[test]
+fn varint_on_logs_without_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("config"))?;
+
+    let varint = BitcaskOptions {
+        entry_format: EntryFormat::Varint,
+        ..BitcaskOptions::default()
+    };
+    match BitcaskEngine::open_with_options(temp_dir.path(), varint) {
+        Err(KvStoreErr::ConfigConflict(setting, stored, passed)) => {
+            assert_eq!(setting, "codec");
+            assert_eq!(stored, "default");
+            assert_eq!(passed, "varint");
+        }
+        other => panic!("expected a config conflict, got {:?}", other.map(|_| ())),
+    }
+    assert!(!temp_dir.path().join("config").exists());
+
+    // the default codec reads them and records the config
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(temp_dir.path().join("config").exists());
+    Ok(())
+}
+
+#[test]
+fn varint_entry_format() -> Result<()> {
+    let write_small_records = |options: BitcaskOptions| -> Result<(TempDir, u64)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i), format!("v{}", i))?;
+        }
+        drop(store);
+        let size = log_files_size(temp_dir.path());
+        Ok((temp_dir, size))
+    };
+    let (_, fixed_size) = write_small_records(BitcaskOptions::default())?;
+    let varint = BitcaskOptions {
+        entry_format: EntryFormat::Varint,
+        ..small_file_options()
+    };
+    let (temp_dir, varint_size) = write_small_records(varint.clone())?;
+    // each entry saves 14 of its 24 header bytes
+    assert_eq!(fixed_size - varint_size, 1000 * 14);
+
+    // the files replay, merge into hint files and load from those
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), varint.clone())?;
+    for i in (0..1000).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+    store.merge()?;
+    assert!(!files_with_extension(temp_dir.path(), "hint").is_empty());
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), varint.clone())?;
+    for i in 0..1000 {
+        let expected = (i % 2 == 1).then(|| format!("v{}", i));
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    drop(store);
+
+    // the format is part of the config of the store
+    match BitcaskEngine::open_with_options(temp_dir.path(), small_file_options()) {
+        Err(KvStoreErr::ConfigConflict(setting, stored, passed)) => {
+            assert_eq!(setting, "codec");
+            assert_eq!(stored, "varint");
+            assert_eq!(passed, "default");
+        }
+        other => panic!("expected a config conflict, got {:?}", other.map(|_| ())),
+    }
+
+    // aligned values and front-coded hints work with varints too
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let aligned = BitcaskOptions {
+        value_alignment: 64,
+        hint_prefix_compression: true,
+        ..varint
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), aligned.clone())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), "x".repeat(i % 200))?;
+    }
+    store.merge()?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), aligned)?;
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("x".repeat(i % 200)));
+    }
+    Ok(())
+}
+
+#[test]
+fn swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set_with_flags("a".to_owned(), "value-a".to_owned(), 1)?;
+    store.set_with_flags("b".to_owned(), "value-b".to_owned(), 2)?;
+
+    store.swap("a".to_owned(), "b".to_owned())?;
+    assert_eq!(
+        store.get_with_flags("a".to_owned())?,
+        Some(("value-b".to_owned(), 2))
+    );
+    assert_eq!(
+        store.get_with_flags("b".to_owned())?,
+        Some(("value-a".to_owned(), 1))
+    );
+
+    // a missing key swaps as no value
+    store.swap("b".to_owned(), "missing".to_owned())?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("missing".to_owned())?, Some("value-a".to_owned()));
+
+    // swapping a key with itself or two missing keys changes nothing
+    store.swap("a".to_owned(), "a".to_owned())?;
+    store.swap("b".to_owned(), "c".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("value-b".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+    drop(store);
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_flags("a".to_owned())?,
+        Some(("value-b".to_owned(), 2))
+    );
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(
+        store.get_with_flags("missing".to_owned())?,
+        Some(("value-a".to_owned(), 1))
+    );
+    Ok(())
+}
+
+#[test]
+fn concurrent_swaps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    store.set("a".to_owned(), "x".to_owned())?;
+    store.set("b".to_owned(), "y".to_owned())?;
+
+    let thread_count = 8;
+    let ops_per_thread = 100;
+    let handles: Vec<_> = (0..thread_count)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..ops_per_thread {
+                    if thread_id % 2 == 0 {
+                        store.swap("a".to_owned(), "b".to_owned())?;
+                        store.swap("n1".to_owned(), "n2".to_owned())?;
+                    } else {
+                        store.swap("b".to_owned(), "a".to_owned())?;
+                        // increments racing with swaps of the same key are never lost
+                        store.increment(format!("n{}", i % 2 + 1), 1)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        // an even number of swaps puts the values back
+        assert_eq!(store.get("a".to_owned())?, Some("x".to_owned()));
+        assert_eq!(store.get("b".to_owned())?, Some("y".to_owned()));
+        let number = |key: &str| -> Result<usize> {
+            Ok(store
+                .get(key.to_owned())?
+                .map_or(0, |value| value.parse().unwrap()))
+        };
+        assert_eq!(
+            number("n1")? + number("n2")?,
+            thread_count / 2 * ops_per_thread
+        );
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&BitcaskEngine::open(temp_dir.path())?)
+}
+
+// A set racing with swaps of its key is never lost, nor copied into both keys
+#[test]
+fn swaps_racing_with_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let set_count = 500;
+    let setter = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..set_count {
+                store.set("a".to_owned(), i.to_string())?;
+                if i % 10 == 0 {
+                    match store.remove("b".to_owned()) {
+                        Ok(()) | Err(KvStoreErr::KeyNotFound(_)) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            Ok(())
+        })
+    };
+    let swappers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    store.swap("a".to_owned(), "b".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    setter.join().unwrap()?;
+    for swapper in swappers {
+        swapper.join().unwrap()?;
+    }
+
+    let a = store.get("a".to_owned())?;
+    let b = store.get("b".to_owned())?;
+    let last = Some((set_count - 1).to_string());
+    assert!(a == last || b == last, "{:?} {:?}", a, b);
+    assert_ne!(a, b);
+    Ok(())
+}
+
+#[test]
+fn get_with_tombstone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    store.set("present".to_owned(), "value".to_owned())?;
+    store.set("deleted".to_owned(), "value".to_owned())?;
+    store.remove("deleted".to_owned())?;
+    // push the tombstone out of the active file
+    for key_id in 0..50 {
+        store.set(format!("filler{}", key_id), "value".to_owned())?;
+    }
+    store.set("revived".to_owned(), "old".to_owned())?;
+    store.remove("revived".to_owned())?;
+    store.set("revived".to_owned(), "new".to_owned())?;
+
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        assert_eq!(
+            store.get_with_tombstone("present")?,
+            KeyState::Present("value".to_owned())
+        );
+        assert_eq!(store.get_with_tombstone("deleted")?, KeyState::Deleted);
+        assert_eq!(store.get_with_tombstone("never-seen")?, KeyState::Absent);
+        assert_eq!(
+            store.get_with_tombstone("revived")?,
+            KeyState::Present("new".to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    check(&store)?;
+
+    // a merge drops the tombstones of the files it rewrites
+    store.merge()?;
+    assert_eq!(store.get_with_tombstone("deleted")?, KeyState::Absent);
+    store.remove("present".to_owned())?;
+    assert_eq!(store.get_with_tombstone("present")?, KeyState::Deleted);
+    Ok(())
+}
+
+#[test]
+fn merges_share_a_pool() -> Result<()> {
+    // how many merges write their temp files at the same time
+    let merging = Arc::new(AtomicUsize::new(0));
+    let max_merging = Arc::new(AtomicUsize::new(0));
+    let block_store = {
+        let (merging, max_merging) = (merging.clone(), max_merging.clone());
+        Arc::new(HookStore::new(FileStore).on_open_append(move |path| {
+            if path.extension() == Some("temp".as_ref()) {
+                let now_merging = merging.fetch_add(1, Ordering::SeqCst) + 1;
+                max_merging.fetch_max(now_merging, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                merging.fetch_sub(1, Ordering::SeqCst);
+            }
+        }))
+    };
+    let pool = MergePool::new(1);
+    let temp_dirs: Vec<TempDir> = (0..2)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let stores = temp_dirs
+        .iter()
+        .map(|temp_dir| {
+            let options = BitcaskOptions {
+                merge_pool: Some(pool.clone()),
+                block_store: block_store.clone(),
+                ..small_file_options()
+            };
+            BitcaskEngine::open_with_options(temp_dir.path(), options)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for store in &stores {
+        for iter in 0..10 {
+            for key_id in 0..50 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+    }
+
+    let barrier = Arc::new(Barrier::new(stores.len()));
+    let handles: Vec<_> = stores
+        .iter()
+        .map(|store| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.merge()
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(max_merging.load(Ordering::SeqCst), 1);
+    for (store, temp_dir) in stores.iter().zip(&temp_dirs) {
+        assert!(!files_with_extension(temp_dir.path(), "hint").is_empty());
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value9".to_owned())
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn triggered_merge_on_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        merge_pool: Some(MergePool::new(1)),
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: 1024,
+        // a merge of only overwritten entries still leaves the hint file waited for
+        empty_merged_files: EmptyMergedFiles::KeepOne,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    // the merges run in the background, wait until one went through
+    wait_until(|| !files_with_extension(temp_dir.path(), "hint").is_empty());
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Store stalling the first merge at its first merged log file, until `resume` is signalled
+fn stalling_merge_store(stalled: mpsc::Sender<()>, resume: mpsc::Receiver<()>) -> HookStore {
+    let stalled = Mutex::new(Some(stalled));
+    let resume = Mutex::new(resume);
+    HookStore::new(MemoryStore::new()).on_open_append(move |path| {
+        if path.to_string_lossy().ends_with(".log.temp") {
+            if let Some(stalled) = stalled.lock().unwrap().take() {
+                stalled.send(()).unwrap();
+                resume.lock().unwrap().recv().unwrap();
+            }
+        }
+    })
+}
+
+// Writes and reads go on while the merge they triggered runs on the merge worker
+#[test]
+fn triggered_merge_in_background() -> Result<()> {
+    let (stalled_sender, stalled) = mpsc::channel();
+    let (resume, resume_receiver) = mpsc::channel();
+    let options = BitcaskOptions {
+        block_store: Arc::new(stalling_merge_store(stalled_sender, resume_receiver)),
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: 1024,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options("kvs", options)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    stalled
+        .recv_timeout(Duration::from_secs(10))
+        .expect("no merge was triggered");
+
+    // the merge is stuck writing its files, the active file takes writes meanwhile
+    for iter in 100..200 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert_eq!(store.get("key3".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(store.stats().merge_bytes_written, 0);
+
+    resume.send(()).unwrap();
+    wait_until(|| store.stats().merge_bytes_reclaimed > 0);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value199".to_owned())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn durable_position_follows_flushes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.durable_position(), (0, 0));
+
+    let on_disk = || {
+        let file_id = files_with_extension(temp_dir.path(), "log")
+            .iter()
+            .map(|name| name.trim_end_matches(".log").parse::<u64>().unwrap())
+            .max()
+            .unwrap();
+        let len = fs::metadata(temp_dir.path().join(format!("{}.log", file_id)))
+            .unwrap()
+            .len();
+        (file_id, len)
+    };
+    let mut last = store.durable_position();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        // a buffered write doesn't move it, only sealing a file does
+        let buffered = store.durable_position();
+        assert_eq!(buffered, on_disk());
+        assert!(buffered == last || buffered.0 > last.0);
+
+        store.flush()?;
+        // a tail reading the active file up to the durable position only sees whole entries
+        let durable = store.durable_position();
+        assert_eq!(durable, on_disk());
+        assert!(durable > buffered);
+        last = durable;
+    }
+    assert!(last.0 > 0);
+
+    store.flush()?;
+    assert_eq!(store.durable_position(), last);
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.durable_position(), last);
+    Ok(())
+}
+
+#[test]
+fn pending_flush_bytes_follow_the_watermark() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    // writes are buffered by default
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store.pending_flush_bytes() > 0);
+    drop(store);
+
+    let options = BitcaskOptions {
+        write_flush_bytes: 0,
+        ..BitcaskOptions::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.pending_flush_bytes(), 0);
+    drop(store);
+
+    let options = BitcaskOptions {
+        write_flush_bytes: 4096,
+        ..BitcaskOptions::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    let mut last = 0;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        let pending = store.pending_flush_bytes();
+        assert!(pending > last);
+        last = pending;
+    }
+    // what is pending isn't on disk yet, but reads see it
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("0.log"))?.len(),
+        store.durable_position().1
+    );
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    assert_eq!(store.pending_flush_bytes(), 0);
+
+    store.set("key50".to_owned(), "value50".to_owned())?;
+    assert!(store.pending_flush_bytes() > 0);
+    store.flush()?;
+    assert_eq!(store.pending_flush_bytes(), 0);
+
+    // the writer flushes once the watermark is crossed
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        assert!(store.pending_flush_bytes() <= 4096 + 64);
+    }
+    Ok(())
+}
+
+// A brand-new key is readable right after its set, while its entry is still buffered
+#[test]
+fn read_your_writes_on_the_active_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        write_flush_bytes: 1024 * 1024,
+        ..BitcaskOptions::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), format!("value{}", key_id))?;
+        assert!(store.pending_flush_bytes() > 0);
+        assert_eq!(store.get(key.clone())?, Some(format!("value{}", key_id)));
+        assert_eq!(store.pending_flush_bytes(), 0);
+
+        store.set(key.clone(), format!("other{}", key_id))?;
+        assert!(store.pending_flush_bytes() > 0);
+        assert_eq!(store.get_range(&key, 0, 5)?, Some(b"other".to_vec()));
+    }
+    // a read of a value flushed already doesn't flush the later writes
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    let pending = store.pending_flush_bytes();
+    assert_eq!(store.get("key0".to_owned())?, Some("other0".to_owned()));
+    assert_eq!(store.pending_flush_bytes(), pending);
+    Ok(())
+}
+
+#[test]
+fn skip_files_not_named_like_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let files = store.log_files()?;
+    drop(store);
+
+    for junk in [
+        "foo.log",
+        ".log",
+        "1.log.bak",
+        "01.log",
+        "+2.log",
+        "3.log.log",
+        "-1.log",
+        "99.log.bak",
+        "100.LOG",
+    ] {
+        fs::write(temp_dir.path().join(junk), b"junk")?;
+    }
+    fs::create_dir(temp_dir.path().join("dir.log"))?;
+
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.log_files()?, files);
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.merge()?;
+    drop(store);
+
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    assert_eq!(fs::read(temp_dir.path().join("01.log"))?, b"junk");
+    Ok(())
+}
+
+#[test]
+fn find_keys_by_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        secondary_index: Some(Arc::new(|value: &str| value.to_owned())),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for key in ["alice", "bob", "carol"] {
+        store.set(key.to_owned(), "admin".to_owned())?;
+    }
+    store.set("dave".to_owned(), "guest".to_owned())?;
+    assert_eq!(store.find_by_value("admin"), vec!["alice", "bob", "carol"]);
+    assert_eq!(store.find_by_value("guest"), vec!["dave"]);
+    assert!(store.find_by_value("nobody").is_empty());
+
+    // a changed value moves the key to its new bucket
+    store.set("bob".to_owned(), "guest".to_owned())?;
+    store.remove("carol".to_owned())?;
+    store.swap("alice".to_owned(), "erin".to_owned())?;
+    assert_eq!(store.find_by_value("admin"), vec!["erin"]);
+    assert_eq!(store.find_by_value("guest"), vec!["bob", "dave"]);
+    store.merge()?;
+    drop(store);
+
+    // rebuilt from the values on open
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.find_by_value("admin"), vec!["erin"]);
+    assert_eq!(store.find_by_value("guest"), vec!["bob", "dave"]);
+    drop(store);
+
+    // by a part of the value, or not at all
+    let options = BitcaskOptions {
+        secondary_index: Some(Arc::new(|value: &str| {
+            value.split(':').next().unwrap_or_default().to_owned()
+        })),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.set("frank".to_owned(), "admin:since-2020".to_owned())?;
+    assert_eq!(store.find_by_value("admin"), vec!["erin", "frank"]);
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert!(store.find_by_value("admin").is_empty());
+    Ok(())
+}
+
+#[test]
+fn merge_on_schedule() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        merge_schedule: Some(MergeSchedule::Every(Duration::from_millis(50))),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let size = log_files_size(temp_dir.path());
+
+    // no write triggers it, the schedule does
+    let start = Instant::now();
+    while files_with_extension(temp_dir.path(), "hint").is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(log_files_size(temp_dir.path()) < size);
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    // the schedule stops with the engine
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+    for name in files_with_extension(temp_dir.path(), "hint") {
+        fs::remove_file(temp_dir.path().join(name))?;
+    }
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert!(files_with_extension(temp_dir.path(), "hint").is_empty());
+    Ok(())
+}
+
+#[test]
+fn daily_merge_schedule() {
+    let hour = Duration::from_secs(60 * 60);
+    let at_three = MergeSchedule::DailyAt(3 * hour);
+    let day = 24 * hour;
+    let one_am = UNIX_EPOCH + 100 * day + hour;
+    assert_eq!(at_three.next_delay(one_am), 2 * hour);
+    let five_am = UNIX_EPOCH + 100 * day + 5 * hour;
+    assert_eq!(at_three.next_delay(five_am), 22 * hour);
+    let three_am = UNIX_EPOCH + 100 * day + 3 * hour;
+    assert_eq!(at_three.next_delay(three_am), day);
+    assert_eq!(MergeSchedule::Every(hour).next_delay(five_am), hour);
+}
+
+// Clock which only moves when told to
+struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[test]
+fn merge_schedule_follows_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hour = Duration::from_secs(60 * 60);
+    // almost 3 am on the engine's clock, whatever the time really is
+    let clock = Arc::new(MockClock {
+        now: Mutex::new(UNIX_EPOCH + 10_000 * 24 * hour + 3 * hour - 2 * hour),
+    });
+    let options = BitcaskOptions {
+        merge_schedule: Some(MergeSchedule::DailyAt(3 * hour)),
+        clock: clock.clone(),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(files_with_extension(temp_dir.path(), "hint").is_empty());
+    assert_eq!(
+        MergeSchedule::DailyAt(3 * hour).next_delay(clock.now()),
+        2 * hour
+    );
+    drop(store);
+
+    // reopen just before 3 am, the daily merge follows within moments
+    clock.advance(2 * hour - Duration::from_millis(20));
+    let options = BitcaskOptions {
+        merge_schedule: Some(MergeSchedule::DailyAt(3 * hour)),
+        clock: clock.clone(),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    let start = Instant::now();
+    while files_with_extension(temp_dir.path(), "hint").is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn set_expire_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hour = Duration::from_secs(60 * 60);
+    let clock = Arc::new(MockClock {
+        now: Mutex::new(UNIX_EPOCH + 10_000 * 24 * hour),
+    });
+    let options = || BitcaskOptions {
+        clock: clock.clone(),
+        ..small_file_options()
+    };
+    let logs_contain = |needle: &[u8]| {
+        files_with_extension(temp_dir.path(), "log")
+            .iter()
+            .map(|name| fs::read(temp_dir.path().join(name)).unwrap())
+            .any(|bytes| bytes.windows(needle.len()).any(|window| window == needle))
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+    store.set_expire_at("later".to_owned(), "value1".to_owned(), clock.now() + hour)?;
+    store.set_expire_at("past".to_owned(), "expired0".to_owned(), clock.now() - hour)?;
+    store.set("never".to_owned(), "value3".to_owned())?;
+
+    // an expired key is gone although its entry is still in the log
+    assert_eq!(store.get("later".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("past".to_owned())?, None);
+    assert!(!store.contains_key("past".to_owned())?);
+    assert!(matches!(
+        store.remove("past".to_owned()),
+        Err(KvStoreErr::KeyNotFound(_))
+    ));
+    assert!(logs_contain(b"expired0"));
+
+    clock.advance(2 * hour);
+    assert_eq!(store.get("later".to_owned())?, None);
+    let mut keys = store.keys()?;
+    keys.sort();
+    assert_eq!(keys, vec!["never".to_owned()]);
+
+    // a later set decides whether the key expires
+    store.set_expire_at("past".to_owned(), "expired1".to_owned(), clock.now() - hour)?;
+    store.set("later".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // the expiry outlives a reopen
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("past".to_owned())?, None);
+    assert_eq!(store.get("later".to_owned())?, Some("value2".to_owned()));
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    // merges leave the expired entries behind, and their hints keep the expiry
+    store.merge()?;
+    assert!(!logs_contain(b"expired0"));
+    assert!(!logs_contain(b"expired1"));
+    assert_eq!(store.get("past".to_owned())?, None);
+    drop(store);
+    assert!(!files_with_extension(temp_dir.path(), "hint").is_empty());
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("past".to_owned())?, None);
+    assert_eq!(store.get("later".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("never".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.len(), 52);
+    Ok(())
+}
+
+// A key set with a ttl reads as absent and leaves len once it runs out, and the read drops it
+// from the index
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let minute = Duration::from_secs(60);
+    let clock = Arc::new(MockClock {
+        now: Mutex::new(UNIX_EPOCH + 10_000 * 24 * 60 * minute),
+    });
+    let options = || BitcaskOptions {
+        clock: clock.clone(),
+        ..BitcaskOptions::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+    store.set_with_ttl("session".to_owned(), "token".to_owned(), 5 * minute)?;
+    store.set("user".to_owned(), "name".to_owned())?;
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+
+    clock.advance(5 * minute);
+    assert_eq!(store.len(), 1);
+    let garbage = store.stats().garbage_bytes;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.len(), 1);
+    assert!(store.stats().garbage_bytes > garbage);
+    assert_eq!(store.keys()?, ["user"]);
+
+    // a new ttl starts from the set
+    store.set_with_ttl("session".to_owned(), "renewed".to_owned(), 5 * minute)?;
+    clock.advance(4 * minute);
+    assert_eq!(store.get("session".to_owned())?, Some("renewed".to_owned()));
+    drop(store);
+
+    // the ttl outlives a reopen
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("session".to_owned())?, Some("renewed".to_owned()));
+    clock.advance(minute);
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("user".to_owned())?, Some("name".to_owned()));
+    assert_eq!(store.len(), 1);
+
+    // a ttl past the last point in time fails, one past the last millisecond never expires
+    assert!(store
+        .set_with_ttl("session".to_owned(), "token".to_owned(), Duration::MAX)
+        .is_err());
+    store.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        Duration::from_secs(1 << 62),
+    )?;
+    clock.advance(10_000 * 24 * 60 * minute);
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// Bulk loaded entries are read like set ones, before and after reopen and merge
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+
+    let entries = (0..100)
+        .rev()
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .chain(Some(("key2".to_owned(), "last".to_owned())));
+    store.bulk_load(entries)?;
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for i in (0..100).filter(|&i| i != 2) {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        assert_eq!(store.get("key2".to_owned())?, Some("last".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    // loaded files are sealed, each with a hint file
+    assert!(files_with_extension(temp_dir.path(), "hint").len() > 1);
+    assert!(files_with_extension(temp_dir.path(), "temp").is_empty());
+
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    check(&store)?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+
+    store.merge()?;
+    check(&store)?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    Ok(())
+}
+
+// Should refuse unsorted input when it has to come sorted, and load the first or last entry
+// of a duplicate key as asked
+#[test]
+fn bulk_load_opts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    let entry = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+    let sorted = BulkLoadOpts {
+        require_sorted: true,
+        ..Default::default()
+    };
+
+    let unsorted = vec![entry("a", "1"), entry("c", "2"), entry("b", "3")];
+    assert!(matches!(
+        store.bulk_load_with_opts(unsorted.clone().into_iter(), sorted),
+        Err(KvStoreErr::UnsortedInput(key, previous)) if key == "b" && previous == "c"
+    ));
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert!(files_with_extension(temp_dir.path(), "temp").is_empty());
+
+    // sorted input may repeat a key
+    let input = vec![entry("a", "1"), entry("a", "2"), entry("b", "3")];
+    store.bulk_load_with_opts(input.into_iter(), sorted)?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("3".to_owned()));
+
+    // unsorted input is sorted, keeping the last entry of a key
+    let input = vec![
+        entry("d", "1"),
+        entry("c", "first"),
+        entry("e", "2"),
+        entry("c", "last"),
+    ];
+    store.bulk_load_with_opts(input.clone().into_iter(), BulkLoadOpts::default())?;
+    assert_eq!(store.get("c".to_owned())?, Some("last".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, Some("1".to_owned()));
+
+    let first = BulkLoadOpts {
+        duplicates: DuplicateKeys::KeepFirst,
+        ..Default::default()
+    };
+    store.bulk_load_with_opts(input.into_iter(), first)?;
+    assert_eq!(store.get("c".to_owned())?, Some("first".to_owned()));
+
+    // the hint files of the loaded files agree
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("first".to_owned()));
+    assert_eq!(store.get("e".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// The sharded index serves the same reads as the default one
+#[test]
+fn sharded_hash_map_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        index_kind: IndexKind::ShardedHashMap,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for i in 0..100 {
+            let expected = (i % 2 == 1).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.merge()?;
+    check(&store)?;
+    drop(store);
+
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    Ok(())
+}
+
+#[test]
+fn for_each_key_streams_keys() -> Result<()> {
+    for index_kind in [IndexKind::DashMap, IndexKind::ShardedHashMap] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            index_kind,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        assert!(store.is_empty());
+        for i in 0..500 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in (0..500).step_by(3) {
+            store.remove(format!("key{}", i))?;
+        }
+
+        let mut count = 0;
+        let mut removed_seen = false;
+        store.for_each_key(|key| {
+            count += 1;
+            let i: usize = key["key".len()..].parse().unwrap();
+            removed_seen |= i.is_multiple_of(3);
+        });
+        assert_eq!(count, store.len());
+        assert_eq!(store.len(), 500 - 167);
+        assert!(!removed_seen);
+    }
+    Ok(())
+}
+
+// Every entry of the merged files is either kept or dropped
+#[test]
+fn merge_report_counts_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    // all entries are the same size, to count them from the file sizes
+    for i in 0..200 {
+        store.set(format!("key{:02}", i % 20), format!("value{:03}", i))?;
+    }
+    let entry_size = (24 + "key00".len() + "value000".len()) as u64;
+    let old_files: Vec<LogFileInfo> = store
+        .log_files()?
+        .into_iter()
+        .filter(|file| !file.is_active)
+        .collect();
+    let scanned: u64 = old_files.iter().map(|file| file.size / entry_size).sum();
+
+    let report = store.merge_report()?;
+    assert_eq!(report.entries_kept + report.entries_dropped, scanned);
+    assert!(report.entries_kept <= 20);
+    assert!(report.entries_dropped > 0);
+    assert_eq!(report.files_merged, old_files.len() as u64);
+    assert!(report.files_produced >= 1);
+    assert_eq!(report.bytes_reclaimed, store.stats().merge_bytes_reclaimed);
+    for i in 180..200 {
+        assert_eq!(
+            store.get(format!("key{:02}", i % 20))?,
+            Some(format!("value{:03}", i))
+        );
+    }
+
+    // nothing to merge without old log files
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let report = store.merge_report()?;
+    assert_eq!(
+        report,
+        MergeReport {
+            duration: report.duration,
+            ..Default::default()
+        }
+    );
+    Ok(())
+}
+
+// Should merge ranges of files in parallel into the same keys and values as a serial merge
+#[test]
+fn parallel_merge_matches_serial_merge() -> Result<()> {
+    // a few MB spread over a few hundred files, with every key overwritten in several ranges
+    let options = |merge_threads: usize| BitcaskOptions {
+        log_file_max_bytes: 16 * 1024,
+        merge_trigger_threshold: u64::MAX,
+        merge_threads,
+        ..Default::default()
+    };
+    let write = |merge_threads: usize| -> Result<(BitcaskEngine, TempDir)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options(merge_threads))?;
+        for round in 0..8 {
+            for i in 0..1000 {
+                if (i + round) % 7 == 0 {
+                    store
+                        .remove(format!("key{:04}", i))
+                        .or_else(|err| match err {
+                            KvStoreErr::KeyNotFound(_) => Ok(()),
+                            err => Err(err),
+                        })?;
+                } else {
+                    store.set(
+                        format!("key{:04}", i),
+                        format!("{}-{}", round, "v".repeat(i % 500)),
+                    )?;
+                }
+            }
+        }
+        Ok((store, temp_dir))
+    };
+    let contents = |store: &BitcaskEngine| -> Result<Vec<(String, String)>> {
+        let mut keys = store.keys()?;
+        keys.sort();
+        keys.into_iter()
+            .map(|key| Ok((key.clone(), store.get(key)?.unwrap())))
+            .collect()
+    };
+
+    let (serial, _serial_dir) = write(1)?;
+    let (parallel, parallel_dir) = write(4)?;
+    let expected = contents(&serial)?;
+    assert!(serial.log_files()?.len() > 100);
+    let serial_report = serial.merge_report()?;
+    let parallel_report = parallel.merge_report()?;
+    assert_eq!(parallel_report.files_merged, serial_report.files_merged);
+    assert_eq!(parallel_report.entries_kept, serial_report.entries_kept);
+    assert_eq!(
+        parallel_report.entries_dropped,
+        serial_report.entries_dropped
+    );
+    assert_eq!(contents(&serial)?, expected);
+    assert_eq!(contents(&parallel)?, expected);
+
+    // the merged files replay into the same contents
+    drop(parallel);
+    let parallel = BitcaskEngine::open_with_options(parallel_dir.path(), options(4))?;
+    assert_eq!(contents(&parallel)?, expected);
+    Ok(())
+}
+
+#[test]
+fn garbage_accounting_matches_reclaimed_bytes() -> Result<()> {
+    let write = |garbage_accounting: GarbageAccounting| -> Result<(BitcaskEngine, TempDir)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            garbage_accounting,
+            ..small_file_options()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..300 {
+            // keys of different lengths, overwritten, removed and set again
+            let key = format!("key{}", "k".repeat(i % 7));
+            store.set(key.clone(), format!("value{}", i))?;
+            if i % 5 == 4 {
+                store.remove(key)?;
+            }
+        }
+        Ok((store, temp_dir))
+    };
+
+    let (store, _temp_dir) = write(GarbageAccounting::EntryBytes)?;
+    let counted = store.stats().garbage_bytes;
+    let report = store.merge_report()?;
+    assert!(report.bytes_reclaimed > 0);
+    // the garbage left is in the active file, which the merge didn't touch
+    assert_eq!(
+        counted - store.stats().garbage_bytes,
+        report.bytes_reclaimed
+    );
+
+    // counting only the values misses the keys and headers
+    let (store, _temp_dir) = write(GarbageAccounting::ValueBytes)?;
+    assert!(store.stats().garbage_bytes < counted);
+    Ok(())
+}
+
+// A replica follows the writes, rotations and merges of the engine writing to its directory
+#[test]
+fn read_replica_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for i in 0..50 {
+        primary.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    primary.flush()?;
+    let replica_options = BitcaskOptions {
+        read_only: true,
+        ..small_file_options()
+    };
+    let replica = BitcaskEngine::open_with_options(temp_dir.path(), replica_options)?;
+    assert_eq!(replica.get("key49".to_owned())?, Some("value49".to_owned()));
+
+    // the tail of the active file and new files show up after a refresh
+    for i in 50..100 {
+        primary.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    primary.remove("key0".to_owned())?;
+    assert_eq!(replica.get("key99".to_owned())?, None);
+    primary.flush()?;
+    replica.refresh()?;
+    assert_eq!(replica.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(
+            replica.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // files rewritten by a merge are replayed again
+    for i in 1..100 {
+        primary.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    primary.merge()?;
+    primary.set("key100".to_owned(), "value100".to_owned())?;
+    primary.flush()?;
+    replica.refresh()?;
+    for i in 1..100 {
+        assert_eq!(replica.get(format!("key{}", i))?, Some(format!("new{}", i)));
+    }
+    assert_eq!(
+        replica.get("key100".to_owned())?,
+        Some("value100".to_owned())
+    );
+
+    assert!(matches!(
+        replica.set("key".to_owned(), "value".to_owned()),
+        Err(KvStoreErr::ReadOnly)
+    ));
+    assert!(matches!(replica.merge(), Err(KvStoreErr::ReadOnly)));
+    Ok(())
+}
+
+#[test]
+fn read_replica_refresh_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = BitcaskEngine::open(temp_dir.path())?;
+    let replica = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        BitcaskOptions {
+            read_only: true,
+            refresh_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        },
+    )?;
+    primary.set("key".to_owned(), "value".to_owned())?;
+    primary.flush()?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while replica.get("key".to_owned())?.is_none() {
+        assert!(Instant::now() < deadline, "the replica never refreshed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+// Writes beyond `max_live_bytes` evict the keys the policy picks
+#[test]
+fn max_live_bytes_eviction() -> Result<()> {
+    for (policy, evicted) in [(EvictionPolicy::Lru, 1..6), (EvictionPolicy::Fifo, 0..5)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            // room for ten keys of 5 bytes with values of 10 bytes
+            max_live_bytes: Some(150),
+            eviction_policy: policy,
+            ..Default::default()
+        };
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..10 {
+            store.set(format!("key{:02}", i), format!("value{:05}", i))?;
+        }
+        // a read keeps key00 under LRU only
+        assert!(store.get("key00".to_owned())?.is_some());
+        for i in 10..15 {
+            store.set(format!("key{:02}", i), format!("value{:05}", i))?;
+        }
+        for i in 0..15 {
+            let value = store.get(format!("key{:02}", i))?;
+            assert_eq!(
+                value.is_none(),
+                evicted.contains(&i),
+                "{:?} key{:02}",
+                policy,
+                i
+            );
+        }
+        drop(store);
+
+        // the live bytes are counted again on open
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        store.set("key15".to_owned(), "value00015".to_owned())?;
+        let live = (0..16)
+            .filter(|i| store.get(format!("key{:02}", i)).unwrap().is_some())
+            .count();
+        assert_eq!(live, 10);
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_glob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for key in ["log:2024:02", "log:2024:01", "cfg:x", "log:2023:12"] {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    store.remove("log:2023:12".to_owned())?;
+
+    let entries = |pattern| -> Result<Vec<String>> {
+        Ok(store
+            .scan_glob(pattern)?
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value, format!("value of {}", key));
+                key
+            })
+            .collect())
+    };
+    assert_eq!(entries("log:2024:*")?, ["log:2024:01", "log:2024:02"]);
+    assert_eq!(entries("log:*:0?")?, ["log:2024:01", "log:2024:02"]);
+    assert_eq!(entries("*:x")?, ["cfg:x"]);
+    assert_eq!(entries("???:?")?, ["cfg:x"]);
+    assert_eq!(entries("*")?.len(), 3);
+    assert!(entries("log:2023:*")?.is_empty());
+    assert!(entries("cfg:")?.is_empty());
+    Ok(())
+}
+
+// A sealed file which is mostly garbage is merged at rotation, under the merge threshold
+#[test]
+fn merge_garbage_file_at_rotation() -> Result<()> {
+    let write = |options: BitcaskOptions, overwrite: bool| -> Result<(TempDir, BitcaskEngine)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..500 {
+            let key = if overwrite { i % 10 } else { i };
+            store.set(format!("key{}", key), format!("value{:05}", i))?;
+        }
+        for i in 490..500 {
+            let key = if overwrite { i % 10 } else { i };
+            assert_eq!(
+                store.get(format!("key{}", key))?,
+                Some(format!("value{:05}", i))
+            );
+        }
+        Ok((temp_dir, store))
+    };
+    let options = BitcaskOptions {
+        rotation_merge_garbage_ratio: Some(0.5),
+        ..small_file_options()
+    };
+
+    let (_temp_dir, store) = write(options.clone(), true)?;
+    wait_until(|| store.stats().merge_bytes_reclaimed > 0);
+    // without the option the writes stay below the merge threshold. Dropping the engine
+    // waits for a running merge, which would have written hint files.
+    let (temp_dir, store) = write(small_file_options(), true)?;
+    drop(store);
+    assert!(files_with_extension(temp_dir.path(), "hint").is_empty());
+    // sealed files of live keys only aren't merged
+    let (temp_dir, store) = write(options, false)?;
+    drop(store);
+    assert!(files_with_extension(temp_dir.path(), "hint").is_empty());
+    Ok(())
+}
+
+// Every set and remove lands in the audit log in order, with the time and the client
+#[test]
+fn audit_log_records_mutations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_path = temp_dir.path().join("audit.log");
+    let clock = Arc::new(MockClock {
+        now: Mutex::new(UNIX_EPOCH + Duration::from_millis(1_000)),
+    });
+    let options = BitcaskOptions {
+        audit_log: Some(audit_path.clone()),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path().join("data"), options.clone())?;
+    let client = "10.0.0.7:4321".parse().unwrap();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clock.advance(Duration::from_millis(5));
+    store.set_from("key two".to_owned(), "value2".to_owned(), Some(client))?;
+    store.remove_from("key1".to_owned(), Some(client))?;
+    store.swap("key1".to_owned(), "key two".to_owned())?;
+    // a failed remove changes nothing, so it isn't recorded
+    assert!(store.remove("missing".to_owned()).is_err());
+    store.flush()?;
+    assert_eq!(
+        fs::read_to_string(&audit_path)?.lines().collect::<Vec<_>>(),
+        [
+            "1000 set \"key1\" -",
+            "1005 set \"key two\" 10.0.0.7:4321",
+            "1005 remove \"key1\" 10.0.0.7:4321",
+            "1005 set \"key1\" -",
+            "1005 remove \"key two\" -",
+        ]
+    );
+
+    // the log is appended to across opens, and replay records nothing
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path().join("data"), options)?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let audit = fs::read_to_string(&audit_path)?;
+    assert_eq!(audit.lines().count(), 6);
+    assert_eq!(audit.lines().last(), Some("1005 remove \"key1\" -"));
+    Ok(())
+}
+
+// The Bloom filter never hides a live key, and answers most gets of missing keys by itself
+#[test]
+fn bloom_filter_skips_missing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        bloom_filter_keys: Some(1000),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        for i in 0..1000 {
+            let expected = (i % 2 == 1).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    let misses = |store: &BitcaskEngine| -> Result<u64> {
+        let before = store.stats().filtered_misses;
+        for i in 0..10_000 {
+            assert_eq!(store.get(format!("missing{}", i))?, None);
+        }
+        Ok(store.stats().filtered_misses - before)
+    };
+    // about 1% of the misses get past the filter
+    assert!(misses(&store)? > 9_500);
+
+    // the rebuilt filters know every live key as well
+    store.merge()?;
+    check(&store)?;
+    assert!(misses(&store)? > 9_500);
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    assert!(misses(&store)? > 9_500);
+
+    // without the filter every miss goes to the index
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    check(&store)?;
+    assert_eq!(misses(&store)?, 0);
+    Ok(())
+}
+
+// No merge is triggered while compaction is paused, resuming merges what piled up meanwhile
+#[test]
+fn pause_compaction_holds_off_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: 500,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.pause_compaction();
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("value{:05}", i))?;
+    }
+    // far beyond the threshold, yet nothing merged
+    assert_eq!(store.stats().merge_bytes_written, 0);
+    assert!(store.log_files()?.len() > 5);
+
+    store.resume_compaction();
+    wait_until(|| store.stats().merge_bytes_reclaimed > 0);
+    for i in 190..200 {
+        assert_eq!(
+            store.get(format!("key{}", i % 10))?,
+            Some(format!("value{:05}", i))
+        );
+    }
+    Ok(())
+}
+
+// A value read once is served from the cache, until it's replaced or a merge moves it
+#[test]
+fn get_with_source_reports_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_cache_bytes: Some(1024),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let value1 = |source| Some(("value1".to_owned(), source));
+    assert_eq!(store.get_with_source("key1")?, value1(ValueSource::Disk));
+    assert_eq!(store.get_with_source("key1")?, value1(ValueSource::Cache));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_with_source("missing")?, None);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let value2 = |source| Some(("value2".to_owned(), source));
+    assert_eq!(store.get_with_source("key1")?, value2(ValueSource::Disk));
+    assert_eq!(store.get_with_source("key1")?, value2(ValueSource::Cache));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_with_source("key1")?, None);
+
+    // the least recently read values leave a full cache
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "v".repeat(90))?;
+        store.get(format!("key{:03}", i))?;
+    }
+    assert_eq!(
+        store.get_with_source("key099")?.map(|(_, source)| source),
+        Some(ValueSource::Cache)
+    );
+    assert_eq!(
+        store.get_with_source("key000")?.map(|(_, source)| source),
+        Some(ValueSource::Disk)
+    );
+    store.merge()?;
+    assert_eq!(
+        store.get_with_source("key099")?,
+        Some(("v".repeat(90), ValueSource::Disk))
+    );
+
+    // without the cache every value comes from disk
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_with_source("key1")?, value1(ValueSource::Disk));
+    assert_eq!(store.get_with_source("key1")?, value1(ValueSource::Disk));
+    Ok(())
+}
+
+// Should evict from the value cache once it holds more than either its entries or its bytes
+#[test]
+fn value_cache_bounded_by_entries_and_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_cache_bytes: Some(4096),
+        value_cache_entries: Some(10),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    let source = |key: &str| -> Result<Option<ValueSource>> {
+        Ok(store.get_with_source(key)?.map(|(_, source)| source))
+    };
+
+    // many small values hit the entries bound well before the bytes bound
+    for i in 0..20 {
+        store.set(format!("small{:02}", i), "v".to_owned())?;
+        store.get(format!("small{:02}", i))?;
+    }
+    for i in 0..10 {
+        assert_eq!(source(&format!("small{:02}", i))?, Some(ValueSource::Disk));
+    }
+    // reading the old ones back evicted the rest
+    for i in 10..20 {
+        assert_eq!(source(&format!("small{:02}", i))?, Some(ValueSource::Disk));
+    }
+
+    // a few large values hit the bytes bound well before the entries bound
+    for i in 0..3 {
+        store.set(format!("large{}", i), "v".repeat(1500))?;
+        store.get(format!("large{}", i))?;
+    }
+    assert_eq!(source("large2")?, Some(ValueSource::Cache));
+    assert_eq!(source("large1")?, Some(ValueSource::Cache));
+    assert_eq!(source("large0")?, Some(ValueSource::Disk));
+
+    // the entries bound alone enables the cache
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        value_cache_entries: Some(1),
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "v".repeat(10_000))?;
+    store.get("key1".to_owned())?;
+    assert_eq!(
+        store.get_with_source("key1")?.map(|(_, source)| source),
+        Some(ValueSource::Cache)
+    );
+    Ok(())
+}
+
+// A small store merges into one file, which takes the writes after the merge
+#[test]
+fn single_file_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: u64::MAX,
+        single_file_merge: true,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..300 {
+        store.set(format!("key{}", i % 10), format!("value{:05}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.log_files()?.len() > 5);
+
+    let report = store.merge_report()?;
+    assert_eq!(report.files_produced, 1);
+    assert_eq!(report.entries_kept, 9);
+    assert_eq!(report.entries_dropped, 292);
+    assert_eq!(files_with_extension(temp_dir.path(), "log"), ["0.log"]);
+    assert!(files_with_extension(temp_dir.path(), "hint").is_empty());
+    let check = |store: &BitcaskEngine| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 291..300 {
+            assert_eq!(
+                store.get(format!("key{}", i % 10))?,
+                Some(format!("value{:05}", i))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // writes go on in the merged file, and survive a reopen
+    store.set("key0".to_owned(), "again".to_owned())?;
+    assert_eq!(files_with_extension(temp_dir.path(), "log"), ["0.log"]);
+    store.remove("key0".to_owned())?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+
+    // live data which doesn't fit in one file is merged into sealed files as usual
+    for i in 0..100 {
+        store.set(format!("big{}", i), "b".repeat(50))?;
+    }
+    let report = store.merge_report()?;
+    assert!(report.files_produced > 1);
+    check(&store)?;
+    Ok(())
+}
+
+// The index estimate grows about linearly with the keys, for either kind of index
+#[test]
+fn index_memory_estimate() -> Result<()> {
+    for index_kind in [IndexKind::DashMap, IndexKind::ShardedHashMap] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitcaskEngine::open_with_options(
+            temp_dir.path(),
+            BitcaskOptions {
+                index_kind,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(store.index_memory_estimate(), 0);
+        let mut estimates = Vec::new();
+        for round in 1..=4 {
+            for i in (round - 1) * 1000..round * 1000 {
+                store.set(format!("key{:04}", i), "value".to_owned())?;
+            }
+            estimates.push(store.index_memory_estimate());
+        }
+        assert_eq!(store.stats().index_bytes_estimate, estimates[3]);
+        // each key costs its bytes and an entry at least, and not much more
+        let per_key = estimates[3] / 4000;
+        assert!(per_key > 7 + 24);
+        assert!(per_key < 4 * (7 + 24 + 24));
+        for (round, estimate) in estimates.iter().enumerate() {
+            let keys = (round as u64 + 1) * 1000;
+            assert!(*estimate >= keys * per_key * 9 / 10);
+            assert!(*estimate <= keys * per_key * 11 / 10);
+        }
+        // overwrites don't add keys
+        store.set("key0000".to_owned(), "other".to_owned())?;
+        assert_eq!(store.index_memory_estimate(), estimates[3]);
+    }
+    Ok(())
+}
+
+// Open fails clearly when the index would take more memory than allowed
+#[test]
+fn max_index_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.stats().index_bytes_estimate, 0);
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), "value".to_owned())?;
+    }
+    let estimate = store.stats().index_bytes_estimate;
+    // at least the keys and their entries
+    assert!(estimate > 1000 * (7 + 24));
+    for i in 1000..2000 {
+        store.set(format!("key{:04}", i), "value".to_owned())?;
+    }
+    let estimate = store.stats().index_bytes_estimate;
+    assert!(estimate > 2000 * (7 + 24));
+    drop(store);
+
+    let open = |max_index_bytes| {
+        BitcaskEngine::open_with_options(
+            temp_dir.path(),
+            BitcaskOptions {
+                max_index_bytes: Some(max_index_bytes),
+                ..Default::default()
+            },
+        )
+    };
+    match open(1024) {
+        Err(KvStoreErr::IndexTooLarge(index_bytes, 1024)) => {
+            assert_eq!(index_bytes, estimate)
+        }
+        other => panic!("expected IndexTooLarge, got {:?}", other.map(|_| ())),
+    }
+    let store = open(estimate)?;
+    assert_eq!(store.get("key1999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Gets running alongside merges always see the latest value, never an error or an older value
+#[test]
+fn concurrent_merge_and_reads() -> Result<()> {
+    const KEYS: usize = 50;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), format!("{:06}", 0))?;
+    }
+    let done = Arc::new(AtomicUsize::new(0));
+    // the writer overwrites the keys with increasing versions, which gives the merges work
+    let writer = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut version = 0;
+            while done.load(Ordering::SeqCst) == 0 {
+                version += 1;
+                for i in 0..KEYS {
+                    store.set(format!("key{}", i), format!("{:06}", version))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut seen = vec![0; KEYS];
+                while done.load(Ordering::SeqCst) == 0 {
+                    for (i, seen) in seen.iter_mut().enumerate() {
+                        let value = store.get(format!("key{}", i))?.expect("key is gone");
+                        let version: u64 = value.parse().expect("not a version");
+                        assert!(version >= *seen, "key{} went back in time", i);
+                        *seen = version;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for _ in 0..50 {
+        store.merge()?;
+    }
+    done.store(1, Ordering::SeqCst);
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Opening the data of one engine with the other fails clearly
+#[test]
+fn engine_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    match SledEngine::open(temp_dir.path()) {
+        Err(KvStoreErr::EngineMismatch { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("sled", "kvs"));
+        }
+        _ => panic!("opened the data of bitcask with sled"),
+    }
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.keys()?, ["key"]);
+    drop(store);
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(KvStoreErr::EngineMismatch { .. })
+    ));
+
+    // a directory from before the markers is taken for bitcask by its log files
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("engine"))?;
+    assert!(matches!(
+        SledEngine::open(temp_dir.path()),
+        Err(KvStoreErr::EngineMismatch { .. })
+    ));
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    // and other files are nobody's data bitcask should write into
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("notes.txt"), "not a store")?;
+    assert!(BitcaskEngine::open(temp_dir.path()).is_err());
+    assert!(!temp_dir.path().join("engine").exists());
+    Ok(())
+}
+
+// Should copy the live keys of a bitcask store, and no removed ones, to another engine
+#[test]
+fn migrate_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = BitcaskEngine::open_with_options(temp_dir.path().join("kvs"), small_file_options())?;
+    for i in 0..1000 {
+        src.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(10) {
+        src.remove(format!("key{}", i))?;
+    }
+    for i in (1..1000).step_by(10) {
+        src.set(format!("key{}", i), format!("updated{}", i))?;
+    }
+    let dst = SledEngine::open(temp_dir.path().join("sled"))?;
+
+    assert_eq!(kvs::migrate(&src, &dst)?, 900);
+    for i in 0..1000 {
+        let expected = match i % 10 {
+            0 => None,
+            1 => Some(format!("updated{}", i)),
+            _ => Some(format!("value{}", i)),
+        };
+        assert_eq!(dst.get(format!("key{}", i))?, expected);
+    }
+    assert_eq!(dst.keys()?.len(), 900);
+
+    // and back again
+    let back = BitcaskEngine::open(temp_dir.path().join("back"))?;
+    assert_eq!(kvs::migrate(&dst, &back)?, 900);
+    assert_eq!(back.get("key1".to_owned())?, Some("updated1".to_owned()));
+    assert_eq!(back.get("key10".to_owned())?, None);
+    Ok(())
+}
+
+// Keys and values which aren't utf-8 are copied as they are, or refused by a string engine
+#[test]
+fn migrate_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = BitcaskEngine::open(temp_dir.path().join("src"))?;
+    src.set_bytes(vec![0xFF, b'k', 0xFE], vec![0x80, 0x00])?;
+    src.set("key".to_owned(), "value".to_owned())?;
+    let dst = BitcaskEngine::open(temp_dir.path().join("dst"))?;
+
+    assert_eq!(kvs::migrate(&src, &dst)?, 2);
+    assert_eq!(dst.get_bytes(&[0xFF, b'k', 0xFE])?, Some(vec![0x80, 0x00]));
+    assert_eq!(dst.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(dst.len(), 2);
+
+    let sled = SledEngine::open(temp_dir.path().join("sled"))?;
+    assert!(kvs::migrate(&src, &sled).is_err());
+    Ok(())
+}
+
+// Every way of listing the keys must agree on the live ones, whatever tombstones the log holds
+#[test]
+fn enumeration_skips_tombstones() -> Result<()> {
+    let assert_live = |store: &BitcaskEngine, live: &[String]| -> Result<()> {
+        let mut expected = live.to_vec();
+        expected.sort();
+        assert_eq!(store.len(), expected.len());
+        assert_eq!(store.is_empty(), expected.is_empty());
+        let mut visited = Vec::new();
+        store.for_each_key(|key| visited.push(key.to_owned()));
+        visited.sort();
+        assert_eq!(visited, expected);
+        let mut keys = store.keys()?;
+        keys.sort();
+        assert_eq!(keys, expected);
+        let scanned: Vec<String> = store
+            .scan_glob("*")?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(scanned, expected);
+        for key in &expected {
+            assert!(store.get(key.clone())?.is_some());
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        always_tombstone_on_remove: true,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    let replica = BitcaskEngine::open_with_options(
+        temp_dir.path(),
+        BitcaskOptions {
+            read_only: true,
+            ..options.clone()
+        },
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // removed keys, keys which were never set, and a key a swap leaves without a value
+    for i in (0..100).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in 0..20 {
+        store.remove(format!("absent{}", i))?;
+    }
+    store.swap("key1".to_owned(), "absent0".to_owned())?;
+    let live: Vec<String> = (0..100)
+        .filter(|i| i % 3 != 0 && *i != 1)
+        .map(|i| format!("key{}", i))
+        .chain(["absent0".to_owned()])
+        .collect();
+    assert_live(&store, &live)?;
+    replica.refresh()?;
+    assert_live(&replica, &live)?;
+
+    // replayed from the log files, and then from the hint files of a merge
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    assert_live(&store, &live)?;
+    store.merge()?;
+    assert_live(&store, &live)?;
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    assert_live(&store, &live)?;
+
+    // a store of nothing but tombstones is empty
+    for key in &live {
+        store.remove(key.clone())?;
+    }
+    assert_live(&store, &[])?;
+    Ok(())
+}
+
+// A checkpoint loads instead of replaying the files it covers, and the writes flushed after it
+// are replayed on top, while the ones a crash lost are gone
+#[test]
+fn checkpoint_then_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        on_corruption: CorruptionPolicy::Fail,
+        write_flush_bytes: 1024 * 1024,
+        ..small_file_options()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+    store.checkpoint()?;
+    assert!(temp_dir.path().join("checkpoint").exists());
+    assert_eq!(store.pending_flush_bytes(), 0);
+
+    // flushed after the checkpoint
+    store.set("key2".to_owned(), "flushed".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key100".to_owned(), "flushed".to_owned())?;
+    store.flush()?;
+    // still buffered when the process dies
+    store.set("key4".to_owned(), "lost".to_owned())?;
+    store.set("key101".to_owned(), "lost".to_owned())?;
+    assert!(store.pending_flush_bytes() > 0);
+    std::mem::forget(store);
+
+    // the first entry, which the checkpoint covers, can't be replayed any more
+    corrupt_key_byte(&temp_dir.path().join("0.log"), 24);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options.clone())?;
+    let expected = |i: u64| match i {
+        1 | 3 | 101 => None,
+        2 | 100 => Some("flushed".to_owned()),
+        _ => Some(format!("value{}", i)),
+    };
+    for i in 0..102 {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
+    assert_eq!(store.len(), 99);
+    drop(store);
+
+    // without the checkpoint the corrupt entry is replayed
+    fs::rename(
+        temp_dir.path().join("checkpoint"),
+        temp_dir.path().join("checkpoint.saved"),
+    )?;
+    assert!(matches!(
+        BitcaskEngine::open_with_options(temp_dir.path(), options.clone()),
+        Err(KvStoreErr::CorruptEntry(0, 0))
+    ));
+    fs::rename(
+        temp_dir.path().join("checkpoint.saved"),
+        temp_dir.path().join("checkpoint"),
+    )?;
+
+    // a merge moves the entries, so it drops the checkpoint
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.log"))?;
+    file.seek(SeekFrom::Start(24))?;
+    file.write_all(b"k")?;
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+    store.merge()?;
+    assert!(!temp_dir.path().join("checkpoint").exists());
+    drop(store);
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), small_file_options())?;
+    for i in 0..102 {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
     Ok(())
 }
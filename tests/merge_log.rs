@@ -0,0 +1,102 @@
+// The logger is global to the process, so these tests live in their own binary
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::{BitcaskEngine, BitcaskOptions, KvsEngine, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tempfile::TempDir;
+
+// Keeps the messages of the info records logged by the crate
+struct CapturingLogger {
+    messages: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info && metadata.target().starts_with("kvs")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    messages: Mutex::new(Vec::new()),
+};
+
+fn logged(pattern: &str) -> Vec<String> {
+    LOGGER
+        .messages
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|message| message.contains(pattern))
+        .cloned()
+        .collect()
+}
+
+fn merge_trigger_events() -> Vec<String> {
+    logged("crossed the merge threshold")
+}
+
+// Wait until the merge worker finished `count` triggered merges, and return the triggers logged
+fn wait_for_merges(count: usize) -> Vec<String> {
+    let start = Instant::now();
+    while logged("triggered merge reclaimed").len() < count {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    merge_trigger_events()
+}
+
+// Overwrite `key` in a new log file, so the merge it triggers reclaims the old value
+fn overwrite_in_new_file(store: &BitcaskEngine, key: &str) -> Result<()> {
+    store.set(key.to_owned(), "v".repeat(150))?;
+    for i in 0..20 {
+        store.set(format!("{}-filler{}", key, i), "f".repeat(50))?;
+    }
+    store.set(key.to_owned(), "w".repeat(150))
+}
+
+#[test]
+fn merge_trigger_logged_once_per_merge() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions {
+        log_file_max_bytes: 1024,
+        merge_trigger_threshold: 100,
+        ..Default::default()
+    };
+    let store = BitcaskEngine::open_with_options(temp_dir.path(), options)?;
+
+    overwrite_in_new_file(&store, "a")?;
+    let events = wait_for_merges(1);
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0],
+        "useless value bytes 150 crossed the merge threshold 100, start merging"
+    );
+
+    // writes which leave nothing useless don't trigger another merge
+    for i in 0..20 {
+        store.set(format!("new{}", i), "n".repeat(50))?;
+    }
+    assert_eq!(merge_trigger_events().len(), 1);
+
+    overwrite_in_new_file(&store, "b")?;
+    assert_eq!(wait_for_merges(2).len(), 2);
+    assert_eq!(store.get("a".to_owned())?, Some("w".repeat(150)));
+    assert_eq!(store.get("b".to_owned())?, Some("w".repeat(150)));
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use std::io::Cursor;
+
+use kvs::Frame;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Bytes the frame format gives a meaning to, picked more often than the others
+const SPECIAL_BYTES: [u8; 23] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 0xff,
+];
+
+fn random_byte(rng: &mut StdRng) -> u8 {
+    if rng.gen_bool(0.5) {
+        SPECIAL_BYTES[rng.gen_range(0..SPECIAL_BYTES.len())]
+    } else {
+        rng.gen()
+    }
+}
+
+// some of them made of the characters which once delimited the fields
+fn random_text(rng: &mut StdRng) -> String {
+    let len = rng.gen_range(0..32);
+    if rng.gen_bool(0.2) {
+        return (0..len)
+            .map(|_| ['%', '#', '\n'][rng.gen_range(0..3)])
+            .collect();
+    }
+    (0..len).map(|_| rng.gen::<char>()).collect()
+}
+
+fn random_frame(rng: &mut StdRng) -> Frame {
+    match rng.gen_range(0..22) {
+        0 => Frame::Set(random_text(rng), random_text(rng)),
+        1 => Frame::Get(random_text(rng)),
+        2 => Frame::Remove(random_text(rng)),
+        3 => Frame::Value(random_text(rng)),
+        4 => Frame::Error(random_text(rng)),
+        5 => Frame::Null,
+        6 => Frame::Ok,
+        7 => Frame::Hello(random_text(rng)),
+        8 => Frame::PauseCompaction,
+        9 => Frame::ResumeCompaction,
+        10 => Frame::Watch(random_text(rng)),
+        11 => Frame::ExistsMany((0..rng.gen_range(0..8)).map(|_| random_text(rng)).collect()),
+        12 => Frame::Bitmap((0..rng.gen_range(0..32)).map(|_| rng.gen()).collect()),
+        13 => Frame::Increment(random_text(rng), rng.gen()),
+        14 => {
+            let frame = loop {
+                match random_frame(rng) {
+                    Frame::Request(..) | Frame::Traced(..) => continue,
+                    frame => break frame,
+                }
+            };
+            Frame::Request(rng.gen(), Box::new(frame))
+        }
+        15 => Frame::FullSync,
+        16 => Frame::Position(rng.gen(), rng.gen()),
+        17 => Frame::ContainsKey(random_text(rng)),
+        18 => {
+            let frame = loop {
+                match random_frame(rng) {
+                    Frame::Traced(..) => continue,
+                    frame => break frame,
+                }
+            };
+            Frame::Traced(rng.gen(), Box::new(frame))
+        }
+        19 => Frame::Dump(rng.gen()),
+        20 => Frame::Keys,
+        _ => Frame::Compressed((0..rng.gen_range(0..32)).map(|_| rng.gen()).collect()),
+    }
+}
+
+fn encode(frame: &Frame) -> Vec<u8> {
+    let mut buf = Vec::new();
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(frame.write(&mut buf))
+        .unwrap();
+    buf
+}
+
+// Feed `bytes` through check, parse and decompress the way `Connection` does,
+// and through parse alone, only errors may come out
+fn feed(bytes: &[u8]) {
+    let mut buf = Cursor::new(bytes);
+    if Frame::check(&mut buf).is_ok() {
+        buf.set_position(0);
+        if let Ok(frame) = Frame::parse(&mut buf) {
+            let _ = frame.decompress(1 << 20);
+        }
+    }
+    let _ = Frame::parse(&mut Cursor::new(bytes));
+}
+
+#[test]
+fn frame_round_trip() {
+    let mut rng = StdRng::seed_from_u64(975);
+    for _ in 0..10_000 {
+        let frame = random_frame(&mut rng);
+        let bytes = encode(&frame);
+        let mut buf = Cursor::new(&bytes[..]);
+        Frame::check(&mut buf).unwrap();
+        assert_eq!(buf.position() as usize, bytes.len());
+        buf.set_position(0);
+        let parsed = Frame::parse(&mut buf).unwrap();
+        assert_eq!(buf.position() as usize, bytes.len());
+        assert_eq!(parsed, frame);
+    }
+}
+
+#[test]
+fn arbitrary_bytes_never_panic() {
+    let mut rng = StdRng::seed_from_u64(975);
+    for _ in 0..100_000 {
+        let len = rng.gen_range(0..64);
+        let bytes: Vec<u8> = (0..len).map(|_| random_byte(&mut rng)).collect();
+        feed(&bytes);
+    }
+}
+
+#[test]
+fn mutated_frames_never_panic() {
+    let mut rng = StdRng::seed_from_u64(975);
+    for _ in 0..100_000 {
+        let mut bytes = encode(&random_frame(&mut rng));
+        for _ in 0..rng.gen_range(1..4) {
+            let at = rng.gen_range(0..bytes.len());
+            match rng.gen_range(0..3) {
+                0 => bytes[at] = random_byte(&mut rng),
+                1 => bytes.insert(at, random_byte(&mut rng)),
+                _ => bytes.truncate(at.max(1)),
+            }
+        }
+        feed(&bytes);
+    }
+}
+
+#[test]
+fn malformed_frames_are_errors() {
+    let cases: [&[u8]; 14] = [
+        // an unknown code
+        b"\x13",
+        // a set frame without a value
+        b"\x00\x00\x00\x00\x03key",
+        // a get frame whose key is cut off
+        b"\x01\x00\x00\x00\x04key",
+        // a field whose length overflows the frame
+        b"\x08\xff\xff\xff\xffab",
+        // a key which isn't utf-8
+        b"\x01\x00\x00\x00\x01\xff",
+        // an exists frame whose key is longer than the frame
+        b"\x0c\x00\x00\x00\x05\x00\x00\x00\x02k",
+        // an exists frame whose key length is cut off
+        b"\x0c\x00\x00\x00\x02\x00\x00",
+        // an increment frame whose delta isn't 8 bytes
+        b"\x0e\x00\x00\x00\x03key\x00\x00\x00\x03one",
+        // a request frame whose id is cut off
+        b"\x0f\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00\x01\x05",
+        // a request frame nested in a request frame
+        b"\x0f\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x12\x0f\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x01\x05",
+        // a request frame with bytes after its frame
+        b"\x0f\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x02\x05\x05",
+        // a traced frame nested in a traced frame
+        b"\x13\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x12\x13\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x01\x05",
+        // a traced frame in a request frame
+        b"\x0f\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x12\x13\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x01\x05",
+        // a position frame without an offset
+        b"\x11\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x03",
+    ];
+    for bytes in cases {
+        assert!(Frame::parse(&mut Cursor::new(bytes)).is_err());
+    }
+}
+
+#[test]
+fn separators_in_keys_and_values_round_trip() {
+    let frame = Frame::Set("a%b#c\n".to_owned(), "b%c#\n%\n#".to_owned());
+    let bytes = frame.to_bytes();
+    let mut buf = Cursor::new(&bytes[..]);
+    Frame::check(&mut buf).unwrap();
+    assert_eq!(buf.position() as usize, bytes.len());
+    buf.set_position(0);
+    assert_eq!(Frame::parse(&mut buf).unwrap(), frame);
+}
+
+#[test]
+fn frames_compare_equal() {
+    assert_eq!(
+        Frame::Set("key".to_owned(), "value".to_owned()),
+        Frame::Set("key".to_owned(), "value".to_owned())
+    );
+    assert_ne!(
+        Frame::Set("key".to_owned(), "value".to_owned()),
+        Frame::Set("key".to_owned(), "other".to_owned())
+    );
+    assert_ne!(
+        Frame::Get("key".to_owned()),
+        Frame::Remove("key".to_owned())
+    );
+    let frame = Frame::ExistsMany(vec!["a".to_owned(), "b".to_owned()]);
+    assert_eq!(frame.clone(), frame);
+}
+
+#[test]
+fn display_frames() {
+    let long = "x".repeat(100);
+    let cases = [
+        (
+            Frame::Set("key".to_owned(), "value".to_owned()),
+            r#"set "key" "value""#.to_owned(),
+        ),
+        (
+            Frame::Set("key".to_owned(), long.clone()),
+            format!(r#"set "key" "{}"..."#, "x".repeat(32)),
+        ),
+        (Frame::Get("key".to_owned()), r#"get "key""#.to_owned()),
+        (
+            Frame::Remove("key".to_owned()),
+            r#"remove "key""#.to_owned(),
+        ),
+        (Frame::Value("a%b".to_owned()), r#"value "a%b""#.to_owned()),
+        (Frame::Error("no".to_owned()), r#"error "no""#.to_owned()),
+        (Frame::Null, "null".to_owned()),
+        (Frame::Ok, "ok".to_owned()),
+        (
+            Frame::Hello("zstd".to_owned()),
+            r#"hello "zstd""#.to_owned(),
+        ),
+        (
+            Frame::Compressed(vec![0; 10]),
+            "compressed 10 bytes".to_owned(),
+        ),
+        (Frame::PauseCompaction, "pause compaction".to_owned()),
+        (Frame::ResumeCompaction, "resume compaction".to_owned()),
+        (
+            Frame::Watch("user:".to_owned()),
+            r#"watch "user:""#.to_owned(),
+        ),
+        (
+            Frame::ExistsMany(vec!["a".to_owned(), "b\n".to_owned()]),
+            r#"exists 2 keys ["a", "b\n"]"#.to_owned(),
+        ),
+        (
+            Frame::ExistsMany((0..6).map(|i| i.to_string()).collect()),
+            r#"exists 6 keys ["0", "1", "2", "3", ...]"#.to_owned(),
+        ),
+        (Frame::Bitmap(vec![1, 2]), "bitmap 2 bytes".to_owned()),
+        (
+            Frame::Increment("counter".to_owned(), -3),
+            r#"increment "counter" by -3"#.to_owned(),
+        ),
+        (
+            Frame::Request(7, Box::new(Frame::Increment("counter".to_owned(), 1))),
+            r#"request 7: increment "counter" by 1"#.to_owned(),
+        ),
+        (Frame::FullSync, "full sync".to_owned()),
+        (Frame::Position(3, 120), "position 3 at 120".to_owned()),
+        (
+            Frame::ContainsKey("key".to_owned()),
+            r#"contains "key""#.to_owned(),
+        ),
+        (
+            Frame::Traced(0xbeef, Box::new(Frame::Get("key".to_owned()))),
+            r#"trace 000000000000beef: get "key""#.to_owned(),
+        ),
+        (Frame::Dump(50), "dump 50 keys".to_owned()),
+        (Frame::Keys, "keys".to_owned()),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(frame.to_string(), expected);
+    }
+}
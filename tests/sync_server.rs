@@ -0,0 +1,111 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use kvs::{BitcaskEngine, Client, ClientBuilder, Connection, Frame, KvsEngine, SyncServer};
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+// Start a sync server on its own thread, in front of a fresh engine
+fn start_sync_server() -> (SocketAddr, Arc<BitcaskEngine>, TempDir) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = Arc::new(BitcaskEngine::open(temp_dir.path()).unwrap());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let engine = kv.clone();
+    thread::spawn(move || SyncServer::start(listener, engine));
+    (addr, kv, temp_dir)
+}
+
+#[tokio::test]
+async fn sync_server_round_trip() {
+    let (addr, kv, _temp_dir) = start_sync_server();
+    let mut client = Client::new(TcpStream::connect(addr).await.unwrap());
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        kv.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await.unwrap(), None);
+    assert_eq!(
+        client
+            .get_many(vec!["key1".to_owned(), "key2".to_owned()])
+            .await
+            .unwrap(),
+        vec![Some("value1".to_owned()), None]
+    );
+    assert_eq!(
+        client
+            .exists_many(vec!["key2".to_owned(), "key1".to_owned()])
+            .await
+            .unwrap(),
+        vec![false, true]
+    );
+    assert!(client.contains_key("key1".to_owned()).await.unwrap());
+    assert!(!client.contains_key("key2".to_owned()).await.unwrap());
+    client.remove("key1".to_owned()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+    assert!(client.remove("key1".to_owned()).await.is_err());
+    client.pause_compaction().await.unwrap();
+    client.resume_compaction().await.unwrap();
+
+    // compression is declined, so frames keep going uncompressed
+    let mut client = ClientBuilder::new()
+        .compression(true)
+        .connect(addr)
+        .await
+        .unwrap();
+    client
+        .set("key3".to_owned(), "value3".repeat(100))
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key3".to_owned()).await.unwrap(),
+        Some("value3".repeat(100))
+    );
+    assert!(client.watch("key".to_owned()).await.is_err());
+}
+
+#[tokio::test]
+async fn sync_server_refuses_compressed_frames() {
+    let (addr, kv, _temp_dir) = start_sync_server();
+    // compressed without asking, the sync server never agrees to compression
+    let mut conn = Connection::new(TcpStream::connect(addr).await.unwrap());
+    conn.set_compression(true);
+    conn.write_frame(Frame::Set("key1".to_owned(), "value1".repeat(100)))
+        .await
+        .unwrap();
+    assert!(!matches!(conn.read_frame().await, Ok(Some(_))));
+    assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
+}
+
+#[tokio::test]
+async fn sync_server_serves_connections_concurrently() {
+    let (addr, _kv, _temp_dir) = start_sync_server();
+    // a connection which sends nothing doesn't hold the others up
+    let _idle = TcpStream::connect(addr).await.unwrap();
+    let mut tasks = Vec::new();
+    for client_id in 0..8 {
+        tasks.push(tokio::spawn(async move {
+            let mut client = Client::new(TcpStream::connect(addr).await.unwrap());
+            for i in 0..50 {
+                let key = format!("client{}-key{}", client_id, i);
+                client
+                    .set(key.clone(), format!("value{}", i))
+                    .await
+                    .unwrap();
+                assert_eq!(client.get(key).await.unwrap(), Some(format!("value{}", i)));
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
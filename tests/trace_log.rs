@@ -0,0 +1,99 @@
+// The logger is global to the process, so these tests live in their own binary
+
+use std::sync::{Arc, Mutex};
+
+use kvs::{BitcaskEngine, ClientBuilder, Server};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// Keeps the targets and messages of the info records logged by the crate
+struct CapturingLogger {
+    records: Mutex<Vec<(String, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info && metadata.target().starts_with("kvs")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.records
+                .lock()
+                .unwrap()
+                .push((record.target().to_owned(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    records: Mutex::new(Vec::new()),
+};
+
+// The messages logged from `target` which contain `pattern`
+fn logged(target: &str, pattern: &str) -> Vec<String> {
+    LOGGER
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(logged_target, message)| logged_target == target && message.contains(pattern))
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn trace_id_logged_by_client_and_server() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv = BitcaskEngine::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::start(listener, Arc::new(kv)));
+
+    let mut client = ClientBuilder::new()
+        .trace_requests(true)
+        .connect(addr)
+        .await
+        .unwrap();
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    let set_trace = format!("[trace {:016x}]", client.last_trace_id().unwrap());
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    let get_trace = format!("[trace {:016x}]", client.last_trace_id().unwrap());
+    assert_ne!(set_trace, get_trace);
+
+    // the set shows up under its trace id on both sides, and nothing else does
+    assert_eq!(
+        logged("kvs::client", &set_trace),
+        vec![format!(
+            r#"{} client send a frame: set "key1" "value1""#,
+            set_trace
+        )]
+    );
+    assert_eq!(
+        logged("kvs::server", &set_trace),
+        vec![
+            format!(
+                r#"{} handler read a frame: set "key1" "value1" from socket"#,
+                set_trace
+            ),
+            format!("{} handler write a frame: ok to client", set_trace),
+        ]
+    );
+    assert_eq!(logged("kvs::server", &get_trace).len(), 2);
+
+    // untraced requests are sent as they are
+    let mut client = ClientBuilder::new().connect(addr).await.unwrap();
+    client.get("key1".to_owned()).await.unwrap();
+    assert_eq!(client.last_trace_id(), None);
+}